@@ -1,12 +1,19 @@
 use std::{
-    collections::{hash_map, BTreeMap, HashMap, HashSet},
+    collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     time::{Duration, SystemTime},
 };
 
-use crate::{utils, ConduitResult, Database, Error, Ruma};
+use crate::{
+    database::{pushers::Pusher, users::SlidingSyncConnection, watchers::WatchKey},
+    filter, server_server,
+    stateres::StateMap,
+    utils, ConduitResult, Database, Error, JsonRequest, PduEvent, Result, Ruma,
+};
 use keys::{upload_signatures, upload_signing_keys};
 use log::warn;
+use rocket::response::content::Json;
+use serde::{de::IgnoredAny, Deserialize};
 
 #[cfg(not(feature = "conduit_bin"))]
 use super::State;
@@ -34,7 +41,7 @@ use ruma::{
                 self, get_public_rooms, get_public_rooms_filtered, get_room_visibility,
                 set_room_visibility,
             },
-            filter::{self, create_filter, get_filter},
+            filter::{create_filter, get_filter},
             keys::{self, claim_keys, get_key_changes, get_keys, upload_keys},
             media::{create_content, get_content, get_content_thumbnail, get_media_config},
             membership::{
@@ -47,7 +54,7 @@ use ruma::{
             profile::{
                 get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
             },
-            push::{get_pushers, get_pushrules_all, set_pushrule, set_pushrule_enabled},
+            push::get_pushrules_all,
             read_marker::set_read_marker,
             redact::redact_event,
             room::{self, create_room, get_room_event},
@@ -74,7 +81,7 @@ use ruma::{
         },
         AnyEphemeralRoomEvent, AnyEvent, AnySyncEphemeralRoomEvent, BasicEvent, EventType,
     },
-    Raw, RoomAliasId, RoomId, RoomVersionId, UserId,
+    DeviceId, EventId, Raw, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
 
 const GUEST_NAME_LENGTH: usize = 10;
@@ -82,6 +89,10 @@ const DEVICE_ID_LENGTH: usize = 10;
 const TOKEN_LENGTH: usize = 256;
 const MXC_LENGTH: usize = 256;
 const SESSION_ID_LENGTH: usize = 256;
+const PEEK_LIMIT: usize = 100;
+const PEEK_TIMEOUT: u64 = 30_000;
+const NOTIFICATIONS_LIMIT: usize = 50;
+const SEARCH_LIMIT: usize = 100;
 
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/client/versions"))]
 pub fn get_supported_versions_route() -> ConduitResult<get_supported_versions::Response> {
@@ -144,10 +155,13 @@ pub fn register_route(
         ));
     }
 
-    // Validate user id
+    let is_guest = matches!(body.kind, Some(register::RegistrationKind::Guest));
+
+    // Validate user id, guests always get a random one
     let user_id = UserId::parse_with_server_name(
         body.username
             .clone()
+            .filter(|_| !is_guest)
             .unwrap_or_else(|| utils::random_string(GUEST_NAME_LENGTH))
             .to_lowercase(),
         db.globals.server_name(),
@@ -178,7 +192,9 @@ pub fn register_route(
         auth_error: None,
     };
 
-    if let Some(auth) = &body.auth {
+    if is_guest {
+        // Guests don't need to authenticate
+    } else if let Some(auth) = &body.auth {
         let (worked, uiaainfo) =
             db.uiaa
                 .try_auth(&user_id, "".into(), auth, &uiaainfo, &db.users, &db.globals)?;
@@ -192,10 +208,13 @@ pub fn register_route(
         return Err(Error::Uiaa(uiaainfo));
     }
 
-    let password = body.password.clone().unwrap_or_default();
-
     // Create user
-    db.users.create(&user_id, &password)?;
+    if is_guest {
+        db.users.create_guest(&user_id)?;
+    } else {
+        let password = body.password.clone().unwrap_or_default();
+        db.users.create(&user_id, &password)?;
+    }
 
     // Generate new device id if the user didn't specify one
     let device_id = body
@@ -328,6 +347,7 @@ pub fn logout_route(
     let device_id = body.device_id.as_ref().expect("user is authenticated");
 
     db.users.remove_device(&sender_id, device_id)?;
+    db.pushers.remove_pushers(&sender_id, Some(device_id))?;
 
     Ok(logout::Response.into())
 }
@@ -347,6 +367,7 @@ pub fn logout_all_route(
             db.users.remove_device(&sender_id, &device_id)?;
         }
     }
+    db.pushers.remove_pushers(&sender_id, None)?;
 
     Ok(logout_all::Response.into())
 }
@@ -403,6 +424,7 @@ pub fn change_password_route(
         .filter(|id| id != device_id)
     {
         db.users.remove_device(&sender_id, &id)?;
+        db.pushers.remove_pushers(&sender_id, Some(&id))?;
     }
 
     Ok(change_password::Response.into())
@@ -472,11 +494,14 @@ pub fn deactivate_route(
             Some(sender_id.to_string()),
             None,
             &db.globals,
+            &db.sending,
+            &db.account_data,
         )?;
     }
 
     // Remove devices and mark account as deactivated
     db.users.deactivate_account(&sender_id)?;
+    db.pushers.remove_pushers(&sender_id, None)?;
 
     Ok(deactivate::Response {
         id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
@@ -533,48 +558,402 @@ pub fn get_pushrules_all_route(
     .into())
 }
 
-#[cfg_attr(feature = "conduit_bin", put(
-    "/_matrix/client/r0/pushrules/<_>/<_>/<_>",
-    //data = "<body>"
-))]
-pub fn set_pushrule_route(//db: State<'_, Database>,
-    //body: Ruma<set_pushrule::Request>,
-) -> ConduitResult<set_pushrule::Response> {
-    // TODO
-    warn!("TODO: set_pushrule_route");
-    Ok(set_pushrule::Response.into())
+/// The kinds of push rules, in the order they are evaluated.
+const PUSH_RULE_KINDS: &[&str] = &["override", "content", "room", "sender", "underride"];
+
+/// Returns the push rules of the user as json, so rules of all kinds can be edited the same way.
+fn push_rules_json(db: &Database, user_id: &UserId) -> Result<serde_json::Value> {
+    let event = db
+        .account_data
+        .get::<ruma::events::push_rules::PushRulesEvent>(None, user_id, EventType::PushRules)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "PushRules event not found.",
+        ))?;
+
+    Ok(serde_json::to_value(&event.content.global).expect("Ruleset::to_value always works"))
+}
+
+fn save_push_rules(db: &Database, user_id: &UserId, ruleset: serde_json::Value) -> Result<()> {
+    let global = serde_json::from_value(ruleset)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid push rule."))?;
+
+    db.account_data.update(
+        None,
+        user_id,
+        EventType::PushRules,
+        &ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent { global },
+        },
+        &db.globals,
+    )
+}
+
+/// Checks the scope and kind from the path and returns the rules of that kind.
+fn push_rules_of_kind<'a>(
+    ruleset: &'a mut serde_json::Value,
+    scope: &str,
+    kind: &str,
+) -> Result<&'a mut Vec<serde_json::Value>> {
+    if scope != "global" {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only the global scope is supported.",
+        ));
+    }
+
+    if !PUSH_RULE_KINDS.contains(&kind) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Unknown push rule kind.",
+        ));
+    }
+
+    ruleset
+        .as_object_mut()
+        .and_then(|ruleset| {
+            ruleset
+                .entry(kind)
+                .or_insert_with(|| serde_json::json!([]))
+                .as_array_mut()
+        })
+        .ok_or_else(|| Error::bad_database("Invalid push rules in db."))
+}
+
+fn push_rule_position(rules: &[serde_json::Value], rule_id: &str) -> Option<usize> {
+    rules
+        .iter()
+        .position(|rule| rule.get("rule_id").and_then(|id| id.as_str()) == Some(rule_id))
+}
+
+fn find_push_rule<'a>(
+    rules: &'a mut Vec<serde_json::Value>,
+    rule_id: &str,
+) -> Result<&'a mut serde_json::Value> {
+    match push_rule_position(rules, rule_id) {
+        Some(index) => Ok(&mut rules[index]),
+        None => Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Push rule not found.",
+        )),
+    }
 }
 
 #[cfg_attr(
     feature = "conduit_bin",
-    put("/_matrix/client/r0/pushrules/<_>/<_>/<_>/enabled")
+    get(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>",
+        data = "<body>"
+    )
 )]
-pub fn set_pushrule_enabled_route() -> ConduitResult<set_pushrule_enabled::Response> {
-    // TODO
-    warn!("TODO: set_pushrule_enabled_route");
-    Ok(set_pushrule_enabled::Response.into())
+pub fn get_pushrule_route(
+    db: State<'_, Database>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    body: JsonRequest<IgnoredAny>,
+) -> Result<Json<String>> {
+    let mut ruleset = push_rules_json(&db, &body.sender_id)?;
+    let rules = push_rules_of_kind(&mut ruleset, &scope, &kind)?;
+
+    Ok(Json(find_push_rule(rules, &rule_id)?.to_string()))
 }
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/user/<_>/filter/<_>"))]
-pub fn get_filter_route() -> ConduitResult<get_filter::Response> {
-    // TODO
-    Ok(get_filter::Response {
-        filter: filter::FilterDefinition {
-            event_fields: None,
-            event_format: None,
-            account_data: None,
-            room: None,
-            presence: None,
-        },
+#[derive(Debug, Deserialize)]
+pub struct SetPushRuleRequest {
+    actions: Vec<ruma::push::Action>,
+    #[serde(default)]
+    conditions: Vec<ruma::push::PushCondition>,
+    pattern: Option<String>,
+}
+
+/// Creates or replaces a push rule of the user. New rules are more important than all other
+/// rules of the user, unless `before` or `after` name another rule of the user.
+#[cfg_attr(
+    feature = "conduit_bin",
+    put(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>?<before>&<after>",
+        data = "<body>"
+    )
+)]
+pub fn set_pushrule_route(
+    db: State<'_, Database>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    before: Option<String>,
+    after: Option<String>,
+    body: JsonRequest<SetPushRuleRequest>,
+) -> Result<Json<String>> {
+    let sender_id = &body.sender_id;
+
+    if rule_id.starts_with('.') || crate::push_rules::is_default_rule(sender_id, &kind, &rule_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Rule ids starting with a dot are reserved for server default rules.",
+        ));
     }
-    .into())
+
+    let mut rule = serde_json::json!({
+        "rule_id": rule_id,
+        "default": false,
+        "enabled": true,
+        "actions": body.actions,
+    });
+
+    match kind.as_str() {
+        "override" | "underride" => rule["conditions"] = serde_json::json!(body.conditions),
+        "content" => {
+            rule["pattern"] = body
+                .pattern
+                .clone()
+                .ok_or(Error::BadRequest(
+                    ErrorKind::MissingParam,
+                    "Content rules need a pattern.",
+                ))?
+                .into()
+        }
+        "room" => {
+            RoomId::try_from(rule_id.as_str()).map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Room rule ids must be room ids.")
+            })?;
+        }
+        "sender" => {
+            UserId::try_from(rule_id.as_str()).map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Sender rule ids must be user ids.")
+            })?;
+        }
+        _ => {}
+    }
+
+    let mut ruleset = push_rules_json(&db, sender_id)?;
+    let rules = push_rules_of_kind(&mut ruleset, &scope, &kind)?;
+
+    let previous = push_rule_position(rules, &rule_id);
+    if let Some(index) = previous {
+        rules.remove(index);
+    }
+
+    let index = match (&before, &after) {
+        (Some(other), _) | (None, Some(other)) => {
+            if crate::push_rules::is_default_rule(sender_id, &kind, other) {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Rules can't be positioned relative to server default rules.",
+                ));
+            }
+
+            let index = push_rule_position(rules, other).ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "The rule in `before` or `after` does not exist.",
+            ))?;
+
+            if before.is_some() {
+                index
+            } else {
+                index + 1
+            }
+        }
+        // Replaced rules keep their priority, new rules only come after the master rule
+        (None, None) => previous.unwrap_or_else(|| {
+            rules
+                .iter()
+                .take_while(|rule| {
+                    rule.get("rule_id").and_then(|id| id.as_str()) == Some(".m.rule.master")
+                })
+                .count()
+        }),
+    };
+    rules.insert(index, rule);
+
+    save_push_rules(&db, sender_id, ruleset)?;
+
+    Ok(Json("{}".to_owned()))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    delete(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>",
+        data = "<body>"
+    )
+)]
+pub fn delete_pushrule_route(
+    db: State<'_, Database>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    body: JsonRequest<IgnoredAny>,
+) -> Result<Json<String>> {
+    let sender_id = &body.sender_id;
+
+    if crate::push_rules::is_default_rule(sender_id, &kind, &rule_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server default push rules can't be deleted.",
+        ));
+    }
+
+    let mut ruleset = push_rules_json(&db, sender_id)?;
+    let rules = push_rules_of_kind(&mut ruleset, &scope, &kind)?;
+
+    let index = push_rule_position(rules, &rule_id).ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Push rule not found.",
+    ))?;
+    rules.remove(index);
+
+    save_push_rules(&db, sender_id, ruleset)?;
+
+    Ok(Json("{}".to_owned()))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>/enabled",
+        data = "<body>"
+    )
+)]
+pub fn get_pushrule_enabled_route(
+    db: State<'_, Database>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    body: JsonRequest<IgnoredAny>,
+) -> Result<Json<String>> {
+    let mut ruleset = push_rules_json(&db, &body.sender_id)?;
+    let rules = push_rules_of_kind(&mut ruleset, &scope, &kind)?;
+    let rule = find_push_rule(rules, &rule_id)?;
+
+    Ok(Json(
+        serde_json::json!({ "enabled": rule.get("enabled") }).to_string(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPushRuleEnabledRequest {
+    enabled: bool,
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>/enabled",
+        data = "<body>"
+    )
+)]
+pub fn set_pushrule_enabled_route(
+    db: State<'_, Database>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    body: JsonRequest<SetPushRuleEnabledRequest>,
+) -> Result<Json<String>> {
+    let mut ruleset = push_rules_json(&db, &body.sender_id)?;
+    let rules = push_rules_of_kind(&mut ruleset, &scope, &kind)?;
+    find_push_rule(rules, &rule_id)?["enabled"] = body.enabled.into();
+
+    save_push_rules(&db, &body.sender_id, ruleset)?;
+
+    Ok(Json("{}".to_owned()))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>/actions",
+        data = "<body>"
+    )
+)]
+pub fn get_pushrule_actions_route(
+    db: State<'_, Database>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    body: JsonRequest<IgnoredAny>,
+) -> Result<Json<String>> {
+    let mut ruleset = push_rules_json(&db, &body.sender_id)?;
+    let rules = push_rules_of_kind(&mut ruleset, &scope, &kind)?;
+    let rule = find_push_rule(rules, &rule_id)?;
+
+    Ok(Json(
+        serde_json::json!({ "actions": rule.get("actions") }).to_string(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPushRuleActionsRequest {
+    actions: Vec<ruma::push::Action>,
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>/actions",
+        data = "<body>"
+    )
+)]
+pub fn set_pushrule_actions_route(
+    db: State<'_, Database>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    body: JsonRequest<SetPushRuleActionsRequest>,
+) -> Result<Json<String>> {
+    let mut ruleset = push_rules_json(&db, &body.sender_id)?;
+    let rules = push_rules_of_kind(&mut ruleset, &scope, &kind)?;
+    find_push_rule(rules, &rule_id)?["actions"] = serde_json::json!(body.actions);
+
+    save_push_rules(&db, &body.sender_id, ruleset)?;
+
+    Ok(Json("{}".to_owned()))
 }
 
-#[cfg_attr(feature = "conduit_bin", post("/_matrix/client/r0/user/<_>/filter"))]
-pub fn create_filter_route() -> ConduitResult<create_filter::Response> {
-    // TODO
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/user/<_>/filter/<_>", data = "<body>")
+)]
+pub fn get_filter_route(
+    db: State<'_, Database>,
+    body: Ruma<get_filter::Request>,
+) -> ConduitResult<get_filter::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if sender_id != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only view your own filters.",
+        ));
+    }
+
+    let filter = db
+        .users
+        .get_filter(sender_id, &body.filter_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found."))?;
+
+    Ok(get_filter::Response { filter }.into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/user/<_>/filter", data = "<body>")
+)]
+pub fn create_filter_route(
+    db: State<'_, Database>,
+    body: Ruma<create_filter::Request>,
+) -> ConduitResult<create_filter::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if sender_id != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only create filters for yourself.",
+        ));
+    }
+
     Ok(create_filter::Response {
-        filter_id: utils::random_string(10),
+        filter_id: db.users.create_filter(sender_id, &body.filter)?,
     }
     .into())
 }
@@ -674,6 +1053,8 @@ pub fn set_displayname_route(
             Some(sender_id.to_string()),
             None,
             &db.globals,
+            &db.sending,
+            &db.account_data,
         )?;
 
         // Presence update
@@ -770,6 +1151,8 @@ pub fn set_avatar_url_route(
             Some(sender_id.to_string()),
             None,
             &db.globals,
+            &db.sending,
+            &db.account_data,
         )?;
 
         // Presence update
@@ -1177,11 +1560,15 @@ pub fn set_read_marker_route(
         db.rooms.edus.room_read_set(
             &body.room_id,
             &sender_id,
-            db.rooms.get_pdu_count(event)?.ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Event does not exist.",
-            ))?,
+            db.rooms
+                .get_pdu_live_count(event)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Event does not exist.",
+                ))?,
         )?;
+        db.rooms
+            .reset_notification_counts(&sender_id, &body.room_id)?;
 
         let mut user_receipts = BTreeMap::new();
         user_receipts.insert(
@@ -1209,6 +1596,25 @@ pub fn set_read_marker_route(
             )),
             &db.globals,
         )?;
+
+        let edu = serde_json::json!({
+            "edu_type": "m.receipt",
+            "content": {
+                body.room_id.to_string(): {
+                    "m.read": {
+                        sender_id.to_string(): {
+                            "data": { "ts": utils::millis_since_unix_epoch() },
+                            "event_ids": [event],
+                        }
+                    }
+                }
+            }
+        });
+        for server in db.rooms.room_servers(&body.room_id)? {
+            if &*server != db.globals.server_name() {
+                db.sending.send_edu(&server, &edu, &db.globals)?;
+            }
+        }
     }
     Ok(set_read_marker::Response.into())
 }
@@ -1237,6 +1643,20 @@ pub fn create_typing_event_route(
             .roomactive_remove(&sender_id, &body.room_id, &db.globals)?;
     }
 
+    let edu = serde_json::json!({
+        "edu_type": "m.typing",
+        "content": {
+            "room_id": body.room_id,
+            "user_id": sender_id,
+            "typing": body.typing,
+        }
+    });
+    for server in db.rooms.room_servers(&body.room_id)? {
+        if &*server != db.globals.server_name() {
+            db.sending.send_edu(&server, &edu, &db.globals)?;
+        }
+    }
+
     Ok(create_typing_event::Response.into())
 }
 
@@ -1289,6 +1709,8 @@ pub fn create_room_route(
         Some("".to_owned()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     // 2. Let the room creator join
@@ -1308,6 +1730,8 @@ pub fn create_room_route(
         Some(sender_id.to_string()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     // Figure out preset. We need it for power levels and preset specific events
@@ -1354,6 +1778,8 @@ pub fn create_room_route(
         Some("".to_owned()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     // 4. Events set by preset
@@ -1377,6 +1803,8 @@ pub fn create_room_route(
         Some("".to_owned()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     // 4.2 History Visibility
@@ -1392,6 +1820,8 @@ pub fn create_room_route(
         Some("".to_owned()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     // 4.3 Guest Access
@@ -1413,6 +1843,8 @@ pub fn create_room_route(
         Some("".to_owned()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     // 5. Events listed in initial_state
@@ -1438,6 +1870,8 @@ pub fn create_room_route(
             state_key.clone(),
             None,
             &db.globals,
+            &db.sending,
+            &db.account_data,
         )?;
     }
 
@@ -1456,6 +1890,8 @@ pub fn create_room_route(
             Some("".to_owned()),
             None,
             &db.globals,
+            &db.sending,
+            &db.account_data,
         )?;
     }
 
@@ -1472,6 +1908,8 @@ pub fn create_room_route(
             Some("".to_owned()),
             None,
             &db.globals,
+            &db.sending,
+            &db.account_data,
         )?;
     }
 
@@ -1493,6 +1931,8 @@ pub fn create_room_route(
             Some(user.to_string()),
             None,
             &db.globals,
+            &db.sending,
+            &db.account_data,
         )?;
     }
 
@@ -1550,6 +1990,8 @@ pub fn redact_event_route(
         None,
         Some(body.event_id.clone()),
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     Ok(redact_event::Response { event_id }.into())
@@ -1590,12 +2032,22 @@ pub fn delete_alias_route(
     feature = "conduit_bin",
     get("/_matrix/client/r0/directory/room/<_>", data = "<body>")
 )]
-pub fn get_alias_route(
+pub async fn get_alias_route(
     db: State<'_, Database>,
     body: Ruma<get_alias::Request>,
 ) -> ConduitResult<get_alias::Response> {
     if body.room_alias.server_name() != db.globals.server_name() {
-        todo!("ask remote server");
+        let (room_id, servers) =
+            server_server::query_remote_alias(&db.globals, &body.room_alias).await?;
+
+        return Ok(get_alias::Response {
+            room_id,
+            servers: servers
+                .into_iter()
+                .map(|server| server.to_string())
+                .collect(),
+        }
+        .into());
     }
 
     let room_id = db
@@ -1617,66 +2069,98 @@ pub fn get_alias_route(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/join", data = "<body>")
 )]
-pub fn join_room_by_id_route(
+pub async fn join_room_by_id_route(
     db: State<'_, Database>,
     body: Ruma<join_room_by_id::Request>,
 ) -> ConduitResult<join_room_by_id::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    // TODO: Ask a remote server if we don't have this room
-
-    let event = member::MemberEventContent {
-        membership: member::MembershipState::Join,
-        displayname: db.users.displayname(&sender_id)?,
-        avatar_url: db.users.avatar_url(&sender_id)?,
-        is_direct: None,
-        third_party_invite: None,
-    };
-
-    db.rooms.append_pdu(
-        body.room_id.clone(),
-        sender_id.clone(),
-        EventType::RoomMember,
-        serde_json::to_value(event).expect("event is valid, we just created it"),
-        None,
-        Some(sender_id.to_string()),
-        None,
-        &db.globals,
-    )?;
-
-    Ok(join_room_by_id::Response {
-        room_id: body.room_id.clone(),
-    }
-    .into())
+    join_room_by_id_helper(&db, sender_id, &body.room_id, &[]).await
 }
 
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/join/<_>", data = "<body>")
 )]
-pub fn join_room_by_id_or_alias_route(
+pub async fn join_room_by_id_or_alias_route(
     db: State<'_, Database>,
     body: Ruma<join_room_by_id_or_alias::Request>,
 ) -> ConduitResult<join_room_by_id_or_alias::Response> {
-    let room_id = RoomId::try_from(body.room_id_or_alias.clone()).or_else(|alias| {
-        Ok::<_, Error>(db.rooms.id_from_alias(&alias)?.ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Room not found (TODO: Federation).",
-        ))?)
-    })?;
-
-    let body = Ruma {
-        sender_id: body.sender_id.clone(),
-        device_id: body.device_id.clone(),
-        json_body: None,
-        body: join_room_by_id::Request {
-            room_id,
-            third_party_signed: body.third_party_signed.clone(),
-        },
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let (room_id, servers) = match RoomId::try_from(body.room_id_or_alias.clone()) {
+        Ok(room_id) => (room_id, Vec::new()),
+        Err(room_alias) if room_alias.server_name() != db.globals.server_name() => {
+            server_server::query_remote_alias(&db.globals, &room_alias).await?
+        }
+        Err(room_alias) => (
+            db.rooms
+                .id_from_alias(&room_alias)?
+                .ok_or(Error::BadRequest(ErrorKind::NotFound, "Room not found."))?,
+            Vec::new(),
+        ),
     };
 
     Ok(join_room_by_id_or_alias::Response {
-        room_id: join_room_by_id_route(db, body)?.0.room_id,
+        room_id: join_room_by_id_helper(&db, sender_id, &room_id, &servers)
+            .await?
+            .0
+            .room_id,
+    }
+    .into())
+}
+
+/// Joins the room locally if this server is in the room and over federation otherwise.
+/// `servers` are asked for help with the join in addition to the servers we know are in the room.
+async fn join_room_by_id_helper(
+    db: &Database,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    servers: &[Box<ServerName>],
+) -> ConduitResult<join_room_by_id::Response> {
+    if db.users.is_guest(sender_id)? && !db.rooms.guests_can_join(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to join this room.",
+        ));
+    }
+
+    let servers_in_room = db.rooms.room_servers(room_id)?;
+
+    // Our view of the room is only up to date if one of our users is still in it (or nobody is)
+    if db.rooms.exists(room_id)?
+        && (servers_in_room.is_empty() || servers_in_room.contains(db.globals.server_name()))
+    {
+        let event = member::MemberEventContent {
+            membership: member::MembershipState::Join,
+            displayname: db.users.displayname(&sender_id)?,
+            avatar_url: db.users.avatar_url(&sender_id)?,
+            is_direct: None,
+            third_party_invite: None,
+        };
+
+        db.rooms.append_pdu(
+            room_id.clone(),
+            sender_id.clone(),
+            EventType::RoomMember,
+            serde_json::to_value(event).expect("event is valid, we just created it"),
+            None,
+            Some(sender_id.to_string()),
+            None,
+            &db.globals,
+            &db.sending,
+            &db.account_data,
+        )?;
+    } else {
+        let mut servers = servers.to_vec();
+        servers.extend(servers_in_room);
+        servers.push(room_id.server_name().to_owned());
+
+        server_server::join_remote_room(db, sender_id, room_id, &servers).await?;
+    }
+
+    Ok(join_room_by_id::Response {
+        room_id: room_id.clone(),
     }
     .into())
 }
@@ -1720,6 +2204,8 @@ pub fn leave_room_route(
         Some(sender_id.to_string()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     Ok(leave_room::Response.into())
@@ -1765,6 +2251,8 @@ pub fn kick_user_route(
         Some(body.user_id.to_string()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     Ok(kick_user::Response.into())
@@ -1856,6 +2344,8 @@ pub fn ban_user_route(
         Some(body.user_id.to_string()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     Ok(ban_user::Response.into())
@@ -1900,6 +2390,8 @@ pub fn unban_user_route(
         Some(body.user_id.to_string()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     Ok(unban_user::Response.into())
@@ -1924,14 +2416,14 @@ pub fn forget_room_route(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/invite", data = "<body>")
 )]
-pub fn invite_user_route(
+pub async fn invite_user_route(
     db: State<'_, Database>,
     body: Ruma<invite_user::Request>,
 ) -> ConduitResult<invite_user::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
     if let invite_user::InvitationRecipient::UserId { user_id } = &body.recipient {
-        db.rooms.append_pdu(
+        let (mut pdu, mut pdu_json) = db.rooms.build_pdu(
             body.room_id.clone(),
             sender_id.clone(),
             EventType::RoomMember,
@@ -1949,9 +2441,21 @@ pub fn invite_user_route(
             &db.globals,
         )?;
 
-        Ok(invite_user::Response.into())
-    } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+        // The server of a remote user has to accept and sign the invite first
+        if user_id.server_name() != db.globals.server_name() {
+            let signatures = server_server::send_invite(&db, &pdu, &pdu_json).await?;
+
+            pdu.signatures = serde_json::from_value(signatures.clone())
+                .map_err(|_| Error::BadServerResponse("Server returned invalid signatures."))?;
+            pdu_json["signatures"] = signatures;
+        }
+
+        db.rooms
+            .append_signed_pdu(pdu, pdu_json, &db.globals, &db.sending, &db.account_data)?;
+
+        Ok(invite_user::Response.into())
+    } else {
+        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
     }
 }
 
@@ -2006,6 +2510,7 @@ pub async fn get_public_rooms_route(
             },
         sender_id,
         device_id,
+        sender_servername,
         json_body,
     } = body;
 
@@ -2026,6 +2531,7 @@ pub async fn get_public_rooms_route(
             },
             sender_id,
             device_id,
+            sender_servername,
             json_body,
         },
     )
@@ -2209,7 +2715,9 @@ pub fn get_member_events_route(
 ) -> ConduitResult<get_member_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    if !db.rooms.is_joined(sender_id, &body.room_id)?
+        && !db.rooms.is_world_readable(&body.room_id)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
@@ -2249,19 +2757,24 @@ pub fn get_room_event_route(
 ) -> ConduitResult<get_room_event::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    let pdu = db
+        .rooms
+        .get_pdu(&body.event_id)?
+        .filter(|pdu| pdu.room_id == body.room_id)
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+
+    if !db
+        .rooms
+        .user_can_see_event(sender_id, &body.room_id, &body.event_id)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
+            "You don't have permission to view this event.",
         ));
     }
 
     Ok(get_room_event::Response {
-        event: db
-            .rooms
-            .get_pdu(&body.event_id)?
-            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?
-            .to_room_event(),
+        event: pdu.to_room_event(),
     }
     .into())
 }
@@ -2293,6 +2806,8 @@ pub fn create_message_event_route(
         None,
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
     Ok(create_message_event::Response { event_id }.into())
@@ -2352,8 +2867,46 @@ pub fn create_state_event_for_key_route(
         Some(body.state_key.clone()),
         None,
         &db.globals,
+        &db.sending,
+        &db.account_data,
     )?;
 
+    // Guests have to leave if they are not allowed in the room anymore
+    if body.event_type == EventType::RoomGuestAccess && !db.rooms.guests_can_join(&body.room_id)? {
+        for user_id in db.rooms.room_members(&body.room_id).collect::<Vec<_>>() {
+            let user_id = user_id?;
+            if user_id.server_name() != db.globals.server_name() || !db.users.is_guest(&user_id)? {
+                continue;
+            }
+
+            let event = member::MemberEventContent {
+                membership: member::MembershipState::Leave,
+                displayname: None,
+                avatar_url: None,
+                is_direct: None,
+                third_party_invite: None,
+            };
+
+            if let Err(e) = db.rooms.append_pdu(
+                body.room_id.clone(),
+                sender_id.clone(),
+                EventType::RoomMember,
+                serde_json::to_value(event).expect("event is valid, we just created it"),
+                None,
+                Some(user_id.to_string()),
+                None,
+                &db.globals,
+                &db.sending,
+                &db.account_data,
+            ) {
+                warn!(
+                    "Could not remove guest {} from {}: {}",
+                    user_id, body.room_id, e
+                );
+            }
+        }
+    }
+
     Ok(create_state_event_for_key::Response { event_id }.into())
 }
 
@@ -2375,6 +2928,7 @@ pub fn create_state_event_for_empty_key_route(
             },
         sender_id,
         device_id,
+        sender_servername,
         json_body,
     } = body;
 
@@ -2390,6 +2944,7 @@ pub fn create_state_event_for_empty_key_route(
                 },
                 sender_id,
                 device_id,
+                sender_servername,
                 json_body,
             },
         )?
@@ -2409,7 +2964,9 @@ pub fn get_state_events_route(
 ) -> ConduitResult<get_state_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    if !db.rooms.is_joined(sender_id, &body.room_id)?
+        && !db.rooms.is_world_readable(&body.room_id)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view the room state.",
@@ -2437,7 +2994,9 @@ pub fn get_state_events_for_key_route(
 ) -> ConduitResult<get_state_events_for_key::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    if !db.rooms.is_joined(sender_id, &body.room_id)?
+        && !db.rooms.is_world_readable(&body.room_id)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view the room state.",
@@ -2469,7 +3028,9 @@ pub fn get_state_events_for_empty_key_route(
 ) -> ConduitResult<get_state_events_for_empty_key::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    if !db.rooms.is_joined(sender_id, &body.room_id)?
+        && !db.rooms.is_world_readable(&body.room_id)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view the room state.",
@@ -2491,6 +3052,97 @@ pub fn get_state_events_for_empty_key_route(
     .into())
 }
 
+/// Returns the filter of a sync request, which is either sent with the request or the id of a
+/// filter the user created before.
+fn sync_filter(
+    db: &Database,
+    user_id: &UserId,
+    filter: &Option<sync_events::Filter>,
+) -> Result<filter::SyncFilter> {
+    match filter {
+        Some(sync_events::Filter::FilterDefinition(definition)) => {
+            Ok(filter::from_ruma(definition))
+        }
+        Some(sync_events::Filter::FilterId(filter_id)) => Ok(filter::from_ruma(
+            &db.users
+                .get_filter(user_id, filter_id)?
+                .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found."))?,
+        )),
+        None => Ok(filter::SyncFilter::default()),
+    }
+}
+
+/// Returns the member events of `senders` for filters with lazy loading. Members that were
+/// already sent to the device are skipped, unless the filter includes redundant members.
+///
+/// The member events are taken from `state`, or the current state if it's `None`.
+fn lazy_loaded_members<'a>(
+    db: &Database,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    room_id: &RoomId,
+    filter: &filter::RoomEventFilter,
+    state: Option<&StateMap<EventId>>,
+    senders: impl IntoIterator<Item = &'a UserId>,
+) -> Result<Vec<PduEvent>> {
+    let mut members = Vec::new();
+    let mut sent = Vec::new();
+
+    for sender in senders.into_iter().collect::<HashSet<_>>() {
+        if !filter.include_redundant_members
+            && db
+                .rooms
+                .lazy_load_was_sent_before(user_id, device_id, room_id, sender)?
+        {
+            continue;
+        }
+
+        let member = match state {
+            Some(state) => state
+                .get(&(EventType::RoomMember, sender.to_string()))
+                .map_or(Ok(None), |event_id| db.rooms.get_pdu(event_id))?,
+            None => db
+                .rooms
+                .room_state_get(room_id, &EventType::RoomMember, sender.as_str())?,
+        };
+
+        if let Some(member) = member {
+            members.push(member);
+            sent.push(sender);
+        }
+    }
+
+    db.rooms
+        .lazy_load_mark_sent(user_id, device_id, room_id, sent)?;
+
+    Ok(members)
+}
+
+/// Returns the ids of the room state after the last event before `until`. The current state is
+/// used if there is no snapshot of that state.
+fn state_before_token(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+    until: u64,
+) -> Result<StateMap<EventId>> {
+    let event_id = match db.rooms.pdus_until(user_id, room_id, until).next() {
+        Some(r) => r?.1.event_id,
+        // The room didn't exist yet
+        None => return Ok(StateMap::new()),
+    };
+
+    match db.rooms.state_at(&event_id)? {
+        Some(state) => Ok(state),
+        None => Ok(db
+            .rooms
+            .room_state_full(room_id)?
+            .into_iter()
+            .map(|(key, pdu)| (key, pdu.event_id))
+            .collect()),
+    }
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/sync", data = "<body>")
@@ -2505,8 +3157,41 @@ pub async fn sync_events_route(
     // TODO: match body.set_presence {
     db.rooms.edus.ping_presence(&sender_id)?;
 
-    // Setup watchers, so if there's no response, we can wait for them
-    let watcher = db.watch(sender_id, device_id);
+    // Subscribe before looking for changes, so nothing that happens in between is missed
+    let watch = db.watch(sender_id, device_id);
+
+    let mut delay = tokio::time::delay_for(body.timeout.unwrap_or_default());
+
+    loop {
+        let response = sync_helper(&db, sender_id, device_id, &body)?;
+
+        if body.full_state
+            || !response.rooms.is_empty()
+            || !response.presence.is_empty()
+            || !response.account_data.is_empty()
+            || !response.device_lists.is_empty()
+            || !response.device_one_time_keys_count.is_empty()
+            || !response.to_device.is_empty()
+        {
+            return Ok(response.into());
+        }
+
+        // Wait until something changes or the client doesn't want to wait anymore
+        tokio::select! {
+            _ = &mut delay => return Ok(response.into()),
+            _ = watch.wait() => {}
+        }
+    }
+}
+
+/// Builds the sync response for a device.
+fn sync_helper(
+    db: &Database,
+    sender_id: &UserId,
+    device_id: &DeviceId,
+    body: &sync_events::Request,
+) -> Result<sync_events::Response> {
+    let filter = sync_filter(db, sender_id, &body.filter)?;
 
     let next_batch = db.globals.current_count()?.to_string();
 
@@ -2518,46 +3203,84 @@ pub async fn sync_events_route(
         .unwrap_or(0);
 
     let mut presence_updates = HashMap::new();
-    let mut device_list_updates = HashSet::new();
+
+    // The client doesn't have any members anymore
+    if since == 0 {
+        db.rooms.lazy_load_reset(sender_id, device_id)?;
+    }
 
     for room_id in db.rooms.rooms_joined(&sender_id) {
         let room_id = room_id?;
 
-        let mut non_timeline_pdus = db
+        // Take presence updates from this room
+        for (user_id, presence) in
+            db.rooms
+                .edus
+                .presence_since(&room_id, since, &db.rooms, &db.globals)?
+        {
+            match presence_updates.entry(user_id) {
+                hash_map::Entry::Vacant(v) => {
+                    v.insert(presence);
+                }
+                hash_map::Entry::Occupied(mut o) => {
+                    let p = o.get_mut();
+
+                    // Update existing presence event with more info
+                    p.content.presence = presence.content.presence;
+                    if let Some(status_msg) = presence.content.status_msg {
+                        p.content.status_msg = Some(status_msg);
+                    }
+                    if let Some(last_active_ago) = presence.content.last_active_ago {
+                        p.content.last_active_ago = Some(last_active_ago);
+                    }
+                    if let Some(displayname) = presence.content.displayname {
+                        p.content.displayname = Some(displayname);
+                    }
+                    if let Some(avatar_url) = presence.content.avatar_url {
+                        p.content.avatar_url = Some(avatar_url);
+                    }
+                    if let Some(currently_active) = presence.content.currently_active {
+                        p.content.currently_active = Some(currently_active);
+                    }
+                }
+            }
+        }
+
+        if !filter.room.allows_room(&room_id) {
+            continue;
+        }
+
+        let timeline_limit = filter
+            .room
+            .timeline
+            .limit_or(filter::DEFAULT_TIMELINE_LIMIT);
+
+        // Take the last events for the timeline, and one more to see if we skipped some
+        let mut timeline_pdus = db
             .rooms
             .pdus_since(&sender_id, &room_id, since)?
-            .filter_map(|r| r.ok()); // Filter out buggy events
-
-        // Take the last 10 events for the timeline
-        let timeline_pdus = non_timeline_pdus
-            .by_ref()
-            .rev()
-            .take(10)
-            .collect::<Vec<_>>()
-            .into_iter()
+            .filter_map(|r| r.ok()) // Filter out buggy events
+            .filter(|pdu| filter.room.timeline.allows_pdu(pdu))
+            .filter(|pdu| {
+                db.rooms
+                    .user_can_see_event(sender_id, &room_id, &pdu.event_id)
+                    .unwrap_or(false)
+            })
             .rev()
+            .take(timeline_limit + 1)
             .collect::<Vec<_>>();
 
-        // They /sync response doesn't always return all messages, so we say the output is
-        // limited unless there are events in non_timeline_pdus
-        //let mut limited = false;
-
-        let mut state_pdus = Vec::new();
-        for pdu in non_timeline_pdus {
-            if pdu.state_key.is_some() {
-                state_pdus.push(pdu);
-            }
-        }
+        let limited = timeline_pdus.len() > timeline_limit;
+        timeline_pdus.truncate(timeline_limit);
+        timeline_pdus.reverse();
 
         let mut send_member_count = false;
         let mut joined_since_last_sync = false;
-        let mut send_notification_counts = false;
         for pdu in db
             .rooms
             .pdus_since(&sender_id, &room_id, since)?
             .filter_map(|r| r.ok())
         {
-            send_notification_counts = true;
             if pdu.kind == EventType::RoomMember {
                 send_member_count = true;
                 if !joined_since_last_sync && pdu.state_key == Some(sender_id.to_string()) {
@@ -2662,40 +3385,71 @@ pub async fn sync_events_route(
             (None, None, Vec::new())
         };
 
-        let notification_count = if send_notification_counts {
-            if let Some(last_read) = db.rooms.edus.room_read_get(&room_id, &sender_id)? {
-                Some(
-                    (db.rooms
-                        .pdus_since(&sender_id, &room_id, last_read)?
-                        .filter_map(|pdu| pdu.ok()) // Filter out buggy events
-                        .filter(|pdu| {
-                            matches!(
-                                pdu.kind.clone(),
-                                EventType::RoomMessage | EventType::RoomEncrypted
-                            )
-                        })
-                        .count() as u32)
-                        .into(),
-                )
-            } else {
-                None
-            }
+        let timeline_start = match timeline_pdus.first() {
+            Some(pdu) => db
+                .rooms
+                .get_pdu_count(&pdu.event_id)?
+                .ok_or_else(|| Error::bad_database("Can't find count from event in db."))?,
+            None => db.globals.current_count()? + 1,
+        };
+
+        // Clients can use this to load the events we skipped
+        let prev_batch = Some(timeline_start.to_string());
+
+        let lazy_load_members = filter.room.state.lazy_load_members;
+        let full_state = since == 0 || joined_since_last_sync || body.full_state;
+
+        let start_state = if full_state || limited || lazy_load_members {
+            state_before_token(db, sender_id, &room_id, timeline_start)?
         } else {
-            None
+            StateMap::new()
         };
 
-        let prev_batch = timeline_pdus.first().map_or(Ok::<_, Error>(None), |e| {
-            Ok(Some(
-                db.rooms
-                    .get_pdu_count(&e.event_id)?
-                    .ok_or_else(|| Error::bad_database("Can't find count from event in db."))?
-                    .to_string(),
-            ))
-        })?;
+        // The client already knows the state at `since`, so it only needs the changes if there
+        // are events missing between `since` and the timeline
+        let state_ids = if full_state {
+            start_state.values().cloned().collect::<Vec<_>>()
+        } else if limited {
+            let since_state = state_before_token(db, sender_id, &room_id, since + 1)?;
+            start_state
+                .iter()
+                .filter(|(key, event_id)| since_state.get(key) != Some(event_id))
+                .map(|(_, event_id)| event_id.clone())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut state_events = state_ids
+            .iter()
+            .filter_map(|event_id| db.rooms.get_pdu(event_id).ok()?)
+            // Lazy loaded members are added below
+            .filter(|pdu| !lazy_load_members || pdu.kind != EventType::RoomMember)
+            .filter(|pdu| filter.room.state.allows_pdu(pdu))
+            .take(filter.room.state.limit_or(usize::MAX))
+            .collect::<Vec<_>>();
+
+        if lazy_load_members {
+            // The client always needs to know its own membership
+            let senders = timeline_pdus
+                .iter()
+                .map(|pdu| &pdu.sender)
+                .chain(Some(sender_id));
+
+            state_events.extend(lazy_loaded_members(
+                db,
+                sender_id,
+                device_id,
+                &room_id,
+                &filter.room.state,
+                Some(&start_state),
+                senders,
+            )?);
+        }
 
         let room_events = timeline_pdus
             .into_iter()
-            .map(|pdu| pdu.to_sync_room_event())
+            .map(|pdu| filter.format_event(pdu.to_sync_room_event()))
             .collect::<Vec<_>>();
 
         let mut edus = db
@@ -2722,12 +3476,20 @@ pub async fn sync_events_route(
             );
         }
 
+        let edus = edus
+            .into_iter()
+            .filter(|edu| filter.room.ephemeral.allows_raw(edu))
+            .take(filter.room.ephemeral.limit_or(usize::MAX))
+            .collect();
+
         let joined_room = sync_events::JoinedRoom {
             account_data: sync_events::AccountData {
                 events: db
                     .account_data
                     .changes_since(Some(&room_id), &sender_id, since)?
                     .into_iter()
+                    .filter(|(_, v)| filter.room.account_data.allows_raw(v))
+                    .take(filter.room.account_data.limit_or(usize::MAX))
                     .filter_map(|(_, v)| {
                         serde_json::from_str(v.json().get())
                             .map_err(|_| Error::bad_database("Invalid account event in database."))
@@ -2741,25 +3503,23 @@ pub async fn sync_events_route(
                 invited_member_count: invited_member_count.map(|n| (n as u32).into()),
             },
             unread_notifications: sync_events::UnreadNotificationsCount {
-                highlight_count: None,
-                notification_count,
+                highlight_count: Some(
+                    (db.rooms.highlight_count(sender_id, &room_id)? as u32).into(),
+                ),
+                notification_count: Some(
+                    (db.rooms.notification_count(sender_id, &room_id)? as u32).into(),
+                ),
             },
             timeline: sync_events::Timeline {
-                limited: false || joined_since_last_sync,
+                limited,
                 prev_batch,
                 events: room_events,
             },
-            // TODO: state before timeline
             state: sync_events::State {
-                events: if joined_since_last_sync {
-                    db.rooms
-                        .room_state_full(&room_id)?
-                        .into_iter()
-                        .map(|(_, pdu)| pdu.to_sync_state_event())
-                        .collect()
-                } else {
-                    Vec::new()
-                },
+                events: state_events
+                    .into_iter()
+                    .map(|pdu| filter.format_event(pdu.to_sync_state_event()))
+                    .collect(),
             },
             ephemeral: sync_events::Ephemeral { events: edus },
         };
@@ -2767,91 +3527,102 @@ pub async fn sync_events_route(
         if !joined_room.is_empty() {
             joined_rooms.insert(room_id.clone(), joined_room);
         }
-
-        // Look for device list updates in this room
-        device_list_updates.extend(
-            db.users
-                .keys_changed(&room_id, since, None)
-                .filter_map(|r| r.ok()),
-        );
-
-        // Take presence updates from this room
-        for (user_id, presence) in
-            db.rooms
-                .edus
-                .presence_since(&room_id, since, &db.rooms, &db.globals)?
-        {
-            match presence_updates.entry(user_id) {
-                hash_map::Entry::Vacant(v) => {
-                    v.insert(presence);
-                }
-                hash_map::Entry::Occupied(mut o) => {
-                    let p = o.get_mut();
-
-                    // Update existing presence event with more info
-                    p.content.presence = presence.content.presence;
-                    if let Some(status_msg) = presence.content.status_msg {
-                        p.content.status_msg = Some(status_msg);
-                    }
-                    if let Some(last_active_ago) = presence.content.last_active_ago {
-                        p.content.last_active_ago = Some(last_active_ago);
-                    }
-                    if let Some(displayname) = presence.content.displayname {
-                        p.content.displayname = Some(displayname);
-                    }
-                    if let Some(avatar_url) = presence.content.avatar_url {
-                        p.content.avatar_url = Some(avatar_url);
-                    }
-                    if let Some(currently_active) = presence.content.currently_active {
-                        p.content.currently_active = Some(currently_active);
-                    }
-                }
-            }
-        }
     }
 
     let mut left_rooms = BTreeMap::new();
     for room_id in db.rooms.rooms_left(&sender_id) {
         let room_id = room_id?;
-        let pdus = db.rooms.pdus_since(&sender_id, &room_id, since)?;
-        let room_events = pdus
-            .filter_map(|pdu| pdu.ok()) // Filter out buggy events
-            .map(|pdu| pdu.to_sync_room_event())
-            .collect();
+        if !filter.room.allows_room(&room_id) {
+            continue;
+        }
 
-        // TODO: Only until leave point
-        let mut edus = db
+        // The membership event that made us leave is the last event we are allowed to see
+        let leave_pdu = match db.rooms.room_state_get(
+            &room_id,
+            &EventType::RoomMember,
+            &sender_id.to_string(),
+        )? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+        let leave_count = match db.rooms.get_pdu_live_count(&leave_pdu.event_id)? {
+            Some(count) => count,
+            None => continue,
+        };
+
+        // The client was already told about rooms we left before the last sync. Initial syncs
+        // only contain left rooms if the filter asks for them
+        if leave_count <= since || (since == 0 && !filter.room.include_leave) {
+            continue;
+        }
+
+        let timeline_limit = filter
+            .room
+            .timeline
+            .limit_or(filter::DEFAULT_TIMELINE_LIMIT);
+
+        let mut timeline_pdus = db
             .rooms
-            .edus
-            .roomlatests_since(&room_id, since)?
+            .pdus_until(&sender_id, &room_id, leave_count + 1)
             .filter_map(|r| r.ok()) // Filter out buggy events
+            .take_while(|(count, _)| *count > since)
+            // The leave event is always sent, it contains the reason if we were kicked or banned
+            .filter(|(_, pdu)| {
+                pdu.event_id == leave_pdu.event_id
+                    || filter.room.timeline.allows_pdu(pdu)
+                        && db
+                            .rooms
+                            .user_can_see_event(sender_id, &room_id, &pdu.event_id)
+                            .unwrap_or(false)
+            })
+            .take(timeline_limit + 1)
             .collect::<Vec<_>>();
 
-        if db
-            .rooms
-            .edus
-            .last_roomactive_update(&room_id, &db.globals)?
-            > since
-        {
-            edus.push(
-                serde_json::from_str(
-                    &serde_json::to_string(&AnySyncEphemeralRoomEvent::Typing(
-                        db.rooms.edus.roomactives_all(&room_id)?,
-                    ))
-                    .expect("event is valid, we just created it"),
-                )
-                .expect("event is valid, we just created it"),
-            );
-        }
+        let limited = timeline_pdus.len() > timeline_limit;
+        timeline_pdus.truncate(timeline_limit);
+        timeline_pdus.reverse();
+
+        let timeline_start = timeline_pdus
+            .first()
+            .map_or(leave_count + 1, |(count, _)| *count);
+
+        let state_ids = if since == 0 {
+            state_before_token(db, sender_id, &room_id, timeline_start)?
+                .into_iter()
+                .map(|(_, event_id)| event_id)
+                .collect::<Vec<_>>()
+        } else if limited {
+            let since_state = state_before_token(db, sender_id, &room_id, since + 1)?;
+            state_before_token(db, sender_id, &room_id, timeline_start)?
+                .into_iter()
+                .filter(|(key, event_id)| since_state.get(key) != Some(event_id))
+                .map(|(_, event_id)| event_id)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let state_events = state_ids
+            .iter()
+            .filter_map(|event_id| db.rooms.get_pdu(event_id).ok()?)
+            .filter(|pdu| filter.room.state.allows_pdu(pdu))
+            .take(filter.room.state.limit_or(usize::MAX))
+            .map(|pdu| filter.format_event(pdu.to_sync_state_event()))
+            .collect();
 
         let left_room = sync_events::LeftRoom {
             account_data: sync_events::AccountData { events: Vec::new() },
             timeline: sync_events::Timeline {
-                limited: false,
-                prev_batch: Some(next_batch.clone()),
-                events: room_events,
+                limited,
+                prev_batch: Some(timeline_start.to_string()),
+                events: timeline_pdus
+                    .into_iter()
+                    .map(|(_, pdu)| filter.format_event(pdu.to_sync_room_event()))
+                    .collect(),
+            },
+            state: sync_events::State {
+                events: state_events,
             },
-            state: sync_events::State { events: Vec::new() },
         };
 
         if !left_room.is_empty() {
@@ -2862,15 +3633,24 @@ pub async fn sync_events_route(
     let mut invited_rooms = BTreeMap::new();
     for room_id in db.rooms.rooms_invited(&sender_id) {
         let room_id = room_id?;
+        if !filter.room.allows_room(&room_id) {
+            continue;
+        }
+
+        // Invites from other servers come with their own stripped state
+        let invite_state = match db.rooms.invite_state(&sender_id, &room_id)? {
+            Some(invite_state) => invite_state,
+            None => db
+                .rooms
+                .room_state_full(&room_id)?
+                .into_iter()
+                .map(|(_, pdu)| pdu.to_stripped_state_event())
+                .collect(),
+        };
 
         let invited_room = sync_events::InvitedRoom {
             invite_state: sync_events::InviteState {
-                events: db
-                    .rooms
-                    .room_state_full(&room_id)?
-                    .into_iter()
-                    .map(|(_, pdu)| pdu.to_stripped_state_event())
-                    .collect(),
+                events: invite_state,
             },
         };
 
@@ -2879,11 +3659,14 @@ pub async fn sync_events_route(
         }
     }
 
+    let (device_lists_changed, device_lists_left) =
+        device_list_changes(db, sender_id, since, None)?;
+
     // Remove all to-device events the device received *last time*
     db.users
         .remove_to_device_events(sender_id, device_id, since)?;
 
-    let response = sync_events::Response {
+    Ok(sync_events::Response {
         next_batch,
         rooms: sync_events::Rooms {
             leave: left_rooms,
@@ -2891,17 +3674,25 @@ pub async fn sync_events_route(
             invite: invited_rooms,
         },
         presence: sync_events::Presence {
-            events: presence_updates
-                .into_iter()
-                .map(|(_, v)| Raw::from(v))
-                .collect(),
+            events: filter.presence.apply(
+                presence_updates
+                    .into_iter()
+                    .map(|(_, v)| Raw::from(v))
+                    .collect(),
+            ),
         },
         account_data: sync_events::AccountData {
-            events: db
+            events: filter
                 .account_data
-                .changes_since(None, &sender_id, since)?
+                .apply(
+                    db.account_data
+                        .changes_since(None, &sender_id, since)?
+                        .into_iter()
+                        .map(|(_, v)| v)
+                        .collect(),
+                )
                 .into_iter()
-                .filter_map(|(_, v)| {
+                .filter_map(|v| {
                     serde_json::from_str(v.json().get())
                         .map_err(|_| Error::bad_database("Invalid account event in database."))
                         .ok()
@@ -2909,8 +3700,8 @@ pub async fn sync_events_route(
                 .collect::<Vec<_>>(),
         },
         device_lists: sync_events::DeviceLists {
-            changed: device_list_updates.into_iter().collect(),
-            left: Vec::new(), // TODO
+            changed: device_lists_changed.into_iter().collect(),
+            left: device_lists_left.into_iter().collect(),
         },
         device_one_time_keys_count: if db.users.last_one_time_keys_update(sender_id)? > since {
             db.users.count_one_time_keys(sender_id, device_id)?
@@ -2920,50 +3711,531 @@ pub async fn sync_events_route(
         to_device: sync_events::ToDevice {
             events: db.users.get_to_device_events(sender_id, device_id)?,
         },
-    };
+    })
+}
 
-    // TODO: Retry the endpoint instead of returning (waiting for #118)
-    if !body.full_state
-        && response.rooms.is_empty()
-        && response.presence.is_empty()
-        && response.account_data.is_empty()
-        && response.device_lists.is_empty()
-        && response.device_one_time_keys_count.is_empty()
-        && response.to_device.is_empty()
+/// Returns new events of a room. Clients use this to peek into rooms they didn't join.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/r0/events?<from>&<timeout>&<room_id>",
+        data = "<body>"
+    )
+)]
+pub async fn peek_events_route(
+    db: State<'_, Database>,
+    from: Option<String>,
+    timeout: Option<u64>,
+    room_id: String,
+    body: JsonRequest<IgnoredAny>,
+) -> Result<Json<String>> {
+    let sender_id = &body.sender_id;
+
+    let room_id = RoomId::try_from(room_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+
+    if !db
+        .rooms
+        .can_read_history(sender_id, &room_id, db.users.is_guest(sender_id)?)?
     {
-        // Hang a few seconds so requests are not spammed
-        // Stop hanging if new info arrives
-        let mut duration = body.timeout.unwrap_or(Duration::default());
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    // Without a token the client only gets the events that are sent from now on
+    let from = match from {
+        Some(from) => from
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?,
+        None => db.globals.current_count()?,
+    };
+
+    // Subscribe before looking for events, so nothing that happens in between is missed
+    let watch = db
+        .globals
+        .watchers()
+        .watch(vec![WatchKey::Room(room_id.clone())]);
+
+    let mut delay = tokio::time::delay_for(Duration::from_millis(timeout.unwrap_or(PEEK_TIMEOUT)));
+
+    loop {
+        let events = db
+            .rooms
+            .pdus_after(sender_id, &room_id, from)
+            .filter_map(|r| r.ok()) // Filter out buggy events
+            .filter(|(_, pdu)| {
+                db.rooms
+                    .user_can_see_event(sender_id, &room_id, &pdu.event_id)
+                    .unwrap_or(false)
+            })
+            .take(PEEK_LIMIT)
+            .collect::<Vec<_>>();
+
+        let done = !events.is_empty() || {
+            // Wait until something happens or the client doesn't want to wait anymore
+            tokio::select! {
+                _ = &mut delay => true,
+                _ = watch.wait() => false,
+            }
+        };
+
+        if done {
+            let end = events.last().map_or(from, |(count, _)| *count);
+
+            return Ok(Json(
+                serde_json::json!({
+                    "start": from.to_string(),
+                    "end": end.to_string(),
+                    "chunk": events
+                        .into_iter()
+                        .map(|(_, pdu)| pdu.to_room_event())
+                        .collect::<Vec<_>>(),
+                })
+                .to_string(),
+            ));
         }
-        let mut delay = tokio::time::delay_for(duration);
-        tokio::select! {
-            _ = &mut delay => {}
-            _ = watcher => {}
+    }
+}
+
+/// A room list of a sliding sync request.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SlidingSyncList {
+    /// Inclusive index ranges of the rooms the client wants to see
+    ranges: Vec<(usize, usize)>,
+    /// `by_recency` or `by_name`, the most important one first
+    sort: Vec<String>,
+    /// `[type, state_key]` pairs of the state events to send, `*` matches everything
+    required_state: Vec<(String, String)>,
+    timeline_limit: Option<usize>,
+}
+
+/// The body of a sliding sync request.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SlidingSyncRequest {
+    lists: Vec<SlidingSyncList>,
+}
+
+/// Sends sorted windows of the user's rooms instead of all of them. The response tells the
+/// client how the windows changed since `pos`.
+#[cfg_attr(
+    feature = "conduit_bin",
+    post(
+        "/_matrix/client/unstable/org.matrix.msc3575/sync?<pos>&<timeout>",
+        data = "<body>"
+    )
+)]
+pub async fn sliding_sync_route(
+    db: State<'_, Database>,
+    pos: Option<String>,
+    timeout: Option<u64>,
+    body: JsonRequest<SlidingSyncRequest>,
+) -> Result<Json<String>> {
+    let sender_id = &body.sender_id;
+    let device_id = &body.device_id;
+
+    db.rooms.edus.ping_presence(sender_id)?;
+
+    // We only remember the last response. If the client doesn't know it, it has to start over
+    let pos = pos.and_then(|pos| pos.parse::<u64>().ok());
+    let connection = match db.users.sliding_sync_connection(sender_id, device_id)? {
+        Some(connection) if pos == Some(connection.pos) => connection,
+        _ => SlidingSyncConnection::default(),
+    };
+
+    // Subscribe before looking for changes, so nothing that happens in between is missed
+    let watch = db.watch(sender_id, device_id);
+
+    let mut delay = tokio::time::delay_for(Duration::from_millis(timeout.unwrap_or_default()));
+
+    loop {
+        let (response, new_connection, changed) =
+            sliding_sync_helper(&db, sender_id, &body, &connection)?;
+
+        let done = changed || pos.is_none() || {
+            // Wait until something changes or the client doesn't want to wait anymore
+            tokio::select! {
+                _ = &mut delay => true,
+                _ = watch.wait() => false,
+            }
+        };
+
+        if done {
+            db.users
+                .set_sliding_sync_connection(sender_id, device_id, &new_connection)?;
+            return Ok(Json(response.to_string()));
+        }
+    }
+}
+
+/// Builds the sliding sync response, the new state of the connection and whether the client has
+/// to be told about anything.
+fn sliding_sync_helper(
+    db: &Database,
+    sender_id: &UserId,
+    body: &SlidingSyncRequest,
+    connection: &SlidingSyncConnection,
+) -> Result<(serde_json::Value, SlidingSyncConnection, bool)> {
+    let next_pos = db.globals.current_count()?;
+
+    let invited_rooms = db
+        .rooms
+        .rooms_invited(sender_id)
+        .filter_map(|r| r.ok())
+        .collect::<BTreeSet<_>>();
+    let all_rooms = db
+        .rooms
+        .rooms_joined(sender_id)
+        .filter_map(|r| r.ok())
+        .chain(invited_rooms.iter().cloned())
+        .collect::<Vec<_>>();
+
+    let mut new_connection = SlidingSyncConnection {
+        pos: next_pos,
+        ..Default::default()
+    };
+    let mut changed = false;
+
+    // The timeline limit and required state of every room in one of the windows
+    let mut room_configs = BTreeMap::<RoomId, (usize, Vec<&(String, String)>)>::new();
+
+    let mut lists = Vec::new();
+    for (list_index, list) in body.lists.iter().enumerate() {
+        let sorted = sort_rooms(db, all_rooms.clone(), &list.sort)?;
+        let old_window = connection
+            .lists
+            .get(list_index)
+            .cloned()
+            .unwrap_or_default();
+        let mut new_window = BTreeMap::new();
+        let mut ops = Vec::new();
+
+        for &(start, end) in &list.ranges {
+            if start > end {
+                continue;
+            }
+
+            let rooms = sorted
+                .get(start..sorted.len().min(end.saturating_add(1)))
+                .unwrap_or_default();
+            let old_rooms = old_window
+                .range(start..=end)
+                .map(|(_, room_id)| room_id)
+                .collect::<Vec<_>>();
+
+            if rooms.iter().eq(old_rooms.iter().cloned()) {
+                // The client already has this window
+            } else if old_rooms.is_empty() {
+                ops.push(serde_json::json!({
+                    "op": "SYNC",
+                    "range": [start, end],
+                    "room_ids": rooms,
+                }));
+            } else if let Some((delete, insert)) = single_move(&old_rooms, rooms) {
+                ops.push(serde_json::json!({ "op": "DELETE", "index": start + delete }));
+                ops.push(serde_json::json!({
+                    "op": "INSERT",
+                    "index": start + insert,
+                    "room_id": rooms[insert],
+                }));
+            } else {
+                ops.push(serde_json::json!({ "op": "INVALIDATE", "range": [start, end] }));
+                if !rooms.is_empty() {
+                    ops.push(serde_json::json!({
+                        "op": "SYNC",
+                        "range": [start, end],
+                        "room_ids": rooms,
+                    }));
+                }
+            }
+
+            for (i, room_id) in rooms.iter().enumerate() {
+                new_window.insert(start + i, room_id.clone());
+
+                let config = room_configs.entry(room_id.clone()).or_default();
+                config.0 = config.0.max(
+                    list.timeline_limit
+                        .unwrap_or(filter::DEFAULT_TIMELINE_LIMIT),
+                );
+                config.1.extend(&list.required_state);
+            }
+        }
+
+        changed |= !ops.is_empty();
+        lists.push(serde_json::json!({
+            "count": sorted.len(),
+            "ops": ops,
+        }));
+        new_connection.lists.push(new_window);
+    }
+
+    let mut rooms = serde_json::Map::new();
+    for (room_id, (timeline_limit, required_state)) in room_configs {
+        let room = if invited_rooms.contains(&room_id) {
+            new_connection.known_invites.insert(room_id.clone());
+            if connection.known_invites.contains(&room_id) {
+                continue;
+            }
+            sliding_sync_invite(db, sender_id, &room_id)?
+        } else {
+            new_connection.known_rooms.insert(room_id.clone());
+            let since = if connection.known_rooms.contains(&room_id) {
+                Some(connection.pos)
+            } else {
+                None
+            };
+            match sliding_sync_room(
+                db,
+                sender_id,
+                &room_id,
+                since,
+                timeline_limit,
+                &required_state,
+            )? {
+                Some(room) => room,
+                None => continue,
+            }
+        };
+
+        rooms.insert(room_id.to_string(), room);
+    }
+    changed |= !rooms.is_empty();
+
+    Ok((
+        serde_json::json!({
+            "pos": next_pos.to_string(),
+            "lists": lists,
+            "rooms": rooms,
+        }),
+        new_connection,
+        changed,
+    ))
+}
+
+/// Sorts the rooms of a sliding sync list. Rooms that are equal by the first sort key are sorted
+/// by the next one.
+fn sort_rooms(db: &Database, mut rooms: Vec<RoomId>, sort: &[String]) -> Result<Vec<RoomId>> {
+    let default_sort = ["by_recency".to_owned()];
+    let sort = if sort.is_empty() {
+        &default_sort[..]
+    } else {
+        sort
+    };
+
+    // Stable sorts, starting with the least important key
+    for key in sort.iter().rev() {
+        match key.as_str() {
+            "by_recency" => {
+                let mut keyed = rooms
+                    .into_iter()
+                    .map(|room_id| Ok((db.rooms.last_pdu_count(&room_id)?, room_id)))
+                    .collect::<Result<Vec<_>>>()?;
+                keyed.sort_by(|(a, _), (b, _)| b.cmp(a));
+                rooms = keyed.into_iter().map(|(_, room_id)| room_id).collect();
+            }
+            "by_name" => {
+                let mut keyed = rooms
+                    .into_iter()
+                    .map(|room_id| {
+                        let name = room_name(db, &room_id)?
+                            .unwrap_or_else(|| room_id.to_string())
+                            .to_lowercase();
+                        Ok((name, room_id))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+                rooms = keyed.into_iter().map(|(_, room_id)| room_id).collect();
+            }
+            _ => {}
         }
     }
 
-    Ok(response.into())
+    Ok(rooms)
+}
+
+/// Checks if `new` is `old` with one room moved to another index, or with a new room inserted
+/// and the last one pushed out. Returns the index to delete in `old` and the index to insert at
+/// in `new`.
+fn single_move(old: &[&RoomId], new: &[RoomId]) -> Option<(usize, usize)> {
+    if old.len() != new.len() {
+        return None;
+    }
+
+    (0..new.len()).find_map(|insert| {
+        let delete = old
+            .iter()
+            .position(|room_id| *room_id == &new[insert])
+            .unwrap_or(old.len() - 1);
+
+        let mut moved = old.to_vec();
+        moved.remove(delete);
+        moved.insert(insert, &new[insert]);
+
+        if moved.iter().cloned().eq(new.iter()) {
+            Some((delete, insert))
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the name of the room from its m.room.name event.
+fn room_name(db: &Database, room_id: &RoomId) -> Result<Option<String>> {
+    Ok(db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomName, "")?
+        .and_then(|pdu| {
+            pdu.content
+                .get("name")
+                .and_then(|name| name.as_str())
+                .map(str::to_owned)
+        }))
+}
+
+/// Builds the data of a joined room for a sliding sync response. Only events after `since` are
+/// sent, or the required state and the newest events if `since` is None. Returns None if
+/// nothing happened.
+fn sliding_sync_room(
+    db: &Database,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    since: Option<u64>,
+    timeline_limit: usize,
+    required_state: &[&(String, String)],
+) -> Result<Option<serde_json::Value>> {
+    let mut timeline_pdus = db
+        .rooms
+        .pdus_since(sender_id, room_id, since.unwrap_or(0))?
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .filter(|pdu| {
+            db.rooms
+                .user_can_see_event(sender_id, room_id, &pdu.event_id)
+                .unwrap_or(false)
+        })
+        .rev()
+        .take(timeline_limit + 1)
+        .collect::<Vec<_>>();
+
+    if since.is_some() && timeline_pdus.is_empty() {
+        return Ok(None);
+    }
+
+    let limited = timeline_pdus.len() > timeline_limit;
+    timeline_pdus.truncate(timeline_limit);
+    timeline_pdus.reverse();
+
+    let prev_batch = match timeline_pdus.first() {
+        Some(pdu) => db
+            .rooms
+            .get_pdu_count(&pdu.event_id)?
+            .ok_or_else(|| Error::bad_database("Can't find count from event in db."))?,
+        None => db.globals.current_count()? + 1,
+    };
+
+    let mut room = serde_json::json!({
+        "name": room_name(db, room_id)?,
+        "timeline": timeline_pdus
+            .iter()
+            .map(|pdu| pdu.to_sync_room_event())
+            .collect::<Vec<_>>(),
+        "limited": limited,
+        "prev_batch": prev_batch.to_string(),
+        "joined_count": db.rooms.room_members(room_id).count(),
+        "notification_count": db.rooms.notification_count(sender_id, room_id)?,
+        "highlight_count": db.rooms.highlight_count(sender_id, room_id)?,
+    });
+
+    if since.is_none() {
+        let wanted = |(kind, state_key): &(EventType, String)| {
+            required_state.iter().any(|(wanted_kind, wanted_key)| {
+                (wanted_kind == "*" || *wanted_kind == kind.to_string())
+                    && (wanted_key == "*"
+                        || wanted_key == state_key
+                        || wanted_key == "$ME" && state_key == sender_id.as_str())
+            })
+        };
+
+        room["initial"] = true.into();
+        room["required_state"] = serde_json::to_value(
+            db.rooms
+                .room_state_full(room_id)?
+                .into_iter()
+                .filter(|(key, _)| wanted(key))
+                .map(|(_, pdu)| pdu.to_sync_state_event())
+                .collect::<Vec<_>>(),
+        )
+        .expect("state events can be serialized");
+    }
+
+    Ok(Some(room))
+}
+
+/// Builds the data of an invited room for a sliding sync response.
+fn sliding_sync_invite(
+    db: &Database,
+    sender_id: &UserId,
+    room_id: &RoomId,
+) -> Result<serde_json::Value> {
+    // Invites from other servers come with their own stripped state
+    let invite_state = match db.rooms.invite_state(sender_id, room_id)? {
+        Some(invite_state) => invite_state,
+        None => db
+            .rooms
+            .room_state_full(room_id)?
+            .into_iter()
+            .map(|(_, pdu)| pdu.to_stripped_state_event())
+            .collect(),
+    };
+
+    Ok(serde_json::json!({
+        "name": room_name(db, room_id)?,
+        "initial": true,
+        "invite_state": invite_state,
+    }))
 }
 
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/context/<_>", data = "<body>")
 )]
-pub fn get_context_route(
+pub async fn get_context_route(
     db: State<'_, Database>,
     body: Ruma<get_context::Request>,
 ) -> ConduitResult<get_context::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    if !db
+        .rooms
+        .can_read_history(sender_id, &body.room_id, db.users.is_guest(sender_id)?)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
         ));
     }
 
+    let filter = body
+        .filter
+        .as_ref()
+        .map_or_else(filter::RoomEventFilter::default, filter::from_ruma);
+
+    let limit = u32::try_from(body.limit)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid."))?
+        .min(filter.limit_or(usize::MAX).try_into().unwrap_or(u32::MAX)) as usize
+        / 2;
+
+    // The event might be from history this server doesn't have yet
+    if db.rooms.get_pdu(&body.event_id)?.is_none() {
+        if let Err(e) =
+            server_server::fetch_event_with_history(&db, &body.room_id, &body.event_id, limit).await
+        {
+            warn!("Could not fetch event {}: {}", body.event_id, e);
+        }
+    }
+
     let base_event = db
         .rooms
         .get_pdu(&body.event_id)?
@@ -2973,23 +4245,51 @@ pub fn get_context_route(
         ))?
         .to_room_event();
 
+    if !db
+        .rooms
+        .user_can_see_event(sender_id, &body.room_id, &body.event_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this event.",
+        ));
+    }
+
+    let visible = |pdu: &PduEvent| {
+        db.rooms
+            .user_can_see_event(sender_id, &pdu.room_id, &pdu.event_id)
+            .unwrap_or(false)
+    };
+
     let base_token = db
         .rooms
         .get_pdu_count(&body.event_id)?
         .expect("event still exists");
 
-    let events_before = db
+    let mut events_before = db
         .rooms
         .pdus_until(&sender_id, &body.room_id, base_token)
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
         .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| filter.allows_pdu(pdu) && visible(pdu))
+        .take(limit)
         .collect::<Vec<_>>();
 
+    // We reached the oldest event we have, so we ask the other servers for older ones
+    if events_before.len() < limit {
+        if let Err(e) = server_server::backfill(&db, &body.room_id, limit).await {
+            warn!("Could not backfill {}: {}", body.room_id, e);
+        }
+
+        let until = events_before.last().map_or(base_token, |(count, _)| *count);
+        events_before.extend(
+            db.rooms
+                .pdus_until(&sender_id, &body.room_id, until)
+                .filter_map(|r| r.ok()) // Remove buggy events
+                .filter(|(_, pdu)| filter.allows_pdu(pdu) && visible(pdu))
+                .take(limit - events_before.len()),
+        );
+    }
+
     let start_token = events_before.last().map(|(count, _)| count.to_string());
 
     let events_before = events_before
@@ -3000,17 +4300,32 @@ pub fn get_context_route(
     let events_after = db
         .rooms
         .pdus_after(&sender_id, &body.room_id, base_token)
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
         .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| filter.allows_pdu(pdu) && visible(pdu))
+        .take(limit)
         .collect::<Vec<_>>();
 
     let end_token = events_after.last().map(|(count, _)| count.to_string());
 
+    // The state is the state at the last returned event
+    let state = match db.rooms.state_at(
+        events_after
+            .last()
+            .map_or(&body.event_id, |(_, pdu)| &pdu.event_id),
+    )? {
+        Some(state) => state
+            .values()
+            .filter_map(|event_id| db.rooms.get_pdu(event_id).ok()?)
+            .map(|pdu| pdu.to_state_event())
+            .collect(),
+        None => db
+            .rooms
+            .room_state_full(&body.room_id)?
+            .values()
+            .map(|pdu| pdu.to_state_event())
+            .collect(),
+    };
+
     let events_after = events_after
         .into_iter()
         .map(|(_, pdu)| pdu.to_room_event())
@@ -3022,27 +4337,53 @@ pub fn get_context_route(
         events_before,
         event: Some(base_event),
         events_after,
-        state: db // TODO: State at event
-            .rooms
-            .room_state_full(&body.room_id)?
-            .values()
-            .map(|pdu| pdu.to_state_event())
-            .collect(),
+        state,
     }
     .into())
 }
 
+/// Returns the lazy loaded member events for the senders of events returned by /messages.
+fn message_members(
+    db: &Database,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    room_id: &RoomId,
+    filter: &filter::RoomEventFilter,
+    events: &[(u64, PduEvent)],
+) -> Result<Vec<Raw<ruma::events::AnyStateEvent>>> {
+    if !filter.lazy_load_members {
+        return Ok(Vec::new());
+    }
+
+    Ok(lazy_loaded_members(
+        db,
+        user_id,
+        device_id,
+        room_id,
+        filter,
+        None,
+        events.iter().map(|(_, pdu)| &pdu.sender),
+    )?
+    .into_iter()
+    .map(|pdu| pdu.to_state_event())
+    .collect())
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/messages", data = "<body>")
 )]
-pub fn get_message_events_route(
+pub async fn get_message_events_route(
     db: State<'_, Database>,
     body: Ruma<get_message_events::Request>,
 ) -> ConduitResult<get_message_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    if !db
+        .rooms
+        .can_read_history(sender_id, &body.room_id, db.users.is_guest(sender_id)?)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
@@ -3055,64 +4396,448 @@ pub fn get_message_events_route(
         .parse()
         .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?;
 
-    let to = body.to.as_ref().map(|t| t.parse());
+    let to = body.to.as_ref().map(|t| t.parse());
+
+    let filter = body
+        .filter
+        .as_ref()
+        .map_or_else(filter::RoomEventFilter::default, filter::from_ruma);
+
+    let visible = |pdu: &PduEvent| {
+        db.rooms
+            .user_can_see_event(sender_id, &pdu.room_id, &pdu.event_id)
+            .unwrap_or(false)
+    };
+
+    // Use limit or else 10, but never more than the filter allows
+    let limit = body
+        .limit
+        .try_into()
+        .map_or(Ok::<_, Error>(10_usize), |l: u32| Ok(l as usize))?
+        .min(filter.limit_or(usize::MAX));
+
+    match body.dir {
+        get_message_events::Direction::Forward => {
+            let events_after = db
+                .rooms
+                .pdus_after(&sender_id, &body.room_id, from)
+                .filter_map(|r| r.ok()) // Filter out buggy events
+                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
+                .filter(|(_, pdu)| filter.allows_pdu(pdu) && visible(pdu))
+                .take(limit)
+                .collect::<Vec<_>>();
+
+            let end_token = events_after.last().map(|(count, _)| count.to_string());
+
+            let state = message_members(
+                &db,
+                sender_id,
+                device_id,
+                &body.room_id,
+                &filter,
+                &events_after,
+            )?;
+
+            let events_after = events_after
+                .into_iter()
+                .map(|(_, pdu)| pdu.to_room_event())
+                .collect::<Vec<_>>();
+
+            Ok(get_message_events::Response {
+                start: Some(body.from.clone()),
+                end: end_token,
+                chunk: events_after,
+                state,
+            }
+            .into())
+        }
+        get_message_events::Direction::Backward => {
+            let mut events_before = db
+                .rooms
+                .pdus_until(&sender_id, &body.room_id, from)
+                .filter_map(|r| r.ok()) // Filter out buggy events
+                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
+                .filter(|(_, pdu)| filter.allows_pdu(pdu) && visible(pdu))
+                .take(limit)
+                .collect::<Vec<_>>();
+
+            // We reached the oldest event we have, so we ask the other servers for older ones
+            if events_before.len() < limit && to.is_none() {
+                if let Err(e) = server_server::backfill(&db, &body.room_id, limit).await {
+                    warn!("Could not backfill {}: {}", body.room_id, e);
+                }
+
+                let until = events_before.last().map_or(from, |(count, _)| *count);
+                events_before.extend(
+                    db.rooms
+                        .pdus_until(&sender_id, &body.room_id, until)
+                        .filter_map(|r| r.ok()) // Filter out buggy events
+                        .filter(|(_, pdu)| filter.allows_pdu(pdu) && visible(pdu))
+                        .take(limit - events_before.len()),
+                );
+            }
+
+            let start_token = events_before.last().map(|(count, _)| count.to_string());
+
+            let state = message_members(
+                &db,
+                sender_id,
+                device_id,
+                &body.room_id,
+                &filter,
+                &events_before,
+            )?;
+
+            let events_before = events_before
+                .into_iter()
+                .map(|(_, pdu)| pdu.to_room_event())
+                .collect::<Vec<_>>();
+
+            Ok(get_message_events::Response {
+                start: Some(body.from.clone()),
+                end: start_token,
+                chunk: events_before,
+                state,
+            }
+            .into())
+        }
+    }
+}
+
+/// The body of a /search request. Only room events can be searched.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchRequest {
+    search_categories: SearchCategories,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SearchCategories {
+    room_events: Option<SearchCriteria>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SearchCriteria {
+    search_term: String,
+    /// `content.body`, `content.name` or `content.topic`
+    keys: Option<Vec<String>>,
+    filter: filter::RoomEventFilter,
+    /// `rank` or `recent`
+    order_by: Option<String>,
+    event_context: Option<SearchEventContext>,
+    include_state: bool,
+    groupings: SearchGroupings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SearchEventContext {
+    before_limit: Option<usize>,
+    after_limit: Option<usize>,
+    include_profile: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SearchGroupings {
+    group_by: Vec<SearchGroup>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SearchGroup {
+    /// `room_id` or `sender`
+    key: String,
+}
+
+/// Searches the messages, room names and topics of all rooms the user is or was in.
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/search?<next_batch>", data = "<body>")
+)]
+pub fn search_events_route(
+    db: State<'_, Database>,
+    next_batch: Option<String>,
+    body: JsonRequest<SearchRequest>,
+) -> Result<Json<String>> {
+    let sender_id = &body.sender_id;
+
+    let criteria = match &body.search_categories.room_events {
+        Some(criteria) => criteria,
+        None => {
+            return Ok(Json(
+                serde_json::json!({ "search_categories": {} }).to_string(),
+            ))
+        }
+    };
+
+    let mut tokens = utils::tokenize(&criteria.search_term).collect::<Vec<_>>();
+    tokens.sort();
+    tokens.dedup();
+
+    let kinds = match &criteria.keys {
+        Some(keys) => keys
+            .iter()
+            .filter_map(|key| match key.as_str() {
+                "content.body" => Some(EventType::RoomMessage),
+                "content.name" => Some(EventType::RoomName),
+                "content.topic" => Some(EventType::RoomTopic),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        None => vec![
+            EventType::RoomMessage,
+            EventType::RoomName,
+            EventType::RoomTopic,
+        ],
+    };
+
+    // The history visibility decides which events of these rooms the user can see
+    let room_ids = db
+        .rooms
+        .rooms_joined(sender_id)
+        .chain(db.rooms.rooms_left(sender_id))
+        .filter_map(|r| r.ok())
+        .filter(|room_id| criteria.filter.allows_room(room_id))
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::new();
+    for room_id in &room_ids {
+        for (pdu_id, rank) in db.rooms.search_pdus(room_id, &tokens)? {
+            let pdu = match db.rooms.get_pdu_from_id(&pdu_id)? {
+                Some(pdu) => pdu,
+                None => continue,
+            };
+
+            if kinds.contains(&pdu.kind)
+                && criteria.filter.allows_pdu(&pdu)
+                && db
+                    .rooms
+                    .user_can_see_event(sender_id, room_id, &pdu.event_id)?
+            {
+                results.push((rank, pdu));
+            }
+        }
+    }
+
+    // The pdu counts of different rooms can't be compared, so recent means the newest timestamp
+    if criteria.order_by.as_deref() == Some("recent") {
+        results.sort_by(|(_, a), (_, b)| b.origin_server_ts.cmp(&a.origin_server_ts));
+    } else {
+        results.sort_by(|(rank_a, a), (rank_b, b)| {
+            rank_b
+                .cmp(rank_a)
+                .then_with(|| b.origin_server_ts.cmp(&a.origin_server_ts))
+        });
+    }
+
+    let count = results.len();
+    let skip = match next_batch {
+        Some(next_batch) => next_batch
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch token."))?,
+        None => 0,
+    };
+    let limit = criteria
+        .filter
+        .limit_or(filter::DEFAULT_TIMELINE_LIMIT)
+        .min(SEARCH_LIMIT);
+    let next_batch = if skip.saturating_add(limit) < count {
+        Some((skip + limit).to_string())
+    } else {
+        None
+    };
+
+    let results = results
+        .into_iter()
+        .skip(skip)
+        .take(limit)
+        .collect::<Vec<_>>();
+
+    let mut result_json = Vec::new();
+    for (rank, pdu) in &results {
+        let mut result = serde_json::json!({
+            "rank": *rank as f64,
+            "result": pdu.to_room_event(),
+        });
+
+        if let Some(event_context) = &criteria.event_context {
+            result["context"] = search_context(&db, sender_id, pdu, event_context)?;
+        }
 
-    // Use limit or else 10
-    let limit = body
-        .limit
-        .try_into()
-        .map_or(Ok::<_, Error>(10_usize), |l: u32| Ok(l as usize))?;
+        result_json.push(result);
+    }
 
-    match body.dir {
-        get_message_events::Direction::Forward => {
-            let events_after = db
-                .rooms
-                .pdus_after(&sender_id, &body.room_id, from)
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
-                .collect::<Vec<_>>();
+    let mut room_events = serde_json::json!({
+        "count": count,
+        "highlights": tokens,
+        "next_batch": next_batch,
+        "results": result_json,
+    });
 
-            let end_token = events_after.last().map(|(count, _)| count.to_string());
+    if criteria.include_state {
+        let mut state = serde_json::Map::new();
+        for (_, pdu) in &results {
+            if !state.contains_key(pdu.room_id.as_str()) {
+                state.insert(
+                    pdu.room_id.to_string(),
+                    search_room_state(&db, sender_id, &pdu.room_id)?.into(),
+                );
+            }
+        }
+        room_events["state"] = state.into();
+    }
 
-            let events_after = events_after
-                .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect::<Vec<_>>();
+    if !criteria.groupings.group_by.is_empty() {
+        let mut groups = serde_json::Map::new();
+        for group in &criteria.groupings.group_by {
+            let mut group_json = serde_json::Map::new();
+            for (_, pdu) in &results {
+                let id = match group.key.as_str() {
+                    "room_id" => pdu.room_id.to_string(),
+                    "sender" => pdu.sender.to_string(),
+                    _ => continue,
+                };
 
-            Ok(get_message_events::Response {
-                start: Some(body.from.clone()),
-                end: end_token,
-                chunk: events_after,
-                state: Vec::new(),
+                let order = group_json.len();
+                group_json.entry(id).or_insert_with(|| {
+                    serde_json::json!({
+                        "next_batch": null,
+                        "order": order,
+                        "results": [],
+                    })
+                })["results"]
+                    .as_array_mut()
+                    .expect("results is an array")
+                    .push(pdu.event_id.to_string().into());
             }
-            .into())
+            groups.insert(group.key.clone(), group_json.into());
         }
-        get_message_events::Direction::Backward => {
-            let events_before = db
-                .rooms
-                .pdus_until(&sender_id, &body.room_id, from)
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
-                .collect::<Vec<_>>();
+        room_events["groups"] = groups.into();
+    }
 
-            let start_token = events_before.last().map(|(count, _)| count.to_string());
+    Ok(Json(
+        serde_json::json!({
+            "search_categories": {
+                "room_events": room_events,
+            },
+        })
+        .to_string(),
+    ))
+}
 
-            let events_before = events_before
+/// Returns the state of a room in the search results. Users who left the room only see the state
+/// when they left.
+fn search_room_state(
+    db: &Database,
+    sender_id: &UserId,
+    room_id: &RoomId,
+) -> Result<Vec<serde_json::Value>> {
+    let state = if db.rooms.is_joined(sender_id, room_id)? {
+        db.rooms.room_state_full(room_id)?
+    } else {
+        let leave_event_id =
+            match db
+                .rooms
+                .room_state_get(room_id, &EventType::RoomMember, sender_id.as_str())?
+            {
+                Some(leave_event) => leave_event.event_id,
+                None => return Ok(Vec::new()),
+            };
+
+        match db.rooms.state_at(&leave_event_id)? {
+            Some(state) => state
                 .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect::<Vec<_>>();
+                .filter_map(|(key, event_id)| Some((key, db.rooms.get_pdu(&event_id).ok()??)))
+                .collect(),
+            // We can't tell what the state was
+            None => return Ok(Vec::new()),
+        }
+    };
 
-            Ok(get_message_events::Response {
-                start: Some(body.from.clone()),
-                end: start_token,
-                chunk: events_before,
-                state: Vec::new(),
+    Ok(state.values().map(|pdu| pdu.to_state_event()).collect())
+}
+
+/// Returns the events around a search result that the user can see.
+fn search_context(
+    db: &Database,
+    sender_id: &UserId,
+    pdu: &PduEvent,
+    event_context: &SearchEventContext,
+) -> Result<serde_json::Value> {
+    let count = db
+        .rooms
+        .get_pdu_count(&pdu.event_id)?
+        .ok_or_else(|| Error::bad_database("Can't find count from event in db."))?;
+
+    let visible = |other: &PduEvent| {
+        db.rooms
+            .user_can_see_event(sender_id, &other.room_id, &other.event_id)
+            .unwrap_or(false)
+    };
+
+    let events_before = db
+        .rooms
+        .pdus_until(sender_id, &pdu.room_id, count)
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .filter(|(_, pdu)| visible(pdu))
+        .take(event_context.before_limit.unwrap_or(5))
+        .collect::<Vec<_>>();
+
+    let events_after = db
+        .rooms
+        .pdus_after(sender_id, &pdu.room_id, count)
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .filter(|(_, pdu)| visible(pdu))
+        .take(event_context.after_limit.unwrap_or(5))
+        .collect::<Vec<_>>();
+
+    let mut context = serde_json::json!({
+        "start": events_before.last().map(|(count, _)| count.to_string()),
+        "end": events_after.last().map(|(count, _)| count.to_string()),
+        "events_before": events_before
+            .iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect::<Vec<_>>(),
+        "events_after": events_after
+            .iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect::<Vec<_>>(),
+    });
+
+    if event_context.include_profile {
+        let mut profile_info = serde_json::Map::new();
+        for sender in events_before
+            .iter()
+            .chain(&events_after)
+            .map(|(_, pdu)| &pdu.sender)
+            .chain(Some(&pdu.sender))
+        {
+            if profile_info.contains_key(sender.as_str()) {
+                continue;
             }
-            .into())
+
+            // The profile at the result, not the current one
+            let content = db
+                .rooms
+                .state_get_at(&pdu.event_id, &EventType::RoomMember, sender.as_str())?
+                .map(|member| member.content)
+                .unwrap_or_default();
+
+            profile_info.insert(
+                sender.to_string(),
+                serde_json::json!({
+                    "displayname": content.get("displayname"),
+                    "avatar_url": content.get("avatar_url"),
+                }),
+            );
         }
+        context["profile_info"] = profile_info.into();
     }
+
+    Ok(context)
 }
 
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/voip/turnServer"))]
@@ -3372,6 +5097,8 @@ pub fn delete_device_route(
     }
 
     db.users.remove_device(&sender_id, &body.body.device_id)?;
+    db.pushers
+        .remove_pushers(&sender_id, Some(&body.body.device_id))?;
 
     Ok(delete_device::Response.into())
 }
@@ -3418,7 +5145,8 @@ pub fn delete_devices_route(
     }
 
     for device_id in &body.devices {
-        db.users.remove_device(&sender_id, &device_id)?
+        db.users.remove_device(&sender_id, &device_id)?;
+        db.pushers.remove_pushers(&sender_id, Some(&device_id))?;
     }
 
     Ok(delete_devices::Response.into())
@@ -3547,43 +5275,345 @@ pub fn get_key_changes_route(
 ) -> ConduitResult<get_key_changes::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    let mut device_list_updates = HashSet::new();
-    for room_id in db.rooms.rooms_joined(sender_id).filter_map(|r| r.ok()) {
-        device_list_updates.extend(
+    let (changed, left) = device_list_changes(
+        &db,
+        sender_id,
+        body.from
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`."))?,
+        Some(
+            body.to
+                .parse()
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`."))?,
+        ),
+    )?;
+
+    Ok(get_key_changes::Response {
+        changed: changed.into_iter().collect(),
+        left: left.into_iter().collect(),
+    }
+    .into())
+}
+
+/// Returns the users whose devices changed between `since` and `to` and the users we don't
+/// share an encrypted room with anymore. Only users that share an encrypted room with us can be
+/// in the first set.
+fn device_list_changes(
+    db: &Database,
+    sender_id: &UserId,
+    since: u64,
+    to: Option<u64>,
+) -> Result<(HashSet<UserId>, HashSet<UserId>)> {
+    let to = to.unwrap_or(u64::MAX);
+
+    let mut changed = HashSet::new();
+    let mut left = HashSet::new();
+    // Everyone we share an encrypted room with right now
+    let mut shared = HashSet::new();
+
+    for room_id in db.rooms.rooms_joined(sender_id) {
+        let room_id = room_id?;
+        if !db.rooms.is_encrypted(&room_id)? {
+            continue;
+        }
+
+        let members = db
+            .rooms
+            .room_members(&room_id)
+            .filter_map(|r| r.ok())
+            .collect::<Vec<_>>();
+        shared.extend(members.iter().cloned());
+
+        changed.extend(
             db.users
-                .keys_changed(
-                    &room_id,
-                    body.from.parse().map_err(|_| {
-                        Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`.")
-                    })?,
-                    Some(body.to.parse().map_err(|_| {
-                        Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`.")
-                    })?),
-                )
+                .keys_changed(&room_id, since, Some(to))
                 .filter_map(|r| r.ok()),
         );
+
+        // Initial syncs don't have device list changes
+        if since == 0 {
+            continue;
+        }
+
+        // We don't know the devices of users that started sharing this room with us
+        for (_, pdu) in db
+            .rooms
+            .pdus_after(sender_id, &room_id, since)
+            .filter_map(|r| r.ok()) // Filter out buggy events
+            .take_while(|(count, _)| *count < to)
+        {
+            if pdu.kind == EventType::RoomEncryption {
+                changed.extend(members.iter().cloned());
+            }
+
+            if pdu.kind != EventType::RoomMember {
+                continue;
+            }
+
+            let user_id = match pdu
+                .state_key
+                .as_deref()
+                .and_then(|state_key| UserId::try_from(state_key).ok())
+            {
+                Some(user_id) => user_id,
+                None => continue,
+            };
+            let membership =
+                serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content.clone())
+                    .expect("Raw::from_value always works")
+                    .deserialize()
+                    .map_err(|_| Error::bad_database("Invalid PDU in database."))?
+                    .membership;
+
+            match membership {
+                member::MembershipState::Join if &user_id == sender_id => {
+                    changed.extend(members.iter().cloned())
+                }
+                member::MembershipState::Join => {
+                    changed.insert(user_id);
+                }
+                member::MembershipState::Leave | member::MembershipState::Ban => {
+                    left.insert(user_id);
+                }
+                _ => {}
+            }
+        }
     }
-    Ok(get_key_changes::Response {
-        changed: device_list_updates.into_iter().collect(),
-        left: Vec::new(), // TODO
+
+    if since != 0 {
+        // Everyone in encrypted rooms we left in the meantime
+        for room_id in db.rooms.rooms_left(sender_id) {
+            let room_id = room_id?;
+            let leave_count = match db.rooms.room_state_get(
+                &room_id,
+                &EventType::RoomMember,
+                &sender_id.to_string(),
+            )? {
+                Some(pdu) => db.rooms.get_pdu_live_count(&pdu.event_id)?,
+                None => None,
+            };
+
+            if leave_count.map_or(false, |count| count > since && count < to)
+                && db.rooms.is_encrypted(&room_id)?
+            {
+                left.extend(db.rooms.room_members(&room_id).filter_map(|r| r.ok()));
+            }
+        }
     }
-    .into())
+
+    changed.retain(|user_id| shared.contains(user_id));
+    left.retain(|user_id| !shared.contains(user_id));
+
+    Ok((changed, left))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/pushers", data = "<body>")
+)]
+pub fn pushers_route(
+    db: State<'_, Database>,
+    body: JsonRequest<IgnoredAny>,
+) -> Result<Json<String>> {
+    Ok(Json(
+        serde_json::json!({ "pushers": db.pushers.get_pushers(&body.sender_id)? }).to_string(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPusherRequest {
+    pushkey: String,
+    /// No kind means that the pusher should be removed
+    kind: Option<String>,
+    app_id: String,
+    #[serde(default)]
+    app_display_name: String,
+    #[serde(default)]
+    device_display_name: String,
+    profile_tag: Option<String>,
+    #[serde(default)]
+    lang: String,
+    #[serde(default)]
+    data: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    append: bool,
 }
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/pushers"))]
-pub fn pushers_route() -> ConduitResult<get_pushers::Response> {
-    Ok(get_pushers::Response {
-        pushers: Vec::new(),
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/pushers/set", data = "<body>")
+)]
+pub fn set_pushers_route(
+    db: State<'_, Database>,
+    body: JsonRequest<SetPusherRequest>,
+) -> Result<Json<String>> {
+    let sender_id = &body.sender_id;
+
+    let kind = match &body.kind {
+        Some(kind) => kind,
+        None => {
+            db.pushers
+                .remove_pusher(sender_id, &body.app_id, &body.pushkey)?;
+            return Ok(Json("{}".to_owned()));
+        }
+    };
+
+    match kind.as_str() {
+        "http" => {
+            let url =
+                body.data
+                    .get("url")
+                    .and_then(|url| url.as_str())
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::MissingParam,
+                        "Http pushers need a url.",
+                    ))?;
+            if !(url.starts_with("https://") || url.starts_with("http://"))
+                || !url.ends_with("/_matrix/push/v1/notify")
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "The url has to point to /_matrix/push/v1/notify.",
+                ));
+            }
+        }
+        "email" => {
+            // Users can't bind email addresses yet, so nobody could validate the pushkey. Without
+            // validation anyone could send emails to any address, so the admin has to allow it.
+            if !db.globals.allow_unvalidated_email_pushers() {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidDenied,
+                    "The pushkey of email pushers has to be a validated email address.",
+                ));
+            }
+            if db.globals.mail_transport().is_none() {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Email notifications are not enabled on this server.",
+                ));
+            }
+            // The pushkey is the email address
+            if !body.pushkey.contains('@') || body.pushkey.contains(char::is_whitespace) {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "The pushkey of email pushers has to be an email address.",
+                ));
+            }
+        }
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Only http and email pushers are supported.",
+            ))
+        }
+    }
+
+    if !body.append {
+        db.pushers
+            .remove_pusher_of_other_users(sender_id, &body.app_id, &body.pushkey)?;
     }
-    .into())
+
+    db.pushers.set_pusher(
+        sender_id,
+        &body.device_id,
+        &Pusher {
+            pushkey: body.pushkey.clone(),
+            kind: kind.clone(),
+            app_id: body.app_id.clone(),
+            app_display_name: body.app_display_name.clone(),
+            device_display_name: body.device_display_name.clone(),
+            profile_tag: body.profile_tag.clone(),
+            lang: body.lang.clone(),
+            data: body.data.clone(),
+            pushkey_ts: utils::millis_since_unix_epoch() / 1000,
+        },
+        &db.globals,
+    )?;
+
+    Ok(Json("{}".to_owned()))
 }
 
-#[cfg_attr(feature = "conduit_bin", post("/_matrix/client/r0/pushers/set"))]
-pub fn set_pushers_route() -> ConduitResult<get_pushers::Response> {
-    Ok(get_pushers::Response {
-        pushers: Vec::new(),
+/// Lists the events that notified the user, newest first.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/r0/notifications?<from>&<limit>&<only>",
+        data = "<body>"
+    )
+)]
+pub fn get_notifications_route(
+    db: State<'_, Database>,
+    from: Option<String>,
+    limit: Option<usize>,
+    only: Option<String>,
+    body: JsonRequest<IgnoredAny>,
+) -> Result<Json<String>> {
+    let sender_id = &body.sender_id;
+
+    let from = match from {
+        Some(from) => from
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?,
+        None => u64::MAX,
+    };
+    let limit = limit
+        .unwrap_or(NOTIFICATIONS_LIMIT)
+        .min(NOTIFICATIONS_LIMIT);
+    let only_highlight = only.as_deref() == Some("highlight");
+
+    let mut notifications = Vec::new();
+    let mut next_token = None;
+    for (count, notification) in db
+        .rooms
+        .notifications_until(sender_id, from)
+        .filter_map(|r| r.ok()) // Filter out buggy notifications
+        .filter(|(_, notification)| {
+            !only_highlight || crate::push_rules::highlights(&notification.actions)
+        })
+    {
+        if notifications.len() == limit {
+            next_token = notifications.last().map(|(count, _)| *count);
+            break;
+        }
+
+        let pdu = match db.rooms.get_pdu(&notification.event_id)? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+
+        // Everything up to the read receipt was read
+        let read = match (
+            db.rooms.get_pdu_live_count(&pdu.event_id)?,
+            db.rooms
+                .edus
+                .room_read_get(&notification.room_id, sender_id)?,
+        ) {
+            (Some(event_count), Some(last_read)) => event_count <= last_read,
+            _ => false,
+        };
+
+        notifications.push((
+            count,
+            serde_json::json!({
+                "actions": notification.actions,
+                "event": pdu.to_room_event(),
+                "read": read,
+                "room_id": notification.room_id,
+                "ts": notification.ts,
+            }),
+        ));
     }
-    .into())
+
+    Ok(Json(
+        serde_json::json!({
+            "next_token": next_token.map(|count| count.to_string()),
+            "notifications": notifications
+                .into_iter()
+                .map(|(_, notification)| notification)
+                .collect::<Vec<_>>(),
+        })
+        .to_string(),
+    ))
 }
 
 #[cfg_attr(
@@ -3681,3 +5711,262 @@ pub fn get_tags_route(
 pub fn options_route() -> ConduitResult<send_event_to_device::Response> {
     Ok(send_event_to_device::Response.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use serde_json::json;
+
+    fn alice() -> UserId {
+        UserId::try_from("@alice:localhost").expect("user id is valid")
+    }
+
+    fn set_email_pusher(rocket: &rocket::Rocket, pushkey: &str) -> Result<Json<String>> {
+        let body = serde_json::from_value(json!({
+            "pushkey": pushkey,
+            "kind": "email",
+            "app_id": "m.email",
+            "data": {},
+        }))
+        .expect("pusher request is valid");
+
+        set_pushers_route(
+            State::from(rocket).expect("rocket manages the database"),
+            JsonRequest {
+                body,
+                sender_id: alice(),
+                device_id: "DEVICE".to_owned().into(),
+            },
+        )
+    }
+
+    #[test]
+    fn email_pushers_have_to_be_allowed_by_the_admin() {
+        let maildir =
+            std::env::temp_dir().join(format!("conduit-test-mail-{}", utils::random_string(16)));
+        let maildir = maildir
+            .to_str()
+            .expect("temporary directory is valid unicode");
+
+        let rocket = test_utils::rocket(Database::load_for_tests(&[("email_maildir", maildir)]));
+        assert!(matches!(
+            set_email_pusher(&rocket, "alice@example.org"),
+            Err(Error::BadRequest(ErrorKind::ThreepidDenied, _))
+        ));
+
+        let rocket = test_utils::rocket(Database::load_for_tests(&[
+            ("email_maildir", maildir),
+            ("allow_unvalidated_email_pushers", "true"),
+        ]));
+        assert!(matches!(
+            set_email_pusher(&rocket, "not an address"),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+        set_email_pusher(&rocket, "alice@example.org").expect("email pushers are allowed");
+
+        let db = rocket
+            .state::<Database>()
+            .expect("rocket manages the database");
+        let pushers = db.pushers.get_pushers(&alice()).unwrap();
+        assert_eq!(pushers.len(), 1);
+        assert_eq!(pushers[0].kind, "email");
+        assert_eq!(pushers[0].pushkey, "alice@example.org");
+    }
+
+    #[test]
+    fn email_pushers_need_a_mail_transport() {
+        let rocket = test_utils::rocket(Database::load_for_tests(&[(
+            "allow_unvalidated_email_pushers",
+            "true",
+        )]));
+
+        assert!(matches!(
+            set_email_pusher(&rocket, "alice@example.org"),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+    }
+
+    /// Requests the events before `from` as a user of the room.
+    async fn messages_before(
+        rocket: &rocket::Rocket,
+        room_id: &RoomId,
+        user_id: &UserId,
+        from: String,
+    ) -> get_message_events::Response {
+        get_message_events_route(
+            State::from(rocket).expect("rocket manages the database"),
+            Ruma {
+                body: get_message_events::Request {
+                    room_id: room_id.clone(),
+                    from,
+                    to: None,
+                    dir: get_message_events::Direction::Backward,
+                    limit: 10_u32.into(),
+                    filter: None,
+                },
+                sender_id: Some(user_id.clone()),
+                device_id: Some("DEVICE".to_owned().into()),
+                sender_servername: None,
+                json_body: None,
+            },
+        )
+        .await
+        .expect("user can read the room")
+        .0
+    }
+
+    /// Returns the body of each message and the type of all other events.
+    fn summary(response: &get_message_events::Response) -> Vec<String> {
+        response
+            .chunk
+            .iter()
+            .map(|event| {
+                let event = serde_json::from_str::<serde_json::Value>(event.json().get())
+                    .expect("events are json");
+                event["content"]["body"]
+                    .as_str()
+                    .or_else(|| event["type"].as_str())
+                    .expect("events have a type")
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    /// Lets bob join a room on the remote server that has 20 messages. Returns bob and the
+    /// messages.
+    fn join_remote_room(
+        db: &Database,
+        remote: &test_utils::RemoteServer,
+    ) -> (UserId, Vec<EventId>) {
+        remote.trust(db);
+
+        let alice = remote.create_room();
+        let messages = (1..=20)
+            .map(|i| {
+                let content = json!({ "msgtype": "m.text", "body": format!("message {}", i) });
+                remote.send(&alice, "m.room.message", None, content)
+            })
+            .collect::<Vec<_>>();
+
+        // We only get our join event and the state of the room when we join
+        let bob = UserId::try_from("@bob:localhost").expect("user id is valid");
+        remote.join(db, &bob);
+
+        (bob, messages)
+    }
+
+    #[rocket::async_test]
+    async fn backward_pagination_backfills_the_history_from_other_servers() {
+        let db = Database::load_for_tests(&[]);
+        let remote = test_utils::RemoteServer::start().await;
+        let (bob, messages) = join_remote_room(&db, &remote);
+
+        let from = (db.globals.current_count().unwrap() + 1).to_string();
+        let rocket = test_utils::rocket(db);
+
+        let first_page = messages_before(&rocket, &remote.room_id, &bob, from).await;
+        let mut expected = vec!["m.room.member".to_owned()];
+        expected.extend((12..=20).rev().map(|i| format!("message {}", i)));
+        assert_eq!(summary(&first_page), expected);
+
+        let second_page = messages_before(
+            &rocket,
+            &remote.room_id,
+            &bob,
+            first_page.end.expect("there are older events"),
+        )
+        .await;
+        let expected = (2..=11)
+            .rev()
+            .map(|i| format!("message {}", i))
+            .collect::<Vec<_>>();
+        assert_eq!(summary(&second_page), expected);
+
+        let third_page = messages_before(
+            &rocket,
+            &remote.room_id,
+            &bob,
+            second_page.end.expect("there are older events"),
+        )
+        .await;
+        assert_eq!(
+            summary(&third_page),
+            vec![
+                "message 1",
+                "m.room.history_visibility",
+                "m.room.join_rules",
+                "m.room.power_levels",
+                "m.room.member",
+                "m.room.create",
+            ]
+        );
+
+        // The room starts with the create event
+        let last_page = messages_before(
+            &rocket,
+            &remote.room_id,
+            &bob,
+            third_page.end.expect("there are older events"),
+        )
+        .await;
+        assert!(last_page.chunk.is_empty());
+        assert!(last_page.end.is_none());
+
+        // The state before the oldest event of each backfilled batch was requested, the state of
+        // the other events was derived from their prev events
+        let state_requests = remote
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(request, _)| request.starts_with("GET /_matrix/federation/v1/state/"))
+            .count();
+        assert_eq!(state_requests, 2);
+
+        let db = rocket
+            .state::<Database>()
+            .expect("rocket manages the database");
+        for message in &messages {
+            let state = db
+                .rooms
+                .state_at(message)
+                .unwrap()
+                .expect("backfilled events have state");
+            assert!(state.contains_key(&(EventType::RoomHistoryVisibility, "".to_owned())));
+            assert!(!state.contains_key(&(EventType::RoomMember, bob.to_string())));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn backfilled_events_without_remote_state_use_the_earliest_known_state() {
+        let db = Database::load_for_tests(&[]);
+        let remote = test_utils::RemoteServer::start().await;
+        let (bob, messages) = join_remote_room(&db, &remote);
+        remote.refuse_state_requests();
+
+        let from = (db.globals.current_count().unwrap() + 1).to_string();
+        let rocket = test_utils::rocket(db);
+
+        // The events are not hidden just because the other server didn't send their state
+        let page = messages_before(&rocket, &remote.room_id, &bob, from).await;
+        assert_eq!(page.chunk.len(), 10);
+
+        // The state at our join is the only state we know
+        let db = rocket
+            .state::<Database>()
+            .expect("rocket manages the database");
+        let join = db
+            .rooms
+            .pdus_until(&bob, &remote.room_id, u64::MAX >> 1)
+            .next()
+            .expect("room has events")
+            .unwrap()
+            .1;
+        assert_eq!(join.sender, bob);
+        let join_state = db.rooms.state_at(&join.event_id).unwrap();
+        for message in &messages[10..] {
+            assert_eq!(db.rooms.state_at(message).unwrap(), join_state);
+        }
+    }
+}
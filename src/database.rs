@@ -1,18 +1,24 @@
 pub(self) mod account_data;
-pub(self) mod globals;
+pub mod globals;
 pub(self) mod key_backups;
 pub(self) mod media;
+pub mod pushers;
 pub(self) mod rooms;
+pub(self) mod sending;
 pub(self) mod uiaa;
-pub(self) mod users;
+pub mod users;
+pub mod watchers;
 
 use crate::{Error, Result};
 use directories::ProjectDirs;
 use log::info;
-use std::fs::remove_dir_all;
+use std::{
+    collections::HashSet,
+    fs::remove_dir_all,
+    sync::{Arc, Mutex},
+};
 
-use futures::StreamExt;
-use rocket::{futures, Config};
+use rocket::{tokio::sync::Notify, Config};
 use ruma::{DeviceId, UserId};
 
 pub struct Database {
@@ -23,6 +29,8 @@ pub struct Database {
     pub account_data: account_data::AccountData,
     pub media: media::Media,
     pub key_backups: key_backups::KeyBackups,
+    pub sending: sending::Sending,
+    pub pushers: pushers::Pushers,
     pub _db: sled::Db,
 }
 
@@ -65,12 +73,18 @@ impl Database {
         let db = sled::open(&path)?;
         info!("Opened sled database at {}", path);
 
-        Ok(Self {
-            globals: globals::Globals::load(db.open_tree("global")?, config)?,
+        let db = Self {
+            globals: globals::Globals::load(
+                db.open_tree("global")?,
+                db.open_tree("servername_destination")?,
+                db.open_tree("servernamekeyid_verifykey")?,
+                config,
+            )?,
             users: users::Users {
                 userid_password: db.open_tree("userid_password")?,
                 userid_displayname: db.open_tree("userid_displayname")?,
                 userid_avatarurl: db.open_tree("userid_avatarurl")?,
+                guestuserids: db.open_tree("guestuserids")?,
                 userdeviceid_token: db.open_tree("userdeviceid_token")?,
                 userdeviceid_metadata: db.open_tree("userdeviceid_metadata")?,
                 token_userdeviceid: db.open_tree("token_userdeviceid")?,
@@ -82,6 +96,10 @@ impl Database {
                 userid_selfsigningkeyid: db.open_tree("userid_selfsigningkeyid")?,
                 userid_usersigningkeyid: db.open_tree("userid_usersigningkeyid")?,
                 todeviceid_events: db.open_tree("todeviceid_events")?,
+
+                userfilterid_filter: db.open_tree("userfilterid_filter")?,
+
+                userdeviceid_slidingsync: db.open_tree("userdeviceid_slidingsync")?,
             },
             uiaa: uiaa::Uiaa {
                 userdeviceid_uiaainfo: db.open_tree("userdeviceid_uiaainfo")?,
@@ -99,6 +117,13 @@ impl Database {
                 eventid_pduid: db.open_tree("eventid_pduid")?,
                 roomid_pduleaves: db.open_tree("roomid_pduleaves")?,
                 roomstateid_pdu: db.open_tree("roomstateid_pdu")?,
+                eventid_stategroup: db.open_tree("eventid_stategroup")?,
+                stategroup_parent: db.open_tree("stategroup_parent")?,
+                stategroupkey_eventid: db.open_tree("stategroupkey_eventid")?,
+                eventid_softfailedpdu: db.open_tree("eventid_softfailedpdu")?,
+                eventid_rejectionreason: db.open_tree("eventid_rejectionreason")?,
+                roomeventid_missing: db.open_tree("roomeventid_missing")?,
+                eventid_outlierpdu: db.open_tree("eventid_outlierpdu")?,
 
                 alias_roomid: db.open_tree("alias_roomid")?,
                 aliasid_alias: db.open_tree("alias_roomid")?,
@@ -109,6 +134,17 @@ impl Database {
                 userroomid_invited: db.open_tree("userroomid_invited")?,
                 roomuserid_invited: db.open_tree("roomuserid_invited")?,
                 userroomid_left: db.open_tree("userroomid_left")?,
+                userroomid_invitestate: db.open_tree("userroomid_invitestate")?,
+
+                lazyloadedids: db.open_tree("lazyloadedids")?,
+
+                tokenids: db.open_tree("tokenids")?,
+
+                userroomid_notificationcount: db.open_tree("userroomid_notificationcount")?,
+                userroomid_highlightcount: db.open_tree("userroomid_highlightcount")?,
+                usernotificationid_notification: db.open_tree("usernotificationid_notification")?,
+                notified_users: Arc::new(Mutex::new(HashSet::new())),
+                new_notifications: Arc::new(Notify::new()),
             },
             account_data: account_data::AccountData {
                 roomuserdataid_accountdata: db.open_tree("roomuserdataid_accountdata")?,
@@ -121,77 +157,89 @@ impl Database {
                 backupid_etag: db.open_tree("backupid_etag")?,
                 backupkeyid_backup: db.open_tree("backupkeyid_backupmetadata")?,
             },
+            sending: sending::Sending::load(
+                db.open_tree("servernamepduids")?,
+                db.open_tree("servernameeduids")?,
+            )?,
+            pushers: pushers::Pushers {
+                pusherid_pusher: db.open_tree("pusherid_pusher")?,
+                pusherid_lastnotification: db.open_tree("pusherid_lastnotification")?,
+            },
             _db: db,
-        })
-    }
-
-    pub async fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> () {
-        let mut userid_prefix = user_id.to_string().as_bytes().to_vec();
-        userid_prefix.push(0xff);
-        let mut userdeviceid_prefix = userid_prefix.clone();
-        userdeviceid_prefix.extend_from_slice(device_id.as_bytes());
-        userdeviceid_prefix.push(0xff);
+        };
 
-        let mut futures = futures::stream::FuturesUnordered::new();
+        db.migrate()?;
 
-        futures.push(self.users.keychangeid_userid.watch_prefix(b""));
+        Ok(db)
+    }
 
-        // Return when *any* user changed his key
-        // TODO: only send for user they share a room with
-        futures.push(
-            self.users
-                .todeviceid_events
-                .watch_prefix(&userdeviceid_prefix),
-        );
+    /// Brings databases that were created by older versions up to date.
+    fn migrate(&self) -> Result<()> {
+        if self.globals.database_version()? < 1 {
+            info!("Building state snapshots for existing rooms");
+            self.rooms.build_state_snapshots(&self.globals)?;
+            // Only the leaves had their state saved before
+            self._db.drop_tree("leafeventid_state")?;
 
-        futures.push(self.rooms.userroomid_joined.watch_prefix(&userid_prefix));
-        futures.push(self.rooms.userroomid_invited.watch_prefix(&userid_prefix));
-        futures.push(self.rooms.userroomid_left.watch_prefix(&userid_prefix));
+            self.globals.bump_database_version(1)?;
+            info!("Migration to database version 1 done");
+        }
 
-        // Events for rooms we are in
-        for room_id in self.rooms.rooms_joined(user_id).filter_map(|r| r.ok()) {
-            let mut roomid_prefix = room_id.to_string().as_bytes().to_vec();
-            roomid_prefix.push(0xff);
+        if self.globals.database_version()? < 2 {
+            info!("Building the search index for existing events");
+            self.rooms.build_search_index()?;
 
-            // PDUs
-            futures.push(self.rooms.pduid_pdu.watch_prefix(&roomid_prefix));
+            self.globals.bump_database_version(2)?;
+            info!("Migration to database version 2 done");
+        }
 
-            // EDUs
-            futures.push(
-                self.rooms
-                    .edus
-                    .roomid_lastroomactiveupdate
-                    .watch_prefix(&roomid_prefix),
-            );
+        Ok(())
+    }
 
-            futures.push(
-                self.rooms
-                    .edus
-                    .roomlatestid_roomlatest
-                    .watch_prefix(&roomid_prefix),
-            );
+    /// Subscribes to everything that can change the sync response of this device.
+    pub fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> watchers::Watch {
+        let mut keys = vec![
+            watchers::WatchKey::User(user_id.clone()),
+            watchers::WatchKey::Device(user_id.clone(), device_id.into()),
+        ];
+
+        // Events, EDUs and device list changes in rooms we are in
+        keys.extend(
+            self.rooms
+                .rooms_joined(user_id)
+                .filter_map(|r| r.ok())
+                .map(watchers::WatchKey::Room),
+        );
 
-            // Room account data
-            let mut roomuser_prefix = roomid_prefix.clone();
-            roomuser_prefix.extend_from_slice(&userid_prefix);
+        self.globals.watchers().watch(keys)
+    }
+}
 
-            futures.push(
-                self.account_data
-                    .roomuserdataid_accountdata
-                    .watch_prefix(&roomuser_prefix),
+#[cfg(test)]
+impl Database {
+    /// Opens an empty database in a new temporary directory. `extras` are added to the config,
+    /// "true" and "false" as booleans.
+    /// Stub servers in tests use self-signed certificates, so invalid certificates are allowed.
+    pub fn load_for_tests(extras: &[(&str, &str)]) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("conduit-test-{}", crate::utils::random_string(16)));
+
+        let mut config = Config::build(rocket::config::Environment::Development)
+            .extra("server_name", "localhost")
+            .extra("allow_invalid_tls_certificates", true)
+            .extra(
+                "database_path",
+                path.to_str().expect("temporary directory is valid unicode"),
             );
+        for (name, value) in extras {
+            // Flags are the only options tests set that are not strings
+            config = match value.parse::<bool>() {
+                Ok(flag) => config.extra(name, flag),
+                Err(_) => config.extra(name, *value),
+            };
         }
 
-        let mut globaluserdata_prefix = vec![0xff];
-        globaluserdata_prefix.extend_from_slice(&userid_prefix);
-
-        futures.push(
-            self.account_data
-                .roomuserdataid_accountdata
-                .watch_prefix(&globaluserdata_prefix),
-        );
-
-        // Wait until one of them finds something
-        futures.next().await;
+        Self::load_or_create(&config.finalize().expect("test config is valid"))
+            .expect("test database can be created")
     }
 }
@@ -1,3 +1,4 @@
+use super::watchers::WatchKey;
 use crate::{utils, Error, Result};
 use ruma::{
     api::client::error::ErrorKind,
@@ -54,6 +55,8 @@ impl AccountData {
         self.roomuserdataid_accountdata
             .insert(key, &*json.to_string())?;
 
+        globals.watchers().wake(&WatchKey::User(user_id.clone()));
+
         Ok(())
     }
 
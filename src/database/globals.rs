@@ -1,39 +1,238 @@
-use crate::{utils, Error, Result};
+use super::watchers::Watchers;
+use crate::{
+    email::{MailTransport, MaildirTransport, SmtpTransport},
+    utils, Error, Result,
+};
+use http::Uri;
+use hyper::{client::HttpConnector, service::Service};
+use hyper_tls::HttpsConnector;
+use log::warn;
+use rocket::tokio::net::TcpStream;
 use ruma::ServerName;
-use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use trust_dns_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
 
 pub const COUNTER: &str = "c";
+pub const DATABASE_VERSION: &str = "version";
+pub const KEYPAIR_VERSION: &str = "keypair_version";
+pub const OLD_VERIFY_KEY_PREFIX: &str = "oldverifykey_"; // + KeyId -> (PublicKey, ExpiredTs)
 
+/// Where requests to another server are sent, as found by the server discovery.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Destination {
+    /// Host and port to connect to
+    pub address: String,
+    /// Value of the Host header
+    pub host: String,
+}
+
+/// Connects to the address of a destination. The url of a request to another server has the
+/// hostname of the destination, which is used for TLS. If the address has a different host, e.g.
+/// the target of an SRV record, the url has it as its userinfo: `https://target@hostname:port`.
+#[derive(Clone)]
+pub struct DestinationConnector {
+    http: HttpConnector,
+}
+
+impl Service<Uri> for DestinationConnector {
+    type Response = TcpStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<TcpStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, destination: Uri) -> Self::Future {
+        let authority = destination
+            .authority()
+            .map_or("", |authority| authority.as_str());
+        let host = match authority.rfind('@') {
+            Some(userinfo_end) => &authority[..userinfo_end],
+            None => destination.host().unwrap_or_default(),
+        };
+
+        match format!("http://{}:{}", host, destination.port_u16().unwrap_or(8448)).parse() {
+            Ok(address) => {
+                let connecting = self.http.call(address);
+                Box::pin(async move { connecting.await.map_err(Into::into) })
+            }
+            Err(e) => Box::pin(async move { Err(e.into()) }),
+        }
+    }
+}
+
+/// A public key of another server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServerVerifyKey {
+    /// The public key, encoded as unpadded base64
+    pub key: String,
+    /// Until when the key may be used, in milliseconds since the unix epoch
+    pub valid_until_ts: u64,
+    /// When the server stopped using this key, if it's an old key
+    pub expired_ts: Option<u64>,
+}
+
+#[derive(Clone)]
 pub struct Globals {
     pub(super) globals: sled::Tree,
-    keypair: ruma::signatures::Ed25519KeyPair,
+    pub(super) servername_destination: sled::Tree, // Value: (Destination, ExpiresTs)
+    pub(super) servernamekeyid_verifykey: sled::Tree, // ServerNameKeyId = ServerName + KeyId
+    keypair: Arc<ruma::signatures::Ed25519KeyPair>,
     reqwest_client: reqwest::Client,
+    federation_client: hyper::Client<HttpsConnector<DestinationConnector>>,
     server_name: Box<ServerName>,
     max_request_size: u32,
     registration_disabled: bool,
     encryption_disabled: bool,
+    well_known_server: Option<String>,
+    key_validity_period: u64,
+    trusted_servers: Vec<Box<ServerName>>,
+    dns_nameserver: Option<SocketAddr>,
+    dns_resolver: Arc<Mutex<Option<TokioAsyncResolver>>>, // Created on first use
+    mail_transport: Option<Arc<dyn MailTransport>>,
+    email_from: String,
+    email_digest_delay: u64,
+    allow_unvalidated_email_pushers: bool,
+    watchers: Watchers,
 }
 
 impl Globals {
-    pub fn load(globals: sled::Tree, config: &rocket::Config) -> Result<Self> {
+    pub fn load(
+        globals: sled::Tree,
+        servername_destination: sled::Tree,
+        servernamekeyid_verifykey: sled::Tree,
+        config: &rocket::Config,
+    ) -> Result<Self> {
+        let keypair_version = config
+            .get_str("signing_key_version")
+            .unwrap_or("key1")
+            .to_owned();
+        if keypair_version.is_empty()
+            || !keypair_version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(Error::BadConfig("Invalid signing_key_version."));
+        }
+
+        let old_keypair_version =
+            globals
+                .get(KEYPAIR_VERSION)?
+                .map_or(Ok("key1".to_owned()), |bytes| {
+                    utils::string_from_bytes(&bytes)
+                        .map_err(|_| Error::bad_database("Keypair version is invalid."))
+                })?;
+
+        // A new version means a new key, the old one is still served so that events signed with
+        // it can be verified
+        if keypair_version != old_keypair_version {
+            if let Some(old_keypair_bytes) = globals.get("keypair")? {
+                let old_keypair =
+                    ruma::signatures::Ed25519KeyPair::new(&old_keypair_bytes, old_keypair_version)
+                        .map_err(|_| Error::bad_database("Private or public keys are invalid."))?;
+
+                let mut key = OLD_VERIFY_KEY_PREFIX.as_bytes().to_vec();
+                key.extend_from_slice(format!("ed25519:{}", old_keypair.version()).as_bytes());
+                globals.insert(
+                    key,
+                    &*serde_json::to_string(&(
+                        base64::encode_config(old_keypair.public_key(), base64::STANDARD_NO_PAD),
+                        utils::millis_since_unix_epoch(),
+                    ))
+                    .expect("old verify keys can be serialized"),
+                )?;
+                globals.remove("keypair")?;
+            }
+            globals.insert(KEYPAIR_VERSION, keypair_version.as_bytes())?;
+        }
+
         let keypair = ruma::signatures::Ed25519KeyPair::new(
             &*globals
                 .update_and_fetch("keypair", utils::generate_keypair)?
                 .expect("utils::generate_keypair always returns Some"),
-            "key1".to_owned(),
+            keypair_version,
         )
         .map_err(|_| Error::bad_database("Private or public keys are invalid."))?;
 
+        let allow_invalid_tls_certificates = config
+            .get_bool("allow_invalid_tls_certificates")
+            .unwrap_or(false);
+
+        // Other servers and push gateways that don't answer must not block us forever
+        let reqwest_client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(30))
+            .timeout(Duration::from_secs(60))
+            .danger_accept_invalid_certs(allow_invalid_tls_certificates)
+            .build()?;
+
+        let mut http_connector = HttpConnector::new();
+        http_connector.set_connect_timeout(Some(Duration::from_secs(30)));
+        let tls_connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(allow_invalid_tls_certificates)
+            .build()
+            .map_err(|_| Error::BadConfig("Could not create the TLS connector."))?;
+        let federation_client = hyper::Client::builder().build(HttpsConnector::from((
+            DestinationConnector {
+                http: http_connector,
+            },
+            tokio_tls::TlsConnector::from(tls_connector),
+        )));
+
+        let server_name: Box<ServerName> = config
+            .get_str("server_name")
+            .unwrap_or("localhost")
+            .to_string()
+            .try_into()
+            .map_err(|_| Error::BadConfig("Invalid server_name."))?;
+
+        // The maildir is meant for testing, so it wins over a configured SMTP server
+        let mail_transport: Option<Arc<dyn MailTransport>> =
+            if let Ok(path) = config.get_str("email_maildir") {
+                Some(Arc::new(MaildirTransport { path: path.into() }))
+            } else if let Ok(address) = config.get_str("smtp_address") {
+                Some(Arc::new(SmtpTransport {
+                    address: address.to_owned(),
+                    hostname: server_name.to_string(),
+                    credentials: match (
+                        config.get_str("smtp_username"),
+                        config.get_str("smtp_password"),
+                    ) {
+                        (Ok(username), Ok(password)) => {
+                            Some((username.to_owned(), password.to_owned()))
+                        }
+                        _ => None,
+                    },
+                }))
+            } else {
+                None
+            };
+
         Ok(Self {
             globals,
-            keypair,
-            reqwest_client: reqwest::Client::new(),
-            server_name: config
-                .get_str("server_name")
-                .unwrap_or("localhost")
-                .to_string()
-                .try_into()
-                .map_err(|_| Error::BadConfig("Invalid server_name."))?,
+            servername_destination,
+            servernamekeyid_verifykey,
+            keypair: Arc::new(keypair),
+            reqwest_client,
+            federation_client,
+            email_from: config
+                .get_str("email_from")
+                .map(|email_from| email_from.to_owned())
+                .unwrap_or_else(|_| format!("conduit@{}", server_name)),
+            server_name,
             max_request_size: config
                 .get_int("max_request_size")
                 .unwrap_or(20 * 1024 * 1024) // Default to 20 MB
@@ -41,6 +240,49 @@ impl Globals {
                 .map_err(|_| Error::BadConfig("Invalid max_request_size."))?,
             registration_disabled: config.get_bool("registration_disabled").unwrap_or(false),
             encryption_disabled: config.get_bool("encryption_disabled").unwrap_or(false),
+            well_known_server: config
+                .get_str("well_known_server")
+                .ok()
+                .map(|s| s.to_owned()),
+            key_validity_period: config
+                .get_int("key_validity_period")
+                .unwrap_or(60 * 60 * 24 * 7) // Default to one week
+                .try_into()
+                .map_err(|_| Error::BadConfig("Invalid key_validity_period."))?,
+            trusted_servers: config
+                .get_slice("trusted_servers")
+                .map(|servers| {
+                    servers
+                        .iter()
+                        .map(|server| {
+                            server
+                                .as_str()
+                                .and_then(|server| server.to_owned().try_into().ok())
+                                .ok_or(Error::BadConfig("Invalid server in trusted_servers."))
+                        })
+                        .collect::<Result<_>>()
+                })
+                .unwrap_or_else(|_| Ok(Vec::new()))?,
+            dns_nameserver: config
+                .get_str("dns_nameserver")
+                .ok()
+                .map(|address| {
+                    address
+                        .parse()
+                        .map_err(|_| Error::BadConfig("Invalid dns_nameserver."))
+                })
+                .transpose()?,
+            dns_resolver: Arc::new(Mutex::new(None)),
+            mail_transport,
+            email_digest_delay: config
+                .get_int("email_digest_delay")
+                .unwrap_or(60 * 10) // Default to 10 minutes
+                .try_into()
+                .map_err(|_| Error::BadConfig("Invalid email_digest_delay."))?,
+            allow_unvalidated_email_pushers: config
+                .get_bool("allow_unvalidated_email_pushers")
+                .unwrap_or(false),
+            watchers: Watchers::default(),
         })
     }
 
@@ -54,6 +296,17 @@ impl Globals {
         &self.reqwest_client
     }
 
+    /// Returns the client for requests to other servers. See `DestinationConnector` for the urls
+    /// it expects.
+    pub fn federation_client(&self) -> &hyper::Client<HttpsConnector<DestinationConnector>> {
+        &self.federation_client
+    }
+
+    /// Returns the hub that wakes up sync requests.
+    pub fn watchers(&self) -> &Watchers {
+        &self.watchers
+    }
+
     pub fn next_count(&self) -> Result<u64> {
         Ok(utils::u64_from_bytes(
             &self
@@ -71,6 +324,22 @@ impl Globals {
         })
     }
 
+    /// Returns the version of the database layout, which is increased by migrations.
+    pub fn database_version(&self) -> Result<u64> {
+        self.globals
+            .get(DATABASE_VERSION)?
+            .map_or(Ok(0_u64), |bytes| {
+                Ok(utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Database version has invalid bytes."))?)
+            })
+    }
+
+    pub fn bump_database_version(&self, version: u64) -> Result<()> {
+        self.globals
+            .insert(DATABASE_VERSION, &version.to_be_bytes())?;
+        Ok(())
+    }
+
     pub fn server_name(&self) -> &ServerName {
         self.server_name.as_ref()
     }
@@ -86,4 +355,184 @@ impl Globals {
     pub fn encryption_disabled(&self) -> bool {
         self.encryption_disabled
     }
+
+    /// Returns the server other homeservers should connect to, if it is delegated.
+    pub fn well_known_server(&self) -> Option<&str> {
+        self.well_known_server.as_deref()
+    }
+
+    /// Returns for how many seconds other servers may cache our signing keys.
+    pub fn key_validity_period(&self) -> u64 {
+        self.key_validity_period
+    }
+
+    /// Returns how notification emails are sent, if they are enabled.
+    pub fn mail_transport(&self) -> Option<&dyn MailTransport> {
+        self.mail_transport.as_deref()
+    }
+
+    /// Returns the sender address of notification emails.
+    pub fn email_from(&self) -> &str {
+        &self.email_from
+    }
+
+    /// Returns how many seconds email digests wait for more notifications.
+    pub fn email_digest_delay(&self) -> u64 {
+        self.email_digest_delay
+    }
+
+    /// Returns if users can add email pushers for addresses that were not validated.
+    pub fn allow_unvalidated_email_pushers(&self) -> bool {
+        self.allow_unvalidated_email_pushers
+    }
+
+    /// Returns the keys this server used before, with the time they expired.
+    pub fn old_verify_keys(&self) -> Result<BTreeMap<String, (String, u64)>> {
+        self.globals
+            .scan_prefix(OLD_VERIFY_KEY_PREFIX)
+            .map(|r| {
+                let (key, value) = r?;
+                let key_id = utils::string_from_bytes(&key[OLD_VERIFY_KEY_PREFIX.len()..])
+                    .map_err(|_| Error::bad_database("Old verify key id is invalid."))?;
+                let (public_key, expired_ts) = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Old verify key is invalid."))?;
+
+                Ok((key_id, (public_key, expired_ts)))
+            })
+            .collect()
+    }
+
+    /// Returns the servers that are asked for the keys of other servers if the servers can't be
+    /// reached directly.
+    pub fn trusted_servers(&self) -> &[Box<ServerName>] {
+        &self.trusted_servers
+    }
+
+    /// Returns the resolver for DNS lookups of other servers. All lookups share one resolver, so
+    /// they share its cache too.
+    pub async fn dns_resolver(&self) -> Result<TokioAsyncResolver> {
+        let cached = self
+            .dns_resolver
+            .lock()
+            .expect("dns_resolver lock is not poisoned")
+            .clone();
+        if let Some(resolver) = cached {
+            return Ok(resolver);
+        }
+
+        let resolver = match self.dns_nameserver {
+            Some(nameserver) => {
+                TokioAsyncResolver::tokio(
+                    ResolverConfig::from_parts(
+                        None,
+                        Vec::new(),
+                        NameServerConfigGroup::from_ips_clear(
+                            &[nameserver.ip()],
+                            nameserver.port(),
+                        ),
+                    ),
+                    ResolverOpts::default(),
+                )
+                .await
+            }
+            None => TokioAsyncResolver::tokio_from_system_conf().await,
+        }
+        .map_err(|e| {
+            warn!("Could not create DNS resolver: {}", e);
+            Error::BadConfig("Could not create DNS resolver.")
+        })?;
+
+        // Two lookups might both create a resolver at first, but only one is kept
+        Ok(self
+            .dns_resolver
+            .lock()
+            .expect("dns_resolver lock is not poisoned")
+            .get_or_insert(resolver)
+            .clone())
+    }
+
+    /// Returns where requests to the server should be sent, if it was looked up recently.
+    pub fn cached_destination(&self, server: &ServerName) -> Result<Option<Destination>> {
+        self.servername_destination
+            .get(server.as_str())?
+            .map_or(Ok(None), |bytes| {
+                let (destination, expires_ts) =
+                    serde_json::from_slice::<(Destination, u64)>(&bytes).map_err(|_| {
+                        Error::bad_database("Invalid destination in servername_destination.")
+                    })?;
+
+                Ok(Some(destination).filter(|_| expires_ts > utils::millis_since_unix_epoch()))
+            })
+    }
+
+    /// Saves where requests to the server should be sent until `expires_ts`.
+    pub fn cache_destination(
+        &self,
+        server: &ServerName,
+        destination: &Destination,
+        expires_ts: u64,
+    ) -> Result<()> {
+        self.servername_destination.insert(
+            server.as_str(),
+            &*serde_json::to_string(&(destination, expires_ts))
+                .expect("destinations can be serialized"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Saves public keys of another server. Known keys are updated.
+    pub fn add_verify_keys(
+        &self,
+        server: &ServerName,
+        keys: &BTreeMap<String, ServerVerifyKey>,
+    ) -> Result<()> {
+        for (key_id, verify_key) in keys {
+            let mut key = server.as_str().as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(key_id.as_bytes());
+
+            self.servernamekeyid_verifykey.insert(
+                key,
+                &*serde_json::to_string(verify_key).expect("verify keys can be serialized"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns all known public keys of another server, including expired ones.
+    pub fn verify_keys(&self, server: &ServerName) -> Result<BTreeMap<String, ServerVerifyKey>> {
+        let mut prefix = server.as_str().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.servernamekeyid_verifykey
+            .scan_prefix(&prefix)
+            .map(|r| {
+                let (key, value) = r?;
+                let key_id = utils::string_from_bytes(&key[prefix.len()..]).map_err(|_| {
+                    Error::bad_database("Key id in servernamekeyid_verifykey is invalid.")
+                })?;
+                let verify_key = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid verify key in db."))?;
+
+                Ok((key_id, verify_key))
+            })
+            .collect()
+    }
+
+    /// Returns a public key of another server and until when it's valid.
+    pub fn verify_key(&self, server: &ServerName, key_id: &str) -> Result<Option<ServerVerifyKey>> {
+        let mut key = server.as_str().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(key_id.as_bytes());
+
+        self.servernamekeyid_verifykey
+            .get(key)?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid verify key in db.")
+                })?))
+            })
+    }
 }
@@ -0,0 +1,1023 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+use crate::{email::Email, push_rules, utils, Error, PduEvent, Result};
+use http::header::CONTENT_TYPE;
+use log::{info, warn};
+use rocket::{
+    futures::stream::{FuturesUnordered, StreamExt},
+    tokio,
+};
+use ruma::{events::EventType, push::Action, DeviceId, UserId};
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+
+use super::{
+    globals::Globals,
+    rooms::{Notification, Rooms},
+    users::Users,
+};
+
+/// The maximum number of notifications a pusher sends before the queue is checked again.
+const MAX_NOTIFICATIONS_PER_ROUND: usize = 20;
+/// The maximum number of notifications in one email.
+const MAX_NOTIFICATIONS_PER_DIGEST: usize = 100;
+
+/// A device that wants to be notified about events, as described by the client.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Pusher {
+    pub pushkey: String,
+    pub kind: String,
+    pub app_id: String,
+    pub app_display_name: String,
+    pub device_display_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile_tag: Option<String>,
+    pub lang: String,
+    /// The `url` of http pushers and everything else the push gateway needs
+    pub data: serde_json::Map<String, serde_json::Value>,
+    /// When the pushkey was set, in seconds
+    #[serde(default)]
+    pub pushkey_ts: u64,
+}
+
+#[derive(Clone)]
+pub struct Pushers {
+    pub(super) pusherid_pusher: sled::Tree, // PusherId = UserId + DeviceId + AppId + PushKey
+    pub(super) pusherid_lastnotification: sled::Tree, // The count of the last notification the pusher sent
+}
+
+fn pusher_id(user_id: &UserId, device_id: &DeviceId, app_id: &str, pushkey: &str) -> Vec<u8> {
+    let mut key = user_id.to_string().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(device_id.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(app_id.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(pushkey.as_bytes());
+    key
+}
+
+impl Pushers {
+    /// Adds a pusher for the device of the user or replaces the pusher with the same app id and
+    /// pushkey. Only notifications that happen after this are sent to it.
+    pub fn set_pusher(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        pusher: &Pusher,
+        globals: &Globals,
+    ) -> Result<()> {
+        self.remove_pusher(user_id, &pusher.app_id, &pusher.pushkey)?;
+
+        let key = pusher_id(user_id, device_id, &pusher.app_id, &pusher.pushkey);
+        self.pusherid_lastnotification
+            .insert(&key, &globals.current_count()?.to_be_bytes())?;
+        self.pusherid_pusher.insert(
+            key,
+            &*serde_json::to_string(pusher).expect("Pusher::to_string always works"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Removes the pusher with this app id and pushkey from all devices of the user.
+    pub fn remove_pusher(&self, user_id: &UserId, app_id: &str, pushkey: &str) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for r in self.pusherid_pusher.scan_prefix(&prefix) {
+            let (key, value) = r?;
+            let pusher = serde_json::from_slice::<Pusher>(&value)
+                .map_err(|_| Error::bad_database("Invalid pusher in db."))?;
+
+            if pusher.app_id == app_id && pusher.pushkey == pushkey {
+                self.remove_pusher_by_id(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the pushers with this app id and pushkey of all other users.
+    pub fn remove_pusher_of_other_users(
+        &self,
+        user_id: &UserId,
+        app_id: &str,
+        pushkey: &str,
+    ) -> Result<()> {
+        for (key, other_user_id, pusher) in self.all_pushers()? {
+            if &other_user_id != user_id && pusher.app_id == app_id && pusher.pushkey == pushkey {
+                self.remove_pusher_by_id(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the pushers of a device, or all pushers of the user if no device is given.
+    pub fn remove_pushers(&self, user_id: &UserId, device_id: Option<&DeviceId>) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        if let Some(device_id) = device_id {
+            prefix.extend_from_slice(device_id.as_bytes());
+            prefix.push(0xff);
+        }
+
+        for key in self.pusherid_pusher.scan_prefix(&prefix).keys() {
+            self.remove_pusher_by_id(&key?)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_pusher_by_id(&self, pusher_id: &[u8]) -> Result<()> {
+        self.pusherid_pusher.remove(pusher_id)?;
+        self.pusherid_lastnotification.remove(pusher_id)?;
+
+        Ok(())
+    }
+
+    /// Returns the pushers of all devices of the user.
+    pub fn get_pushers(&self, user_id: &UserId) -> Result<Vec<Pusher>> {
+        Ok(self
+            .user_pushers(user_id)?
+            .into_iter()
+            .map(|(_, pusher)| pusher)
+            .collect())
+    }
+
+    /// Returns the pushers of all devices of the user with their pusher ids.
+    fn user_pushers(&self, user_id: &UserId) -> Result<Vec<(IVec, Pusher)>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.pusherid_pusher
+            .scan_prefix(prefix)
+            .map(|r| {
+                let (key, value) = r?;
+                let pusher = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid pusher in db."))?;
+
+                Ok((key, pusher))
+            })
+            .collect()
+    }
+
+    /// Returns all pushers of all users with their pusher ids.
+    fn all_pushers(&self) -> Result<Vec<(IVec, UserId, Pusher)>> {
+        self.pusherid_pusher
+            .iter()
+            .map(|r| {
+                let (key, value) = r?;
+                let user_id = UserId::try_from(
+                    utils::string_from_bytes(
+                        key.split(|&b| b == 0xff)
+                            .next()
+                            .expect("split always returns one element"),
+                    )
+                    .map_err(|_| Error::bad_database("User ID in pusherid_pusher is invalid."))?,
+                )
+                .map_err(|_| Error::bad_database("User ID in pusherid_pusher is invalid."))?;
+                let pusher = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid pusher in db."))?;
+
+                Ok((key, user_id, pusher))
+            })
+            .collect()
+    }
+
+    fn last_notification(&self, pusher_id: &[u8]) -> Result<u64> {
+        self.pusherid_lastnotification
+            .get(pusher_id)?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid notification count of pusher."))
+            })
+    }
+
+    /// Starts a background task that sends new notifications to the push gateways of http
+    /// pushers and email digests to email pushers.
+    pub fn start_handler(&self, globals: &Globals, rooms: &Rooms, users: &Users) {
+        let pushers = self.clone();
+        let globals = globals.clone();
+        let rooms = rooms.clone();
+        let users = users.clone();
+
+        tokio::spawn(async move {
+            let mut futures = FuturesUnordered::new();
+            // PusherId -> UserId
+            let mut in_flight = HashMap::new();
+            // PusherId -> (Number of failed attempts, Next try)
+            let mut backoff = HashMap::<IVec, (u32, Instant)>::new();
+
+            // Users who might have unsent notifications. After a restart that's everyone with a
+            // pusher, later only users who got new notifications
+            let mut waiting = pushers
+                .all_pushers()
+                .unwrap_or_else(|e| {
+                    warn!("Could not read pushers: {}", e);
+                    Vec::new()
+                })
+                .into_iter()
+                .map(|(_, user_id, _)| user_id)
+                .collect::<HashSet<_>>();
+
+            loop {
+                waiting.extend(rooms.take_notified_users());
+
+                let now = Instant::now();
+                // When the next email digest is due
+                let mut next_digest = None::<Instant>;
+                let mut done = Vec::new();
+                for user_id in &waiting {
+                    let user_pushers = match pushers.user_pushers(user_id) {
+                        Ok(user_pushers) => user_pushers,
+                        Err(e) => {
+                            warn!("Could not read pushers of {}: {}", user_id, e);
+                            continue;
+                        }
+                    };
+
+                    // Pushers that have to wait keep the user in the waiting list, pushers that
+                    // are sending add the user again when they are done
+                    let mut pending = false;
+                    for (pusher_id, pusher) in user_pushers {
+                        if in_flight.contains_key(&pusher_id) {
+                            continue;
+                        }
+                        if backoff
+                            .get(&pusher_id)
+                            .filter(|(_, next_try)| *next_try > now)
+                            .is_some()
+                        {
+                            pending = true;
+                            continue;
+                        }
+
+                        let oldest = match pushers.last_notification(&pusher_id).and_then(|last| {
+                            rooms.notifications_since(user_id, last).next().transpose()
+                        }) {
+                            Ok(Some((_, notification))) => notification,
+                            _ => continue,
+                        };
+
+                        match pusher.kind.as_str() {
+                            "http" => {}
+                            "email" => {
+                                // Digests wait a while to collect more notifications
+                                let due = oldest.ts + globals.email_digest_delay() * 1000;
+                                let now_ms = utils::millis_since_unix_epoch();
+                                if due > now_ms {
+                                    let due = now + Duration::from_millis(due - now_ms);
+                                    next_digest =
+                                        Some(next_digest.map_or(due, |next| next.min(due)));
+                                    pending = true;
+                                    continue;
+                                }
+                            }
+                            _ => continue,
+                        }
+
+                        in_flight.insert(pusher_id.clone(), user_id.clone());
+                        futures.push(Self::send_notifications(
+                            pusher_id,
+                            user_id.clone(),
+                            pusher,
+                            pushers.clone(),
+                            globals.clone(),
+                            rooms.clone(),
+                            users.clone(),
+                        ));
+                    }
+
+                    if !pending {
+                        done.push(user_id.clone());
+                    }
+                }
+                for user_id in done {
+                    waiting.remove(&user_id);
+                }
+
+                let next_try = backoff
+                    .values()
+                    .map(|(_, next_try)| *next_try)
+                    .filter(|next_try| *next_try > now)
+                    .chain(next_digest)
+                    .min()
+                    .map_or(Duration::from_secs(60 * 60), |next_try| next_try - now);
+                let delay = tokio::time::delay_for(next_try);
+
+                tokio::select! {
+                    Some((pusher_id, result)) = futures.next() => {
+                        // The rest of the notifications are sent in the next round
+                        if let Some(user_id) = in_flight.remove(&pusher_id) {
+                            waiting.insert(user_id);
+                        }
+
+                        match result {
+                            Ok(()) => {
+                                backoff.remove(&pusher_id);
+                            }
+                            Err(e) => {
+                                let failures = backoff
+                                    .get(&pusher_id)
+                                    .map_or(0, |(failures, _)| *failures)
+                                    + 1;
+                                warn!("Could not send notification (attempt {}): {}", failures, e);
+                                backoff.insert(
+                                    pusher_id,
+                                    (
+                                        failures,
+                                        Instant::now()
+                                            + utils::backoff_duration(
+                                                failures,
+                                                // 10 seconds, at most one hour
+                                                Duration::from_secs(10),
+                                                Duration::from_secs(60 * 60),
+                                            ),
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                    _ = rooms.wait_for_notifications() => {}
+                    _ = delay => {}
+                }
+            }
+        });
+    }
+
+    /// Sends the oldest unsent notifications of the user to the pusher.
+    async fn send_notifications(
+        pusher_id: IVec,
+        user_id: UserId,
+        pusher: Pusher,
+        pushers: Pushers,
+        globals: Globals,
+        rooms: Rooms,
+        users: Users,
+    ) -> (IVec, Result<()>) {
+        let result = match pusher.kind.as_str() {
+            "email" => {
+                Self::send_email_digest(
+                    &pusher_id, &user_id, &pusher, &pushers, &globals, &rooms, &users,
+                )
+                .await
+            }
+            _ => {
+                Self::send_http_notifications(
+                    &pusher_id, &user_id, &pusher, &pushers, &globals, &rooms,
+                )
+                .await
+            }
+        };
+
+        (pusher_id, result)
+    }
+
+    /// Sends the notifications to the push gateway of the pusher, one request per
+    /// notification. The pusher is removed if the gateway rejects its pushkey.
+    async fn send_http_notifications(
+        pusher_id: &IVec,
+        user_id: &UserId,
+        pusher: &Pusher,
+        pushers: &Pushers,
+        globals: &Globals,
+        rooms: &Rooms,
+    ) -> Result<()> {
+        let url = pusher
+            .data
+            .get("url")
+            .and_then(|url| url.as_str())
+            .ok_or_else(|| Error::bad_database("Http pusher has no url."))?;
+        let event_id_only =
+            pusher.data.get("format").and_then(|format| format.as_str()) == Some("event_id_only");
+
+        let notifications = rooms
+            .notifications_since(user_id, pushers.last_notification(pusher_id)?)
+            .take(MAX_NOTIFICATIONS_PER_ROUND)
+            .collect::<Result<Vec<_>>>()?;
+
+        for (count, notification) in notifications {
+            // The pusher might have been removed in the meantime
+            if !pushers.pusherid_pusher.contains_key(pusher_id)? {
+                return Ok(());
+            }
+
+            if let Some(pdu) = rooms.get_pdu(&notification.event_id)? {
+                let body =
+                    notification_json(rooms, user_id, pusher, &notification, &pdu, event_id_only)?;
+
+                let response = globals
+                    .reqwest_client()
+                    .post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.to_string())
+                    .send()
+                    .await?;
+
+                if !response.status().is_success() {
+                    return Err(Error::BadServerResponse(
+                        "Push gateway did not accept the notification.",
+                    ));
+                }
+
+                let response = serde_json::from_str::<serde_json::Value>(&response.text().await?)
+                    .unwrap_or_default();
+
+                let rejected = response
+                    .get("rejected")
+                    .and_then(|rejected| rejected.as_array())
+                    .map_or(false, |rejected| {
+                        rejected
+                            .iter()
+                            .any(|pushkey| pushkey.as_str() == Some(&pusher.pushkey))
+                    });
+
+                if rejected {
+                    info!(
+                        "Push gateway rejected pushkey, removing pusher of {}",
+                        user_id
+                    );
+                    pushers.remove_pusher_by_id(pusher_id)?;
+                    return Ok(());
+                }
+            }
+
+            pushers
+                .pusherid_lastnotification
+                .insert(pusher_id, &count.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends one email with all notifications that are still unread, grouped by room.
+    async fn send_email_digest(
+        pusher_id: &IVec,
+        user_id: &UserId,
+        pusher: &Pusher,
+        pushers: &Pushers,
+        globals: &Globals,
+        rooms: &Rooms,
+        users: &Users,
+    ) -> Result<()> {
+        let transport = globals
+            .mail_transport()
+            .ok_or(Error::BadConfig("Email notifications are not configured."))?;
+
+        let notifications = rooms
+            .notifications_since(user_id, pushers.last_notification(pusher_id)?)
+            .take(MAX_NOTIFICATIONS_PER_DIGEST)
+            .collect::<Result<Vec<_>>>()?;
+
+        let last_count = match notifications.last() {
+            Some((count, _)) => *count,
+            None => return Ok(()),
+        };
+
+        // Room -> (Sender, Text)
+        let mut messages = BTreeMap::<_, Vec<_>>::new();
+        for (_, notification) in notifications {
+            let pdu = match rooms.get_pdu(&notification.event_id)? {
+                Some(pdu) => pdu,
+                None => continue,
+            };
+
+            // The user might have read the message in the meantime
+            let read = match (
+                rooms.get_pdu_live_count(&pdu.event_id)?,
+                rooms.edus.room_read_get(&pdu.room_id, user_id)?,
+            ) {
+                (Some(event_count), Some(last_read)) => event_count <= last_read,
+                _ => false,
+            };
+            if read {
+                continue;
+            }
+
+            let sender = match users.displayname(&pdu.sender)? {
+                Some(display_name) => display_name,
+                None => rooms
+                    .room_state_get(&pdu.room_id, &EventType::RoomMember, pdu.sender.as_str())?
+                    .and_then(|member| {
+                        member
+                            .content
+                            .get("displayname")
+                            .and_then(|name| name.as_str())
+                            .map(str::to_owned)
+                    })
+                    .unwrap_or_else(|| pdu.sender.to_string()),
+            };
+
+            let text = match pdu.content.get("body").and_then(|body| body.as_str()) {
+                Some(body) => body.to_owned(),
+                None if pdu.kind == EventType::RoomEncrypted => "(encrypted message)".to_owned(),
+                None if pdu.kind == EventType::RoomMember => "invited you".to_owned(),
+                None => format!("sent an event of type {}", pdu.kind),
+            };
+
+            messages
+                .entry(pdu.room_id.clone())
+                .or_default()
+                .push((sender, text));
+        }
+
+        if !messages.is_empty() {
+            let message_count = messages.values().map(Vec::len).sum::<usize>();
+
+            let mut body = format!(
+                "You have {} unread {} on {}.\n",
+                message_count,
+                if message_count == 1 {
+                    "message"
+                } else {
+                    "messages"
+                },
+                globals.server_name()
+            );
+            for (room_id, room_messages) in &messages {
+                let room_name = rooms
+                    .room_state_get(room_id, &EventType::RoomName, "")?
+                    .and_then(|name| {
+                        name.content
+                            .get("name")
+                            .and_then(|name| name.as_str())
+                            .map(str::to_owned)
+                    })
+                    .unwrap_or_else(|| room_id.to_string());
+
+                body.push_str(&format!("\n{}\n", room_name));
+                for (sender, text) in room_messages {
+                    body.push_str(&format!("  {}: {}\n", sender, text));
+                }
+            }
+
+            transport
+                .send(&Email {
+                    from: globals.email_from().to_owned(),
+                    to: pusher.pushkey.clone(),
+                    subject: format!(
+                        "{} unread {} in {} {}",
+                        message_count,
+                        if message_count == 1 {
+                            "message"
+                        } else {
+                            "messages"
+                        },
+                        messages.len(),
+                        if messages.len() == 1 { "room" } else { "rooms" }
+                    ),
+                    body,
+                })
+                .await?;
+        }
+
+        pushers
+            .pusherid_lastnotification
+            .insert(pusher_id, &last_count.to_be_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Builds the request body of the push gateway's `/_matrix/push/v1/notify` endpoint.
+fn notification_json(
+    rooms: &Rooms,
+    user_id: &UserId,
+    pusher: &Pusher,
+    notification: &Notification,
+    pdu: &PduEvent,
+    event_id_only: bool,
+) -> Result<serde_json::Value> {
+    let unread = rooms
+        .rooms_joined(user_id)
+        .filter_map(|r| r.ok())
+        .map(|room_id| rooms.notification_count(user_id, &room_id))
+        .sum::<Result<u64>>()?;
+
+    let mut tweaks = serde_json::Map::new();
+    for action in &notification.actions {
+        if let Action::SetTweak(_) = action {
+            let action = serde_json::to_value(action).expect("Action::to_value always works");
+            if let Some(name) = action.get("set_tweak").and_then(|name| name.as_str()) {
+                tweaks.insert(
+                    name.to_owned(),
+                    action.get("value").cloned().unwrap_or_else(|| true.into()),
+                );
+            }
+        }
+    }
+
+    // The url is only for us
+    let mut data = pusher.data.clone();
+    data.remove("url");
+
+    let prio = if push_rules::highlights(&notification.actions) || tweaks.contains_key("sound") {
+        "high"
+    } else {
+        "low"
+    };
+
+    let mut json = serde_json::json!({
+        "event_id": pdu.event_id,
+        "room_id": pdu.room_id,
+        "prio": prio,
+        "counts": {
+            "unread": unread,
+        },
+        "devices": [{
+            "app_id": pusher.app_id,
+            "pushkey": pusher.pushkey,
+            "pushkey_ts": pusher.pushkey_ts,
+            "data": data,
+            "tweaks": tweaks,
+        }],
+    });
+
+    if !event_id_only {
+        let state_content = |event_type, state_key: &str, field| {
+            Ok::<_, Error>(
+                rooms
+                    .room_state_get(&pdu.room_id, &event_type, state_key)?
+                    .and_then(|state| state.content.get(field).cloned()),
+            )
+        };
+
+        json["type"] = pdu.kind.to_string().into();
+        json["sender"] = pdu.sender.to_string().into();
+        json["content"] = pdu.content.clone();
+        json["user_is_target"] = (pdu.kind == EventType::RoomMember
+            && pdu.state_key.as_deref() == Some(user_id.as_str()))
+        .into();
+
+        if let Some(display_name) =
+            state_content(EventType::RoomMember, pdu.sender.as_str(), "displayname")?
+        {
+            json["sender_display_name"] = display_name;
+        }
+        if let Some(room_name) = state_content(EventType::RoomName, "", "name")? {
+            json["room_name"] = room_name;
+        }
+        if let Some(room_alias) = state_content(EventType::RoomCanonicalAlias, "", "alias")? {
+            json["room_alias"] = room_alias;
+        }
+    }
+
+    Ok(serde_json::json!({ "notification": json }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, Database};
+    use ruma::{EventId, RoomId};
+    use serde_json::json;
+
+    /// Creates a public room that alice and bob joined.
+    fn create_room(db: &Database) -> (UserId, UserId, RoomId) {
+        let alice = UserId::try_from("@alice:localhost").expect("user id is valid");
+        let bob = UserId::try_from("@bob:localhost").expect("user id is valid");
+        let room_id = RoomId::new(db.globals.server_name());
+
+        let create = json!({ "creator": alice, "room_version": "6" });
+        send(
+            db,
+            &room_id,
+            &alice,
+            EventType::RoomCreate,
+            Some(""),
+            create,
+        );
+        let join = json!({ "membership": "join" });
+        send(
+            db,
+            &room_id,
+            &alice,
+            EventType::RoomMember,
+            Some(alice.as_str()),
+            join.clone(),
+        );
+        let join_rules = json!({ "join_rule": "public" });
+        send(
+            db,
+            &room_id,
+            &alice,
+            EventType::RoomJoinRules,
+            Some(""),
+            join_rules,
+        );
+        send(
+            db,
+            &room_id,
+            &bob,
+            EventType::RoomMember,
+            Some(bob.as_str()),
+            join,
+        );
+
+        (alice, bob, room_id)
+    }
+
+    fn send(
+        db: &Database,
+        room_id: &RoomId,
+        sender: &UserId,
+        kind: EventType,
+        state_key: Option<&str>,
+        content: serde_json::Value,
+    ) -> EventId {
+        db.rooms
+            .append_pdu(
+                room_id.clone(),
+                sender.clone(),
+                kind,
+                content,
+                None,
+                state_key.map(str::to_owned),
+                None,
+                &db.globals,
+                &db.sending,
+                &db.account_data,
+            )
+            .expect("event is allowed")
+    }
+
+    fn send_message(db: &Database, room_id: &RoomId, sender: &UserId, body: &str) -> EventId {
+        let content = json!({ "msgtype": "m.text", "body": body });
+        send(db, room_id, sender, EventType::RoomMessage, None, content)
+    }
+
+    fn add_pusher(db: &Database, user_id: &UserId, kind: &str, pushkey: &str, url: &str) -> IVec {
+        let device_id: Box<DeviceId> = "DEVICE".to_owned().into();
+        let mut data = serde_json::Map::new();
+        data.insert("url".to_owned(), url.into());
+
+        let pusher = Pusher {
+            pushkey: pushkey.to_owned(),
+            kind: kind.to_owned(),
+            app_id: "org.example.app".to_owned(),
+            app_display_name: "Example".to_owned(),
+            device_display_name: "Phone".to_owned(),
+            profile_tag: None,
+            lang: "en".to_owned(),
+            data,
+            pushkey_ts: 0,
+        };
+        db.pushers
+            .set_pusher(user_id, &device_id, &pusher, &db.globals)
+            .expect("pusher can be saved");
+
+        pusher_id(user_id, &device_id, &pusher.app_id, pushkey).into()
+    }
+
+    async fn send_notifications(db: &Database, pusher_id: &IVec, user_id: &UserId) -> Result<()> {
+        let (_, _, pusher) = db
+            .pushers
+            .all_pushers()
+            .expect("pushers can be read")
+            .into_iter()
+            .find(|(id, _, _)| id == pusher_id)
+            .expect("pusher exists");
+
+        Pushers::send_notifications(
+            pusher_id.clone(),
+            user_id.clone(),
+            pusher,
+            db.pushers.clone(),
+            db.globals.clone(),
+            db.rooms.clone(),
+            db.users.clone(),
+        )
+        .await
+        .1
+    }
+
+    fn last_notification_count(db: &Database, user_id: &UserId) -> u64 {
+        db.rooms
+            .notifications_since(user_id, 0)
+            .last()
+            .expect("user has notifications")
+            .expect("notification is valid")
+            .0
+    }
+
+    #[rocket::async_test]
+    async fn http_pusher_posts_notifications_to_the_gateway() {
+        let db = Database::load_for_tests(&[]);
+        let (alice, bob, room_id) = create_room(&db);
+        let (address, requests) =
+            test_utils::stub_http_server(|_, _| (200, r#"{"rejected":[]}"#.to_owned())).await;
+        let url = format!("http://{}/_matrix/push/v1/notify", address);
+        let pusher_id = add_pusher(&db, &alice, "http", "pushkey", &url);
+
+        let event_id = send_message(&db, &room_id, &bob, "hello");
+        send_notifications(&db, &pusher_id, &alice)
+            .await
+            .expect("gateway accepts the notification");
+
+        let requests = requests.lock().expect("requests lock is not poisoned");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "POST /_matrix/push/v1/notify");
+
+        let body = serde_json::from_slice::<serde_json::Value>(&requests[0].1)
+            .expect("notification is json");
+        let notification = &body["notification"];
+        assert_eq!(notification["event_id"], event_id.to_string());
+        assert_eq!(notification["room_id"], room_id.to_string());
+        assert_eq!(notification["sender"], bob.to_string());
+        assert_eq!(notification["content"]["body"], "hello");
+        assert_eq!(notification["devices"][0]["pushkey"], "pushkey");
+        assert_eq!(notification["devices"][0]["app_id"], "org.example.app");
+        // The url is only for us
+        assert!(notification["devices"][0]["data"].get("url").is_none());
+
+        assert_eq!(
+            db.pushers.last_notification(&pusher_id).unwrap(),
+            last_notification_count(&db, &alice)
+        );
+    }
+
+    #[rocket::async_test]
+    async fn http_pusher_only_sends_notifications_after_it_was_added() {
+        let db = Database::load_for_tests(&[]);
+        let (alice, bob, room_id) = create_room(&db);
+        let (address, requests) = test_utils::stub_http_server(|_, _| (200, "{}".to_owned())).await;
+        let url = format!("http://{}/_matrix/push/v1/notify", address);
+
+        send_message(&db, &room_id, &bob, "before");
+        let pusher_id = add_pusher(&db, &alice, "http", "pushkey", &url);
+        send_message(&db, &room_id, &bob, "after");
+
+        send_notifications(&db, &pusher_id, &alice).await.unwrap();
+
+        let requests = requests.lock().expect("requests lock is not poisoned");
+        assert_eq!(requests.len(), 1);
+        let body = serde_json::from_slice::<serde_json::Value>(&requests[0].1).unwrap();
+        assert_eq!(body["notification"]["content"]["body"], "after");
+    }
+
+    #[rocket::async_test]
+    async fn http_pusher_is_removed_when_the_gateway_rejects_the_pushkey() {
+        let db = Database::load_for_tests(&[]);
+        let (alice, bob, room_id) = create_room(&db);
+        let (address, _) =
+            test_utils::stub_http_server(|_, _| (200, r#"{"rejected":["pushkey"]}"#.to_owned()))
+                .await;
+        let url = format!("http://{}/_matrix/push/v1/notify", address);
+        let pusher_id = add_pusher(&db, &alice, "http", "pushkey", &url);
+
+        send_message(&db, &room_id, &bob, "hello");
+        send_notifications(&db, &pusher_id, &alice).await.unwrap();
+
+        assert!(db.pushers.get_pushers(&alice).unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn http_pusher_retries_notifications_the_gateway_did_not_accept() {
+        let db = Database::load_for_tests(&[]);
+        let (alice, bob, room_id) = create_room(&db);
+        let (address, requests) = test_utils::stub_http_server(|_, _| (500, "{}".to_owned())).await;
+        let url = format!("http://{}/_matrix/push/v1/notify", address);
+        let pusher_id = add_pusher(&db, &alice, "http", "pushkey", &url);
+        let added = db.pushers.last_notification(&pusher_id).unwrap();
+
+        send_message(&db, &room_id, &bob, "hello");
+        assert!(send_notifications(&db, &pusher_id, &alice).await.is_err());
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        // The notification is sent again in the next attempt
+        assert_eq!(db.pushers.last_notification(&pusher_id).unwrap(), added);
+        assert_eq!(db.pushers.get_pushers(&alice).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn only_users_who_got_notifications_are_checked() {
+        let db = Database::load_for_tests(&[]);
+        let (alice, bob, room_id) = create_room(&db);
+        db.rooms.take_notified_users();
+
+        send_message(&db, &room_id, &bob, "hello");
+        let notified_users = db.rooms.take_notified_users();
+        assert!(notified_users.contains(&alice));
+        // Senders are not notified about their own events
+        assert!(!notified_users.contains(&bob));
+
+        assert!(db.rooms.take_notified_users().is_empty());
+    }
+
+    /// Waits until the pusher sent everything or a few seconds passed.
+    async fn wait_until_sent(db: &Database, pusher_id: &IVec, user_id: &UserId) {
+        for _ in 0..100 {
+            if db.pushers.last_notification(pusher_id).unwrap()
+                == last_notification_count(db, user_id)
+            {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[rocket::async_test]
+    async fn handler_sends_new_notifications() {
+        let db = Database::load_for_tests(&[]);
+        let (alice, bob, room_id) = create_room(&db);
+        let (address, requests) = test_utils::stub_http_server(|_, _| (200, "{}".to_owned())).await;
+        let url = format!("http://{}/_matrix/push/v1/notify", address);
+        let pusher_id = add_pusher(&db, &alice, "http", "pushkey", &url);
+        db.pushers.start_handler(&db.globals, &db.rooms, &db.users);
+
+        send_message(&db, &room_id, &bob, "hello");
+        wait_until_sent(&db, &pusher_id, &alice).await;
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // The handler is woken up again for the next notification
+        send_message(&db, &room_id, &bob, "are you there?");
+        wait_until_sent(&db, &pusher_id, &alice).await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let body = serde_json::from_slice::<serde_json::Value>(&requests[1].1).unwrap();
+        assert_eq!(body["notification"]["content"]["body"], "are you there?");
+    }
+
+    /// Returns the messages in the new directory of the maildir.
+    fn maildir_messages(maildir: &std::path::Path) -> Vec<String> {
+        match std::fs::read_dir(maildir.join("new")) {
+            Ok(entries) => entries
+                .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+                .collect(),
+            // Nothing was written yet
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn temporary_maildir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("conduit-test-mail-{}", utils::random_string(16)))
+    }
+
+    #[rocket::async_test]
+    async fn email_digest_contains_all_unread_messages() {
+        let maildir = temporary_maildir();
+        let db = Database::load_for_tests(&[(
+            "email_maildir",
+            maildir
+                .to_str()
+                .expect("temporary directory is valid unicode"),
+        )]);
+        let (alice, bob, room_id) = create_room(&db);
+        let pusher_id = add_pusher(&db, &alice, "email", "alice@example.org", "");
+
+        send_message(&db, &room_id, &bob, "hello");
+        send_message(&db, &room_id, &bob, "how are you?");
+        send_notifications(&db, &pusher_id, &alice)
+            .await
+            .expect("digest can be written");
+
+        let messages = maildir_messages(&maildir);
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0];
+        assert!(message.contains("To: alice@example.org\r\n"));
+        assert!(message.contains("Subject: 2 unread messages in 1 room\r\n"));
+        assert!(message.contains(&format!("\r\n{}\r\n", room_id)));
+        assert!(message.contains("  @bob:localhost: hello\r\n"));
+        assert!(message.contains("  @bob:localhost: how are you?\r\n"));
+
+        // The notifications are only sent once
+        send_notifications(&db, &pusher_id, &alice).await.unwrap();
+        assert_eq!(maildir_messages(&maildir).len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn email_digest_skips_messages_the_user_read() {
+        let maildir = temporary_maildir();
+        let db = Database::load_for_tests(&[(
+            "email_maildir",
+            maildir
+                .to_str()
+                .expect("temporary directory is valid unicode"),
+        )]);
+        let (alice, bob, room_id) = create_room(&db);
+        let pusher_id = add_pusher(&db, &alice, "email", "alice@example.org", "");
+
+        let event_id = send_message(&db, &room_id, &bob, "hello");
+        let count = db.rooms.get_pdu_count(&event_id).unwrap().unwrap();
+        db.rooms
+            .edus
+            .room_read_set(&room_id, &alice, count)
+            .unwrap();
+
+        send_notifications(&db, &pusher_id, &alice).await.unwrap();
+
+        assert!(maildir_messages(&maildir).is_empty());
+        // The read notification is not looked at again
+        assert_eq!(
+            db.pushers.last_notification(&pusher_id).unwrap(),
+            last_notification_count(&db, &alice)
+        );
+    }
+}
@@ -2,33 +2,114 @@ mod edus;
 
 pub use edus::RoomEdus;
 
-use crate::{utils, Error, PduEvent, Result};
-use log::error;
+use super::watchers::WatchKey;
+use crate::{
+    event_auth::{self, AuthError},
+    pdu, push_rules,
+    stateres::{self, StateMap},
+    utils, Error, PduEvent, Result,
+};
+use log::warn;
+use rocket::tokio::sync::Notify;
 use ruma::{
     api::client::error::ErrorKind,
     events::{
-        room::{
-            join_rules, member,
-            power_levels::{self, PowerLevelsEventContent},
-            redaction,
-        },
-        EventType,
+        push_rules::PushRulesEvent,
+        room::{create, guest_access, history_visibility, member, power_levels, redaction},
+        AnyStrippedStateEvent, EventType,
     },
-    EventId, Raw, RoomAliasId, RoomId, UserId,
+    push::Action,
+    DeviceId, EventId, Raw, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sled::IVec;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     mem,
+    sync::{Arc, Mutex},
 };
 
+/// Tokens of backfilled pdus start here, the counts of all other pdus are lower.
+const BACKFILL_TOKEN_START: u64 = 1 << 63;
+
+/// The maximum number of deltas between a state group and its full snapshot.
+const MAX_STATE_DELTA_CHAIN: u64 = 100;
+
+/// Returns the id of the pdu in the room with this token (the count for normal pdus).
+fn pdu_id_from_token(room_id: &RoomId, token: u64) -> Vec<u8> {
+    let mut pdu_id = room_id.to_string().as_bytes().to_vec();
+    pdu_id.push(0xff);
+
+    // The zero makes backfilled pdus older than all other pdus of the room
+    if token >= BACKFILL_TOKEN_START {
+        pdu_id.extend_from_slice(&0_u64.to_be_bytes());
+    }
+    pdu_id.extend_from_slice(&token.to_be_bytes());
+
+    pdu_id
+}
+
+/// Returns the content field of events of this type that can be found with /search.
+fn search_field(kind: &EventType) -> Option<&'static str> {
+    match kind {
+        EventType::RoomMessage => Some("body"),
+        EventType::RoomName => Some("name"),
+        EventType::RoomTopic => Some("topic"),
+        _ => None,
+    }
+}
+
+/// Counts how often each word appears in the searchable field of the pdu.
+fn search_tokens(pdu: &PduEvent) -> HashMap<String, u64> {
+    let mut tokens = HashMap::new();
+
+    if let Some(text) = search_field(&pdu.kind)
+        .and_then(|field| pdu.content.get(field))
+        .and_then(|text| text.as_str())
+    {
+        for token in utils::tokenize(text) {
+            *tokens.entry(token).or_default() += 1;
+        }
+    }
+
+    tokens
+}
+
+fn parse_notification(entry: sled::Result<(IVec, IVec)>) -> Result<(u64, Notification)> {
+    let (key, value) = entry?;
+    let count = utils::u64_from_bytes(&key[key.len() - mem::size_of::<u64>()..])
+        .map_err(|_| Error::bad_database("Invalid count in notification id."))?;
+    let notification = serde_json::from_slice(&value)
+        .map_err(|_| Error::bad_database("Invalid notification in db."))?;
+
+    Ok((count, notification))
+}
+
+fn search_key(room_id: &RoomId, token: &str, pdu_id: &[u8]) -> Vec<u8> {
+    let mut key = room_id.to_string().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(token.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(pdu_id);
+    key
+}
+
+#[derive(Clone)]
 pub struct Rooms {
     pub edus: edus::RoomEdus,
-    pub(super) pduid_pdu: sled::Tree, // PduId = RoomId + Count
+    pub(super) pduid_pdu: sled::Tree, // PduId = RoomId + Count, or RoomId + 0 + Token for backfilled pdus
     pub(super) eventid_pduid: sled::Tree,
     pub(super) roomid_pduleaves: sled::Tree,
     pub(super) roomstateid_pdu: sled::Tree, // RoomStateId = Room + StateType + StateKey
+    pub(super) eventid_stategroup: sled::Tree, // StateGroup = Count, the state after the event
+    pub(super) stategroup_parent: sled::Tree, // Value = Parent StateGroup + Number of deltas to the full snapshot
+    pub(super) stategroupkey_eventid: sled::Tree, // StateGroupKey = StateGroup + StateType + StateKey, empty value if removed
+    pub(super) eventid_softfailedpdu: sled::Tree, // Valid events that are not allowed by the current state
+    pub(super) eventid_rejectionreason: sled::Tree, // Events that didn't pass the auth checks
+    pub(super) roomeventid_missing: sled::Tree,   // Prev events we don't have yet
+    pub(super) eventid_outlierpdu: sled::Tree, // Auth and state events that are not in the timeline
 
     pub(super) alias_roomid: sled::Tree,
     pub(super) aliasid_alias: sled::Tree, // AliasId = RoomId + Count
@@ -39,6 +120,26 @@ pub struct Rooms {
     pub(super) userroomid_invited: sled::Tree,
     pub(super) roomuserid_invited: sled::Tree,
     pub(super) userroomid_left: sled::Tree,
+    pub(super) userroomid_invitestate: sled::Tree, // Stripped state sent with invites from other servers
+
+    pub(super) lazyloadedids: sled::Tree, // LazyLoadedId = UserId + DeviceId + RoomId + LazyLoadedUserId
+
+    pub(super) tokenids: sled::Tree, // TokenId = RoomId + Token + PduId, Value = Number of occurrences
+
+    pub(super) userroomid_notificationcount: sled::Tree, // Events that notified since the last read receipt
+    pub(super) userroomid_highlightcount: sled::Tree,
+    pub(super) usernotificationid_notification: sled::Tree, // NotificationId = UserId + Count
+    pub(super) notified_users: Arc<Mutex<HashSet<UserId>>>, // Users with notifications the pusher handler didn't look at yet
+    pub(super) new_notifications: Arc<Notify>,
+}
+
+/// An event that notified a user according to their push rules.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Notification {
+    pub event_id: EventId,
+    pub room_id: RoomId,
+    pub actions: Vec<Action>,
+    pub ts: u64,
 }
 
 impl Rooms {
@@ -133,6 +234,54 @@ impl Rooms {
         })
     }
 
+    /// Checks if end-to-end encryption was enabled in the room.
+    pub fn is_encrypted(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self
+            .room_state_get(room_id, &EventType::RoomEncryption, "")?
+            .is_some())
+    }
+
+    /// Returns the room version of a room, which is set by its create event.
+    pub fn room_version(&self, room_id: &RoomId) -> Result<RoomVersionId> {
+        let create_event = self
+            .room_state_get(room_id, &EventType::RoomCreate, "")?
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Room has no create event.",
+            ))?;
+
+        Ok(
+            serde_json::from_value::<Raw<create::CreateEventContent>>(create_event.content)
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid create event in db."))?
+                .room_version,
+        )
+    }
+
+    /// Returns the current state events that are needed to authorize an event with these
+    /// properties.
+    pub fn get_auth_events(
+        &self,
+        room_id: &RoomId,
+        kind: &EventType,
+        sender: &UserId,
+        state_key: Option<&str>,
+        content: &serde_json::Value,
+    ) -> Result<StateMap<PduEvent>> {
+        let mut auth_events = StateMap::new();
+
+        for (event_type, state_key) in
+            event_auth::auth_types_for_event(kind, sender, state_key, content)
+        {
+            if let Some(pdu) = self.room_state_get(room_id, &event_type, &state_key)? {
+                auth_events.insert((event_type, state_key), pdu);
+            }
+        }
+
+        Ok(auth_events)
+    }
+
     /// Returns the `count` of this pdu's id.
     pub fn get_pdu_count(&self, event_id: &EventId) -> Result<Option<u64>> {
         self.eventid_pduid
@@ -147,18 +296,62 @@ impl Rooms {
             })
     }
 
-    /// Returns the json of a pdu.
+    /// Returns a count of the pdu that can be compared with the counts of other pdus, e.g. with
+    /// read receipts. Backfilled pdus are older than all other pdus, so they all count as 0.
+    pub fn get_pdu_live_count(&self, event_id: &EventId) -> Result<Option<u64>> {
+        Ok(self.get_pdu_count(event_id)?.map(|count| {
+            if count >= BACKFILL_TOKEN_START {
+                0
+            } else {
+                count
+            }
+        }))
+    }
+
+    /// Returns the count of the newest pdu in the room, or 0 if the room has no pdus yet.
+    pub fn last_pdu_count(&self, room_id: &RoomId) -> Result<u64> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.pduid_pdu
+            .scan_prefix(&prefix)
+            .keys()
+            .next_back()
+            .map_or(Ok(0), |pdu_id| {
+                let pdu_id = pdu_id?;
+                utils::u64_from_bytes(&pdu_id[pdu_id.len() - mem::size_of::<u64>()..])
+                    .map_err(|_| Error::bad_database("PDU has invalid count bytes."))
+            })
+    }
+
+    /// Returns the raw json of a pdu from the timeline or the outliers.
+    fn get_pdu_bytes(&self, event_id: &EventId) -> Result<Option<IVec>> {
+        match self.eventid_pduid.get(event_id.to_string().as_bytes())? {
+            Some(pdu_id) => Ok(Some(self.pduid_pdu.get(pdu_id)?.ok_or_else(|| {
+                Error::bad_database("eventid_pduid points to nonexistent pdu.")
+            })?)),
+            None => Ok(self.eventid_outlierpdu.get(event_id.to_string())?),
+        }
+    }
+
+    /// Returns the json of a pdu. This also finds outliers.
     pub fn get_pdu_json(&self, event_id: &EventId) -> Result<Option<serde_json::Value>> {
-        self.eventid_pduid
-            .get(event_id.to_string().as_bytes())?
-            .map_or(Ok(None), |pdu_id| {
-                Ok(Some(
-                    serde_json::from_slice(&self.pduid_pdu.get(pdu_id)?.ok_or_else(|| {
-                        Error::bad_database("eventid_pduid points to nonexistent pdu.")
-                    })?)
+        self.get_pdu_bytes(event_id)?.map_or(Ok(None), |pdu| {
+            Ok(Some(
+                serde_json::from_slice(&pdu)
                     .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
-                ))
-            })
+            ))
+        })
+    }
+
+    /// Returns the json of a pdu.
+    pub fn get_pdu_json_from_id(&self, pdu_id: &IVec) -> Result<Option<serde_json::Value>> {
+        self.pduid_pdu.get(pdu_id)?.map_or(Ok(None), |pdu| {
+            Ok(Some(
+                serde_json::from_slice(&pdu)
+                    .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+            ))
+        })
     }
 
     /// Returns the pdu's id.
@@ -168,18 +361,14 @@ impl Rooms {
             .map_or(Ok(None), |pdu_id| Ok(Some(pdu_id)))
     }
 
-    /// Returns the pdu.
+    /// Returns the pdu. This also finds outliers.
     pub fn get_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>> {
-        self.eventid_pduid
-            .get(event_id.to_string().as_bytes())?
-            .map_or(Ok(None), |pdu_id| {
-                Ok(Some(
-                    serde_json::from_slice(&self.pduid_pdu.get(pdu_id)?.ok_or_else(|| {
-                        Error::bad_database("eventid_pduid points to nonexistent pdu.")
-                    })?)
+        self.get_pdu_bytes(event_id)?.map_or(Ok(None), |pdu| {
+            Ok(Some(
+                serde_json::from_slice(&pdu)
                     .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
-                ))
-            })
+            ))
+        })
     }
     /// Returns the pdu.
     pub fn get_pdu_from_id(&self, pdu_id: &IVec) -> Result<Option<PduEvent>> {
@@ -230,376 +419,1686 @@ impl Rooms {
             events.push(event?);
         }
 
-        Ok(events)
+        Ok(events)
+    }
+
+    /// Replaces the prev events of a new event in the leaves of a room with the new event.
+    pub fn replace_pdu_leaves(
+        &self,
+        room_id: &RoomId,
+        prev_events: &[EventId],
+        event_id: &EventId,
+    ) -> Result<()> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for prev_event in prev_events {
+            let mut key = prefix.clone();
+            key.extend_from_slice(prev_event.to_string().as_bytes());
+
+            self.roomid_pduleaves.remove(key)?;
+        }
+
+        prefix.extend_from_slice(event_id.to_string().as_bytes());
+        self.roomid_pduleaves
+            .insert(&prefix, &*event_id.to_string())?;
+
+        Ok(())
+    }
+
+    /// Returns the state group of the state after this event, if it's known.
+    fn state_group(&self, event_id: &EventId) -> Result<Option<u64>> {
+        self.eventid_stategroup
+            .get(event_id.to_string())?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid state group in eventid_stategroup.")
+                })?))
+            })
+    }
+
+    /// Returns the parent of a state group and the number of deltas between the group and its
+    /// full snapshot.
+    fn state_group_parent(&self, group: u64) -> Result<Option<(u64, u64)>> {
+        self.stategroup_parent
+            .get(group.to_be_bytes())?
+            .map_or(Ok(None), |bytes| {
+                if bytes.len() != 2 * mem::size_of::<u64>() {
+                    return Err(Error::bad_database("Invalid entry in stategroup_parent."));
+                }
+                let (parent, chain_length) = bytes.split_at(mem::size_of::<u64>());
+                Ok(Some((
+                    utils::u64_from_bytes(parent)
+                        .map_err(|_| Error::bad_database("Invalid entry in stategroup_parent."))?,
+                    utils::u64_from_bytes(chain_length)
+                        .map_err(|_| Error::bad_database("Invalid entry in stategroup_parent."))?,
+                )))
+            })
+    }
+
+    /// Returns the full state of a state group by applying its deltas to the full snapshot it's
+    /// based on.
+    fn state_group_full(&self, group: u64) -> Result<StateMap<EventId>> {
+        let mut groups = vec![group];
+        while let Some((parent, _)) = self.state_group_parent(*groups.last().expect("not empty"))? {
+            groups.push(parent);
+        }
+
+        let mut state = StateMap::new();
+        for group in groups.into_iter().rev() {
+            for entry in self.stategroupkey_eventid.scan_prefix(group.to_be_bytes()) {
+                let (key, value) = entry?;
+                let mut parts = key[mem::size_of::<u64>()..].splitn(2, |&b| b == 0xff);
+                let kind =
+                    utils::string_from_bytes(parts.next().expect("splitn always returns one"))
+                        .map_err(|_| {
+                            Error::bad_database("Invalid event type in stategroupkey_eventid.")
+                        })?;
+                let state_key =
+                    utils::string_from_bytes(parts.next().ok_or_else(|| {
+                        Error::bad_database("Invalid key in stategroupkey_eventid.")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid state key in stategroupkey_eventid.")
+                    })?;
+                let key = (
+                    EventType::try_from(kind).expect("EventType::try_from can never fail"),
+                    state_key,
+                );
+
+                // An empty value means the entry was removed in this group
+                if value.is_empty() {
+                    state.remove(&key);
+                } else {
+                    let event_id =
+                        EventId::try_from(utils::string_from_bytes(&value).map_err(|_| {
+                            Error::bad_database("Invalid event id in stategroupkey_eventid.")
+                        })?)
+                        .map_err(|_| {
+                            Error::bad_database("Invalid event id in stategroupkey_eventid.")
+                        })?;
+                    state.insert(key, event_id);
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Saves `state` as a delta over the `parent` group and returns the new group. No new group is
+    /// created if the state is the same as the parent's.
+    fn save_state_group(
+        &self,
+        state: &StateMap<EventId>,
+        parent: Option<u64>,
+        globals: &super::globals::Globals,
+    ) -> Result<u64> {
+        let (parent, base) = match parent {
+            Some(parent) => {
+                let parent_state = self.state_group_full(parent)?;
+                if &parent_state == state {
+                    return Ok(parent);
+                }
+
+                let chain_length = self.state_group_parent(parent)?.map_or(0, |(_, l)| l) + 1;
+                if chain_length <= MAX_STATE_DELTA_CHAIN {
+                    (Some((parent, chain_length)), parent_state)
+                } else {
+                    // Save a full snapshot, so loading the state doesn't get slower forever
+                    (None, StateMap::new())
+                }
+            }
+            None => (None, StateMap::new()),
+        };
+
+        let group = globals.next_count()?;
+
+        let entry_key = |(kind, state_key): &(EventType, String)| {
+            let mut key = group.to_be_bytes().to_vec();
+            key.extend_from_slice(kind.to_string().as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(state_key.as_bytes());
+            key
+        };
+
+        for (key, event_id) in state {
+            if base.get(key) != Some(event_id) {
+                self.stategroupkey_eventid
+                    .insert(entry_key(key), &*event_id.to_string())?;
+            }
+        }
+        for key in base.keys().filter(|key| !state.contains_key(key)) {
+            self.stategroupkey_eventid.insert(entry_key(key), b"")?;
+        }
+
+        if let Some((parent, chain_length)) = parent {
+            let mut value = parent.to_be_bytes().to_vec();
+            value.extend_from_slice(&chain_length.to_be_bytes());
+            self.stategroup_parent.insert(&group.to_be_bytes(), value)?;
+        }
+
+        Ok(group)
+    }
+
+    /// Saves the state after a pdu. Pdus that don't change the state share the state group of
+    /// their prev event, all other groups are saved as a delta over it.
+    ///
+    /// `state_after` is only called if the state after the pdu can't be derived from its prev
+    /// event.
+    fn save_state_after(
+        &self,
+        pdu: &PduEvent,
+        state_after: impl FnOnce() -> Result<StateMap<EventId>>,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        let parent = match pdu.prev_events.first() {
+            Some(prev_event) => self.state_group(prev_event)?,
+            None => None,
+        };
+
+        let group = match parent {
+            Some(parent) if pdu.state_key.is_none() && pdu.prev_events.len() == 1 => parent,
+            _ => self.save_state_group(&state_after()?, parent, globals)?,
+        };
+
+        self.eventid_stategroup
+            .insert(pdu.event_id.to_string(), &group.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Checks if the state of the room after the event is known.
+    pub fn state_is_known(&self, event_id: &EventId) -> Result<bool> {
+        Ok(self.state_group(event_id)?.is_some())
+    }
+
+    /// Saves the state after an event that we got from another server, e.g. a prev event of a
+    /// new pdu. `state_before` is the state the other server sent us.
+    pub fn save_remote_state(
+        &self,
+        pdu: &PduEvent,
+        mut state_before: StateMap<EventId>,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        if let Some(state_key) = &pdu.state_key {
+            state_before.insert((pdu.kind.clone(), state_key.clone()), pdu.event_id.clone());
+        }
+
+        let group = self.save_state_group(&state_before, None, globals)?;
+        self.eventid_stategroup
+            .insert(pdu.event_id.to_string(), &group.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns the state of the room after the event, if it's known.
+    pub fn state_at(&self, event_id: &EventId) -> Result<Option<StateMap<EventId>>> {
+        self.state_group(event_id)?
+            .map_or(Ok(None), |group| self.state_group_full(group).map(Some))
+    }
+
+    /// Returns a single state event of the state after the event. Nothing is returned if the
+    /// state after the event is unknown.
+    pub fn state_get_at(
+        &self,
+        event_id: &EventId,
+        event_type: &EventType,
+        state_key: &str,
+    ) -> Result<Option<PduEvent>> {
+        let mut group = match self.state_group(event_id)? {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+
+        let mut suffix = event_type.to_string().as_bytes().to_vec();
+        suffix.push(0xff);
+        suffix.extend_from_slice(state_key.as_bytes());
+
+        // Look at the deltas from the newest to the oldest until one has the entry
+        loop {
+            let mut key = group.to_be_bytes().to_vec();
+            key.extend_from_slice(&suffix);
+
+            if let Some(value) = self.stategroupkey_eventid.get(key)? {
+                // An empty value means the entry was removed in this group
+                if value.is_empty() {
+                    return Ok(None);
+                }
+
+                let event_id =
+                    EventId::try_from(utils::string_from_bytes(&value).map_err(|_| {
+                        Error::bad_database("Invalid event id in stategroupkey_eventid.")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid event id in stategroupkey_eventid.")
+                    })?;
+
+                return self.get_pdu(&event_id);
+            }
+
+            match self.state_group_parent(group)? {
+                Some((parent, _)) => group = parent,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Checks if the user may see the event, based on the history visibility and the membership
+    /// of the user when the event was sent.
+    ///
+    /// Events with unknown state are treated like events in a room with shared history.
+    pub fn user_can_see_event(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<bool> {
+        let history_visibility = self
+            .state_get_at(event_id, &EventType::RoomHistoryVisibility, "")?
+            .map_or(Ok(history_visibility::HistoryVisibility::Shared), |pdu| {
+                Ok::<_, Error>(
+                    serde_json::from_value::<
+                        Raw<history_visibility::HistoryVisibilityEventContent>,
+                    >(pdu.content)
+                    .expect("Raw::from_value always works")
+                    .deserialize()
+                    .map_err(|_| {
+                        Error::bad_database("Invalid history visibility event in database.")
+                    })?
+                    .history_visibility,
+                )
+            })?;
+
+        let membership = self
+            .state_get_at(event_id, &EventType::RoomMember, &user_id.to_string())?
+            .map_or(Ok(member::MembershipState::Leave), |pdu| {
+                Ok::<_, Error>(
+                    serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
+                        .expect("Raw::from_value always works")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid member event in database."))?
+                        .membership,
+                )
+            })?;
+
+        let joined = membership == member::MembershipState::Join;
+        let invited = membership == member::MembershipState::Invite;
+
+        Ok(match history_visibility {
+            history_visibility::HistoryVisibility::WorldReadable => true,
+            // Members can see everything that happened before they joined
+            history_visibility::HistoryVisibility::Shared => {
+                joined || invited || self.is_joined(user_id, room_id)?
+            }
+            history_visibility::HistoryVisibility::Invited => joined || invited,
+            _ => joined,
+        })
+    }
+
+    /// Checks if anyone can read the room without joining it.
+    pub fn is_world_readable(&self, room_id: &RoomId) -> Result<bool> {
+        self.room_state_get(room_id, &EventType::RoomHistoryVisibility, "")?
+            .map_or(Ok(false), |pdu| {
+                Ok(serde_json::from_value::<
+                    Raw<history_visibility::HistoryVisibilityEventContent>,
+                >(pdu.content)
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid history visibility event in database."))?
+                .history_visibility
+                    == history_visibility::HistoryVisibility::WorldReadable)
+            })
+    }
+
+    /// Checks if guests are allowed to join the room.
+    pub fn guests_can_join(&self, room_id: &RoomId) -> Result<bool> {
+        self.room_state_get(room_id, &EventType::RoomGuestAccess, "")?
+            .map_or(Ok(false), |pdu| {
+                Ok(
+                    serde_json::from_value::<Raw<guest_access::GuestAccessEventContent>>(
+                        pdu.content,
+                    )
+                    .expect("Raw::from_value always works")
+                    .deserialize()
+                    .map_err(|_| Error::bad_database("Invalid guest access event in database."))?
+                    .guest_access
+                        == guest_access::GuestAccess::CanJoin,
+                )
+            })
+    }
+
+    /// Checks if the user may read events of the room at all. `user_can_see_event` decides
+    /// which ones.
+    pub fn can_read_history(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        guest: bool,
+    ) -> Result<bool> {
+        if guest {
+            // Guests can only read rooms they are allowed to be in, or rooms anyone can read
+            return Ok(self.is_world_readable(room_id)?
+                || (self.is_joined(user_id, room_id)? && self.guests_can_join(room_id)?));
+        }
+
+        Ok(self.is_joined(user_id, room_id)?
+            || self.is_invited(user_id, room_id)?
+            || self.is_left(user_id, room_id)?
+            || self.is_world_readable(room_id)?)
+    }
+
+    /// Builds the state snapshots of rooms that existed before they were saved, by going through
+    /// the timeline of each room. Pdus that already have a snapshot are left alone.
+    pub fn build_state_snapshots(&self, globals: &super::globals::Globals) -> Result<()> {
+        let mut room_prefix = Vec::new();
+        let mut state = StateMap::new();
+        let mut group = None;
+
+        for entry in self.pduid_pdu.iter() {
+            let (pdu_id, value) = entry?;
+            let prefix_len = pdu_id
+                .iter()
+                .position(|&b| b == 0xff)
+                .ok_or_else(|| Error::bad_database("Invalid pdu id in pduid_pdu."))?
+                + 1;
+
+            // Backfilled pdus are older than the state we know, so we can't tell their state
+            if pdu_id.len() > prefix_len + mem::size_of::<u64>() {
+                continue;
+            }
+
+            if pdu_id[..prefix_len] != room_prefix[..] {
+                room_prefix = pdu_id[..prefix_len].to_vec();
+                state = StateMap::new();
+                group = None;
+            }
+
+            let pdu = serde_json::from_slice::<PduEvent>(&value)
+                .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+
+            if let Some(existing) = self.state_group(&pdu.event_id)? {
+                state = self.state_group_full(existing)?;
+                group = Some(existing);
+                continue;
+            }
+
+            if let Some(state_key) = &pdu.state_key {
+                state.insert((pdu.kind.clone(), state_key.clone()), pdu.event_id.clone());
+                group = Some(self.save_state_group(&state, group, globals)?);
+            }
+
+            if let Some(group) = group {
+                self.eventid_stategroup
+                    .insert(pdu.event_id.to_string(), &group.to_be_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the words of the pdu to the search index.
+    fn index_pdu(&self, pdu: &PduEvent, pdu_id: &[u8]) -> Result<()> {
+        for (token, count) in search_tokens(pdu) {
+            self.tokenids.insert(
+                search_key(&pdu.room_id, &token, pdu_id),
+                &count.to_be_bytes(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds all pdus that existed before the search index to it.
+    pub fn build_search_index(&self) -> Result<()> {
+        for entry in self.pduid_pdu.iter() {
+            let (pdu_id, value) = entry?;
+            let pdu = serde_json::from_slice::<PduEvent>(&value)
+                .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+
+            self.index_pdu(&pdu, &pdu_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds the pdus in the room that contain all tokens. Returns their pdu ids and how often
+    /// the tokens appear in them.
+    pub fn search_pdus(&self, room_id: &RoomId, tokens: &[String]) -> Result<Vec<(IVec, u64)>> {
+        let mut results = None::<HashMap<IVec, u64>>;
+
+        for token in tokens {
+            let prefix = search_key(room_id, token, &[]);
+
+            let mut matches = HashMap::new();
+            for entry in self.tokenids.scan_prefix(&prefix) {
+                let (key, value) = entry?;
+                let pdu_id = IVec::from(&key[prefix.len()..]);
+                let count = utils::u64_from_bytes(&value)
+                    .map_err(|_| Error::bad_database("Invalid count in tokenids."))?;
+
+                // Pdus need to contain all tokens
+                match &results {
+                    Some(results) => {
+                        if let Some(previous) = results.get(&pdu_id) {
+                            matches.insert(pdu_id, previous + count);
+                        }
+                    }
+                    None => {
+                        matches.insert(pdu_id, count);
+                    }
+                }
+            }
+
+            results = Some(matches);
+        }
+
+        Ok(results.unwrap_or_default().into_iter().collect())
+    }
+
+    /// Evaluates the push rules of the local members of the room for a new pdu and updates
+    /// their notification counts.
+    fn update_notification_counts(
+        &self,
+        pdu: &PduEvent,
+        globals: &super::globals::Globals,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<()> {
+        let members = self
+            .room_members(&pdu.room_id)
+            .filter_map(|r| r.ok())
+            .collect::<Vec<_>>();
+
+        let power_levels = self
+            .room_state_get(&pdu.room_id, &EventType::RoomPowerLevels, "")?
+            .map(|pdu| pdu.content);
+
+        let sender_power_level = match &power_levels {
+            Some(power_levels) => power_levels
+                .get("users")
+                .and_then(|users| users.get(pdu.sender.as_str()))
+                .or_else(|| power_levels.get("users_default"))
+                .and_then(|level| level.as_i64())
+                .unwrap_or(0),
+            // Without power levels only the creator has a power level
+            None => self
+                .room_state_get(&pdu.room_id, &EventType::RoomCreate, "")?
+                .map_or(
+                    0,
+                    |create| if create.sender == pdu.sender { 100 } else { 0 },
+                ),
+        };
+
+        let context = push_rules::PushContext {
+            member_count: members.len() as u64,
+            sender_power_level,
+            notification_power_levels: power_levels
+                .as_ref()
+                .and_then(|power_levels| power_levels.get("notifications"))
+                .and_then(|notifications| notifications.as_object())
+                .map(|notifications| {
+                    notifications
+                        .iter()
+                        .filter_map(|(key, level)| Some((key.clone(), level.as_i64()?)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+
+        // Invited users are not members yet, but they should be notified about their invite
+        let mut users = members;
+        if pdu.kind == EventType::RoomMember
+            && pdu.content.get("membership").and_then(|m| m.as_str()) == Some("invite")
+        {
+            users.extend(
+                pdu.state_key
+                    .as_deref()
+                    .and_then(|state_key| UserId::try_from(state_key).ok()),
+            );
+        }
+
+        let mut notified_users = Vec::new();
+        for user_id in users {
+            if user_id == pdu.sender || user_id.server_name() != globals.server_name() {
+                continue;
+            }
+
+            let ruleset = account_data
+                .get::<PushRulesEvent>(None, &user_id, EventType::PushRules)?
+                .map_or_else(
+                    || push_rules::default_pushrules(&user_id),
+                    |event| event.content.global,
+                );
+
+            let display_name = self
+                .room_state_get(&pdu.room_id, &EventType::RoomMember, user_id.as_str())?
+                .and_then(|member| {
+                    member
+                        .content
+                        .get("displayname")
+                        .and_then(|name| name.as_str())
+                        .map(str::to_owned)
+                });
+
+            let actions = push_rules::evaluate(&ruleset, display_name.as_deref(), &context, pdu);
+
+            let mut key = user_id.to_string().as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(pdu.room_id.to_string().as_bytes());
+
+            if push_rules::notifies(&actions) {
+                self.userroomid_notificationcount
+                    .update_and_fetch(&key, utils::increment)?;
+            }
+            if push_rules::highlights(&actions) {
+                self.userroomid_highlightcount
+                    .update_and_fetch(&key, utils::increment)?;
+            }
+
+            if push_rules::notifies(&actions) {
+                let mut key = user_id.to_string().as_bytes().to_vec();
+                key.push(0xff);
+                key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+
+                let notification = Notification {
+                    event_id: pdu.event_id.clone(),
+                    room_id: pdu.room_id.clone(),
+                    actions,
+                    ts: utils::millis_since_unix_epoch(),
+                };
+                self.usernotificationid_notification.insert(
+                    key,
+                    &*serde_json::to_string(&notification)
+                        .expect("Notification::to_string always works"),
+                )?;
+                notified_users.push(user_id);
+            }
+        }
+
+        if !notified_users.is_empty() {
+            self.notified_users
+                .lock()
+                .expect("notified_users lock is not poisoned")
+                .extend(notified_users);
+            self.new_notifications.notify();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the notifications of the user after `since`, oldest first, with their counts.
+    pub fn notifications_since(
+        &self,
+        user_id: &UserId,
+        since: u64,
+    ) -> impl Iterator<Item = Result<(u64, Notification)>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut first_key = prefix.clone();
+        first_key.extend_from_slice(&(since + 1).to_be_bytes());
+
+        self.usernotificationid_notification
+            .range(first_key..)
+            .take_while(move |r| r.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)))
+            .map(parse_notification)
+    }
+
+    /// Returns the notifications of the user before `until`, newest first, with their counts.
+    pub fn notifications_until(
+        &self,
+        user_id: &UserId,
+        until: u64,
+    ) -> impl Iterator<Item = Result<(u64, Notification)>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut last_key = prefix.clone();
+        last_key.extend_from_slice(&until.to_be_bytes());
+
+        // All keys in this range start with the prefix
+        self.usernotificationid_notification
+            .range(prefix..last_key)
+            .rev()
+            .map(parse_notification)
+    }
+
+    /// Returns the users who got notifications since the last call.
+    pub fn take_notified_users(&self) -> HashSet<UserId> {
+        mem::take(
+            &mut *self
+                .notified_users
+                .lock()
+                .expect("notified_users lock is not poisoned"),
+        )
+    }
+
+    /// Resolves when a user gets a new notification. Notifications that happen before this is
+    /// awaited are not missed.
+    pub async fn wait_for_notifications(&self) {
+        self.new_notifications.notified().await
+    }
+
+    /// Returns how many events notified the user since their last read receipt in the room.
+    pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_notificationcount
+            .get(key)?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid notification count in db."))
+            })
+    }
+
+    /// Returns how many events highlighted the user since their last read receipt in the room.
+    pub fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_highlightcount
+            .get(key)?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid highlight count in db."))
+            })
+    }
+
+    /// Resets the notification counts of the user in the room, e.g. after a read receipt.
+    pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_notificationcount.remove(&key)?;
+        self.userroomid_highlightcount.remove(&key)?;
+
+        Ok(())
+    }
+
+    /// Resolves the state after each of the leaves into one state map.
+    ///
+    /// Fails if the state after one of the leaves is unknown.
+    pub fn resolve_leaves(
+        &self,
+        room_id: &RoomId,
+        leaves: &[EventId],
+    ) -> Result<StateMap<EventId>> {
+        let state_sets = leaves
+            .iter()
+            .map(|leaf| {
+                self.state_at(leaf)?.ok_or(Error::BadRequest(
+                    ErrorKind::NotFound,
+                    "The state at a prev event is unknown.",
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        stateres::resolve(&self.room_version(room_id)?, &state_sets, |event_id| {
+            self.get_pdu(event_id)
+        })
+    }
+
+    /// Returns the ids of the current state events of the room.
+    fn room_state_ids(&self, room_id: &RoomId) -> Result<StateMap<EventId>> {
+        // Only the fields we need, so the content doesn't have to be parsed
+        #[derive(Deserialize)]
+        struct StateEntry {
+            event_id: EventId,
+            #[serde(rename = "type")]
+            kind: EventType,
+            state_key: String,
+        }
+
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.roomstateid_pdu
+            .scan_prefix(&prefix)
+            .values()
+            .map(|value| {
+                let entry = serde_json::from_slice::<StateEntry>(&value?)
+                    .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+                Ok::<_, Error>(((entry.kind, entry.state_key), entry.event_id))
+            })
+            .collect()
+    }
+
+    /// Replaces the current state of the room with `state`. Only the entries that changed are
+    /// written and only the members whose member event changed are updated.
+    pub fn force_state(
+        &self,
+        room_id: &RoomId,
+        state: StateMap<EventId>,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let room_state_id = |kind: &EventType, state_key: &str| {
+            let mut key = prefix.clone();
+            key.extend_from_slice(kind.to_string().as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(state_key.as_bytes());
+            key
+        };
+
+        let mut old_state = self.room_state_ids(room_id)?;
+
+        for ((kind, state_key), event_id) in state {
+            if old_state
+                .remove(&(kind.clone(), state_key.clone()))
+                .as_ref()
+                == Some(&event_id)
+            {
+                continue;
+            }
+
+            let pdu_json = self
+                .get_pdu_json(&event_id)?
+                .ok_or_else(|| Error::bad_database("Resolved state contains unknown event."))?;
+
+            if kind == EventType::RoomMember {
+                let membership = serde_json::from_value::<Raw<member::MemberEventContent>>(
+                    pdu_json
+                        .get("content")
+                        .cloned()
+                        .ok_or_else(|| Error::bad_database("Invalid PDU in db."))?,
+                )
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid Member event in db."))?
+                .membership;
+
+                let user_id = UserId::try_from(&*state_key)
+                    .map_err(|_| Error::bad_database("Member event has invalid state_key."))?;
+
+                self.update_membership(room_id, &user_id, &membership, globals)?;
+            }
+
+            self.roomstateid_pdu
+                .insert(room_state_id(&kind, &state_key), &*pdu_json.to_string())?;
+        }
+
+        // These entries are not part of the new state anymore
+        for (kind, state_key) in old_state.keys() {
+            if kind == &EventType::RoomMember {
+                let user_id = UserId::try_from(&**state_key)
+                    .map_err(|_| Error::bad_database("Member event has invalid state_key."))?;
+
+                self.update_membership(
+                    room_id,
+                    &user_id,
+                    &member::MembershipState::Leave,
+                    globals,
+                )?;
+            }
+
+            self.roomstateid_pdu
+                .remove(room_state_id(kind, state_key))?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new persisted data unit and adds it to a room.
+    pub fn append_pdu(
+        &self,
+        room_id: RoomId,
+        sender: UserId,
+        event_type: EventType,
+        content: serde_json::Value,
+        unsigned: Option<serde_json::Map<String, serde_json::Value>>,
+        state_key: Option<String>,
+        redacts: Option<EventId>,
+        globals: &super::globals::Globals,
+        sending: &super::sending::Sending,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let (pdu, pdu_json) = self.build_pdu(
+            room_id, sender, event_type, content, unsigned, state_key, redacts, globals,
+        )?;
+
+        self.append_signed_pdu(pdu, pdu_json, globals, sending, account_data)
+    }
+
+    /// Creates a new hashed and signed pdu on top of the current leaves of the room and checks
+    /// that it is allowed by the state before it. The pdu is not added to the room.
+    pub fn build_pdu(
+        &self,
+        room_id: RoomId,
+        sender: UserId,
+        event_type: EventType,
+        content: serde_json::Value,
+        unsigned: Option<serde_json::Map<String, serde_json::Value>>,
+        state_key: Option<String>,
+        redacts: Option<EventId>,
+        globals: &super::globals::Globals,
+    ) -> Result<(PduEvent, serde_json::Value)> {
+        // TODO: Make sure this isn't called twice in parallel
+        let prev_events = self.get_pdu_leaves(&room_id)?;
+
+        // The room has forked, so the state before the pdu is the resolved state of the leaves.
+        // It only becomes the current state when the pdu is appended.
+        let forked_state = if prev_events.len() > 1 {
+            Some(self.resolve_leaves(&room_id, &prev_events)?)
+        } else {
+            None
+        };
+
+        // Don't allow encryption events when it's disabled
+        if event_type == EventType::RoomEncryption && globals.encryption_disabled() {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Encryption is disabled on this server.",
+            ));
+        }
+
+        // Our depth is the maximum depth of prev_events + 1
+        let depth = prev_events
+            .iter()
+            .filter_map(|event_id| Some(self.get_pdu_json(event_id).ok()??.get("depth")?.as_u64()?))
+            .max()
+            .unwrap_or(0_u64)
+            + 1;
+
+        let mut unsigned = unsigned.unwrap_or_default();
+        if let Some(state_key) = &state_key {
+            let prev_pdu = match &forked_state {
+                Some(state) => state
+                    .get(&(event_type.clone(), state_key.clone()))
+                    .map_or(Ok(None), |event_id| self.get_pdu(event_id))?,
+                None => self.room_state_get(&room_id, &event_type, &state_key)?,
+            };
+
+            if let Some(prev_pdu) = prev_pdu {
+                unsigned.insert("prev_content".to_owned(), prev_pdu.content.clone());
+                unsigned.insert(
+                    "prev_sender".to_owned(),
+                    serde_json::to_value(prev_pdu.sender).expect("UserId::to_value always works"),
+                );
+            }
+        }
+
+        let room_version = if event_type == EventType::RoomCreate {
+            serde_json::from_value::<Raw<create::CreateEventContent>>(content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| {
+                    Error::BadRequest(ErrorKind::InvalidParam, "Invalid create event content.")
+                })?
+                .room_version
+        } else {
+            self.room_version(&room_id)?
+        };
+
+        let auth_events = match &forked_state {
+            Some(state) => {
+                self.auth_events_from(state, &event_type, &sender, state_key.as_deref(), &content)?
+            }
+            None => self.get_auth_events(
+                &room_id,
+                &event_type,
+                &sender,
+                state_key.as_deref(),
+                &content,
+            )?,
+        };
+
+        let mut pdu = PduEvent {
+            event_id: EventId::try_from("$thiswillbefilledinlater").expect("we know this is valid"),
+            room_id: room_id.clone(),
+            sender: sender.clone(),
+            origin: globals.server_name().to_owned(),
+            origin_server_ts: utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("time is valid"),
+            kind: event_type.clone(),
+            content: content.clone(),
+            state_key,
+            prev_events,
+            depth: depth
+                .try_into()
+                .map_err(|_| Error::bad_database("Depth is invalid"))?,
+            auth_events: auth_events
+                .values()
+                .map(|pdu| pdu.event_id.clone())
+                .collect(),
+            redacts: redacts.clone(),
+            unsigned,
+            // Filled in by hash_and_sign_event
+            hashes: ruma::events::pdu::EventHash {
+                sha256: String::new(),
+            },
+            signatures: HashMap::new(),
+        };
+
+        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
+
+        let uses_reference_hash_ids = pdu::uses_reference_hash_ids(&room_version);
+
+        if uses_reference_hash_ids {
+            // The event id is calculated from the rest of the event, so it can't be part of it
+            pdu_json
+                .as_object_mut()
+                .expect("PduEvent is an object")
+                .remove("event_id");
+        } else {
+            pdu.event_id = EventId::try_from(&*format!(
+                "${}:{}",
+                utils::random_string(18),
+                globals.server_name()
+            ))
+            .expect("random strings and server names are valid in event ids");
+            pdu_json["event_id"] = json!(pdu.event_id);
+        }
+
+        ruma::signatures::hash_and_sign_event(
+            globals.server_name().as_str(),
+            globals.keypair(),
+            &mut pdu_json,
+        )
+        .expect("event is valid, we just created it");
+
+        if uses_reference_hash_ids {
+            pdu.event_id = pdu::reference_hash_event_id(&room_version, &pdu_json)
+                .expect("we just created a valid event");
+
+            // We keep the event id in our copy of the event so we can find it again
+            pdu_json["event_id"] = json!(pdu.event_id);
+        }
+
+        pdu.hashes = serde_json::from_value(pdu_json["hashes"].clone())
+            .expect("hash_and_sign_event adds valid hashes");
+        pdu.signatures = serde_json::from_value(pdu_json["signatures"].clone())
+            .expect("hash_and_sign_event adds valid signatures");
+
+        // Is the event authorized?
+        event_auth::auth_check(&room_version, &pdu, &auth_events).map_err(|e| {
+            warn!("Event {} is not authorized: {}", pdu.event_id, e);
+            Error::BadRequest(ErrorKind::Forbidden, "Event is not authorized.")
+        })?;
+
+        Ok((pdu, pdu_json))
+    }
+
+    /// Adds a pdu that was built by `build_pdu` to the room and sends it to the other servers in
+    /// the room.
+    pub fn append_signed_pdu(
+        &self,
+        pdu: PduEvent,
+        pdu_json: serde_json::Value,
+        globals: &super::globals::Globals,
+        sending: &super::sending::Sending,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let room_id = pdu.room_id.clone();
+
+        // The pdu was built on top of a fork, so the resolved state becomes the current state
+        if pdu.prev_events.len() > 1 {
+            let resolved_state = self.resolve_leaves(&room_id, &pdu.prev_events)?;
+            self.force_state(&room_id, resolved_state, globals)?;
+        }
+
+        let (pdu_id, index) = self.store_pdu(&pdu, &pdu_json, globals)?;
+
+        // Servers of users that leave with this event still need to receive it
+        let mut servers = self.room_servers(&room_id)?;
+
+        if let Some(state_key) = &pdu.state_key {
+            if pdu.kind == EventType::RoomMember {
+                let membership =
+                    serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content.clone())
+                        .expect("Raw::from_value always works.")
+                        .deserialize()
+                        .map_err(|_| {
+                            Error::BadRequest(ErrorKind::InvalidParam, "Invalid member event.")
+                        })?
+                        .membership;
+
+                let target_user_id = UserId::try_from(&*state_key).map_err(|_| {
+                    Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "State key of member event does not contain user id.",
+                    )
+                })?;
+
+                // Update our membership info
+                self.update_membership(&room_id, &target_user_id, &membership, globals)?;
+            }
+
+            let mut key = room_id.to_string().as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(pdu.kind.to_string().as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(state_key.as_bytes());
+            self.roomstateid_pdu.insert(key, &*pdu_json.to_string())?;
+        }
+
+        self.save_state_after(
+            &pdu,
+            || {
+                Ok(self
+                    .room_state_full(&room_id)?
+                    .into_iter()
+                    .map(|(key, pdu)| (key, pdu.event_id))
+                    .collect())
+            },
+            globals,
+        )?;
+
+        match pdu.kind {
+            EventType::RoomRedaction => {
+                if let Some(redact_id) = &pdu.redacts {
+                    // TODO: Reason
+                    let _reason = serde_json::from_value::<Raw<redaction::RedactionEventContent>>(
+                        pdu.content.clone(),
+                    )
+                    .expect("Raw::from_value always works.")
+                    .deserialize()
+                    .map_err(|_| {
+                        Error::BadRequest(
+                            ErrorKind::InvalidParam,
+                            "Invalid redaction event content.",
+                        )
+                    })?
+                    .reason;
+
+                    self.redact_pdu(&redact_id)?;
+                }
+            }
+            _ => {}
+        }
+
+        self.update_notification_counts(&pdu, globals, account_data)?;
+
+        // The sender has read everything before their own event
+        self.edus.room_read_set(&room_id, &pdu.sender, index)?;
+        self.reset_notification_counts(&pdu.sender, &room_id)?;
+
+        globals.watchers().wake(&WatchKey::Room(room_id.clone()));
+
+        servers.extend(self.room_servers(&room_id)?);
+        for server in servers {
+            if &*server != globals.server_name() {
+                sending.send_pdu(&server, &pdu_id)?;
+            }
+        }
+
+        Ok(pdu.event_id)
+    }
+
+    /// Adds the pdu to the timeline and makes it the new leaf of the room. Returns the pdu id
+    /// and the count.
+    fn store_pdu(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        globals: &super::globals::Globals,
+    ) -> Result<(Vec<u8>, u64)> {
+        self.replace_pdu_leaves(&pdu.room_id, &pdu.prev_events, &pdu.event_id)?;
+
+        self.insert_pdu(pdu, pdu_json, globals)
+    }
+
+    /// Adds the pdu to the timeline without changing the leaves. Returns the pdu id and the
+    /// count.
+    fn insert_pdu(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        globals: &super::globals::Globals,
+    ) -> Result<(Vec<u8>, u64)> {
+        // Increment the last index and use that
+        // This is also the next_batch/since value
+        let index = globals.next_count()?;
+
+        let mut pdu_id = pdu.room_id.to_string().as_bytes().to_vec();
+        pdu_id.push(0xff);
+        pdu_id.extend_from_slice(&index.to_be_bytes());
+
+        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+
+        self.eventid_pduid
+            .insert(pdu.event_id.to_string(), &*pdu_id)?;
+        // The event is part of the timeline now
+        self.eventid_outlierpdu.remove(pdu.event_id.to_string())?;
+
+        self.index_pdu(pdu, &pdu_id)?;
+
+        self.update_missing_events(pdu)?;
+
+        Ok((pdu_id, index))
+    }
+
+    /// Adds a pdu from another server's history to the start of the timeline.
+    fn insert_backfilled_pdu(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        // Pdus that are backfilled later are older, so they get lower tokens
+        let token = u64::MAX - globals.next_count()?;
+        let pdu_id = pdu_id_from_token(&pdu.room_id, token);
+
+        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+
+        self.eventid_pduid
+            .insert(pdu.event_id.to_string(), &*pdu_id)?;
+        // The event is part of the timeline now
+        self.eventid_outlierpdu.remove(pdu.event_id.to_string())?;
+
+        self.index_pdu(pdu, &pdu_id)?;
+
+        self.update_missing_events(pdu)
+    }
+
+    /// Remembers the prev events of the pdu that we don't have, so they can be backfilled later.
+    fn update_missing_events(&self, pdu: &PduEvent) -> Result<()> {
+        let mut prefix = pdu.room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut key = prefix.clone();
+        key.extend_from_slice(pdu.event_id.to_string().as_bytes());
+        self.roomeventid_missing.remove(key)?;
+
+        for prev_event in &pdu.prev_events {
+            if self.get_pdu_id(prev_event)?.is_none()
+                && self.rejection_reason(prev_event)?.is_none()
+            {
+                let mut key = prefix.clone();
+                key.extend_from_slice(prev_event.to_string().as_bytes());
+                self.roomeventid_missing
+                    .insert(key, &*prev_event.to_string())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the events of the room that other events reference, but that we don't have.
+    pub fn missing_events(&self, room_id: &RoomId) -> impl Iterator<Item = Result<EventId>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.roomeventid_missing
+            .scan_prefix(prefix)
+            .values()
+            .map(|bytes| {
+                Ok(
+                    EventId::try_from(utils::string_from_bytes(&bytes?).map_err(|_| {
+                        Error::bad_database("EventId in roomeventid_missing is invalid unicode.")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("EventId in roomeventid_missing is invalid.")
+                    })?,
+                )
+            })
+    }
+
+    /// Adds pdus from the history of a room that we got from another server. Pdus that are not
+    /// allowed by their auth events are rejected.
+    ///
+    /// Returns the pdus that were added. Their state still has to be saved.
+    pub fn add_backfilled_pdus(
+        &self,
+        room_version: &RoomVersionId,
+        mut pdus: Vec<(PduEvent, serde_json::Value)>,
+        globals: &super::globals::Globals,
+    ) -> Result<Vec<PduEvent>> {
+        // Newest first, so older pdus end up before them in the timeline
+        pdus.sort_by_key(|(pdu, _)| std::cmp::Reverse(pdu.depth));
+
+        let mut added = Vec::new();
+        for (pdu, pdu_json) in pdus {
+            if self.get_pdu_id(&pdu.event_id)?.is_some()
+                || self.rejection_reason(&pdu.event_id)?.is_some()
+            {
+                continue;
+            }
+
+            let auth_state = match self.auth_events_of(&pdu) {
+                Ok(Ok(auth_state)) => auth_state,
+                Ok(Err(e)) => {
+                    self.reject_pdu(&pdu, e)?;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Could not backfill {}: {}", pdu.event_id, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = event_auth::auth_check(room_version, &pdu, &auth_state) {
+                self.reject_pdu(&pdu, e)?;
+                continue;
+            }
+
+            self.insert_backfilled_pdu(&pdu, &pdu_json, globals)?;
+            added.push(pdu);
+        }
+
+        Ok(added)
+    }
+
+    /// Saves the state after a pdu by resolving the states after its prev events, e.g. for
+    /// backfilled pdus.
+    ///
+    /// Returns false if the state after one of the prev events is unknown.
+    pub fn save_state_from_prev_events(
+        &self,
+        pdu: &PduEvent,
+        globals: &super::globals::Globals,
+    ) -> Result<bool> {
+        for prev_event in &pdu.prev_events {
+            if !self.state_is_known(prev_event)? {
+                return Ok(false);
+            }
+        }
+
+        self.save_state_after(
+            pdu,
+            || {
+                let mut state = self.resolve_leaves(&pdu.room_id, &pdu.prev_events)?;
+                if let Some(state_key) = &pdu.state_key {
+                    state.insert((pdu.kind.clone(), state_key.clone()), pdu.event_id.clone());
+                }
+                Ok(state)
+            },
+            globals,
+        )?;
+
+        Ok(true)
     }
 
-    /// Replace the leaves of a room with a new event.
-    pub fn replace_pdu_leaves(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
+    /// Saves the state after a pdu based on the oldest state of the room we know, e.g. for
+    /// backfilled pdus whose state no other server could tell us. This is only a guess.
+    ///
+    /// Returns false if we don't know any state of the room.
+    pub fn save_earliest_known_state(
+        &self,
+        pdu: &PduEvent,
+        globals: &super::globals::Globals,
+    ) -> Result<bool> {
+        let mut prefix = pdu.room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
-        for key in self.roomid_pduleaves.scan_prefix(&prefix).keys() {
-            self.roomid_pduleaves.remove(key?)?;
+        let mut earliest_group = None;
+        for value in self.pduid_pdu.scan_prefix(prefix).values() {
+            let event_id = serde_json::from_slice::<PduEvent>(&value?)
+                .map_err(|_| Error::bad_database("Invalid PDU in db."))?
+                .event_id;
+            if let Some(group) = self.state_group(&event_id)? {
+                earliest_group = Some(group);
+                break;
+            }
         }
 
-        prefix.extend_from_slice(event_id.to_string().as_bytes());
-        self.roomid_pduleaves
-            .insert(&prefix, &*event_id.to_string())?;
+        let earliest_group = match earliest_group {
+            Some(group) => group,
+            None => return Ok(false),
+        };
 
-        Ok(())
+        let group = match &pdu.state_key {
+            Some(state_key) => {
+                let mut state = self.state_group_full(earliest_group)?;
+                state.insert((pdu.kind.clone(), state_key.clone()), pdu.event_id.clone());
+                self.save_state_group(&state, Some(earliest_group), globals)?
+            }
+            None => earliest_group,
+        };
+        self.eventid_stategroup
+            .insert(pdu.event_id.to_string(), &group.to_be_bytes())?;
+
+        Ok(true)
     }
 
-    /// Creates a new persisted data unit and adds it to a room.
-    pub fn append_pdu(
+    /// Adds a pdu that was received over federation to the room.
+    ///
+    /// The pdu is rejected if it's not allowed by its auth events or the state before it.
+    /// Events that are only forbidden by the current state are soft failed: they are saved, but
+    /// don't show up in the timeline.
+    pub fn append_incoming_pdu(
         &self,
-        room_id: RoomId,
-        sender: UserId,
-        event_type: EventType,
-        content: serde_json::Value,
-        unsigned: Option<serde_json::Map<String, serde_json::Value>>,
-        state_key: Option<String>,
-        redacts: Option<EventId>,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
         globals: &super::globals::Globals,
-    ) -> Result<EventId> {
-        // TODO: Make sure this isn't called twice in parallel
-        let prev_events = self.get_pdu_leaves(&room_id)?;
+        account_data: &super::account_data::AccountData,
+    ) -> Result<std::result::Result<(), AuthError>> {
+        // We already know this event
+        if self.get_pdu_id(&pdu.event_id)?.is_some() || self.is_soft_failed(&pdu.event_id)? {
+            return Ok(Ok(()));
+        }
+        if let Some(reason) = self.rejection_reason(&pdu.event_id)? {
+            return Ok(Err(reason));
+        }
 
-        // Is the event authorized?
-        if let Some(state_key) = &state_key {
-            let power_levels = self
-                .room_state_get(&room_id, &EventType::RoomPowerLevels, "")?
-                .map_or_else(
-                    || {
-                        Ok::<_, Error>(power_levels::PowerLevelsEventContent {
-                            ban: 50.into(),
-                            events: BTreeMap::new(),
-                            events_default: 0.into(),
-                            invite: 50.into(),
-                            kick: 50.into(),
-                            redact: 50.into(),
-                            state_default: 0.into(),
-                            users: BTreeMap::new(),
-                            users_default: 0.into(),
-                            notifications:
-                                ruma::events::room::power_levels::NotificationPowerLevels {
-                                    room: 50.into(),
-                                },
-                        })
-                    },
-                    |power_levels| {
-                        Ok(serde_json::from_value::<Raw<PowerLevelsEventContent>>(
-                            power_levels.content.clone(),
-                        )
-                        .expect("Raw::from_value always works.")
-                        .deserialize()
-                        .map_err(|_| Error::bad_database("Invalid PowerLevels event in db."))?)
-                    },
-                )?;
-            let sender_membership = self
-                .room_state_get(&room_id, &EventType::RoomMember, &sender.to_string())?
-                .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                    Ok(serde_json::from_value::<Raw<member::MemberEventContent>>(
-                        pdu.content.clone(),
-                    )
-                    .expect("Raw::from_value always works.")
-                    .deserialize()
-                    .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                    .membership)
-                })?;
+        let room_version = self.room_version(&pdu.room_id)?;
 
-            let sender_power = power_levels.users.get(&sender).map_or_else(
-                || {
-                    if sender_membership != member::MembershipState::Join {
-                        None
-                    } else {
-                        Some(&power_levels.users_default)
-                    }
-                },
-                // If it's okay, wrap with Some(_)
-                Some,
-            );
+        // 1. Is the event allowed by its auth events?
+        if let Err(e) = self
+            .auth_events_of(pdu)?
+            .and_then(|auth_state| event_auth::auth_check(&room_version, pdu, &auth_state))
+        {
+            return self.reject_pdu(pdu, e).map(Err);
+        }
 
-            // Is the event allowed?
-            if !match event_type {
-                EventType::RoomEncryption => {
-                    // Don't allow encryption events when it's disabled
-                    !globals.encryption_disabled()
-                }
-                EventType::RoomMember => {
-                    let target_user_id = UserId::try_from(&**state_key).map_err(|_| {
-                        Error::BadRequest(
-                            ErrorKind::InvalidParam,
-                            "State key of member event does not contain user id.",
-                        )
-                    })?;
+        // 2. Is the event allowed by the state before it?
+        let state_before = self.resolve_leaves(&pdu.room_id, &pdu.prev_events)?;
+        let auth_state = self.auth_state_from(&state_before, pdu)?;
 
-                    let current_membership = self
-                        .room_state_get(
-                            &room_id,
-                            &EventType::RoomMember,
-                            &target_user_id.to_string(),
-                        )?
-                        .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                            Ok(serde_json::from_value::<Raw<member::MemberEventContent>>(
-                                pdu.content.clone(),
-                            )
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership)
-                        })?;
+        if let Err(e) = event_auth::auth_check(&room_version, pdu, &auth_state) {
+            return self.reject_pdu(pdu, e).map(Err);
+        }
 
-                    let target_membership =
-                        serde_json::from_value::<Raw<member::MemberEventContent>>(content.clone())
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership;
-
-                    let target_power = power_levels.users.get(&target_user_id).map_or_else(
-                        || {
-                            if target_membership != member::MembershipState::Join {
-                                None
-                            } else {
-                                Some(&power_levels.users_default)
-                            }
-                        },
-                        // If it's okay, wrap with Some(_)
-                        Some,
-                    );
-
-                    let join_rules =
-                        self.room_state_get(&room_id, &EventType::RoomJoinRules, "")?
-                            .map_or(Ok::<_, Error>(join_rules::JoinRule::Public), |pdu| {
-                                Ok(serde_json::from_value::<
-                                    Raw<join_rules::JoinRulesEventContent>,
-                                >(pdu.content.clone())
-                                .expect("Raw::from_value always works.")
-                                .deserialize()
-                                .map_err(|_| {
-                                    Error::bad_database("Database contains invalid JoinRules event")
-                                })?
-                                .join_rule)
-                            })?;
-
-                    let authorized = if target_membership == member::MembershipState::Join {
-                        let mut prev_events = prev_events.iter();
-                        let prev_event = self
-                            .get_pdu(prev_events.next().ok_or(Error::BadRequest(
-                                ErrorKind::Unknown,
-                                "Membership can't be the first event",
-                            ))?)?
-                            .ok_or_else(|| {
-                                Error::bad_database("PDU leaf points to invalid event!")
-                            })?;
-                        if prev_event.kind == EventType::RoomCreate
-                            && prev_event.prev_events.is_empty()
-                        {
-                            true
-                        } else if sender != target_user_id {
-                            false
-                        } else if let member::MembershipState::Ban = current_membership {
-                            false
-                        } else {
-                            join_rules == join_rules::JoinRule::Invite
-                                && (current_membership == member::MembershipState::Join
-                                    || current_membership == member::MembershipState::Invite)
-                                || join_rules == join_rules::JoinRule::Public
-                        }
-                    } else if target_membership == member::MembershipState::Invite {
-                        if let Some(third_party_invite_json) = content.get("third_party_invite") {
-                            if current_membership == member::MembershipState::Ban {
-                                false
-                            } else {
-                                let _third_party_invite =
-                                    serde_json::from_value::<member::ThirdPartyInvite>(
-                                        third_party_invite_json.clone(),
-                                    )
-                                    .map_err(|_| {
-                                        Error::BadRequest(
-                                            ErrorKind::InvalidParam,
-                                            "ThirdPartyInvite is invalid",
-                                        )
-                                    })?;
-                                todo!("handle third party invites");
-                            }
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                        {
-                            false
-                        } else {
-                            sender_power
-                                .filter(|&p| p >= &power_levels.invite)
-                                .is_some()
-                        }
-                    } else if target_membership == member::MembershipState::Leave {
-                        if sender == target_user_id {
-                            current_membership == member::MembershipState::Join
-                                || current_membership == member::MembershipState::Invite
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                                && sender_power.filter(|&p| p < &power_levels.ban).is_some()
-                        {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                                && target_power < sender_power
-                        }
-                    } else if target_membership == member::MembershipState::Ban {
-                        if sender_membership != member::MembershipState::Join {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                                && target_power < sender_power
-                        }
-                    } else {
-                        false
-                    };
+        // 3. Is the event allowed by the current state?
+        let auth_state = self.get_auth_events(
+            &pdu.room_id,
+            &pdu.kind,
+            &pdu.sender,
+            pdu.state_key.as_deref(),
+            &pdu.content,
+        )?;
+
+        if let Err(e) = event_auth::auth_check(&room_version, pdu, &auth_state) {
+            warn!("Soft failing event {}: {}", pdu.event_id, e);
+            self.eventid_softfailedpdu
+                .insert(pdu.event_id.to_string(), &*pdu_json.to_string())?;
+            return Ok(Ok(()));
+        }
 
-                    if authorized {
-                        // Update our membership info
-                        self.update_membership(&room_id, &target_user_id, &target_membership)?;
-                    }
+        self.store_pdu(pdu, pdu_json, globals)?;
 
-                    authorized
-                }
-                EventType::RoomCreate => prev_events.is_empty(),
-                // Not allow any of the following events if the sender is not joined.
-                _ if sender_membership != member::MembershipState::Join => false,
-
-                _ => {
-                    // TODO
-                    sender_power.unwrap_or(&power_levels.users_default)
-                        >= &power_levels.state_default
+        let mut state_after = state_before;
+        if let Some(state_key) = &pdu.state_key {
+            state_after.insert((pdu.kind.clone(), state_key.clone()), pdu.event_id.clone());
+        }
+        self.save_state_after(pdu, || Ok(state_after.clone()), globals)?;
+
+        let leaves = self.get_pdu_leaves(&pdu.room_id)?;
+        let current_state = if leaves.len() == 1 {
+            state_after
+        } else {
+            self.resolve_leaves(&pdu.room_id, &leaves)?
+        };
+        self.force_state(&pdu.room_id, current_state, globals)?;
+
+        if pdu.kind == EventType::RoomRedaction {
+            if let Some(redacts) = &pdu.redacts {
+                if self.redaction_allowed(pdu, redacts)? {
+                    self.redact_pdu(redacts)?;
                 }
-            } {
-                error!("Unauthorized");
-                // Not authorized
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Event is not authorized",
-                ));
-            }
-        } else if !self.is_joined(&sender, &room_id)? {
-            // TODO: auth rules apply to all events, not only those with a state key
-            error!("Unauthorized");
-            return Err(Error::BadRequest(
-                ErrorKind::Forbidden,
-                "Event is not authorized",
-            ));
+            }
         }
 
-        // Our depth is the maximum depth of prev_events + 1
-        let depth = prev_events
-            .iter()
-            .filter_map(|event_id| Some(self.get_pdu_json(event_id).ok()??.get("depth")?.as_u64()?))
-            .max()
-            .unwrap_or(0_u64)
-            + 1;
+        self.update_notification_counts(pdu, globals, account_data)?;
 
-        let mut unsigned = unsigned.unwrap_or_default();
-        if let Some(state_key) = &state_key {
-            if let Some(prev_pdu) = self.room_state_get(&room_id, &event_type, &state_key)? {
-                unsigned.insert("prev_content".to_owned(), prev_pdu.content.clone());
-                unsigned.insert(
-                    "prev_sender".to_owned(),
-                    serde_json::to_value(prev_pdu.sender).expect("UserId::to_value always works"),
-                );
+        globals
+            .watchers()
+            .wake(&WatchKey::Room(pdu.room_id.clone()));
+
+        Ok(Ok(()))
+    }
+
+    /// Saves events from another server that we only need as auth or state events. They don't
+    /// show up in the timeline. Events that are not allowed by their auth events are rejected.
+    ///
+    /// Returns the events that were saved or that we already knew.
+    pub fn add_outliers(
+        &self,
+        room_version: &RoomVersionId,
+        room_id: &RoomId,
+        mut events: Vec<(PduEvent, serde_json::Value)>,
+    ) -> Result<Vec<PduEvent>> {
+        // Auth events have a lower depth than the events they authorize, so they are saved first
+        events.sort_by_key(|(pdu, _)| pdu.depth);
+
+        let mut accepted = Vec::new();
+        let mut seen = HashSet::new();
+        for (pdu, pdu_json) in events {
+            if &pdu.room_id != room_id
+                || !seen.insert(pdu.event_id.clone())
+                || self.rejection_reason(&pdu.event_id)?.is_some()
+            {
+                continue;
+            }
+
+            if self.get_pdu_json(&pdu.event_id)?.is_none() {
+                if let Err(e) = self
+                    .auth_events_of(&pdu)?
+                    .and_then(|auth_state| event_auth::auth_check(room_version, &pdu, &auth_state))
+                {
+                    self.reject_pdu(&pdu, e)?;
+                    continue;
+                }
+
+                self.eventid_outlierpdu
+                    .insert(pdu.event_id.to_string(), &*pdu_json.to_string())?;
             }
+
+            accepted.push(pdu);
         }
 
-        let mut pdu = PduEvent {
-            event_id: EventId::try_from("$thiswillbefilledinlater").expect("we know this is valid"),
-            room_id: room_id.clone(),
-            sender: sender.clone(),
-            origin: globals.server_name().to_owned(),
-            origin_server_ts: utils::millis_since_unix_epoch()
-                .try_into()
-                .expect("time is valid"),
-            kind: event_type.clone(),
-            content: content.clone(),
-            state_key,
-            prev_events,
-            depth: depth
-                .try_into()
-                .map_err(|_| Error::bad_database("Depth is invalid"))?,
-            auth_events: Vec::new(),
-            redacts: redacts.clone(),
-            unsigned,
-            hashes: ruma::events::pdu::EventHash {
-                sha256: "aaa".to_owned(),
-            },
-            signatures: HashMap::new(),
-        };
+        Ok(accepted)
+    }
 
-        // Generate event id
-        pdu.event_id = EventId::try_from(&*format!(
-            "${}",
-            ruma::signatures::reference_hash(
-                &serde_json::to_value(&pdu).expect("event is valid, we just created it")
-            )
-            .expect("ruma can calculate reference hashes")
-        ))
-        .expect("ruma's reference hashes are valid event ids");
+    /// Adds a room that we joined over federation.
+    ///
+    /// `auth_chain` and `state` are the events the resident server sent us, `join_pdu` is our join
+    /// event. They are saved as outliers, only the join event is added to the timeline.
+    pub fn join_remote_room(
+        &self,
+        room_version: &RoomVersionId,
+        join_pdu: &PduEvent,
+        join_json: &serde_json::Value,
+        auth_chain: Vec<(PduEvent, serde_json::Value)>,
+        state: Vec<(PduEvent, serde_json::Value)>,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        let state_ids = state
+            .iter()
+            .map(|(pdu, _)| pdu.event_id.clone())
+            .collect::<HashSet<_>>();
+
+        let mut room_state = StateMap::new();
+        for pdu in self.add_outliers(
+            room_version,
+            &join_pdu.room_id,
+            auth_chain.into_iter().chain(state).collect(),
+        )? {
+            if let (true, Some(state_key)) = (state_ids.contains(&pdu.event_id), pdu.state_key) {
+                room_state.insert((pdu.kind, state_key), pdu.event_id);
+            }
+        }
 
-        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
-        ruma::signatures::hash_and_sign_event(
-            globals.server_name().as_str(),
-            globals.keypair(),
-            &mut pdu_json,
-        )
-        .expect("event is valid, we just created it");
+        let auth_state = self.auth_state_from(&room_state, join_pdu)?;
+        event_auth::auth_check(room_version, join_pdu, &auth_state).map_err(|e| {
+            warn!("Join event {} is not authorized: {}", join_pdu.event_id, e);
+            Error::BadServerResponse("Join event is not allowed by the room state.")
+        })?;
 
-        self.replace_pdu_leaves(&room_id, &pdu.event_id)?;
+        // Leaves from before we left the room are not part of the room graph we know anymore
+        for leaf in self.get_pdu_leaves(&join_pdu.room_id)? {
+            self.replace_pdu_leaves(&join_pdu.room_id, &[leaf], &join_pdu.event_id)?;
+        }
+        self.store_pdu(join_pdu, join_json, globals)?;
+
+        room_state.insert(
+            (EventType::RoomMember, join_pdu.sender.to_string()),
+            join_pdu.event_id.clone(),
+        );
+        // We don't know the state before the prev events of the join, so this is a full snapshot
+        let group = self.save_state_group(&room_state, None, globals)?;
+        self.eventid_stategroup
+            .insert(join_pdu.event_id.to_string(), &group.to_be_bytes())?;
+        self.force_state(&join_pdu.room_id, room_state, globals)?;
+
+        globals
+            .watchers()
+            .wake(&WatchKey::Room(join_pdu.room_id.clone()));
 
-        // Increment the last index and use that
-        // This is also the next_batch/since value
-        let index = globals.next_count()?;
+        Ok(())
+    }
 
-        let mut pdu_id = room_id.to_string().as_bytes().to_vec();
-        pdu_id.push(0xff);
-        pdu_id.extend_from_slice(&index.to_be_bytes());
+    /// Returns the auth events of a pdu by their (type, state_key) pair.
+    ///
+    /// Fails if an auth event is rejected or the auth events are not a valid set.
+    fn auth_events_of(
+        &self,
+        pdu: &PduEvent,
+    ) -> Result<std::result::Result<StateMap<PduEvent>, AuthError>> {
+        let mut auth_state = StateMap::new();
+        for auth_event_id in &pdu.auth_events {
+            if let Some(reason) = self.rejection_reason(auth_event_id)? {
+                return Ok(Err(reason));
+            }
 
-        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+            let auth_event = self.get_pdu(auth_event_id)?.ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Auth event is unknown.",
+            ))?;
 
-        self.eventid_pduid
-            .insert(pdu.event_id.to_string(), pdu_id)?;
+            let key = match auth_event.state_key.clone() {
+                Some(state_key) => (auth_event.kind.clone(), state_key),
+                None => return Ok(Err(AuthError::UnexpectedAuthEvents)),
+            };
 
-        if let Some(state_key) = pdu.state_key {
-            let mut key = room_id.to_string().as_bytes().to_vec();
-            key.push(0xff);
-            key.extend_from_slice(pdu.kind.to_string().as_bytes());
-            key.push(0xff);
-            key.extend_from_slice(state_key.as_bytes());
-            self.roomstateid_pdu.insert(key, &*pdu_json.to_string())?;
+            // Every (type, state_key) pair can only be used once
+            if auth_state.insert(key, auth_event).is_some() {
+                return Ok(Err(AuthError::UnexpectedAuthEvents));
+            }
         }
 
-        match event_type {
-            EventType::RoomRedaction => {
-                if let Some(redact_id) = &redacts {
-                    // TODO: Reason
-                    let _reason =
-                        serde_json::from_value::<Raw<redaction::RedactionEventContent>>(content)
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| {
-                                Error::BadRequest(
-                                    ErrorKind::InvalidParam,
-                                    "Invalid redaction event content.",
-                                )
-                            })?
-                            .reason;
+        Ok(Ok(auth_state))
+    }
 
-                    self.redact_pdu(&redact_id)?;
+    /// Returns the ids of all events in the auth chains of the given events.
+    pub fn get_auth_chain(&self, event_ids: Vec<EventId>) -> Result<HashSet<EventId>> {
+        let mut auth_chain = HashSet::new();
+        let mut todo = event_ids;
+
+        while let Some(event_id) = todo.pop() {
+            if let Some(pdu) = self.get_pdu(&event_id)? {
+                for auth_event in pdu.auth_events {
+                    if auth_chain.insert(auth_event.clone()) {
+                        todo.push(auth_event);
+                    }
                 }
             }
-            _ => {}
         }
 
-        self.edus.room_read_set(&room_id, &sender, index)?;
+        Ok(auth_chain)
+    }
 
-        Ok(pdu.event_id)
+    /// Returns the state events from `state` that are needed to authorize the pdu.
+    fn auth_state_from(
+        &self,
+        state: &StateMap<EventId>,
+        pdu: &PduEvent,
+    ) -> Result<StateMap<PduEvent>> {
+        self.auth_events_from(
+            state,
+            &pdu.kind,
+            &pdu.sender,
+            pdu.state_key.as_deref(),
+            &pdu.content,
+        )
+    }
+
+    /// Returns the state events from `state` that are needed to authorize an event with these
+    /// properties.
+    fn auth_events_from(
+        &self,
+        state: &StateMap<EventId>,
+        kind: &EventType,
+        sender: &UserId,
+        state_key: Option<&str>,
+        content: &serde_json::Value,
+    ) -> Result<StateMap<PduEvent>> {
+        let mut auth_state = StateMap::new();
+
+        for key in event_auth::auth_types_for_event(kind, sender, state_key, content) {
+            if let Some(event_id) = state.get(&key) {
+                let auth_event = self
+                    .get_pdu(event_id)?
+                    .ok_or_else(|| Error::bad_database("State contains unknown event."))?;
+                auth_state.insert(key, auth_event);
+            }
+        }
+
+        Ok(auth_state)
+    }
+
+    /// Checks if a redaction from another server may be applied to the redacted event.
+    fn redaction_allowed(&self, redaction: &PduEvent, redacts: &EventId) -> Result<bool> {
+        let redacted = match self.get_pdu(redacts)? {
+            Some(redacted) => redacted,
+            None => return Ok(false),
+        };
+
+        if redacted.room_id != redaction.room_id {
+            return Ok(false);
+        }
+
+        if redacted.sender.server_name() == redaction.sender.server_name() {
+            return Ok(true);
+        }
+
+        let power_levels = self
+            .room_state_get(&redaction.room_id, &EventType::RoomPowerLevels, "")?
+            .map(|pdu| {
+                serde_json::from_value::<Raw<power_levels::PowerLevelsEventContent>>(pdu.content)
+                    .expect("Raw::from_value always works.")
+                    .deserialize()
+                    .map_err(|_| Error::bad_database("Invalid PowerLevels event in db."))
+            })
+            .transpose()?;
+
+        Ok(power_levels.map_or(false, |power_levels| {
+            power_levels
+                .users
+                .get(&redaction.sender)
+                .unwrap_or(&power_levels.users_default)
+                >= &power_levels.redact
+        }))
+    }
+
+    /// Marks the pdu as rejected.
+    fn reject_pdu(&self, pdu: &PduEvent, reason: AuthError) -> Result<AuthError> {
+        warn!("Rejecting event {}: {}", pdu.event_id, reason);
+        self.eventid_rejectionreason.insert(
+            pdu.event_id.to_string(),
+            &*serde_json::to_string(&reason).expect("AuthError can be serialized"),
+        )?;
+
+        // There is no point in backfilling it again
+        let mut key = pdu.room_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pdu.event_id.to_string().as_bytes());
+        self.roomeventid_missing.remove(key)?;
+
+        Ok(reason)
+    }
+
+    /// Checks if the event was soft failed, because it's not allowed by the current state.
+    pub fn is_soft_failed(&self, event_id: &EventId) -> Result<bool> {
+        Ok(self
+            .eventid_softfailedpdu
+            .contains_key(event_id.to_string())?)
+    }
+
+    /// Returns why the event was rejected, if it was rejected.
+    pub fn rejection_reason(&self, event_id: &EventId) -> Result<Option<AuthError>> {
+        self.eventid_rejectionreason
+            .get(event_id.to_string())?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid rejection reason in db.")
+                })?))
+            })
     }
 
     /// Returns an iterator over all PDUs in a room.
@@ -656,13 +2155,10 @@ impl Rooms {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
-        let mut current = prefix.clone();
-        current.extend_from_slice(&until.to_be_bytes());
-
+        let current = pdu_id_from_token(room_id, until);
         let current: &[u8] = &current;
 
         let user_id = user_id.clone();
-        let prefixlen = prefix.len();
         self.pduid_pdu
             .range(..current)
             .rev()
@@ -675,7 +2171,7 @@ impl Rooms {
                     pdu.unsigned.remove("transaction_id");
                 }
                 Ok((
-                    utils::u64_from_bytes(&k[prefixlen..])
+                    utils::u64_from_bytes(&k[k.len() - mem::size_of::<u64>()..])
                         .map_err(|_| Error::bad_database("Invalid pdu id in db."))?,
                     pdu,
                 ))
@@ -694,13 +2190,10 @@ impl Rooms {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
-        let mut current = prefix.clone();
-        current.extend_from_slice(&(from + 1).to_be_bytes()); // +1 so we don't send the base event
-
+        let current = pdu_id_from_token(room_id, from + 1); // +1 so we don't send the base event
         let current: &[u8] = &current;
 
         let user_id = user_id.clone();
-        let prefixlen = prefix.len();
         self.pduid_pdu
             .range(current..)
             .filter_map(|r| r.ok())
@@ -712,7 +2205,7 @@ impl Rooms {
                     pdu.unsigned.remove("transaction_id");
                 }
                 Ok((
-                    utils::u64_from_bytes(&k[prefixlen..])
+                    utils::u64_from_bytes(&k[k.len() - mem::size_of::<u64>()..])
                         .map_err(|_| Error::bad_database("Invalid pdu id in db."))?,
                     pdu,
                 ))
@@ -725,6 +2218,12 @@ impl Rooms {
             let mut pdu = self
                 .get_pdu_from_id(&pdu_id)?
                 .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+            // Nobody should find the redacted content anymore
+            for token in search_tokens(&pdu).keys() {
+                self.tokenids
+                    .remove(search_key(&pdu.room_id, token, &pdu_id))?;
+            }
+
             pdu.redact()?;
             self.replace_pdu(&pdu_id, &pdu)?;
             Ok(())
@@ -742,6 +2241,7 @@ impl Rooms {
         room_id: &RoomId,
         user_id: &UserId,
         membership: &member::MembershipState,
+        globals: &super::globals::Globals,
     ) -> Result<()> {
         let mut userroom_id = user_id.to_string().as_bytes().to_vec();
         userroom_id.push(0xff);
@@ -758,6 +2258,7 @@ impl Rooms {
                 self.userroomid_invited.remove(&userroom_id)?;
                 self.roomuserid_invited.remove(&roomuser_id)?;
                 self.userroomid_left.remove(&userroom_id)?;
+                self.userroomid_invitestate.remove(&userroom_id)?;
             }
             member::MembershipState::Invite => {
                 self.userroomid_invited.insert(&userroom_id, &[])?;
@@ -772,13 +2273,56 @@ impl Rooms {
                 self.roomuserid_joined.remove(&roomuser_id)?;
                 self.userroomid_invited.remove(&userroom_id)?;
                 self.roomuserid_invited.remove(&roomuser_id)?;
+                self.userroomid_invitestate.remove(&userroom_id)?;
             }
             _ => {}
         }
 
+        globals.watchers().wake(&WatchKey::User(user_id.clone()));
+
         Ok(())
     }
 
+    /// Marks a local user as invited to a room on another server. `invite_state` is the stripped
+    /// state the other server sent with the invite.
+    pub fn add_remote_invite(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        invite_state: &[serde_json::Value],
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_invitestate.insert(
+            userroom_id,
+            &*serde_json::to_string(invite_state).expect("json values can be serialized"),
+        )?;
+
+        self.update_membership(room_id, user_id, &member::MembershipState::Invite, globals)
+    }
+
+    /// Returns the stripped state of a room on another server that the user was invited to.
+    pub fn invite_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_invitestate
+            .get(userroom_id)?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid invite state in db.")
+                })?))
+            })
+    }
+
     /// Makes a user forget a room.
     pub fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
         let mut userroom_id = user_id.to_string().as_bytes().to_vec();
@@ -790,6 +2334,64 @@ impl Rooms {
         Ok(())
     }
 
+    /// Checks if the member event of `ll_user_id` was already sent to the device because of lazy
+    /// loading.
+    pub fn lazy_load_was_sent_before(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        ll_user_id: &UserId,
+    ) -> Result<bool> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(room_id.to_string().as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(ll_user_id.to_string().as_bytes());
+
+        Ok(self.lazyloadedids.contains_key(key)?)
+    }
+
+    /// Remembers that the member events of these users were sent to the device.
+    pub fn lazy_load_mark_sent<'a>(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        ll_user_ids: impl IntoIterator<Item = &'a UserId>,
+    ) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.to_string().as_bytes());
+        prefix.push(0xff);
+
+        for ll_user_id in ll_user_ids {
+            let mut key = prefix.clone();
+            key.extend_from_slice(ll_user_id.to_string().as_bytes());
+            self.lazyloadedids.insert(key, b"")?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets which member events were sent to the device, e.g. because it does an initial sync.
+    pub fn lazy_load_reset(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        for key in self.lazyloadedids.scan_prefix(prefix).keys() {
+            self.lazyloadedids.remove(key?)?;
+        }
+
+        Ok(())
+    }
+
     pub fn set_alias(
         &self,
         alias: &RoomAliasId,
@@ -893,6 +2495,13 @@ impl Rooms {
             })
     }
 
+    /// Returns the servers of all joined members of a room.
+    pub fn room_servers(&self, room_id: &RoomId) -> Result<HashSet<Box<ServerName>>> {
+        self.room_members(room_id)
+            .map(|user_id| Ok(user_id?.server_name().to_owned()))
+            .collect()
+    }
+
     /// Returns an iterator over all invited members of a room.
     pub fn room_members_invited(&self, room_id: &RoomId) -> impl Iterator<Item = Result<UserId>> {
         self.roomuserid_invited
@@ -1,3 +1,4 @@
+use super::super::watchers::WatchKey;
 use crate::{utils, Error, Result};
 use js_int::UInt;
 use ruma::{
@@ -13,6 +14,7 @@ use std::{
     convert::{TryFrom, TryInto},
 };
 
+#[derive(Clone)]
 pub struct RoomEdus {
     pub(in super::super) roomuserid_lastread: sled::Tree, // RoomUserId = Room + User
     pub(in super::super) roomlatestid_roomlatest: sled::Tree, // Read Receipts, RoomLatestId = RoomId + Count + UserId
@@ -63,6 +65,8 @@ impl RoomEdus {
             &*serde_json::to_string(&event).expect("EduEvent::to_string always works"),
         )?;
 
+        globals.watchers().wake(&WatchKey::Room(room_id.clone()));
+
         Ok(())
     }
 
@@ -115,6 +119,8 @@ impl RoomEdus {
         self.roomid_lastroomactiveupdate
             .insert(&room_id.to_string().as_bytes(), &count)?;
 
+        globals.watchers().wake(&WatchKey::Room(room_id.clone()));
+
         Ok(())
     }
 
@@ -148,6 +154,8 @@ impl RoomEdus {
                 &room_id.to_string().as_bytes(),
                 &globals.next_count()?.to_be_bytes(),
             )?;
+
+            globals.watchers().wake(&WatchKey::Room(room_id.clone()));
         }
 
         Ok(())
@@ -194,6 +202,8 @@ impl RoomEdus {
                 &room_id.to_string().as_bytes(),
                 &globals.next_count()?.to_be_bytes(),
             )?;
+
+            globals.watchers().wake(&WatchKey::Room(room_id.clone()));
         }
 
         Ok(())
@@ -305,6 +315,8 @@ impl RoomEdus {
             &utils::millis_since_unix_epoch().to_be_bytes(),
         )?;
 
+        globals.watchers().wake(&WatchKey::Room(room_id.clone()));
+
         Ok(())
     }
 
@@ -0,0 +1,627 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{pdu, server_server, utils, Error, Result};
+use log::warn;
+use rocket::{
+    futures::stream::{FuturesUnordered, StreamExt},
+    tokio::{self, sync::Notify},
+};
+use ruma::{RoomId, ServerName};
+use sled::IVec;
+
+use super::{globals::Globals, rooms::Rooms};
+
+/// The maximum number of PDUs and EDUs in one transaction, as defined by the spec.
+const MAX_PDUS_PER_TRANSACTION: usize = 50;
+const MAX_EDUS_PER_TRANSACTION: usize = 100;
+
+#[derive(Clone)]
+pub struct Sending {
+    pub(super) servernamepduids: sled::Tree, // ServernamePduId = ServerName + PduId
+    pub(super) servernameeduids: sled::Tree, // ServernameEduId = ServerName + Count
+    new_servers: Arc<Mutex<HashSet<Box<ServerName>>>>, // Servers with events the handler didn't look at yet
+    new_events: Arc<Notify>,
+}
+
+/// Servers that didn't accept transactions, with the number of failures in a row and when they
+/// are tried again. The wait doubles after every failure and is reset by a success.
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    servers: HashMap<Box<ServerName>, (u32, Instant)>,
+}
+
+/// Queued events of a server that are sent in one transaction. Failed transactions are retried
+/// with the same id and events, so the server knows if it already has them.
+struct Transaction {
+    id: String,
+    pdu_keys: Vec<IVec>,
+    edu_keys: Vec<IVec>,
+}
+
+impl Sending {
+    pub fn load(servernamepduids: sled::Tree, servernameeduids: sled::Tree) -> Result<Self> {
+        let sending = Self {
+            servernamepduids,
+            servernameeduids,
+            new_servers: Arc::new(Mutex::new(HashSet::new())),
+            new_events: Arc::new(Notify::new()),
+        };
+
+        // Events that were queued before a restart still need to be sent
+        *sending
+            .new_servers
+            .lock()
+            .expect("new_servers lock is not poisoned") = sending.queued_servers()?;
+
+        Ok(sending)
+    }
+
+    /// Starts a background task that sends the queued events to the other servers.
+    pub fn start_handler(&self, globals: &Globals, rooms: &Rooms) {
+        self.spawn_handler(
+            globals,
+            rooms,
+            // 30 seconds, at most one day
+            Backoff::new(Duration::from_secs(30), Duration::from_secs(60 * 60 * 24)),
+        );
+    }
+
+    fn spawn_handler(&self, globals: &Globals, rooms: &Rooms, mut backoff: Backoff) {
+        let sending = self.clone();
+        let globals = globals.clone();
+        let rooms = rooms.clone();
+
+        tokio::spawn(async move {
+            let mut futures = FuturesUnordered::new();
+            let mut in_flight = HashSet::new();
+            // Servers that might have queued events, but can't get a transaction right now
+            let mut waiting = HashSet::new();
+            // Destination -> Failed transaction
+            let mut retries = HashMap::<Box<ServerName>, Transaction>::new();
+
+            loop {
+                waiting.extend(sending.take_new_servers());
+
+                let now = Instant::now();
+                let ready = waiting
+                    .iter()
+                    .filter(|server| {
+                        !in_flight.contains(*server) && !backoff.is_waiting(server, now)
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                for server in ready {
+                    waiting.remove(&server);
+
+                    let transaction = match retries.remove(&server) {
+                        Some(transaction) => transaction,
+                        None => match sending.next_transaction(&server) {
+                            Ok(Some(transaction)) => transaction,
+                            // Everything was sent already
+                            Ok(None) => continue,
+                            Err(e) => {
+                                warn!("Could not read federation queue of {}: {}", server, e);
+                                continue;
+                            }
+                        },
+                    };
+
+                    in_flight.insert(server.clone());
+                    futures.push(Self::send_transaction(
+                        server,
+                        transaction,
+                        sending.clone(),
+                        globals.clone(),
+                        rooms.clone(),
+                    ));
+                }
+
+                let next_try = backoff
+                    .next_try(now)
+                    .map_or(Duration::from_secs(60 * 60), |next_try| next_try - now);
+                let delay = tokio::time::delay_for(next_try);
+
+                tokio::select! {
+                    Some((server, transaction, result)) = futures.next() => {
+                        in_flight.remove(&server);
+
+                        match result {
+                            Ok(()) => {
+                                backoff.succeeded(&server);
+                            }
+                            Err(e) => {
+                                let failures = backoff.failed(&server, Instant::now());
+                                warn!(
+                                    "Could not send transaction to {} (attempt {}): {}",
+                                    server, failures, e
+                                );
+                                retries.insert(server.clone(), transaction);
+                            }
+                        }
+
+                        // The rest of the queue is sent in the next transaction
+                        waiting.insert(server);
+                    }
+                    _ = sending.new_events.notified() => {}
+                    _ = delay => {}
+                }
+            }
+        });
+    }
+
+    /// Queues a pdu for the given server.
+    pub fn send_pdu(&self, server: &ServerName, pdu_id: &[u8]) -> Result<()> {
+        let mut key = server.as_str().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pdu_id);
+        self.servernamepduids.insert(key, b"")?;
+
+        self.wake_handler(server);
+
+        Ok(())
+    }
+
+    /// Queues an edu for the given server.
+    pub fn send_edu(
+        &self,
+        server: &ServerName,
+        edu: &serde_json::Value,
+        globals: &Globals,
+    ) -> Result<()> {
+        let mut key = server.as_str().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        self.servernameeduids.insert(key, &*edu.to_string())?;
+
+        self.wake_handler(server);
+
+        Ok(())
+    }
+
+    /// Tells the handler that the server has new events.
+    fn wake_handler(&self, server: &ServerName) {
+        self.new_servers
+            .lock()
+            .expect("new_servers lock is not poisoned")
+            .insert(server.to_owned());
+        self.new_events.notify();
+    }
+
+    /// Returns the servers that got new events since the last call.
+    fn take_new_servers(&self) -> HashSet<Box<ServerName>> {
+        mem::take(
+            &mut *self
+                .new_servers
+                .lock()
+                .expect("new_servers lock is not poisoned"),
+        )
+    }
+
+    /// Returns all servers that have queued events.
+    fn queued_servers(&self) -> Result<HashSet<Box<ServerName>>> {
+        self.servernamepduids
+            .iter()
+            .keys()
+            .chain(self.servernameeduids.iter().keys())
+            .map(|key| {
+                let key = key?;
+                let server = key
+                    .split(|&b| b == 0xff)
+                    .next()
+                    .expect("split always returns one element");
+                Ok(Box::<ServerName>::try_from(
+                    utils::string_from_bytes(server)
+                        .map_err(|_| Error::bad_database("Server name in queue is invalid."))?,
+                )
+                .map_err(|_| Error::bad_database("Server name in queue is invalid."))?)
+            })
+            .collect()
+    }
+
+    /// Takes the oldest queued events of a server for a new transaction. Returns nothing if no
+    /// events are queued.
+    fn next_transaction(&self, server: &ServerName) -> Result<Option<Transaction>> {
+        let mut prefix = server.as_str().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let pdu_keys = self
+            .servernamepduids
+            .scan_prefix(&prefix)
+            .keys()
+            .take(MAX_PDUS_PER_TRANSACTION)
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        let edu_keys = self
+            .servernameeduids
+            .scan_prefix(&prefix)
+            .keys()
+            .take(MAX_EDUS_PER_TRANSACTION)
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        if pdu_keys.is_empty() && edu_keys.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Transaction {
+            id: utils::random_string(16),
+            pdu_keys,
+            edu_keys,
+        }))
+    }
+
+    /// Sends a transaction to the server and removes its events from the queue if the server
+    /// accepted it.
+    async fn send_transaction(
+        server: Box<ServerName>,
+        transaction: Transaction,
+        sending: Sending,
+        globals: Globals,
+        rooms: Rooms,
+    ) -> (Box<ServerName>, Transaction, Result<()>) {
+        let result = async {
+            let prefix_len = server.as_str().len() + 1;
+
+            let pdus = transaction
+                .pdu_keys
+                .iter()
+                .map(|key| {
+                    let pdu_id = IVec::from(&key[prefix_len..]);
+                    outgoing_pdu_json(&rooms, &pdu_id)
+                })
+                .filter_map(|r| r.transpose())
+                .collect::<Result<Vec<_>>>()?;
+
+            let edus = transaction
+                .edu_keys
+                .iter()
+                .filter_map(|key| sending.servernameeduids.get(key).transpose())
+                .map(|edu| {
+                    serde_json::from_slice::<serde_json::Value>(&edu?)
+                        .map_err(|_| Error::bad_database("Invalid EDU in federation queue."))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let body = serde_json::json!({
+                "origin": globals.server_name().as_str(),
+                "origin_server_ts": utils::millis_since_unix_epoch(),
+                "pdus": pdus,
+                "edus": edus,
+            });
+
+            let http_request = http::Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/_matrix/federation/v1/send/{}", transaction.id))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string().into_bytes())
+                .expect("transaction request is valid");
+
+            let response =
+                server_server::send_signed_request(&globals, &server, http_request).await?;
+
+            if !response.status().is_success() {
+                return Err(Error::BadServerResponse("Server rejected transaction."));
+            }
+
+            // The server has the events now
+            for key in &transaction.pdu_keys {
+                sending.servernamepduids.remove(key)?;
+            }
+            for key in &transaction.edu_keys {
+                sending.servernameeduids.remove(key)?;
+            }
+
+            Ok::<_, Error>(())
+        }
+        .await;
+
+        (server, transaction, result)
+    }
+}
+
+impl Backoff {
+    fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            servers: HashMap::new(),
+        }
+    }
+
+    /// Checks if the server has to wait before it's tried again.
+    fn is_waiting(&self, server: &ServerName, now: Instant) -> bool {
+        self.servers
+            .get(server)
+            .filter(|(_, next_try)| *next_try > now)
+            .is_some()
+    }
+
+    /// Returns when the next server that has to wait can be tried again.
+    fn next_try(&self, now: Instant) -> Option<Instant> {
+        self.servers
+            .values()
+            .map(|(_, next_try)| *next_try)
+            .filter(|next_try| *next_try > now)
+            .min()
+    }
+
+    /// Records a failure and returns the number of failures in a row.
+    fn failed(&mut self, server: &ServerName, now: Instant) -> u32 {
+        let failures = self
+            .servers
+            .get(server)
+            .map_or(0, |(failures, _)| *failures)
+            + 1;
+        let next_try = now + utils::backoff_duration(failures, self.initial, self.max);
+        self.servers.insert(server.to_owned(), (failures, next_try));
+
+        failures
+    }
+
+    fn succeeded(&mut self, server: &ServerName) {
+        self.servers.remove(server);
+    }
+}
+
+/// Returns the json of a pdu in the format other servers expect.
+fn outgoing_pdu_json(rooms: &Rooms, pdu_id: &IVec) -> Result<Option<serde_json::Value>> {
+    let pdu_json = match rooms.get_pdu_json_from_id(pdu_id)? {
+        Some(pdu_json) => pdu_json,
+        // The pdu doesn't exist anymore
+        None => return Ok(None),
+    };
+
+    let room_id = pdu_json
+        .get("room_id")
+        .and_then(|room_id| room_id.as_str())
+        .and_then(|room_id| RoomId::try_from(room_id).ok())
+        .ok_or_else(|| Error::bad_database("Invalid PDU in db."))?;
+
+    Ok(Some(pdu::to_federation_json(
+        &rooms.room_version(&room_id)?,
+        pdu_json,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, Database};
+    use ruma::{events::EventType, UserId};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Starts a server that answers the first `failures` transactions with an error and accepts
+    /// all others.
+    async fn remote_server(failures: usize) -> (Box<ServerName>, test_utils::StubRequests) {
+        let attempts = AtomicUsize::new(0);
+        let (address, requests) = test_utils::stub_https_server(move |_, _| {
+            if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+                (500, "{}".to_owned())
+            } else {
+                (200, r#"{"pdus":{}}"#.to_owned())
+            }
+        })
+        .await;
+
+        let server_name = Box::<ServerName>::try_from(format!("127.0.0.1:{}", address.port()))
+            .expect("server name is valid");
+        (server_name, requests)
+    }
+
+    /// Starts the handler with a short backoff, so tests don't have to wait long for retries.
+    fn start_handler(db: &Database) {
+        db.sending.spawn_handler(
+            &db.globals,
+            &db.rooms,
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1)),
+        );
+    }
+
+    fn queue_edus(db: &Database, server: &ServerName, count: usize) {
+        for i in 0..count {
+            let edu = json!({ "edu_type": "m.typing", "content": { "i": i } });
+            db.sending
+                .send_edu(server, &edu, &db.globals)
+                .expect("edu can be queued");
+        }
+    }
+
+    /// Creates a room with `count` events and queues them for the server.
+    fn queue_pdus(db: &Database, server: &ServerName, count: usize) {
+        let alice = UserId::try_from("@alice:localhost").expect("user id is valid");
+        let room_id = RoomId::new(db.globals.server_name());
+
+        let send = |kind, state_key: Option<&str>, content| {
+            let event_id = db
+                .rooms
+                .append_pdu(
+                    room_id.clone(),
+                    alice.clone(),
+                    kind,
+                    content,
+                    None,
+                    state_key.map(str::to_owned),
+                    None,
+                    &db.globals,
+                    &db.sending,
+                    &db.account_data,
+                )
+                .expect("event is allowed");
+            let pdu_id = db
+                .rooms
+                .get_pdu_id(&event_id)
+                .unwrap()
+                .expect("event was added");
+            db.sending
+                .send_pdu(server, &pdu_id)
+                .expect("pdu can be queued");
+        };
+
+        send(
+            EventType::RoomCreate,
+            Some(""),
+            json!({ "creator": alice, "room_version": "6" }),
+        );
+        send(
+            EventType::RoomMember,
+            Some(alice.as_str()),
+            json!({ "membership": "join" }),
+        );
+        for i in 2..count {
+            let content = json!({ "msgtype": "m.text", "body": format!("message {}", i) });
+            send(EventType::RoomMessage, None, content);
+        }
+    }
+
+    /// Waits until the queue of the server is empty or a few seconds passed. Returns the
+    /// transactions the server got as (transaction id, body).
+    async fn wait_until_sent(
+        db: &Database,
+        server: &ServerName,
+        requests: &test_utils::StubRequests,
+    ) -> Vec<(String, serde_json::Value)> {
+        let mut prefix = server.as_str().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for _ in 0..100 {
+            if db
+                .sending
+                .servernamepduids
+                .scan_prefix(&prefix)
+                .next()
+                .is_none()
+                && db
+                    .sending
+                    .servernameeduids
+                    .scan_prefix(&prefix)
+                    .next()
+                    .is_none()
+            {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(request, body)| {
+                let id = request
+                    .strip_prefix("PUT /_matrix/federation/v1/send/")
+                    .expect("only transactions are sent");
+                (
+                    id.to_owned(),
+                    serde_json::from_slice(body).expect("transaction is json"),
+                )
+            })
+            .collect()
+    }
+
+    fn lengths(transaction: &serde_json::Value) -> (usize, usize) {
+        (
+            transaction["pdus"].as_array().map_or(0, Vec::len),
+            transaction["edus"].as_array().map_or(0, Vec::len),
+        )
+    }
+
+    #[rocket::async_test]
+    async fn transactions_have_at_most_50_pdus_and_100_edus() {
+        let db = Database::load_for_tests(&[]);
+        let (server, requests) = remote_server(0).await;
+        queue_pdus(&db, &server, 60);
+        queue_edus(&db, &server, 120);
+
+        start_handler(&db);
+        let transactions = wait_until_sent(&db, &server, &requests).await;
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(lengths(&transactions[0].1), (50, 100));
+        assert_eq!(lengths(&transactions[1].1), (10, 20));
+        // The oldest events are sent first
+        assert_eq!(transactions[0].1["pdus"][0]["type"], "m.room.create");
+        assert_eq!(transactions[0].1["edus"][0]["content"]["i"], 0);
+        assert_eq!(transactions[1].1["edus"][19]["content"]["i"], 119);
+    }
+
+    #[rocket::async_test]
+    async fn failed_transactions_are_retried_with_the_same_id() {
+        let db = Database::load_for_tests(&[]);
+        let (server, requests) = remote_server(1).await;
+        queue_pdus(&db, &server, 3);
+        queue_edus(&db, &server, 2);
+
+        start_handler(&db);
+        let transactions = wait_until_sent(&db, &server, &requests).await;
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].0, transactions[1].0);
+        assert_eq!(transactions[0].1["pdus"], transactions[1].1["pdus"]);
+        assert_eq!(transactions[0].1["edus"], transactions[1].1["edus"]);
+        assert_eq!(lengths(&transactions[1].1), (3, 2));
+
+        // New events are sent in a new transaction
+        queue_edus(&db, &server, 1);
+        let transactions = wait_until_sent(&db, &server, &requests).await;
+        assert_eq!(transactions.len(), 3);
+        assert_ne!(transactions[2].0, transactions[1].0);
+    }
+
+    #[test]
+    fn backoff_grows_and_is_reset_by_a_success() {
+        let server = Box::<ServerName>::try_from("a.test").expect("server name is valid");
+        let other_server = Box::<ServerName>::try_from("b.test").expect("server name is valid");
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(4));
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+
+        assert!(!backoff.is_waiting(&server, now));
+        assert_eq!(backoff.next_try(now), None);
+
+        // (failures in a row, wait until the next try)
+        for &(failures, wait) in &[(1, 1), (2, 2), (3, 4), (4, 4)] {
+            assert_eq!(backoff.failed(&server, now), failures);
+            assert!(backoff.is_waiting(&server, now));
+            assert!(!backoff.is_waiting(&server, now + secs(wait)));
+            assert_eq!(backoff.next_try(now), Some(now + secs(wait)));
+        }
+        assert!(!backoff.is_waiting(&other_server, now));
+
+        backoff.succeeded(&server);
+        assert!(!backoff.is_waiting(&server, now));
+        assert_eq!(backoff.next_try(now), None);
+
+        // The next failure starts with the initial wait again
+        assert_eq!(backoff.failed(&server, now), 1);
+        assert_eq!(backoff.next_try(now), Some(now + secs(1)));
+    }
+
+    #[rocket::async_test]
+    async fn queued_events_are_sent_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("conduit-test-{}", utils::random_string(16)));
+        let path = path.to_str().expect("temporary directory is valid unicode");
+        let (server, requests) = remote_server(0).await;
+
+        // Nothing is sent without a handler
+        let db = Database::load_for_tests(&[("database_path", path)]);
+        queue_pdus(&db, &server, 3);
+        queue_edus(&db, &server, 2);
+        drop(db);
+
+        // Sending::load finds the queued events, nothing else tells the handler about them
+        let db = Database::load_for_tests(&[("database_path", path)]);
+        start_handler(&db);
+        let transactions = wait_until_sent(&db, &server, &requests).await;
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(lengths(&transactions[0].1), (3, 2));
+    }
+}
@@ -1,3 +1,4 @@
+use super::watchers::WatchKey;
 use crate::{utils, Error, Result};
 use js_int::UInt;
 use ruma::{
@@ -5,18 +6,29 @@ use ruma::{
         error::ErrorKind,
         r0::{
             device::Device,
+            filter::FilterDefinition,
             keys::{AlgorithmAndDeviceId, CrossSigningKey, DeviceKeys, KeyAlgorithm, OneTimeKey},
         },
     },
     events::{AnyToDeviceEvent, EventType},
     DeviceId, Raw, RoomId, UserId,
 };
-use std::{collections::BTreeMap, convert::TryFrom, mem, time::SystemTime};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    mem,
+    time::SystemTime,
+};
+
+const GUEST_PASSWORD_LENGTH: usize = 64;
 
+#[derive(Clone)]
 pub struct Users {
     pub(super) userid_password: sled::Tree,
     pub(super) userid_displayname: sled::Tree,
     pub(super) userid_avatarurl: sled::Tree,
+    pub(super) guestuserids: sled::Tree,
     pub(super) userdeviceid_token: sled::Tree,
     pub(super) userdeviceid_metadata: sled::Tree, // This is also used to check if a device exists
     pub(super) token_userdeviceid: sled::Tree,
@@ -30,6 +42,23 @@ pub struct Users {
     pub(super) userid_usersigningkeyid: sled::Tree,
 
     pub(super) todeviceid_events: sled::Tree, // ToDeviceId = UserId + DeviceId + Count
+
+    pub(super) userfilterid_filter: sled::Tree, // UserFilterId = UserId + FilterId
+
+    pub(super) userdeviceid_slidingsync: sled::Tree, // SlidingSync = SlidingSyncConnection
+}
+
+/// What a device has already received from the sliding sync endpoint.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SlidingSyncConnection {
+    /// The `pos` token of the last response
+    pub pos: u64,
+    /// The room ids the client knows for every list, by index in the list
+    pub lists: Vec<BTreeMap<usize, RoomId>>,
+    /// Joined rooms in the windows, the client is up to date with them
+    pub known_rooms: BTreeSet<RoomId>,
+    /// Invited rooms in the windows, the client already has their invite state
+    pub known_invites: BTreeSet<RoomId>,
 }
 
 impl Users {
@@ -56,6 +85,19 @@ impl Users {
         Ok(())
     }
 
+    /// Create a new guest account on this homeserver. Guests can only use some endpoints.
+    pub fn create_guest(&self, user_id: &UserId) -> Result<()> {
+        // Nobody knows this password, so it's impossible to log in as a guest
+        self.create(user_id, &utils::random_string(GUEST_PASSWORD_LENGTH))?;
+        self.guestuserids.insert(user_id.to_string(), &[])?;
+        Ok(())
+    }
+
+    /// Check if a user is a guest.
+    pub fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.guestuserids.contains_key(user_id.to_string())?)
+    }
+
     /// Find out which user an access token belongs to.
     pub fn find_from_token(&self, token: &str) -> Result<Option<(UserId, String)>> {
         self.token_userdeviceid
@@ -218,6 +260,8 @@ impl Users {
 
         // TODO: Remove onetimekeys
 
+        self.userdeviceid_slidingsync.remove(&userdeviceid)?;
+
         self.userdeviceid_metadata.remove(&userdeviceid)?;
 
         Ok(())
@@ -301,6 +345,10 @@ impl Users {
             &globals.next_count()?.to_be_bytes(),
         )?;
 
+        globals
+            .watchers()
+            .wake(&WatchKey::Device(user_id.clone(), device_id.into()));
+
         Ok(())
     }
 
@@ -336,6 +384,10 @@ impl Users {
             &globals.next_count()?.to_be_bytes(),
         )?;
 
+        globals
+            .watchers()
+            .wake(&WatchKey::Device(user_id.clone(), device_id.into()));
+
         self.onetimekeyid_onetimekeys
             .scan_prefix(&prefix)
             .next()
@@ -408,13 +460,31 @@ impl Users {
             &*serde_json::to_string(&device_keys).expect("DeviceKeys::to_string always works"),
         )?;
 
+        self.mark_device_key_update(user_id, rooms, globals)
+    }
+
+    /// Notifies the users in the rooms of this user that its device keys changed.
+    pub fn mark_device_key_update(
+        &self,
+        user_id: &UserId,
+        rooms: &super::rooms::Rooms,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
         let count = globals.next_count()?.to_be_bytes();
         for room_id in rooms.rooms_joined(&user_id) {
-            let mut key = room_id?.to_string().as_bytes().to_vec();
+            let room_id = room_id?;
+            // Only users we share an encrypted room with care about our devices
+            if !rooms.is_encrypted(&room_id)? {
+                continue;
+            }
+
+            let mut key = room_id.to_string().as_bytes().to_vec();
             key.push(0xff);
             key.extend_from_slice(&count);
 
             self.keychangeid_userid.insert(key, &*user_id.to_string())?;
+
+            globals.watchers().wake(&WatchKey::Room(room_id));
         }
 
         Ok(())
@@ -515,16 +585,7 @@ impl Users {
                 .insert(&*user_id.to_string(), user_signing_key_key)?;
         }
 
-        let count = globals.next_count()?.to_be_bytes();
-        for room_id in rooms.rooms_joined(&user_id) {
-            let mut key = room_id?.to_string().as_bytes().to_vec();
-            key.push(0xff);
-            key.extend_from_slice(&count);
-
-            self.keychangeid_userid.insert(key, &*user_id.to_string())?;
-        }
-
-        Ok(())
+        self.mark_device_key_update(user_id, rooms, globals)
     }
 
     pub fn sign_key(
@@ -566,17 +627,7 @@ impl Users {
         )?;
 
         // TODO: Should we notify about this change?
-        let count = globals.next_count()?.to_be_bytes();
-        for room_id in rooms.rooms_joined(&target_id) {
-            let mut key = room_id?.to_string().as_bytes().to_vec();
-            key.push(0xff);
-            key.extend_from_slice(&count);
-
-            self.keychangeid_userid
-                .insert(key, &*target_id.to_string())?;
-        }
-
-        Ok(())
+        self.mark_device_key_update(target_id, rooms, globals)
     }
 
     pub fn keys_changed(
@@ -720,6 +771,11 @@ impl Users {
             &*serde_json::to_string(&json).expect("Map::to_string always works"),
         )?;
 
+        globals.watchers().wake(&WatchKey::Device(
+            target_user_id.clone(),
+            target_device_id.into(),
+        ));
+
         Ok(())
     }
 
@@ -849,4 +905,78 @@ impl Users {
         // TODO: Unhook 3PID
         Ok(())
     }
+
+    /// Saves a filter of the user and returns its id.
+    pub fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
+        let filter_id = utils::random_string(10);
+
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.userfilterid_filter.insert(
+            key,
+            &*serde_json::to_string(filter).expect("filter can be serialized"),
+        )?;
+
+        Ok(filter_id)
+    }
+
+    /// Returns a filter of the user.
+    pub fn get_filter(
+        &self,
+        user_id: &UserId,
+        filter_id: &str,
+    ) -> Result<Option<FilterDefinition>> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.userfilterid_filter
+            .get(key)?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Filter in userfilterid_filter is invalid.")
+                })?))
+            })
+    }
+
+    /// Returns what the device received from the sliding sync endpoint so far.
+    pub fn sliding_sync_connection(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<SlidingSyncConnection>> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_slidingsync
+            .get(key)?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Connection in userdeviceid_slidingsync is invalid.")
+                })?))
+            })
+    }
+
+    /// Remembers what the device received from the sliding sync endpoint.
+    pub fn set_sliding_sync_connection(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        connection: &SlidingSyncConnection,
+    ) -> Result<()> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_slidingsync.insert(
+            key,
+            &*serde_json::to_string(connection)
+                .expect("SlidingSyncConnection::to_string always works"),
+        )?;
+
+        Ok(())
+    }
 }
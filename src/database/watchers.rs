@@ -0,0 +1,92 @@
+use rocket::tokio::sync::Notify;
+use ruma::{DeviceId, RoomId, UserId};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Something that sync requests can wait for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WatchKey {
+    /// The memberships or the account data of the user changed
+    User(UserId),
+    /// The device received to-device events or its one-time keys changed
+    Device(UserId, Box<DeviceId>),
+    /// New events, receipts, typing notifications, presence or key changes in the room
+    Room(RoomId),
+}
+
+/// In-memory hub that wakes up waiting sync requests when something they care about changes.
+#[derive(Clone, Default)]
+pub struct Watchers {
+    watchers: Arc<Mutex<HashMap<WatchKey, Vec<Arc<Notify>>>>>,
+}
+
+/// A subscription to some watch keys. It's removed from the hub when it's dropped.
+pub struct Watch {
+    watchers: Watchers,
+    keys: Vec<WatchKey>,
+    notify: Arc<Notify>,
+}
+
+impl Watchers {
+    /// Wakes up everyone who is watching this key.
+    pub fn wake(&self, key: &WatchKey) {
+        if let Some(notifies) = self
+            .watchers
+            .lock()
+            .expect("watchers lock is not poisoned")
+            .get(key)
+        {
+            for notify in notifies {
+                notify.notify();
+            }
+        }
+    }
+
+    /// Subscribes to the keys. Changes that happen after this call are never missed, even if
+    /// `Watch::wait` is called later.
+    pub fn watch(&self, keys: Vec<WatchKey>) -> Watch {
+        let notify = Arc::new(Notify::new());
+
+        let mut watchers = self.watchers.lock().expect("watchers lock is not poisoned");
+        for key in &keys {
+            watchers
+                .entry(key.clone())
+                .or_default()
+                .push(Arc::clone(&notify));
+        }
+
+        Watch {
+            watchers: self.clone(),
+            keys,
+            notify,
+        }
+    }
+}
+
+impl Watch {
+    /// Waits until one of the keys is woken up.
+    pub async fn wait(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut watchers = self
+            .watchers
+            .watchers
+            .lock()
+            .expect("watchers lock is not poisoned");
+
+        for key in &self.keys {
+            if let Some(notifies) = watchers.get_mut(key) {
+                notifies.retain(|notify| !Arc::ptr_eq(notify, &self.notify));
+                if notifies.is_empty() {
+                    watchers.remove(key);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{utils, Error, Result};
+use rocket::tokio::{
+    fs,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use std::{future::Future, path::PathBuf, pin::Pin};
+
+/// A plain text email.
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    /// Formats the email as an internet message with CRLF line endings.
+    pub fn to_message(&self) -> String {
+        let domain = self.from.rsplit('@').next().unwrap_or_default();
+
+        let mut message = format!(
+            "From: {}\r\n\
+             To: {}\r\n\
+             Subject: {}\r\n\
+             Message-ID: <{}@{}>\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\
+             \r\n",
+            header_value(&self.from),
+            header_value(&self.to),
+            encoded_header_value(&self.subject),
+            utils::random_string(32),
+            header_value(domain),
+        );
+
+        for line in self.body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+
+        message
+    }
+}
+
+/// Removes line breaks, so values can't add their own headers.
+fn header_value(value: &str) -> String {
+    value.replace(|c: char| c == '\r' || c == '\n', " ")
+}
+
+/// Encodes header values with non-ascii characters as described in RFC 2047.
+fn encoded_header_value(value: &str) -> String {
+    let value = header_value(value);
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?utf-8?b?{}?=", base64::encode(value))
+    }
+}
+
+/// Delivers emails, e.g. to an SMTP server or into a local directory.
+pub trait MailTransport: Send + Sync {
+    fn send<'a>(
+        &'a self,
+        email: &'a Email,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+}
+
+/// Sends emails to an SMTP relay. The connection is not encrypted, so the relay should run on
+/// the same host or in a trusted network.
+pub struct SmtpTransport {
+    /// Host and port of the relay
+    pub address: String,
+    /// The name we greet the relay with
+    pub hostname: String,
+    /// Username and password for AUTH PLAIN, only sent to relays on the same host
+    pub credentials: Option<(String, String)>,
+}
+
+impl SmtpTransport {
+    async fn send_email(&self, email: &Email) -> Result<()> {
+        let mut stream = TcpStream::connect(&self.address)
+            .await
+            .map_err(|_| Error::BadServerResponse("Could not connect to the SMTP server."))?;
+        let is_local = stream
+            .peer_addr()
+            .map_or(false, |address| address.ip().is_loopback());
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+
+        expect_reply(&mut reader, 2).await?;
+        smtp_command(
+            &mut writer,
+            &mut reader,
+            &format!("EHLO {}", self.hostname),
+            2,
+        )
+        .await?;
+
+        if let Some((username, password)) = &self.credentials {
+            // AUTH PLAIN sends the password as it is, so it must not leave this host
+            if !is_local {
+                return Err(Error::BadConfig(
+                    "SMTP credentials are only sent to relays on the same host.",
+                ));
+            }
+
+            let token = base64::encode(format!("\0{}\0{}", username, password));
+            smtp_command(
+                &mut writer,
+                &mut reader,
+                &format!("AUTH PLAIN {}", token),
+                2,
+            )
+            .await?;
+        }
+
+        smtp_command(
+            &mut writer,
+            &mut reader,
+            &format!("MAIL FROM:<{}>", header_value(&email.from)),
+            2,
+        )
+        .await?;
+        smtp_command(
+            &mut writer,
+            &mut reader,
+            &format!("RCPT TO:<{}>", header_value(&email.to)),
+            2,
+        )
+        .await?;
+        smtp_command(&mut writer, &mut reader, "DATA", 3).await?;
+
+        // Lines starting with a dot would end the data early, so they get another dot
+        let mut data = String::new();
+        for line in email.to_message().split("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        writer
+            .write_all(data.as_bytes())
+            .await
+            .map_err(|_| Error::BadServerResponse("Could not send email to the SMTP server."))?;
+        expect_reply(&mut reader, 2).await?;
+
+        // The email was accepted, so it doesn't matter if the relay says goodbye
+        let _ = smtp_command(&mut writer, &mut reader, "QUIT", 2).await;
+
+        Ok(())
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send<'a>(
+        &'a self,
+        email: &'a Email,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(self.send_email(email))
+    }
+}
+
+/// Sends a command and checks that the class of the reply code (e.g. 2 for 250) is `expected`.
+async fn smtp_command(
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut (impl AsyncBufRead + Unpin),
+    command: &str,
+    expected: u16,
+) -> Result<()> {
+    writer
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .await
+        .map_err(|_| Error::BadServerResponse("Could not send command to the SMTP server."))?;
+
+    expect_reply(reader, expected).await
+}
+
+/// Reads a possibly multiline reply and checks the class of its code.
+async fn expect_reply(reader: &mut (impl AsyncBufRead + Unpin), expected: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .await
+            .map_err(|_| Error::BadServerResponse("Could not read reply of the SMTP server."))?;
+        if read == 0 {
+            return Err(Error::BadServerResponse(
+                "SMTP server closed the connection.",
+            ));
+        }
+
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or(Error::BadServerResponse("Invalid reply from SMTP server."))?;
+
+        // `250-` means that more lines follow
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return if code / 100 == expected {
+                Ok(())
+            } else {
+                Err(Error::BadServerResponse("SMTP server rejected the email."))
+            };
+        }
+    }
+}
+
+/// Writes emails into a maildir, where any mail client can read them. Useful for testing.
+pub struct MaildirTransport {
+    pub path: PathBuf,
+}
+
+impl MaildirTransport {
+    async fn write_email(&self, email: &Email) -> Result<()> {
+        for dir in &["tmp", "new", "cur"] {
+            fs::create_dir_all(self.path.join(dir)).await?;
+        }
+
+        // Mail clients only look at complete files, so they are moved to new after writing
+        let name = format!(
+            "{}.{}.conduit",
+            utils::millis_since_unix_epoch(),
+            utils::random_string(16)
+        );
+        let tmp_path = self.path.join("tmp").join(&name);
+        fs::write(&tmp_path, email.to_message()).await?;
+        fs::rename(&tmp_path, self.path.join("new").join(&name)).await?;
+
+        Ok(())
+    }
+}
+
+impl MailTransport for MaildirTransport {
+    fn send<'a>(
+        &'a self,
+        email: &'a Email,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(self.write_email(email))
+    }
+}
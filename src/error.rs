@@ -27,6 +27,23 @@ pub enum Error {
         #[from]
         source: image::error::ImageError,
     },
+    #[error("Could not read or write a file.")]
+    IoError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("Could not connect to server.")]
+    ReqwestError {
+        #[from]
+        source: reqwest::Error,
+    },
+    #[error("Could not connect to server.")]
+    HyperError {
+        #[from]
+        source: hyper::Error,
+    },
+    #[error("{0}")]
+    BadServerResponse(&'static str),
     #[error("{0}")]
     BadConfig(&'static str),
     #[error("{0}")]
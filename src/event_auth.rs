@@ -0,0 +1,1242 @@
+use crate::{stateres::StateMap, PduEvent};
+use js_int::Int;
+use ruma::{
+    events::{
+        room::{
+            create::CreateEventContent,
+            join_rules::{JoinRule, JoinRulesEventContent},
+            member::{MemberEventContent, MembershipState, ThirdPartyInvite},
+            power_levels::PowerLevelsEventContent,
+        },
+        EventType,
+    },
+    Raw, RoomVersionId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom};
+use thiserror::Error;
+
+/// The reason an event was rejected by the authorization rules.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AuthError {
+    #[error("The auth events don't match the auth events required by this event.")]
+    UnexpectedAuthEvents,
+    #[error("There is no create event in the auth events.")]
+    MissingCreateEvent,
+    #[error("The create event is invalid.")]
+    InvalidCreateEvent,
+    #[error("The room does not allow federation with the sender's server.")]
+    NotFederated,
+    #[error("The aliases event is invalid.")]
+    InvalidAliasesEvent,
+    #[error("The member event is invalid.")]
+    InvalidMemberEvent,
+    #[error("The join rules don't allow this user to join.")]
+    JoinForbidden,
+    #[error("The target user is banned from this room.")]
+    TargetBanned,
+    #[error("The target user is already in the room.")]
+    TargetAlreadyJoined,
+    #[error("The third party invite is invalid.")]
+    InvalidThirdPartyInvite,
+    #[error("The sender is not allowed to change the membership of this user.")]
+    MembershipChangeForbidden,
+    #[error("The sender is not in the room.")]
+    SenderNotJoined,
+    #[error("The sender's power level is too low to send this event.")]
+    InsufficientPowerLevel,
+    #[error("Only the user itself can send state with its user id as the state key.")]
+    StateKeyIsOtherUser,
+    #[error("The power levels event is invalid.")]
+    InvalidPowerLevelsEvent,
+    #[error("The sender is not allowed to make these power level changes.")]
+    PowerLevelChangeForbidden,
+    #[error("The sender is not allowed to redact this event.")]
+    RedactionForbidden,
+}
+
+/// Checks if the event is allowed by the authorization rules of the room version, given the
+/// state events selected by `auth_types_for_event`.
+pub fn auth_check(
+    room_version: &RoomVersionId,
+    pdu: &PduEvent,
+    auth_state: &StateMap<PduEvent>,
+) -> Result<(), AuthError> {
+    // 1. The create event
+    if pdu.kind == EventType::RoomCreate {
+        if !pdu.prev_events.is_empty() || pdu.state_key.as_deref() != Some("") {
+            return Err(AuthError::InvalidCreateEvent);
+        }
+
+        if pdu.room_id.server_name() != pdu.sender.server_name() {
+            return Err(AuthError::InvalidCreateEvent);
+        }
+
+        let content = serde_json::from_value::<Raw<CreateEventContent>>(pdu.content.clone())
+            .expect("Raw::from_value always works")
+            .deserialize()
+            .map_err(|_| AuthError::InvalidCreateEvent)?;
+
+        if !is_known_room_version(&content.room_version) {
+            return Err(AuthError::InvalidCreateEvent);
+        }
+
+        return Ok(());
+    }
+
+    // 2. The auth events must be exactly the ones this event needs
+    let auth_types = auth_types_for_event(
+        &pdu.kind,
+        &pdu.sender,
+        pdu.state_key.as_deref(),
+        &pdu.content,
+    );
+    if auth_state.keys().any(|key| !auth_types.contains(key)) {
+        return Err(AuthError::UnexpectedAuthEvents);
+    }
+
+    // 3. There must be a create event
+    let create = auth_state
+        .get(&(EventType::RoomCreate, "".to_owned()))
+        .ok_or(AuthError::MissingCreateEvent)?;
+    let create_content = serde_json::from_value::<Raw<CreateEventContent>>(create.content.clone())
+        .expect("Raw::from_value always works")
+        .deserialize()
+        .map_err(|_| AuthError::InvalidCreateEvent)?;
+
+    if !create_content.federate && pdu.sender.server_name() != create_content.creator.server_name()
+    {
+        return Err(AuthError::NotFederated);
+    }
+
+    // 4. Aliases events only have special rules before room version 6
+    if pdu.kind == EventType::RoomAliases && uses_aliases_rules(room_version) {
+        return match &pdu.state_key {
+            Some(state_key) if state_key == pdu.sender.server_name().as_str() => Ok(()),
+            _ => Err(AuthError::InvalidAliasesEvent),
+        };
+    }
+
+    let power_levels = PowerLevels::from_auth_state(auth_state, &create_content)?;
+    let sender_membership = membership_of(auth_state, &pdu.sender);
+    let sender_power = power_levels.user_level(&pdu.sender);
+
+    // 5. Member events
+    if pdu.kind == EventType::RoomMember {
+        return check_membership(pdu, auth_state, create, &create_content, &power_levels);
+    }
+
+    // 6. The sender must be in the room
+    if sender_membership != MembershipState::Join {
+        return Err(AuthError::SenderNotJoined);
+    }
+
+    // 7. Third party invites
+    if pdu.kind == EventType::RoomThirdPartyInvite {
+        return if sender_power >= power_levels.invite {
+            Ok(())
+        } else {
+            Err(AuthError::InsufficientPowerLevel)
+        };
+    }
+
+    // 8. The sender needs enough power to send this event type
+    if sender_power < power_levels.event_level(&pdu.kind, pdu.state_key.is_some()) {
+        return Err(AuthError::InsufficientPowerLevel);
+    }
+
+    // 9. Users can only set their own user state
+    if let Some(state_key) = &pdu.state_key {
+        if state_key.starts_with('@') && state_key != &pdu.sender.to_string() {
+            return Err(AuthError::StateKeyIsOtherUser);
+        }
+    }
+
+    // 10. Power level changes
+    if pdu.kind == EventType::RoomPowerLevels {
+        return check_power_levels(pdu, auth_state, sender_power);
+    }
+
+    // 11. Redactions only have special rules before room version 3
+    if pdu.kind == EventType::RoomRedaction && uses_redaction_rules(room_version) {
+        if sender_power >= power_levels.redact {
+            return Ok(());
+        }
+
+        let redacts_server = pdu
+            .redacts
+            .as_ref()
+            .and_then(|redacts| event_id_server(&redacts.to_string()).map(str::to_owned));
+        if redacts_server.is_some()
+            && redacts_server.as_deref() == event_id_server(&pdu.event_id.to_string())
+        {
+            return Ok(());
+        }
+
+        return Err(AuthError::RedactionForbidden);
+    }
+
+    Ok(())
+}
+
+/// Returns the (type, state_key) pairs of the state events that are needed to authorize an
+/// event with these properties.
+pub fn auth_types_for_event(
+    kind: &EventType,
+    sender: &UserId,
+    state_key: Option<&str>,
+    content: &serde_json::Value,
+) -> Vec<(EventType, String)> {
+    if kind == &EventType::RoomCreate {
+        return Vec::new();
+    }
+
+    let mut auth_types = vec![
+        (EventType::RoomPowerLevels, "".to_owned()),
+        (EventType::RoomMember, sender.to_string()),
+        (EventType::RoomCreate, "".to_owned()),
+    ];
+
+    if kind == &EventType::RoomMember {
+        if let Some(state_key) = state_key {
+            let membership = content.get("membership").and_then(|m| m.as_str());
+
+            if matches!(membership, Some("join") | Some("invite")) {
+                auth_types.push((EventType::RoomJoinRules, "".to_owned()));
+            }
+
+            auth_types.push((EventType::RoomMember, state_key.to_owned()));
+
+            if membership == Some("invite") {
+                if let Some(token) = content
+                    .get("third_party_invite")
+                    .and_then(|t| t.get("signed"))
+                    .and_then(|s| s.get("token"))
+                    .and_then(|t| t.as_str())
+                {
+                    auth_types.push((EventType::RoomThirdPartyInvite, token.to_owned()));
+                }
+            }
+        }
+    }
+
+    auth_types
+}
+
+/// The power levels that apply to an event, with the defaults used when the room has no power
+/// levels event.
+struct PowerLevels {
+    content: Option<PowerLevelsEventContent>,
+    creator: UserId,
+    ban: Int,
+    invite: Int,
+    kick: Int,
+    redact: Int,
+}
+
+impl PowerLevels {
+    fn from_auth_state(
+        auth_state: &StateMap<PduEvent>,
+        create_content: &CreateEventContent,
+    ) -> Result<Self, AuthError> {
+        let content = auth_state
+            .get(&(EventType::RoomPowerLevels, "".to_owned()))
+            .map(|pdu| {
+                serde_json::from_value::<Raw<PowerLevelsEventContent>>(pdu.content.clone())
+                    .expect("Raw::from_value always works")
+                    .deserialize()
+                    .map_err(|_| AuthError::InvalidPowerLevelsEvent)
+            })
+            .transpose()?;
+
+        let (ban, invite, kick, redact) = content.as_ref().map_or_else(
+            || (50.into(), 0.into(), 50.into(), 50.into()),
+            |content| (content.ban, content.invite, content.kick, content.redact),
+        );
+
+        Ok(Self {
+            content,
+            creator: create_content.creator.clone(),
+            ban,
+            invite,
+            kick,
+            redact,
+        })
+    }
+
+    fn user_level(&self, user_id: &UserId) -> Int {
+        match &self.content {
+            Some(content) => content
+                .users
+                .get(user_id)
+                .cloned()
+                .unwrap_or(content.users_default),
+            // Without a power levels event, the room creator has all the power
+            None if user_id == &self.creator => 100.into(),
+            None => 0.into(),
+        }
+    }
+
+    fn event_level(&self, kind: &EventType, is_state: bool) -> Int {
+        match &self.content {
+            Some(content) => content.events.get(kind).cloned().unwrap_or(if is_state {
+                content.state_default
+            } else {
+                content.events_default
+            }),
+            None => 0.into(),
+        }
+    }
+}
+
+/// Checks the rules for m.room.member events.
+fn check_membership(
+    pdu: &PduEvent,
+    auth_state: &StateMap<PduEvent>,
+    create: &PduEvent,
+    create_content: &CreateEventContent,
+    power_levels: &PowerLevels,
+) -> Result<(), AuthError> {
+    let target_user_id = pdu
+        .state_key
+        .as_deref()
+        .and_then(|state_key| UserId::try_from(state_key).ok())
+        .ok_or(AuthError::InvalidMemberEvent)?;
+    let content = serde_json::from_value::<Raw<MemberEventContent>>(pdu.content.clone())
+        .expect("Raw::from_value always works")
+        .deserialize()
+        .map_err(|_| AuthError::InvalidMemberEvent)?;
+
+    let sender_membership = membership_of(auth_state, &pdu.sender);
+    let current_membership = membership_of(auth_state, &target_user_id);
+    let sender_power = power_levels.user_level(&pdu.sender);
+    let target_power = power_levels.user_level(&target_user_id);
+
+    match content.membership {
+        MembershipState::Join => {
+            // The creator can join right after creating the room
+            if pdu.prev_events.len() == 1
+                && pdu.prev_events[0] == create.event_id
+                && target_user_id == create_content.creator
+            {
+                return Ok(());
+            }
+
+            if pdu.sender != target_user_id {
+                return Err(AuthError::MembershipChangeForbidden);
+            }
+
+            if current_membership == MembershipState::Ban {
+                return Err(AuthError::TargetBanned);
+            }
+
+            let join_rule = auth_state
+                .get(&(EventType::RoomJoinRules, "".to_owned()))
+                .and_then(|pdu| {
+                    serde_json::from_value::<Raw<JoinRulesEventContent>>(pdu.content.clone())
+                        .expect("Raw::from_value always works")
+                        .deserialize()
+                        .ok()
+                })
+                .map_or(JoinRule::Invite, |content| content.join_rule);
+
+            match join_rule {
+                JoinRule::Public => Ok(()),
+                JoinRule::Invite
+                    if current_membership == MembershipState::Join
+                        || current_membership == MembershipState::Invite =>
+                {
+                    Ok(())
+                }
+                _ => Err(AuthError::JoinForbidden),
+            }
+        }
+        MembershipState::Invite => {
+            if let Some(third_party_invite) = &content.third_party_invite {
+                if current_membership == MembershipState::Ban {
+                    return Err(AuthError::TargetBanned);
+                }
+
+                return check_third_party_invite(
+                    pdu,
+                    &target_user_id,
+                    third_party_invite,
+                    auth_state,
+                );
+            }
+
+            if sender_membership != MembershipState::Join {
+                return Err(AuthError::SenderNotJoined);
+            }
+
+            match current_membership {
+                MembershipState::Join => Err(AuthError::TargetAlreadyJoined),
+                MembershipState::Ban => Err(AuthError::TargetBanned),
+                _ if sender_power >= power_levels.invite => Ok(()),
+                _ => Err(AuthError::InsufficientPowerLevel),
+            }
+        }
+        MembershipState::Leave if pdu.sender == target_user_id => {
+            if current_membership == MembershipState::Join
+                || current_membership == MembershipState::Invite
+            {
+                Ok(())
+            } else {
+                Err(AuthError::MembershipChangeForbidden)
+            }
+        }
+        MembershipState::Leave => {
+            if sender_membership != MembershipState::Join {
+                return Err(AuthError::SenderNotJoined);
+            }
+
+            if current_membership == MembershipState::Ban && sender_power < power_levels.ban {
+                return Err(AuthError::InsufficientPowerLevel);
+            }
+
+            if sender_power >= power_levels.kick && target_power < sender_power {
+                Ok(())
+            } else {
+                Err(AuthError::InsufficientPowerLevel)
+            }
+        }
+        MembershipState::Ban => {
+            if sender_membership != MembershipState::Join {
+                return Err(AuthError::SenderNotJoined);
+            }
+
+            if sender_power >= power_levels.ban && target_power < sender_power {
+                Ok(())
+            } else {
+                Err(AuthError::InsufficientPowerLevel)
+            }
+        }
+        _ => Err(AuthError::InvalidMemberEvent),
+    }
+}
+
+/// Checks that an invite for a third party identifier was signed by the identity server that
+/// the matching m.room.third_party_invite event points to.
+fn check_third_party_invite(
+    pdu: &PduEvent,
+    target_user_id: &UserId,
+    third_party_invite: &ThirdPartyInvite,
+    auth_state: &StateMap<PduEvent>,
+) -> Result<(), AuthError> {
+    let signed = &third_party_invite.signed;
+
+    if &signed.mxid != target_user_id {
+        return Err(AuthError::InvalidThirdPartyInvite);
+    }
+
+    let invite_event = auth_state
+        .get(&(EventType::RoomThirdPartyInvite, signed.token.clone()))
+        .ok_or(AuthError::InvalidThirdPartyInvite)?;
+
+    if invite_event.sender != pdu.sender {
+        return Err(AuthError::InvalidThirdPartyInvite);
+    }
+
+    // The invite can list one key directly and more keys in public_keys
+    let public_keys = invite_event
+        .content
+        .get("public_key")
+        .into_iter()
+        .chain(
+            invite_event
+                .content
+                .get("public_keys")
+                .and_then(|keys| keys.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|key| key.get("public_key")),
+        )
+        .filter_map(|key| key.as_str())
+        .collect::<Vec<_>>();
+
+    let signed_json =
+        serde_json::to_value(signed).map_err(|_| AuthError::InvalidThirdPartyInvite)?;
+
+    for (server_name, signatures) in &signed.signatures {
+        for key_id in signatures.keys() {
+            for public_key in &public_keys {
+                let mut key_set = HashMap::new();
+                key_set.insert(key_id.clone(), (*public_key).to_owned());
+                let mut public_key_map = ruma::signatures::PublicKeyMap::new();
+                public_key_map.insert(server_name.clone(), key_set);
+
+                if ruma::signatures::verify_json(&public_key_map, &signed_json).is_ok() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    Err(AuthError::InvalidThirdPartyInvite)
+}
+
+/// Checks that the sender is allowed to make the changes between the current and the new power
+/// levels.
+fn check_power_levels(
+    pdu: &PduEvent,
+    auth_state: &StateMap<PduEvent>,
+    sender_power: Int,
+) -> Result<(), AuthError> {
+    let new = serde_json::from_value::<Raw<PowerLevelsEventContent>>(pdu.content.clone())
+        .expect("Raw::from_value always works")
+        .deserialize()
+        .map_err(|_| AuthError::InvalidPowerLevelsEvent)?;
+
+    let old = match auth_state.get(&(EventType::RoomPowerLevels, "".to_owned())) {
+        Some(old) => serde_json::from_value::<Raw<PowerLevelsEventContent>>(old.content.clone())
+            .expect("Raw::from_value always works")
+            .deserialize()
+            .map_err(|_| AuthError::InvalidPowerLevelsEvent)?,
+        // The first power levels event can set anything
+        None => return Ok(()),
+    };
+
+    let levels = |content: &PowerLevelsEventContent| {
+        vec![
+            content.users_default,
+            content.events_default,
+            content.state_default,
+            content.ban,
+            content.redact,
+            content.kick,
+            content.invite,
+        ]
+    };
+
+    for (old_level, new_level) in levels(&old).into_iter().zip(levels(&new)) {
+        if old_level != new_level && (old_level > sender_power || new_level > sender_power) {
+            return Err(AuthError::PowerLevelChangeForbidden);
+        }
+    }
+
+    let event_types = old.events.keys().chain(new.events.keys());
+    for kind in event_types {
+        let old_level = old.events.get(kind);
+        let new_level = new.events.get(kind);
+        if old_level != new_level
+            && (old_level.filter(|&&level| level > sender_power).is_some()
+                || new_level.filter(|&&level| level > sender_power).is_some())
+        {
+            return Err(AuthError::PowerLevelChangeForbidden);
+        }
+    }
+
+    let user_ids = old.users.keys().chain(new.users.keys());
+    for user_id in user_ids {
+        let old_level = old.users.get(user_id);
+        let new_level = new.users.get(user_id);
+        if old_level == new_level {
+            continue;
+        }
+
+        // Users can lower their own level, but not the level of others with the same level
+        if user_id != &pdu.sender && old_level.filter(|&&level| level >= sender_power).is_some() {
+            return Err(AuthError::PowerLevelChangeForbidden);
+        }
+
+        if old_level.filter(|&&level| level > sender_power).is_some()
+            || new_level.filter(|&&level| level > sender_power).is_some()
+        {
+            return Err(AuthError::PowerLevelChangeForbidden);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the membership of a user according to the auth state.
+fn membership_of(auth_state: &StateMap<PduEvent>, user_id: &UserId) -> MembershipState {
+    auth_state
+        .get(&(EventType::RoomMember, user_id.to_string()))
+        .and_then(|pdu| {
+            serde_json::from_value::<Raw<MemberEventContent>>(pdu.content.clone())
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .ok()
+        })
+        .map_or(MembershipState::Leave, |content| content.membership)
+}
+
+/// Returns the server name part of an event id in the format of room versions 1 and 2.
+fn event_id_server(event_id: &str) -> Option<&str> {
+    event_id.splitn(2, ':').nth(1)
+}
+
+fn is_known_room_version(room_version: &RoomVersionId) -> bool {
+    matches!(
+        room_version,
+        RoomVersionId::Version1
+            | RoomVersionId::Version2
+            | RoomVersionId::Version3
+            | RoomVersionId::Version4
+            | RoomVersionId::Version5
+            | RoomVersionId::Version6
+    )
+}
+
+fn uses_aliases_rules(room_version: &RoomVersionId) -> bool {
+    !matches!(room_version, RoomVersionId::Version6)
+}
+
+fn uses_redaction_rules(room_version: &RoomVersionId) -> bool {
+    matches!(
+        room_version,
+        RoomVersionId::Version1 | RoomVersionId::Version2
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{event_id, pdu},
+        utils,
+    };
+    use ruma::signatures::Ed25519KeyPair;
+    use serde_json::json;
+
+    const ALICE: &str = "@alice:a.test";
+    const MOD: &str = "@mod:a.test";
+    const BOB: &str = "@bob:a.test";
+    const CAROL: &str = "@carol:a.test";
+    const DAVE: &str = "@dave:b.test";
+
+    /// (room versions, description, event, expected result)
+    type Case = (
+        Vec<RoomVersionId>,
+        &'static str,
+        PduEvent,
+        Result<(), AuthError>,
+    );
+
+    fn all_versions() -> Vec<RoomVersionId> {
+        vec![
+            RoomVersionId::Version1,
+            RoomVersionId::Version2,
+            RoomVersionId::Version3,
+            RoomVersionId::Version4,
+            RoomVersionId::Version5,
+            RoomVersionId::Version6,
+        ]
+    }
+
+    fn create(sender: &str, content: serde_json::Value) -> PduEvent {
+        pdu("$create:a.test", sender, "m.room.create", Some(""), content)
+    }
+
+    fn member(id: &str, sender: &str, target: &str, membership: &str) -> PduEvent {
+        pdu(
+            id,
+            sender,
+            "m.room.member",
+            Some(target),
+            json!({ "membership": membership }),
+        )
+    }
+
+    fn message(id: &str, sender: &str) -> PduEvent {
+        pdu(id, sender, "m.room.message", None, json!({ "body": "hi" }))
+    }
+
+    fn power_levels_content() -> serde_json::Value {
+        json!({
+            "users": { ALICE: 100, MOD: 50, BOB: 0 },
+            "users_default": 0,
+            "events": { "m.room.power_levels": 50 },
+            "events_default": 0,
+            "state_default": 50,
+            "ban": 50,
+            "kick": 50,
+            "invite": 0,
+            "redact": 50,
+        })
+    }
+
+    /// Alice created the room, mod is a moderator, bob a normal member and carol is banned.
+    fn room(join_rule: &str) -> Vec<PduEvent> {
+        vec![
+            create(ALICE, json!({ "creator": ALICE })),
+            member("$alice:a.test", ALICE, ALICE, "join"),
+            pdu(
+                "$power_levels:a.test",
+                ALICE,
+                "m.room.power_levels",
+                Some(""),
+                power_levels_content(),
+            ),
+            pdu(
+                "$join_rules:a.test",
+                ALICE,
+                "m.room.join_rules",
+                Some(""),
+                json!({ "join_rule": join_rule }),
+            ),
+            member("$mod:a.test", MOD, MOD, "join"),
+            member("$bob:a.test", BOB, BOB, "join"),
+            member("$carol:a.test", ALICE, CAROL, "ban"),
+        ]
+    }
+
+    /// Checks every case against the state events of the room the event needs.
+    fn check(room: &[PduEvent], cases: Vec<Case>) {
+        for (room_versions, description, pdu, expected) in cases {
+            let auth_types = auth_types_for_event(
+                &pdu.kind,
+                &pdu.sender,
+                pdu.state_key.as_deref(),
+                &pdu.content,
+            );
+            let auth_state = room
+                .iter()
+                .map(|event| {
+                    let state_key = event.state_key.clone().expect("room state has state keys");
+                    ((event.kind.clone(), state_key), event.clone())
+                })
+                .filter(|(key, _)| auth_types.contains(key))
+                .collect::<StateMap<_>>();
+
+            for room_version in room_versions {
+                assert_eq!(
+                    auth_check(&room_version, &pdu, &auth_state),
+                    expected,
+                    "{} in room version {:?}",
+                    description,
+                    room_version
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn create_events() {
+        check(
+            &[],
+            vec![
+                (
+                    all_versions(),
+                    "create event",
+                    create(ALICE, json!({ "creator": ALICE })),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "create event with prev events",
+                    PduEvent {
+                        prev_events: vec![event_id("$previous:a.test")],
+                        ..create(ALICE, json!({ "creator": ALICE }))
+                    },
+                    Err(AuthError::InvalidCreateEvent),
+                ),
+                (
+                    all_versions(),
+                    "create event with a state key",
+                    PduEvent {
+                        state_key: Some("state_key".to_owned()),
+                        ..create(ALICE, json!({ "creator": ALICE }))
+                    },
+                    Err(AuthError::InvalidCreateEvent),
+                ),
+                (
+                    all_versions(),
+                    "create event of another server's room",
+                    create(DAVE, json!({ "creator": DAVE })),
+                    Err(AuthError::InvalidCreateEvent),
+                ),
+                (
+                    all_versions(),
+                    "create event with an unknown room version",
+                    create(
+                        ALICE,
+                        json!({ "creator": ALICE, "room_version": "unknown" }),
+                    ),
+                    Err(AuthError::InvalidCreateEvent),
+                ),
+            ],
+        );
+
+        let without_create = room("public")
+            .into_iter()
+            .filter(|pdu| pdu.kind != EventType::RoomCreate)
+            .collect::<Vec<_>>();
+        check(
+            &without_create,
+            vec![(
+                all_versions(),
+                "message without create event",
+                message("$message:a.test", ALICE),
+                Err(AuthError::MissingCreateEvent),
+            )],
+        );
+
+        let not_federated = room("public")
+            .into_iter()
+            .map(|pdu| match pdu.kind {
+                EventType::RoomCreate => {
+                    create(ALICE, json!({ "creator": ALICE, "m.federate": false }))
+                }
+                _ => pdu,
+            })
+            .collect::<Vec<_>>();
+        check(
+            &not_federated,
+            vec![
+                (
+                    all_versions(),
+                    "local join in a room that doesn't federate",
+                    member("$join:a.test", ALICE, ALICE, "join"),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "remote join in a room that doesn't federate",
+                    member("$join:b.test", DAVE, DAVE, "join"),
+                    Err(AuthError::NotFederated),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn auth_events_have_to_match_the_event() {
+        let auth_state = room("public")
+            .into_iter()
+            .map(|pdu| ((pdu.kind.clone(), pdu.state_key.clone().unwrap()), pdu))
+            .collect::<StateMap<_>>();
+
+        for room_version in all_versions() {
+            assert_eq!(
+                auth_check(
+                    &room_version,
+                    &message("$message:a.test", ALICE),
+                    &auth_state
+                ),
+                Err(AuthError::UnexpectedAuthEvents)
+            );
+        }
+    }
+
+    #[test]
+    fn join_rules() {
+        check(
+            &room("public"),
+            vec![
+                (
+                    all_versions(),
+                    "join of a public room",
+                    member("$join:b.test", DAVE, DAVE, "join"),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "join of a user that is already joined",
+                    member("$join:a.test", BOB, BOB, "join"),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "join for another user",
+                    member("$join:a.test", ALICE, DAVE, "join"),
+                    Err(AuthError::MembershipChangeForbidden),
+                ),
+            ],
+        );
+
+        let mut invite_room = room("invite");
+        invite_room.push(member("$dave:a.test", MOD, DAVE, "invite"));
+        check(
+            &invite_room,
+            vec![
+                (
+                    all_versions(),
+                    "join of an invited user",
+                    member("$join:b.test", DAVE, DAVE, "join"),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "join of a user without invite",
+                    member("$join:b.test", "@eve:b.test", "@eve:b.test", "join"),
+                    Err(AuthError::JoinForbidden),
+                ),
+            ],
+        );
+
+        // Rooms without join rules are invite only, except for the creator's first join
+        check(
+            &[create(ALICE, json!({ "creator": ALICE }))],
+            vec![
+                (
+                    all_versions(),
+                    "join of the creator right after the create event",
+                    PduEvent {
+                        prev_events: vec![event_id("$create:a.test")],
+                        ..member("$alice:a.test", ALICE, ALICE, "join")
+                    },
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "join of another user right after the create event",
+                    PduEvent {
+                        prev_events: vec![event_id("$create:a.test")],
+                        ..member("$join:b.test", DAVE, DAVE, "join")
+                    },
+                    Err(AuthError::JoinForbidden),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn bans() {
+        check(
+            &room("public"),
+            vec![
+                (
+                    all_versions(),
+                    "moderator bans a member",
+                    member("$ban:a.test", MOD, BOB, "ban"),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "member bans a moderator",
+                    member("$ban:a.test", BOB, MOD, "ban"),
+                    Err(AuthError::InsufficientPowerLevel),
+                ),
+                (
+                    all_versions(),
+                    "moderator bans an admin",
+                    member("$ban:a.test", MOD, ALICE, "ban"),
+                    Err(AuthError::InsufficientPowerLevel),
+                ),
+                (
+                    all_versions(),
+                    "ban by a user that is not in the room",
+                    member("$ban:b.test", DAVE, BOB, "ban"),
+                    Err(AuthError::SenderNotJoined),
+                ),
+                (
+                    all_versions(),
+                    "moderator unbans a user",
+                    member("$unban:a.test", MOD, CAROL, "leave"),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "member unbans a user",
+                    member("$unban:a.test", BOB, CAROL, "leave"),
+                    Err(AuthError::InsufficientPowerLevel),
+                ),
+                (
+                    all_versions(),
+                    "banned user joins",
+                    member("$join:a.test", CAROL, CAROL, "join"),
+                    Err(AuthError::TargetBanned),
+                ),
+                (
+                    all_versions(),
+                    "banned user leaves",
+                    member("$leave:a.test", CAROL, CAROL, "leave"),
+                    Err(AuthError::MembershipChangeForbidden),
+                ),
+                (
+                    all_versions(),
+                    "banned user is invited",
+                    member("$invite:a.test", BOB, CAROL, "invite"),
+                    Err(AuthError::TargetBanned),
+                ),
+                (
+                    all_versions(),
+                    "banned user sends a message",
+                    message("$message:a.test", CAROL),
+                    Err(AuthError::SenderNotJoined),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn power_level_changes() {
+        let changed_power_levels = |sender: &str, pointer: &str, level: i64| {
+            let mut content = power_levels_content();
+            *content
+                .pointer_mut(pointer)
+                .expect("test power levels contain the pointer") = level.into();
+            pdu(
+                "$new_power_levels:a.test",
+                sender,
+                "m.room.power_levels",
+                Some(""),
+                content,
+            )
+        };
+
+        check(
+            &room("public"),
+            vec![
+                (
+                    all_versions(),
+                    "admin raises the ban level",
+                    changed_power_levels(ALICE, "/ban", 100),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "moderator raises the ban level above its own level",
+                    changed_power_levels(MOD, "/ban", 100),
+                    Err(AuthError::PowerLevelChangeForbidden),
+                ),
+                (
+                    all_versions(),
+                    "moderator lowers the kick level",
+                    changed_power_levels(MOD, "/kick", 25),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "moderator raises the level needed for power levels above its own level",
+                    changed_power_levels(MOD, "/events/m.room.power_levels", 100),
+                    Err(AuthError::PowerLevelChangeForbidden),
+                ),
+                (
+                    all_versions(),
+                    "moderator promotes a member to moderator",
+                    changed_power_levels(MOD, "/users/@bob:a.test", 50),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "moderator promotes a member to admin",
+                    changed_power_levels(MOD, "/users/@bob:a.test", 100),
+                    Err(AuthError::PowerLevelChangeForbidden),
+                ),
+                (
+                    all_versions(),
+                    "moderator demotes an admin",
+                    changed_power_levels(MOD, "/users/@alice:a.test", 0),
+                    Err(AuthError::PowerLevelChangeForbidden),
+                ),
+                (
+                    all_versions(),
+                    "moderator demotes itself",
+                    changed_power_levels(MOD, "/users/@mod:a.test", 0),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "member changes the power levels",
+                    changed_power_levels(BOB, "/kick", 0),
+                    Err(AuthError::InsufficientPowerLevel),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn third_party_invites() {
+        let generate_keypair = || {
+            Ed25519KeyPair::new(
+                &utils::generate_keypair(None).expect("generate_keypair always returns Some"),
+                "key1".to_owned(),
+            )
+            .expect("generated keypair is valid")
+        };
+        let identity_server_key = generate_keypair();
+        let other_key = generate_keypair();
+
+        let third_party_invite = |sender: &str, target: &str, mxid: &str, key: &Ed25519KeyPair| {
+            let mut signed = json!({ "mxid": mxid, "token": "token" });
+            ruma::signatures::sign_json("identity.test", key, &mut signed)
+                .expect("signed is a json object");
+            pdu(
+                "$invite:a.test",
+                sender,
+                "m.room.member",
+                Some(target),
+                json!({
+                    "membership": "invite",
+                    "third_party_invite": { "display_name": "dave@b.test", "signed": signed },
+                }),
+            )
+        };
+
+        let mut room = room("invite");
+        room.push(pdu(
+            "$third_party_invite:a.test",
+            MOD,
+            "m.room.third_party_invite",
+            Some("token"),
+            json!({
+                "display_name": "dave@b.test",
+                "key_validity_url": "https://identity.test/_matrix/identity/api/v1/pubkey/isvalid",
+                "public_key": base64::encode_config(
+                    identity_server_key.public_key(),
+                    base64::STANDARD_NO_PAD
+                ),
+            }),
+        ));
+
+        check(
+            &room,
+            vec![
+                (
+                    all_versions(),
+                    "invite signed by the identity server",
+                    third_party_invite(MOD, DAVE, DAVE, &identity_server_key),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "invite signed by another key",
+                    third_party_invite(MOD, DAVE, DAVE, &other_key),
+                    Err(AuthError::InvalidThirdPartyInvite),
+                ),
+                (
+                    all_versions(),
+                    "invite for another user than the signed one",
+                    third_party_invite(MOD, DAVE, BOB, &identity_server_key),
+                    Err(AuthError::InvalidThirdPartyInvite),
+                ),
+                (
+                    all_versions(),
+                    "invite by another user than the third party invite sender",
+                    third_party_invite(BOB, DAVE, DAVE, &identity_server_key),
+                    Err(AuthError::InvalidThirdPartyInvite),
+                ),
+                (
+                    all_versions(),
+                    "invite of a banned user",
+                    third_party_invite(MOD, CAROL, CAROL, &identity_server_key),
+                    Err(AuthError::TargetBanned),
+                ),
+                (
+                    all_versions(),
+                    "third party invite by a member",
+                    pdu(
+                        "$third_party_invite:a.test",
+                        BOB,
+                        "m.room.third_party_invite",
+                        Some("other_token"),
+                        json!({}),
+                    ),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "third party invite by a banned user",
+                    pdu(
+                        "$third_party_invite:a.test",
+                        CAROL,
+                        "m.room.third_party_invite",
+                        Some("other_token"),
+                        json!({}),
+                    ),
+                    Err(AuthError::SenderNotJoined),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn redactions() {
+        let redaction = |id: &str, sender: &str, redacts: &str| PduEvent {
+            redacts: Some(event_id(redacts)),
+            ..pdu(id, sender, "m.room.redaction", None, json!({}))
+        };
+        let before_version_3 = vec![RoomVersionId::Version1, RoomVersionId::Version2];
+        let since_version_3 = vec![
+            RoomVersionId::Version3,
+            RoomVersionId::Version4,
+            RoomVersionId::Version5,
+            RoomVersionId::Version6,
+        ];
+
+        check(
+            &room("public"),
+            vec![
+                (
+                    before_version_3.clone(),
+                    "member redacts an event from its own server",
+                    redaction("$redaction:a.test", BOB, "$message:a.test"),
+                    Ok(()),
+                ),
+                (
+                    before_version_3.clone(),
+                    "member redacts an event from another server",
+                    redaction("$redaction:a.test", BOB, "$message:b.test"),
+                    Err(AuthError::RedactionForbidden),
+                ),
+                (
+                    before_version_3,
+                    "moderator redacts an event from another server",
+                    redaction("$redaction:a.test", MOD, "$message:b.test"),
+                    Ok(()),
+                ),
+                // The redaction is checked when it's applied to the redacted event
+                (
+                    since_version_3,
+                    "member redacts an event from another server",
+                    redaction("$redaction:a.test", BOB, "$message:b.test"),
+                    Ok(()),
+                ),
+                (
+                    all_versions(),
+                    "banned user redacts an event",
+                    redaction("$redaction:a.test", CAROL, "$message:a.test"),
+                    Err(AuthError::SenderNotJoined),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn aliases_events() {
+        let aliases = |state_key: &str| {
+            pdu(
+                "$aliases:b.test",
+                DAVE,
+                "m.room.aliases",
+                Some(state_key),
+                json!({ "aliases": ["#room:b.test"] }),
+            )
+        };
+        let before_version_6 = vec![
+            RoomVersionId::Version1,
+            RoomVersionId::Version2,
+            RoomVersionId::Version3,
+            RoomVersionId::Version4,
+            RoomVersionId::Version5,
+        ];
+
+        check(
+            &room("public"),
+            vec![
+                (
+                    before_version_6.clone(),
+                    "aliases of the sender's server",
+                    aliases("b.test"),
+                    Ok(()),
+                ),
+                (
+                    before_version_6,
+                    "aliases of another server",
+                    aliases("a.test"),
+                    Err(AuthError::InvalidAliasesEvent),
+                ),
+                // Aliases events are normal state events now
+                (
+                    vec![RoomVersionId::Version6],
+                    "aliases of a user that is not in the room",
+                    aliases("b.test"),
+                    Err(AuthError::SenderNotJoined),
+                ),
+            ],
+        );
+    }
+}
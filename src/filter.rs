@@ -0,0 +1,246 @@
+use crate::PduEvent;
+use ruma::{Raw, RoomId};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+/// The number of timeline events that are sent if the filter has no limit.
+pub const DEFAULT_TIMELINE_LIMIT: usize = 10;
+
+/// A filter for events that are not room events, like presence or account data.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EventFilter {
+    pub limit: Option<usize>,
+    pub types: Option<Vec<String>>,
+    pub not_types: Vec<String>,
+    pub senders: Option<Vec<String>>,
+    pub not_senders: Vec<String>,
+}
+
+/// A filter for events in rooms.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RoomEventFilter {
+    pub limit: Option<usize>,
+    pub types: Option<Vec<String>>,
+    pub not_types: Vec<String>,
+    pub senders: Option<Vec<String>>,
+    pub not_senders: Vec<String>,
+    pub rooms: Option<Vec<String>>,
+    pub not_rooms: Vec<String>,
+    pub contains_url: Option<bool>,
+    pub lazy_load_members: bool,
+    pub include_redundant_members: bool,
+}
+
+/// Filters for the different parts of the rooms section of a sync response.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RoomFilter {
+    pub rooms: Option<Vec<String>>,
+    pub not_rooms: Vec<String>,
+    pub include_leave: bool,
+    pub timeline: RoomEventFilter,
+    pub state: RoomEventFilter,
+    pub ephemeral: RoomEventFilter,
+    pub account_data: RoomEventFilter,
+}
+
+/// The filter of a sync request.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SyncFilter {
+    pub event_fields: Option<Vec<String>>,
+    pub account_data: EventFilter,
+    pub presence: EventFilter,
+    pub room: RoomFilter,
+}
+
+/// Converts a filter from the request types into one of our filters. Invalid or missing fields
+/// don't filter anything.
+pub fn from_ruma<T: Serialize, F: DeserializeOwned + Default>(filter: &T) -> F {
+    serde_json::to_value(filter)
+        .ok()
+        .and_then(|value| serde_json::from_value(value).ok())
+        .unwrap_or_default()
+}
+
+/// Checks if `value` matches a filter pattern, where `*` matches any number of characters.
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().expect("split always returns one element");
+    if !value.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &value[first.len()..];
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // The last part has to be at the end
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    // There was no `*` in the pattern
+    rest.is_empty()
+}
+
+fn allowed(
+    value: &str,
+    allowed: &Option<Vec<String>>,
+    forbidden: &[String],
+    pattern: bool,
+) -> bool {
+    let is_match = |other: &String| {
+        if pattern {
+            matches_pattern(other, value)
+        } else {
+            other == value
+        }
+    };
+
+    !forbidden.iter().any(is_match) && allowed.as_ref().map_or(true, |a| a.iter().any(is_match))
+}
+
+/// Returns the type and sender of a raw event.
+fn raw_type_and_sender<T>(event: &Raw<T>) -> (Option<String>, Option<String>) {
+    let json = serde_json::from_str::<Value>(event.json().get()).unwrap_or_default();
+    let get = |field| json.get(field).and_then(|v| v.as_str()).map(str::to_owned);
+
+    (get("type"), get("sender"))
+}
+
+impl EventFilter {
+    /// Checks if an event with this type and sender is allowed by the filter.
+    pub fn allows(&self, kind: &str, sender: Option<&str>) -> bool {
+        allowed(kind, &self.types, &self.not_types, true)
+            && sender.map_or(true, |sender| {
+                allowed(sender, &self.senders, &self.not_senders, false)
+            })
+    }
+
+    /// Removes all events that are not allowed by the filter and applies the limit.
+    pub fn apply<T>(&self, events: Vec<Raw<T>>) -> Vec<Raw<T>> {
+        events
+            .into_iter()
+            .filter(|event| {
+                let (kind, sender) = raw_type_and_sender(event);
+                self.allows(kind.as_deref().unwrap_or_default(), sender.as_deref())
+            })
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+impl RoomEventFilter {
+    /// Checks if events of this room may be returned.
+    pub fn allows_room(&self, room_id: &RoomId) -> bool {
+        allowed(room_id.as_str(), &self.rooms, &self.not_rooms, false)
+    }
+
+    /// Checks if the pdu is allowed by the filter.
+    pub fn allows_pdu(&self, pdu: &PduEvent) -> bool {
+        self.allows_room(&pdu.room_id)
+            && allowed(&pdu.kind.to_string(), &self.types, &self.not_types, true)
+            && allowed(pdu.sender.as_str(), &self.senders, &self.not_senders, false)
+            && self.contains_url.map_or(true, |contains_url| {
+                pdu.content.get("url").map_or(false, |url| url.is_string()) == contains_url
+            })
+    }
+
+    /// Checks if a raw event like a receipt or a tag is allowed by the filter.
+    pub fn allows_raw<T>(&self, event: &Raw<T>) -> bool {
+        let (kind, sender) = raw_type_and_sender(event);
+
+        allowed(
+            kind.as_deref().unwrap_or_default(),
+            &self.types,
+            &self.not_types,
+            true,
+        ) && sender.map_or(true, |sender| {
+            allowed(&sender, &self.senders, &self.not_senders, false)
+        })
+    }
+
+    /// Returns the maximum number of events, or `default` if the filter has no limit.
+    pub fn limit_or(&self, default: usize) -> usize {
+        self.limit.unwrap_or(default)
+    }
+}
+
+impl RoomFilter {
+    /// Checks if the room should be included in the sync response.
+    pub fn allows_room(&self, room_id: &RoomId) -> bool {
+        allowed(room_id.as_str(), &self.rooms, &self.not_rooms, false)
+    }
+}
+
+impl SyncFilter {
+    /// Only keeps the fields of the event that were requested by `event_fields`. A `.` separates
+    /// the keys of nested objects.
+    pub fn format_event<T>(&self, event: Raw<T>) -> Raw<T> {
+        let event_fields = match &self.event_fields {
+            Some(event_fields) => event_fields,
+            None => return event,
+        };
+
+        let json = match serde_json::from_str::<Value>(event.json().get()) {
+            Ok(json) => json,
+            Err(_) => return event,
+        };
+
+        let mut formatted = serde_json::Map::new();
+        'fields: for field in event_fields {
+            let path = split_field(field);
+
+            let mut value = Some(&json);
+            for key in &path {
+                value = value.and_then(|v| v.get(key));
+            }
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let (last, parents) = path.split_last().expect("path is never empty");
+            let mut target = &mut formatted;
+            for key in parents {
+                target = match target
+                    .entry(key.clone())
+                    .or_insert_with(|| Value::Object(serde_json::Map::new()))
+                    .as_object_mut()
+                {
+                    Some(object) => object,
+                    // A parent of this field was already requested as a whole
+                    None => continue 'fields,
+                };
+            }
+            target.insert(last.clone(), value.clone());
+        }
+
+        serde_json::from_value(Value::Object(formatted)).unwrap_or(event)
+    }
+}
+
+/// Splits a field of `event_fields` at unescaped dots.
+fn split_field(field: &str) -> Vec<String> {
+    let mut path = vec![String::new()];
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    path.last_mut().expect("path is never empty").push(escaped);
+                }
+            }
+            '.' => path.push(String::new()),
+            c => path.last_mut().expect("path is never empty").push(c),
+        }
+    }
+    path
+}
@@ -1,15 +1,22 @@
 pub mod client_server;
 mod database;
+mod email;
 mod error;
+mod event_auth;
+mod filter;
 mod pdu;
 pub mod push_rules;
 mod ruma_wrapper;
+pub mod server_server;
+mod stateres;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
 pub use database::Database;
 pub use error::{Error, Result};
 pub use pdu::PduEvent;
-pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
+pub use ruma_wrapper::{ConduitResult, JsonRequest, Ruma, RumaResponse};
 use std::ops::Deref;
 
 pub struct State<'r, T: Send + Sync + 'static>(&'r T);
@@ -4,17 +4,23 @@ pub mod push_rules;
 
 mod client_server;
 mod database;
+mod email;
 mod error;
+mod event_auth;
+mod filter;
 mod pdu;
 mod ruma_wrapper;
-//mod server_server;
+mod server_server;
+mod stateres;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
 pub use database::Database;
 pub use error::{Error, Result};
 pub use pdu::PduEvent;
 pub use rocket::State;
-pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
+pub use ruma_wrapper::{ConduitResult, JsonRequest, Ruma, RumaResponse};
 
 use rocket::{fairing::AdHoc, routes};
 
@@ -35,8 +41,13 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::deactivate_route,
                 client_server::get_capabilities_route,
                 client_server::get_pushrules_all_route,
+                client_server::get_pushrule_route,
                 client_server::set_pushrule_route,
+                client_server::delete_pushrule_route,
+                client_server::get_pushrule_enabled_route,
                 client_server::set_pushrule_enabled_route,
+                client_server::get_pushrule_actions_route,
+                client_server::set_pushrule_actions_route,
                 client_server::get_room_event_route,
                 client_server::get_filter_route,
                 client_server::create_filter_route,
@@ -88,6 +99,9 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_state_events_for_key_route,
                 client_server::get_state_events_for_empty_key_route,
                 client_server::sync_events_route,
+                client_server::sliding_sync_route,
+                client_server::peek_events_route,
+                client_server::search_events_route,
                 client_server::get_context_route,
                 client_server::get_message_events_route,
                 client_server::turn_server_route,
@@ -111,15 +125,29 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_key_changes_route,
                 client_server::pushers_route,
                 client_server::set_pushers_route,
-                //server_server::well_known_server,
-                //server_server::get_server_version,
-                //server_server::get_server_keys,
-                //server_server::get_server_keys_deprecated,
+                client_server::get_notifications_route,
+                server_server::well_known_server,
+                server_server::get_server_version,
+                server_server::get_server_keys,
+                server_server::get_server_keys_deprecated,
+                server_server::send_transaction_message_route,
+                server_server::get_room_information_route,
+                server_server::make_join_route,
+                server_server::send_join_route,
+                server_server::create_invite_route,
+                server_server::get_backfill_route,
+                server_server::get_event_route,
+                server_server::get_room_state_route,
+                server_server::get_room_state_ids_route,
             ],
         )
         .attach(AdHoc::on_attach("Config", |mut rocket| async {
             let data = Database::load_or_create(rocket.config().await).expect("valid config");
 
+            data.sending.start_handler(&data.globals, &data.rooms);
+            data.pushers
+                .start_handler(&data.globals, &data.rooms, &data.users);
+
             Ok(rocket.manage(data))
         }))
 }
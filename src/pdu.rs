@@ -1,17 +1,18 @@
 use crate::{Error, Result};
 use js_int::UInt;
+use ruma::api::client::error::ErrorKind;
 use ruma::{
     events::{
         pdu::EventHash, room::member::MemberEventContent, AnyRoomEvent, AnyStateEvent,
         AnyStrippedStateEvent, AnySyncRoomEvent, AnySyncStateEvent, EventType, StateEvent,
     },
-    EventId, Raw, RoomId, ServerName, UserId,
+    EventId, Raw, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::TryFrom};
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PduEvent {
     pub event_id: EventId,
     pub room_id: RoomId,
@@ -175,3 +176,50 @@ impl PduEvent {
         serde_json::from_value(json).expect("Raw::from_value always works")
     }
 }
+
+/// Returns true if event ids in this room version are calculated from the event.
+pub fn uses_reference_hash_ids(room_version: &RoomVersionId) -> bool {
+    !matches!(
+        room_version,
+        RoomVersionId::Version1 | RoomVersionId::Version2
+    )
+}
+
+/// Calculates the event id of a pdu in a room version that uses reference hashes as event ids.
+pub fn reference_hash_event_id(
+    room_version: &RoomVersionId,
+    pdu_json: &serde_json::Value,
+) -> Result<EventId> {
+    let mut pdu_json = pdu_json.clone();
+    if let Some(object) = pdu_json.as_object_mut() {
+        object.remove("event_id");
+    }
+
+    let reference_hash = ruma::signatures::reference_hash(&pdu_json)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "PDU is invalid."))?;
+
+    // Room version 3 uses standard base64, later versions use the url safe alphabet
+    let reference_hash = if room_version == &RoomVersionId::Version3 {
+        reference_hash.replace('-', "+").replace('_', "/")
+    } else {
+        reference_hash.replace('+', "-").replace('/', "_")
+    };
+
+    EventId::try_from(&*format!("${}", reference_hash))
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "PDU is invalid."))
+}
+
+/// Returns the pdu json in the format other servers expect.
+pub fn to_federation_json(
+    room_version: &RoomVersionId,
+    mut pdu_json: serde_json::Value,
+) -> serde_json::Value {
+    // Only the first room versions send the event id, later versions calculate it
+    if uses_reference_hash_ids(room_version) {
+        if let Some(object) = pdu_json.as_object_mut() {
+            object.remove("event_id");
+        }
+    }
+
+    pdu_json
+}
@@ -1,3 +1,4 @@
+use crate::PduEvent;
 use ruma::{
     push::{
         Action, ConditionalPushRule, ConditionalPushRuleInit, PatternedPushRule,
@@ -5,6 +6,8 @@ use ruma::{
     },
     UserId,
 };
+use serde_json::Value;
+use std::collections::BTreeMap;
 
 pub fn default_pushrules(user_id: &UserId) -> Ruleset {
     let mut rules = Ruleset::default();
@@ -12,7 +15,7 @@ pub fn default_pushrules(user_id: &UserId) -> Ruleset {
     rules.override_ = vec![
         master_rule(),
         suppress_notices_rule(),
-        invite_for_me_rule(),
+        invite_for_me_rule(&user_id),
         member_event_rule(),
         contains_display_name_rule(),
         tombstone_rule(),
@@ -28,6 +31,19 @@ pub fn default_pushrules(user_id: &UserId) -> Ruleset {
     rules
 }
 
+/// Checks if the rule is one of the server default rules of this kind.
+pub fn is_default_rule(user_id: &UserId, kind: &str, rule_id: &str) -> bool {
+    serde_json::to_value(default_pushrules(user_id))
+        .expect("Ruleset::to_value always works")
+        .get(kind)
+        .and_then(|rules| rules.as_array())
+        .map_or(false, |rules| {
+            rules
+                .iter()
+                .any(|rule| rule.get("rule_id").and_then(|id| id.as_str()) == Some(rule_id))
+        })
+}
+
 pub fn master_rule() -> ConditionalPushRule {
     ConditionalPushRuleInit {
         actions: vec![Action::DontNotify],
@@ -53,7 +69,7 @@ pub fn suppress_notices_rule() -> ConditionalPushRule {
     .into()
 }
 
-pub fn invite_for_me_rule() -> ConditionalPushRule {
+pub fn invite_for_me_rule(user_id: &UserId) -> ConditionalPushRule {
     ConditionalPushRuleInit {
         actions: vec![
             Action::Notify,
@@ -63,10 +79,20 @@ pub fn invite_for_me_rule() -> ConditionalPushRule {
         default: true,
         enabled: true,
         rule_id: ".m.rule.invite_for_me".to_owned(),
-        conditions: vec![PushCondition::EventMatch {
-            key: "content.membership".to_owned(),
-            pattern: "m.invite".to_owned(),
-        }],
+        conditions: vec![
+            PushCondition::EventMatch {
+                key: "type".to_owned(),
+                pattern: "m.room.member".to_owned(),
+            },
+            PushCondition::EventMatch {
+                key: "content.membership".to_owned(),
+                pattern: "invite".to_owned(),
+            },
+            PushCondition::EventMatch {
+                key: "state_key".to_owned(),
+                pattern: user_id.to_string(),
+            },
+        ],
     }
     .into()
 }
@@ -78,8 +104,8 @@ pub fn member_event_rule() -> ConditionalPushRule {
         enabled: true,
         rule_id: ".m.rule.member_event".to_owned(),
         conditions: vec![PushCondition::EventMatch {
-            key: "content.membership".to_owned(),
-            pattern: "type".to_owned(),
+            key: "type".to_owned(),
+            pattern: "m.room.member".to_owned(),
         }],
     }
     .into()
@@ -245,3 +271,631 @@ pub fn encrypted_rule() -> ConditionalPushRule {
     }
     .into()
 }
+
+/// What the push rules need to know about the room an event was sent in.
+pub struct PushContext {
+    /// The number of joined members
+    pub member_count: u64,
+    pub sender_power_level: i64,
+    /// The power levels from the `notifications` field of the power levels event
+    pub notification_power_levels: BTreeMap<String, i64>,
+}
+
+/// Evaluates the push rules of a user for an event and returns the actions of the first rule
+/// that matches. No actions mean that the user is not notified.
+pub fn evaluate(
+    ruleset: &Ruleset,
+    user_display_name: Option<&str>,
+    context: &PushContext,
+    pdu: &PduEvent,
+) -> Vec<Action> {
+    let event = serde_json::to_value(pdu).expect("PduEvent::to_value always works");
+    let conditions_match = |conditions: &[PushCondition]| {
+        conditions
+            .iter()
+            .all(|condition| condition_matches(condition, user_display_name, context, &event))
+    };
+
+    for rule in ruleset.override_.iter().filter(|rule| rule.enabled) {
+        if conditions_match(&rule.conditions) {
+            return rule.actions.clone();
+        }
+    }
+
+    for rule in ruleset.content.iter().filter(|rule| rule.enabled) {
+        if event_matches(&event, "content.body", &rule.pattern) {
+            return rule.actions.clone();
+        }
+    }
+
+    for rule in ruleset.room.iter().filter(|rule| rule.enabled) {
+        if rule.rule_id == pdu.room_id.as_str() {
+            return rule.actions.clone();
+        }
+    }
+
+    for rule in ruleset.sender.iter().filter(|rule| rule.enabled) {
+        if rule.rule_id == pdu.sender.as_str() {
+            return rule.actions.clone();
+        }
+    }
+
+    for rule in ruleset.underride.iter().filter(|rule| rule.enabled) {
+        if conditions_match(&rule.conditions) {
+            return rule.actions.clone();
+        }
+    }
+
+    Vec::new()
+}
+
+/// Checks if the actions notify the user.
+pub fn notifies(actions: &[Action]) -> bool {
+    actions
+        .iter()
+        .any(|action| matches!(action, Action::Notify | Action::Coalesce))
+}
+
+/// Checks if the actions highlight the event.
+pub fn highlights(actions: &[Action]) -> bool {
+    notifies(actions)
+        && actions
+            .iter()
+            .any(|action| matches!(action, Action::SetTweak(Tweak::Highlight(true))))
+}
+
+fn condition_matches(
+    condition: &PushCondition,
+    user_display_name: Option<&str>,
+    context: &PushContext,
+    event: &Value,
+) -> bool {
+    match condition {
+        PushCondition::EventMatch { key, pattern } => event_matches(event, key, pattern),
+        PushCondition::ContainsDisplayName => match (
+            user_display_name,
+            event
+                .pointer("/content/body")
+                .and_then(|body| body.as_str()),
+        ) {
+            (Some(display_name), Some(body)) if !display_name.is_empty() => {
+                pattern_matches(display_name, body, false, true)
+            }
+            _ => false,
+        },
+        PushCondition::RoomMemberCount { is } => serde_json::to_value(is)
+            .ok()
+            .and_then(|is| is.as_str().and_then(parse_member_count))
+            .map_or(false, |(operator, count)| match operator {
+                "<" => context.member_count < count,
+                ">" => context.member_count > count,
+                "<=" => context.member_count <= count,
+                ">=" => context.member_count >= count,
+                _ => context.member_count == count,
+            }),
+        PushCondition::SenderNotificationPermission { key } => {
+            context.sender_power_level
+                >= context
+                    .notification_power_levels
+                    .get(key)
+                    .copied()
+                    .unwrap_or(50)
+        }
+    }
+}
+
+/// Splits a `room_member_count` condition like `>=2` into the operator and the count.
+fn parse_member_count(is: &str) -> Option<(&str, u64)> {
+    let split = is
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or_else(|| is.len());
+    let (operator, count) = is.split_at(split);
+
+    match operator {
+        "" | "==" | "<" | ">" | "<=" | ">=" => Some((operator, count.parse().ok()?)),
+        _ => None,
+    }
+}
+
+/// Checks if the field of the event at the dot separated `key` matches the glob pattern. The
+/// body only needs to contain the pattern as a whole word.
+fn event_matches(event: &Value, key: &str, pattern: &str) -> bool {
+    let mut value = Some(event);
+    for part in key.split('.') {
+        value = value.and_then(|v| v.get(part));
+    }
+
+    match value.and_then(|v| v.as_str()) {
+        Some(value) => pattern_matches(pattern, value, true, key == "content.body"),
+        None => false,
+    }
+}
+
+/// Checks if `value` matches the pattern, ignoring case. If `glob` is set, `*` matches any
+/// number of characters and `?` exactly one. If `words` is set, the pattern only has to match
+/// a part of `value` that starts and ends at word boundaries.
+fn pattern_matches(pattern: &str, value: &str, glob: bool, words: bool) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let value = value.to_lowercase().chars().collect::<Vec<_>>();
+
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let starts_word = |i: usize| i == 0 || !is_word(value[i - 1]);
+    let ends_word = |i: usize| i == value.len() || !is_word(value[i]);
+
+    // states[p] is set if the first p characters of the pattern matched up to the current
+    // character of the value
+    let mut states = vec![false; pattern.len() + 1];
+    for i in 0..=value.len() {
+        if i == 0 || words && starts_word(i) {
+            states[0] = true;
+        }
+
+        // A `*` may also match nothing
+        for p in 0..pattern.len() {
+            if states[p] && glob && pattern[p] == '*' {
+                states[p + 1] = true;
+            }
+        }
+
+        if states[pattern.len()]
+            && (if words {
+                ends_word(i)
+            } else {
+                i == value.len()
+            })
+        {
+            return true;
+        }
+
+        if i == value.len() {
+            break;
+        }
+
+        let mut next = vec![false; pattern.len() + 1];
+        for p in (0..pattern.len()).filter(|&p| states[p]) {
+            match pattern[p] {
+                '*' if glob => next[p] = true,
+                '?' if glob => next[p + 1] = true,
+                c if c == value[i] => next[p + 1] = true,
+                _ => {}
+            }
+        }
+        states = next;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::pdu;
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    const ALICE: &str = "@alice:a.test";
+    const BOB: &str = "@bob:a.test";
+
+    fn context(member_count: u64) -> PushContext {
+        PushContext {
+            member_count,
+            sender_power_level: 0,
+            notification_power_levels: BTreeMap::new(),
+        }
+    }
+
+    fn message(body: &str) -> PduEvent {
+        pdu(
+            "$message:a.test",
+            ALICE,
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.text", "body": body }),
+        )
+    }
+
+    /// Returns an event with `value` at the dot separated `key`.
+    fn event_with(key: &str, value: &str) -> Value {
+        key.rsplit('.')
+            .fold(json!(value), |inner, part| json!({ part: inner }))
+    }
+
+    fn condition(condition: Value) -> PushCondition {
+        serde_json::from_value(condition).expect("test condition is valid")
+    }
+
+    fn sound(actions: &[Action]) -> Option<&str> {
+        actions.iter().find_map(|action| match action {
+            Action::SetTweak(Tweak::Sound(sound)) => Some(sound.as_str()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn event_match_patterns() {
+        // (description, key, pattern, value, expected result)
+        let cases = vec![
+            (
+                "exact match",
+                "type",
+                "m.room.message",
+                "m.room.message",
+                true,
+            ),
+            (
+                "star at the end",
+                "type",
+                "m.room.*",
+                "m.room.message",
+                true,
+            ),
+            (
+                "star doesn't match other prefixes",
+                "type",
+                "m.room.*",
+                "m.call.invite",
+                false,
+            ),
+            (
+                "star at the start",
+                "type",
+                "*.invite",
+                "m.call.invite",
+                true,
+            ),
+            ("star matches nothing", "state_key", "*", "", true),
+            (
+                "consecutive stars",
+                "type",
+                "m.**.message",
+                "m.room.message",
+                true,
+            ),
+            (
+                "consecutive stars at the end",
+                "type",
+                "m.room**",
+                "m.room",
+                true,
+            ),
+            (
+                "question mark",
+                "type",
+                "m.room.?essage",
+                "m.room.message",
+                true,
+            ),
+            (
+                "question mark is exactly one character",
+                "type",
+                "m.room.?essage",
+                "m.room.essage",
+                false,
+            ),
+            (
+                "question mark is not two characters",
+                "type",
+                "m.room.?essage",
+                "m.room.mmessage",
+                false,
+            ),
+            (
+                "other fields have to match completely",
+                "type",
+                "m.room",
+                "m.room.message",
+                false,
+            ),
+            (
+                "other fields ignore case",
+                "type",
+                "M.ROOM.MESSAGE",
+                "m.room.message",
+                true,
+            ),
+            (
+                "body contains the word",
+                "content.body",
+                "foo",
+                "a foo b",
+                true,
+            ),
+            (
+                "word before punctuation",
+                "content.body",
+                "foo",
+                "foo!",
+                true,
+            ),
+            (
+                "word is only a prefix",
+                "content.body",
+                "foo",
+                "foobar",
+                false,
+            ),
+            (
+                "word is only a suffix",
+                "content.body",
+                "foo",
+                "barfoo",
+                false,
+            ),
+            (
+                "glob in the body",
+                "content.body",
+                "foo*",
+                "a foobar b",
+                true,
+            ),
+            ("body ignores case", "content.body", "FOO", "say Foo", true),
+            (
+                "pattern starts with punctuation",
+                "content.body",
+                "@room",
+                "hi @room",
+                true,
+            ),
+            (
+                "pattern with several words",
+                "content.body",
+                "foo bar",
+                "a foo bar b",
+                true,
+            ),
+        ];
+
+        for (description, key, pattern, value, expected) in cases {
+            assert_eq!(
+                event_matches(&event_with(key, value), key, pattern),
+                expected,
+                "{}",
+                description
+            );
+        }
+
+        assert!(
+            !event_matches(&json!({ "type": "m.room.message" }), "content.body", "*"),
+            "missing fields never match"
+        );
+    }
+
+    #[test]
+    fn contains_display_name_conditions() {
+        // (description, display name, body, expected result)
+        let cases = vec![
+            ("display name in the body", Some("Alice"), "hi alice!", true),
+            (
+                "display name is only a prefix",
+                Some("Alice"),
+                "hi alices",
+                false,
+            ),
+            (
+                "display name with spaces",
+                Some("Alice B"),
+                "hi alice b",
+                true,
+            ),
+            (
+                "display names are no globs",
+                Some("Al*ce"),
+                "hi alice",
+                false,
+            ),
+            ("empty display name", Some(""), "hi alice", false),
+            ("no display name", None, "hi alice", false),
+        ];
+
+        for (description, display_name, body, expected) in cases {
+            assert_eq!(
+                condition_matches(
+                    &PushCondition::ContainsDisplayName,
+                    display_name,
+                    &context(2),
+                    &serde_json::to_value(message(body)).unwrap()
+                ),
+                expected,
+                "{}",
+                description
+            );
+        }
+    }
+
+    #[test]
+    fn room_member_count_conditions() {
+        // (is, member count, expected result)
+        let cases = vec![
+            ("==2", 2, true),
+            ("==2", 3, false),
+            ("2", 2, true),
+            ("2", 1, false),
+            ("<2", 1, true),
+            ("<2", 2, false),
+            (">2", 3, true),
+            (">2", 2, false),
+            ("<=2", 2, true),
+            ("<=2", 3, false),
+            (">=2", 2, true),
+            (">=2", 1, false),
+        ];
+
+        let event = serde_json::to_value(message("hi")).unwrap();
+        for (is, member_count, expected) in cases {
+            assert_eq!(
+                condition_matches(
+                    &condition(json!({ "kind": "room_member_count", "is": is })),
+                    None,
+                    &context(member_count),
+                    &event
+                ),
+                expected,
+                "{} with {} members",
+                is,
+                member_count
+            );
+        }
+
+        assert_eq!(parse_member_count(">=10"), Some((">=", 10)));
+        assert_eq!(parse_member_count("10"), Some(("", 10)));
+        assert_eq!(parse_member_count("=10"), None);
+        assert_eq!(parse_member_count(">="), None);
+        assert_eq!(parse_member_count(""), None);
+    }
+
+    #[test]
+    fn sender_notification_permission_conditions() {
+        // (description, sender power level, notifications of the power levels, expected result)
+        let cases = vec![
+            ("missing key defaults to 50", 50, json!({}), true),
+            ("missing key with lower power", 49, json!({}), false),
+            ("other keys don't count", 0, json!({ "other": 0 }), false),
+            ("lower requirement", 0, json!({ "room": 0 }), true),
+            ("higher requirement", 50, json!({ "room": 100 }), false),
+        ];
+
+        let event = serde_json::to_value(message("@room")).unwrap();
+        for (description, sender_power_level, notifications, expected) in cases {
+            let context = PushContext {
+                member_count: 2,
+                sender_power_level,
+                notification_power_levels: serde_json::from_value(notifications).unwrap(),
+            };
+            assert_eq!(
+                condition_matches(
+                    &condition(json!({ "kind": "sender_notification_permission", "key": "room" })),
+                    None,
+                    &context,
+                    &event
+                ),
+                expected,
+                "{}",
+                description
+            );
+        }
+    }
+
+    /// One rule of every kind that matches the message, each with a sound named after its kind.
+    fn ruleset(disabled: &[&str]) -> Ruleset {
+        let rule = |kind: &str, rule_id: &str| {
+            json!({
+                "rule_id": rule_id,
+                "default": false,
+                "enabled": !disabled.contains(&kind),
+                "actions": ["notify", { "set_tweak": "sound", "value": kind }],
+            })
+        };
+        let conditional = |kind: &str| {
+            let mut rule = rule(kind, kind);
+            rule["conditions"] =
+                json!([{ "kind": "event_match", "key": "type", "pattern": "m.room.message" }]);
+            rule
+        };
+        let mut content = rule("content", "content");
+        content["pattern"] = json!("hello");
+
+        serde_json::from_value(json!({
+            "override": [conditional("override")],
+            "content": [content],
+            "room": [rule("room", "!room:a.test")],
+            "sender": [rule("sender", ALICE)],
+            "underride": [conditional("underride")],
+        }))
+        .expect("test ruleset is valid")
+    }
+
+    #[test]
+    fn rule_kinds_are_evaluated_in_order() {
+        // (disabled rule kinds, expected sound)
+        let cases = vec![
+            (vec![], Some("override")),
+            (vec!["override"], Some("content")),
+            (vec!["override", "content"], Some("room")),
+            (vec!["override", "content", "room"], Some("sender")),
+            (
+                vec!["override", "content", "room", "sender"],
+                Some("underride"),
+            ),
+            (
+                vec!["override", "content", "room", "sender", "underride"],
+                None,
+            ),
+        ];
+
+        let pdu = message("hello world");
+        for (disabled, expected) in cases {
+            let actions = evaluate(&ruleset(&disabled), None, &context(3), &pdu);
+            assert_eq!(sound(&actions), expected, "disabled: {:?}", disabled);
+            assert_eq!(notifies(&actions), expected.is_some());
+        }
+    }
+
+    #[test]
+    fn default_rules() {
+        let bob = UserId::try_from(BOB).unwrap();
+        let ruleset = default_pushrules(&bob);
+        let notice = pdu(
+            "$notice:a.test",
+            ALICE,
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.notice", "body": "hi bob" }),
+        );
+        let invite = pdu(
+            "$invite:a.test",
+            ALICE,
+            "m.room.member",
+            Some(BOB),
+            json!({ "membership": "invite" }),
+        );
+
+        // (description, display name, member count, event, notifies, highlights)
+        let cases = vec![
+            ("message in a group", None, 3, message("hi"), true, false),
+            (
+                "message in a one to one room",
+                None,
+                2,
+                message("hi"),
+                true,
+                false,
+            ),
+            (
+                "user name in the body",
+                None,
+                3,
+                message("hi bob"),
+                true,
+                true,
+            ),
+            (
+                "display name in the body",
+                Some("Bobby"),
+                3,
+                message("hi bobby"),
+                true,
+                true,
+            ),
+            ("notices are suppressed", None, 3, notice, false, false),
+            ("invite for the user", None, 3, invite, true, false),
+        ];
+
+        for (
+            description,
+            display_name,
+            member_count,
+            pdu,
+            expected_notifies,
+            expected_highlights,
+        ) in cases
+        {
+            // The master rule is disabled, so it doesn't suppress everything
+            let actions = evaluate(&ruleset, display_name, &context(member_count), &pdu);
+            assert_eq!(notifies(&actions), expected_notifies, "{}", description);
+            assert_eq!(highlights(&actions), expected_highlights, "{}", description);
+        }
+
+        let one_to_one = evaluate(&ruleset, None, &context(2), &message("hi"));
+        assert_eq!(sound(&one_to_one), Some("default"));
+    }
+}
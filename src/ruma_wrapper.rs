@@ -1,10 +1,10 @@
 use crate::Error;
-use ruma::identifiers::{DeviceId, UserId};
+use ruma::identifiers::{DeviceId, ServerName, UserId};
 use std::{convert::TryInto, ops::Deref};
 
 #[cfg(feature = "conduit_bin")]
 use {
-    crate::utils,
+    crate::{server_server, utils, Database},
     log::warn,
     rocket::{
         data::{
@@ -17,7 +17,8 @@ use {
         Request, State,
     },
     ruma::api::Endpoint,
-    std::io::Cursor,
+    serde::de::DeserializeOwned,
+    std::{collections::HashMap, convert::TryFrom, io::Cursor},
 };
 
 /// This struct converts rocket requests into ruma structs by converting them into http requests
@@ -26,6 +27,7 @@ pub struct Ruma<T> {
     pub body: T,
     pub sender_id: Option<UserId>,
     pub device_id: Option<Box<DeviceId>>,
+    pub sender_servername: Option<Box<ServerName>>, // Set for authenticated federation requests
     pub json_body: Option<Box<serde_json::value::RawValue>>, // This is None when body is not a valid string
 }
 
@@ -53,29 +55,12 @@ impl<'a, T: Endpoint> FromTransformedData<'a> for Ruma<T> {
                 .await
                 .expect("database was loaded");
 
-            let (user_id, device_id) = if T::METADATA.requires_authentication {
-                // Get token from header or query value
-                let token = match request
-                    .headers()
-                    .get_one("Authorization")
-                    .map(|s| s[7..].to_owned()) // Split off "Bearer "
-                    .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()))
-                {
-                    // TODO: M_MISSING_TOKEN
-                    None => return Failure((Status::Unauthorized, ())),
-                    Some(token) => token,
+            let (body, sender) =
+                match read_request(&db, request, data, T::METADATA.requires_authentication).await {
+                    Ok(request) => request,
+                    Err(status) => return Failure((status, ())),
                 };
 
-                // Check if token is valid
-                match db.users.find_from_token(&token).unwrap() {
-                    // TODO: M_UNKNOWN_TOKEN
-                    None => return Failure((Status::Unauthorized, ())),
-                    Some((user_id, device_id)) => (Some(user_id), Some(device_id.into())),
-                }
-            } else {
-                (None, None)
-            };
-
             let mut http_request = http::Request::builder()
                 .uri(request.uri().to_string())
                 .method(&*request.method().to_string());
@@ -83,19 +68,21 @@ impl<'a, T: Endpoint> FromTransformedData<'a> for Ruma<T> {
                 http_request = http_request.header(header.name.as_str(), &*header.value);
             }
 
-            let limit = db.globals.max_request_size();
-            let mut handle = data.open().take(limit.into());
-            let mut body = Vec::new();
-            handle.read_to_end(&mut body).await.unwrap();
-
-            let http_request = http_request.body(body.clone()).unwrap();
+            let http_request = match http_request.body(body.clone()) {
+                Ok(http_request) => http_request,
+                Err(e) => {
+                    warn!("{:?}", e);
+                    return Failure((Status::BadRequest, ()));
+                }
+            };
             log::info!("{:?}", http_request);
 
             match T::try_from(http_request) {
                 Ok(t) => Success(Ruma {
                     body: t,
-                    sender_id: user_id,
-                    device_id,
+                    sender_id: sender.user_id,
+                    device_id: sender.device_id,
+                    sender_servername: sender.servername,
                     // TODO: Can we avoid parsing it again? (We only need this for append_pdu)
                     json_body: utils::string_from_bytes(&body)
                         .ok()
@@ -110,6 +97,285 @@ impl<'a, T: Endpoint> FromTransformedData<'a> for Ruma<T> {
     }
 }
 
+/// This struct converts rocket requests of client endpoints that don't have ruma types into a
+/// deserialized json body and the authenticated user.
+pub struct JsonRequest<T> {
+    pub body: T,
+    pub sender_id: UserId,
+    pub device_id: Box<DeviceId>,
+}
+
+#[cfg(feature = "conduit_bin")]
+impl<'a, T: DeserializeOwned> FromTransformedData<'a> for JsonRequest<T> {
+    type Error = ();
+    type Owned = Data;
+    type Borrowed = Self::Owned;
+
+    fn transform<'r>(
+        _req: &'r Request<'_>,
+        data: Data,
+    ) -> TransformFuture<'r, Self::Owned, Self::Error> {
+        Box::pin(async move { Transform::Owned(Success(data)) })
+    }
+
+    fn from_data(
+        request: &'a Request<'_>,
+        outcome: Transformed<'a, Self>,
+    ) -> FromDataFuture<'a, Self, Self::Error> {
+        Box::pin(async move {
+            let data = rocket::try_outcome!(outcome.owned());
+            let db = request
+                .guard::<State<'_, crate::Database>>()
+                .await
+                .expect("database was loaded");
+
+            let (body, sender) = match read_request(&db, request, data, true).await {
+                Ok(request) => request,
+                Err(status) => return Failure((status, ())),
+            };
+
+            // These endpoints are only used by clients
+            let (sender_id, device_id) = match (sender.user_id, sender.device_id) {
+                (Some(sender_id), Some(device_id)) => (sender_id, device_id),
+                _ => return Failure((Status::Unauthorized, ())),
+            };
+
+            // An empty body is the same as an empty object
+            let body = if body.is_empty() { &b"{}"[..] } else { &body };
+
+            match serde_json::from_slice(body) {
+                Ok(body) => Success(JsonRequest {
+                    body,
+                    sender_id,
+                    device_id,
+                }),
+                Err(e) => {
+                    warn!("{:?}", e);
+                    Failure((Status::BadRequest, ()))
+                }
+            }
+        })
+    }
+}
+
+impl<T> Deref for JsonRequest<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.body
+    }
+}
+
+/// The authenticated sender of a request.
+#[cfg(feature = "conduit_bin")]
+#[derive(Default)]
+struct Sender {
+    user_id: Option<UserId>,
+    device_id: Option<Box<DeviceId>>,
+    servername: Option<Box<ServerName>>, // Set for federation requests
+}
+
+/// Reads the body of a request, at most `max_request_size` bytes, and authenticates the sender if
+/// the endpoint requires it. The error is the status of the failure response.
+#[cfg(feature = "conduit_bin")]
+async fn read_request(
+    db: &Database,
+    request: &Request<'_>,
+    data: Data,
+    requires_authentication: bool,
+) -> std::result::Result<(Vec<u8>, Sender), Status> {
+    let limit = db.globals.max_request_size();
+    let mut handle = data.open().take(limit.into());
+    let mut body = Vec::new();
+    handle.read_to_end(&mut body).await.map_err(|e| {
+        warn!("Could not read request body: {}", e);
+        Status::BadRequest
+    })?;
+
+    if !requires_authentication {
+        return Ok((body, Sender::default()));
+    }
+
+    let sender = if request.uri().path().starts_with("/_matrix/federation/") {
+        // Federation requests are signed by the origin server
+        match verify_x_matrix(db, request, &body).await {
+            // TODO: M_UNAUTHORIZED
+            None => return Err(Status::Unauthorized),
+            Some(origin) => Sender {
+                servername: Some(origin),
+                ..Sender::default()
+            },
+        }
+    } else {
+        match find_client(db, request).map_err(|e| {
+            warn!("Could not look up access token: {}", e);
+            Status::InternalServerError
+        })? {
+            // TODO: M_MISSING_TOKEN, M_UNKNOWN_TOKEN
+            None => return Err(Status::Unauthorized),
+            // TODO: M_GUEST_ACCESS_FORBIDDEN
+            Some((user_id, _)) if guest_forbidden(db, request, &user_id) => {
+                return Err(Status::Forbidden)
+            }
+            Some((user_id, device_id)) => Sender {
+                user_id: Some(user_id),
+                device_id: Some(device_id),
+                ..Sender::default()
+            },
+        }
+    };
+
+    Ok((body, sender))
+}
+
+/// Finds the user and device of the access token in the Authorization header or the
+/// `access_token` query value.
+#[cfg(feature = "conduit_bin")]
+fn find_client(
+    db: &Database,
+    request: &Request<'_>,
+) -> crate::Result<Option<(UserId, Box<DeviceId>)>> {
+    let token = match request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .map(|s| s.to_owned())
+        .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()))
+    {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    Ok(db
+        .users
+        .find_from_token(&token)?
+        .map(|(user_id, device_id)| (user_id, device_id.into())))
+}
+
+/// The endpoints guests are allowed to use. `*` matches one segment of the path.
+#[cfg(feature = "conduit_bin")]
+const GUEST_ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "/_matrix/client/r0/account/whoami"),
+    ("GET", "/_matrix/client/r0/devices"),
+    ("GET", "/_matrix/client/r0/devices/*"),
+    ("PUT", "/_matrix/client/r0/devices/*"),
+    ("GET", "/_matrix/client/r0/events"),
+    ("POST", "/_matrix/client/r0/join/*"),
+    ("GET", "/_matrix/client/r0/keys/changes"),
+    ("POST", "/_matrix/client/r0/keys/claim"),
+    ("POST", "/_matrix/client/r0/keys/query"),
+    ("POST", "/_matrix/client/r0/keys/upload"),
+    ("POST", "/_matrix/client/r0/logout"),
+    ("GET", "/_matrix/client/r0/profile/*"),
+    ("GET", "/_matrix/client/r0/profile/*/avatar_url"),
+    ("GET", "/_matrix/client/r0/profile/*/displayname"),
+    ("PUT", "/_matrix/client/r0/profile/*/displayname"),
+    ("GET", "/_matrix/client/r0/rooms/*/context/*"),
+    ("GET", "/_matrix/client/r0/rooms/*/event/*"),
+    ("POST", "/_matrix/client/r0/rooms/*/join"),
+    ("POST", "/_matrix/client/r0/rooms/*/leave"),
+    ("GET", "/_matrix/client/r0/rooms/*/members"),
+    ("GET", "/_matrix/client/r0/rooms/*/messages"),
+    ("POST", "/_matrix/client/r0/rooms/*/read_markers"),
+    ("POST", "/_matrix/client/r0/rooms/*/receipt/*/*"),
+    ("PUT", "/_matrix/client/r0/rooms/*/send/m.room.message/*"),
+    ("GET", "/_matrix/client/r0/rooms/*/state"),
+    ("GET", "/_matrix/client/r0/rooms/*/state/*"),
+    ("GET", "/_matrix/client/r0/rooms/*/state/*/*"),
+    ("PUT", "/_matrix/client/r0/rooms/*/typing/*"),
+    ("PUT", "/_matrix/client/r0/sendToDevice/*/*"),
+    ("GET", "/_matrix/client/r0/sync"),
+    ("GET", "/_matrix/client/r0/voip/turnServer"),
+];
+
+/// Checks if the user is a guest and the request is for an endpoint guests can't use.
+#[cfg(feature = "conduit_bin")]
+fn guest_forbidden(db: &Database, request: &Request<'_>, user_id: &UserId) -> bool {
+    if !db.users.is_guest(user_id).unwrap_or(true) {
+        return false;
+    }
+
+    let method = request.method().to_string();
+    let path = request.uri().path().trim_end_matches('/');
+
+    !GUEST_ENDPOINTS.iter().any(|(allowed_method, pattern)| {
+        *allowed_method == method
+            && pattern.split('/').count() == path.split('/').count()
+            && pattern
+                .split('/')
+                .zip(path.split('/'))
+                .all(|(expected, segment)| expected == "*" || expected == segment)
+    })
+}
+
+/// Checks the X-Matrix authorization header of a federation request against the public keys of
+/// the origin server and returns the origin.
+#[cfg(feature = "conduit_bin")]
+async fn verify_x_matrix(
+    db: &Database,
+    request: &Request<'_>,
+    body: &[u8],
+) -> Option<Box<ServerName>> {
+    let params = request
+        .headers()
+        .get_one("Authorization")?
+        .strip_prefix("X-Matrix ")?;
+
+    let (mut origin, mut key, mut sig) = (None, None, None);
+    for param in params.split(',') {
+        let mut param = param.trim().splitn(2, '=');
+        let name = param.next()?;
+        let value = param.next()?.trim_matches('"');
+        match name {
+            "origin" => origin = Some(value),
+            "key" => key = Some(value),
+            "sig" => sig = Some(value),
+            _ => {}
+        }
+    }
+
+    let origin = Box::<ServerName>::try_from(origin?.to_owned()).ok()?;
+    let (key, sig) = (key?, sig?);
+
+    let public_key = server_server::fetch_signing_keys(
+        &db.globals,
+        &origin,
+        &[key.to_owned()],
+        utils::millis_since_unix_epoch(),
+    )
+    .await?
+    .remove(key)?;
+
+    let mut request_map = serde_json::Map::new();
+    if !body.is_empty() {
+        request_map.insert("content".to_owned(), serde_json::from_slice(body).ok()?);
+    }
+    request_map.insert("method".to_owned(), request.method().to_string().into());
+    request_map.insert("uri".to_owned(), request.uri().to_string().into());
+    request_map.insert("origin".to_owned(), origin.as_str().into());
+    request_map.insert(
+        "destination".to_owned(),
+        db.globals.server_name().as_str().into(),
+    );
+    request_map.insert(
+        "signatures".to_owned(),
+        serde_json::json!({ origin.as_str(): { key: sig } }),
+    );
+
+    let mut public_key_set = HashMap::new();
+    public_key_set.insert(key.to_owned(), public_key);
+    let mut public_key_map = ruma::signatures::PublicKeyMap::new();
+    public_key_map.insert(origin.to_string(), public_key_set);
+
+    match ruma::signatures::verify_json(&public_key_map, &request_map.into()) {
+        Ok(_) => Some(origin),
+        Err(e) => {
+            warn!("Invalid X-Matrix signature from {}: {}", origin, e);
+            None
+        }
+    }
+}
+
 impl<T> Deref for Ruma<T> {
     type Target = T;
 
@@ -1,154 +1,622 @@
-use crate::{Database, MatrixResult};
-use http::header::{HeaderValue, AUTHORIZATION};
-use log::error;
-use rocket::{get, response::content::Json, State};
-use ruma::api::Endpoint;
-use ruma::api::client::error::Error;
-use ruma::api::federation::discovery::{
-    get_server_keys::v2 as get_server_keys, get_server_version::v1 as get_server_version,
+use crate::{
+    database::globals::{Destination, Globals, ServerVerifyKey},
+    pdu,
+    stateres::StateMap,
+    utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
+};
+use http::header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, HOST};
+use log::warn;
+use rocket::{response::content::Json, tokio};
+use ruma::{
+    api::{
+        client::{error::ErrorKind, r0::keys::DeviceKeys},
+        federation::{
+            backfill::get_backfill::v1 as get_backfill,
+            discovery::{
+                get_server_keys::v2 as get_server_keys,
+                get_server_version::v1 as get_server_version,
+            },
+            event::{
+                get_event::v1 as get_event, get_room_state::v1 as get_room_state,
+                get_room_state_ids::v1 as get_room_state_ids,
+            },
+            membership::{
+                create_invite::v2 as create_invite, create_join_event::v1 as create_join_event,
+                create_join_event_template::v1 as create_join_event_template,
+            },
+            query::get_room_information::v1 as get_room_information,
+            transactions::send_transaction_message::v1 as send_transaction_message,
+        },
+        Endpoint,
+    },
+    events::{
+        presence::{PresenceEvent, PresenceEventContent},
+        receipt, AnyEphemeralRoomEvent, AnyEvent, EventType,
+    },
+    signatures::PublicKeyMap,
+    DeviceId, EventId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde_json::json;
 use std::{
-    collections::BTreeMap,
-    convert::TryFrom,
-    time::{Duration, SystemTime},
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet, VecDeque},
+    convert::{TryFrom, TryInto},
+    net::{IpAddr, Ipv6Addr},
+    time::{Duration, Instant, SystemTime},
 };
 
-pub async fn request_well_known(db: &crate::Database, destination: &str) -> Option<String> {
-    let body: serde_json::Value = serde_json::from_str(
-        &db.globals
-            .reqwest_client()
-            .get(&format!(
-                "https://{}/.well-known/matrix/server",
-                destination
-            ))
-            .send()
-            .await
-            .ok()?
-            .text()
-            .await
-            .ok()?,
+/// How long the destination of a server is cached if its well-known file doesn't say otherwise.
+const WELL_KNOWN_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+/// The maximum time a well-known file is cached.
+const MAX_WELL_KNOWN_TTL: Duration = Duration::from_secs(60 * 60 * 48);
+/// How long the destination of a server without a well-known file is cached.
+const FAILED_WELL_KNOWN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How many of the events we are missing are sent in one backfill request.
+const MAX_BACKFILL_EXTREMITIES: usize = 20;
+/// The maximum number of events we send in one backfill response.
+const MAX_BACKFILL_LIMIT: u64 = 100;
+
+#[cfg(not(feature = "conduit_bin"))]
+use super::State;
+#[cfg(feature = "conduit_bin")]
+use rocket::{get, put, State};
+
+/// The room versions this server can join and create events in.
+const SUPPORTED_ROOM_VERSIONS: [RoomVersionId; 6] = [
+    RoomVersionId::Version1,
+    RoomVersionId::Version2,
+    RoomVersionId::Version3,
+    RoomVersionId::Version4,
+    RoomVersionId::Version5,
+    RoomVersionId::Version6,
+];
+
+/// Finds out where requests to a server should be sent, following the server discovery
+/// algorithm of the server-server spec. The result is cached.
+pub async fn find_destination(globals: &Globals, server: &ServerName) -> Result<Destination> {
+    if let Some(destination) = globals.cached_destination(server)? {
+        return Ok(destination);
+    }
+
+    let (destination, ttl) = resolve_destination(globals, server.as_str()).await;
+    globals.cache_destination(
+        server,
+        &destination,
+        utils::millis_since_unix_epoch() + ttl.as_millis() as u64,
+    )?;
+
+    Ok(destination)
+}
+
+/// Returns the destination of a server and for how long it may be cached.
+async fn resolve_destination(globals: &Globals, server_name: &str) -> (Destination, Duration) {
+    let (hostname, port) = split_port(server_name);
+
+    // 1. and 2.: IP literals and explicit ports are used as they are
+    if is_ip_literal(hostname) || port.is_some() {
+        return (
+            Destination {
+                address: with_port(hostname, port),
+                host: server_name.to_owned(),
+            },
+            WELL_KNOWN_TTL,
+        );
+    }
+
+    // 3.: The server can delegate to another host with a well-known file
+    if let Some((delegated, ttl)) = request_well_known(globals, hostname).await {
+        let (delegated_hostname, delegated_port) = split_port(&delegated);
+
+        if is_ip_literal(delegated_hostname) || delegated_port.is_some() {
+            return (
+                Destination {
+                    address: with_port(delegated_hostname, delegated_port),
+                    host: delegated.clone(),
+                },
+                ttl,
+            );
+        }
+
+        if let Some((address, srv_ttl)) = query_srv_record(globals, delegated_hostname).await {
+            return (
+                Destination {
+                    address,
+                    host: delegated_hostname.to_owned(),
+                },
+                ttl.min(srv_ttl),
+            );
+        }
+
+        return (
+            Destination {
+                address: with_port(delegated_hostname, None),
+                host: delegated_hostname.to_owned(),
+            },
+            ttl,
+        );
+    }
+
+    // 4.: SRV records of the server name itself
+    if let Some((address, srv_ttl)) = query_srv_record(globals, hostname).await {
+        return (
+            Destination {
+                address,
+                host: hostname.to_owned(),
+            },
+            FAILED_WELL_KNOWN_TTL.min(srv_ttl),
+        );
+    }
+
+    // 5.: The default port
+    (
+        Destination {
+            address: with_port(hostname, None),
+            host: hostname.to_owned(),
+        },
+        FAILED_WELL_KNOWN_TTL,
     )
-    .ok()?;
-    Some(body.get("m.server")?.as_str()?.to_owned())
+}
+
+/// Returns the delegated server name from the well-known file of the host and for how long it
+/// may be cached.
+pub async fn request_well_known(globals: &Globals, hostname: &str) -> Option<(String, Duration)> {
+    let response = globals
+        .reqwest_client()
+        .get(&format!("https://{}/.well-known/matrix/server", hostname))
+        .send()
+        .await
+        .ok()?;
+
+    if !response.status().is_success() {
+        return None;
+    }
+
+    let ttl = response
+        .headers()
+        .get(CACHE_CONTROL)
+        .and_then(|cache_control| cache_control.to_str().ok())
+        .and_then(|cache_control| {
+            cache_control
+                .split(',')
+                .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        })
+        .and_then(|max_age| max_age.parse().ok())
+        .map_or(WELL_KNOWN_TTL, |max_age| {
+            Duration::from_secs(max_age).min(MAX_WELL_KNOWN_TTL)
+        });
+
+    let body = serde_json::from_str::<serde_json::Value>(&response.text().await.ok()?).ok()?;
+    let delegated = body.get("m.server")?.as_str()?;
+
+    // The delegated server name has to be a valid server name itself
+    Box::<ServerName>::try_from(delegated.to_owned()).ok()?;
+
+    Some((delegated.to_owned(), ttl))
+}
+
+/// Looks up the `_matrix._tcp` SRV record of the host and returns the address of the preferred
+/// target and for how long it may be cached.
+async fn query_srv_record(globals: &Globals, hostname: &str) -> Option<(String, Duration)> {
+    let resolver = globals.dns_resolver().await.ok()?;
+
+    let lookup = resolver
+        .srv_lookup(format!("_matrix._tcp.{}.", hostname))
+        .await
+        .ok()?;
+
+    let ttl = lookup
+        .as_lookup()
+        .valid_until()
+        .saturating_duration_since(Instant::now());
+
+    // Lowest priority first, then highest weight
+    let record = lookup
+        .iter()
+        .min_by_key(|record| (record.priority(), Reverse(record.weight())))?;
+
+    Some((
+        format!(
+            "{}:{}",
+            record.target().to_string().trim_end_matches('.'),
+            record.port()
+        ),
+        ttl,
+    ))
+}
+
+/// Splits a server name into the hostname and the port, if there is one.
+fn split_port(server_name: &str) -> (&str, Option<u16>) {
+    // IPv6 literals contain colons themselves, but they are in brackets
+    let hostname_end = if server_name.starts_with('[') {
+        server_name.find(']').map_or(server_name.len(), |i| i + 1)
+    } else {
+        server_name.rfind(':').unwrap_or_else(|| server_name.len())
+    };
+
+    let (hostname, port) = server_name.split_at(hostname_end);
+    (
+        hostname,
+        port.strip_prefix(':').and_then(|port| port.parse().ok()),
+    )
+}
+
+fn is_ip_literal(hostname: &str) -> bool {
+    hostname.parse::<IpAddr>().is_ok()
+        || hostname
+            .strip_prefix('[')
+            .and_then(|hostname| hostname.strip_suffix(']'))
+            .map_or(false, |hostname| hostname.parse::<Ipv6Addr>().is_ok())
+}
+
+fn with_port(hostname: &str, port: Option<u16>) -> String {
+    format!("{}:{}", hostname, port.unwrap_or(8448))
 }
 
 pub async fn send_request<T: Endpoint>(
-    db: &crate::Database,
-    destination: String,
+    globals: &Globals,
+    destination: &ServerName,
     request: T,
-) -> Option<T::Response> {
-    let mut http_request: http::Request<_> = request.try_into().unwrap();
+) -> Result<T::Response> {
+    let http_request: http::Request<Vec<u8>> = request
+        .try_into()
+        .map_err(|_| Error::BadServerResponse("Invalid outgoing request."))?;
 
-    let actual_destination = "https://".to_owned()
-        + &request_well_known(db, &destination)
-            .await
-            .unwrap_or(destination.clone() + ":8448");
-    *http_request.uri_mut() = (actual_destination + T::METADATA.path).parse().unwrap();
+    let http_response = send_signed_request(globals, destination, http_request).await?;
+
+    <T::Response>::try_from(http_response).map_err(|e| {
+        warn!("Invalid response from {}: {:?}", destination, e);
+        Error::BadServerResponse("Server returned invalid response.")
+    })
+}
+
+/// Sends a signed request with an optional json body to the destination server and returns the
+/// json response.
+pub async fn send_json_request(
+    globals: &Globals,
+    destination: &ServerName,
+    method: http::Method,
+    path: &str,
+    body: Option<&serde_json::Value>,
+) -> Result<serde_json::Value> {
+    let http_request = http::Request::builder()
+        .method(method)
+        .uri(path)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.map_or_else(Vec::new, |body| body.to_string().into_bytes()))
+        .map_err(|_| Error::BadServerResponse("Invalid outgoing request."))?;
+
+    let response = send_signed_request(globals, destination, http_request).await?;
+
+    if !response.status().is_success() {
+        warn!(
+            "{} returned {} for {}: {}",
+            destination,
+            response.status(),
+            path,
+            String::from_utf8_lossy(response.body())
+        );
+        return Err(Error::BadServerResponse("Server returned an error."));
+    }
+
+    serde_json::from_slice(response.body())
+        .map_err(|_| Error::BadServerResponse("Server returned invalid json."))
+}
+
+/// Signs the request with the X-Matrix scheme and sends it to the destination server.
+pub async fn send_signed_request(
+    globals: &Globals,
+    destination: &ServerName,
+    mut http_request: http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>> {
+    let path_and_query = http_request
+        .uri()
+        .path_and_query()
+        .map_or_else(|| "/".to_owned(), |p| p.to_string());
+
+    let actual_destination = find_destination(globals, destination).await?;
+
+    // We connect to the address, but the certificate has to be valid for the hostname
+    let (hostname, _) = split_port(&actual_destination.host);
+    let (address_hostname, port) = split_port(&actual_destination.address);
+    let authority = if address_hostname == hostname {
+        hostname.to_owned()
+    } else {
+        format!("{}@{}", address_hostname, hostname)
+    };
+    *http_request.uri_mut() = format!(
+        "https://{}:{}{}",
+        authority,
+        port.unwrap_or(8448),
+        path_and_query
+    )
+    .parse()
+    .map_err(|_| Error::BadServerResponse("Invalid destination."))?;
+    http_request.headers_mut().insert(
+        HOST,
+        HeaderValue::from_str(&actual_destination.host)
+            .map_err(|_| Error::BadServerResponse("Invalid destination."))?,
+    );
 
     let mut request_map = serde_json::Map::new();
 
     if !http_request.body().is_empty() {
         request_map.insert(
             "content".to_owned(),
-            serde_json::to_value(http_request.body()).unwrap(),
+            serde_json::from_slice(http_request.body())
+                .map_err(|_| Error::BadServerResponse("Outgoing request body is not json."))?,
         );
     };
 
-    request_map.insert("method".to_owned(), T::METADATA.method.to_string().into());
-    request_map.insert("uri".to_owned(), T::METADATA.path.into());
-    request_map.insert("origin".to_owned(), db.globals.server_name().into());
-    request_map.insert("destination".to_owned(), destination.into());
+    request_map.insert(
+        "method".to_owned(),
+        http_request.method().to_string().into(),
+    );
+    request_map.insert("uri".to_owned(), path_and_query.into());
+    request_map.insert("origin".to_owned(), globals.server_name().as_str().into());
+    request_map.insert("destination".to_owned(), destination.as_str().into());
 
     let mut request_json = request_map.into();
     ruma::signatures::sign_json(
-        db.globals.server_name(),
-        db.globals.keypair(),
+        globals.server_name().as_str(),
+        globals.keypair(),
         &mut request_json,
     )
-    .unwrap();
+    .expect("our request json is what ruma expects");
 
-    let signatures = request_json["signatures"]
-        .as_object()
-        .unwrap()
-        .values()
-        .next()
-        .unwrap()
+    let signatures = request_json["signatures"][globals.server_name().as_str()]
         .as_object()
-        .unwrap()
-        .iter()
-        .map(|(k, v)| (k, v.as_str().unwrap()));
+        .expect("sign_json adds our signature");
 
-    for s in signatures {
+    for (key_id, signature) in signatures {
         http_request.headers_mut().insert(
             AUTHORIZATION,
             HeaderValue::from_str(&format!(
                 "X-Matrix origin={},key=\"{}\",sig=\"{}\"",
-                db.globals.server_name(),
-                s.0,
-                s.1
+                globals.server_name(),
+                key_id,
+                signature.as_str().expect("signatures are strings"),
             ))
-            .unwrap(),
+            .expect("our server name, key ids and signatures are valid header values"),
         );
     }
 
-    let reqwest_response = db
-        .globals
-        .reqwest_client()
-        .execute(http_request.into())
-        .await;
+    // Servers that don't answer must not block us forever
+    tokio::time::timeout(Duration::from_secs(60), async {
+        let response = globals
+            .federation_client()
+            .request(http_request.map(hyper::Body::from))
+            .await?;
 
-    // Because reqwest::Response -> http::Response is complicated:
-    match reqwest_response {
-        Ok(mut reqwest_response) => {
-            let status = reqwest_response.status();
-            let mut http_response = http::Response::builder().status(status);
-            let headers = http_response.headers_mut().unwrap();
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
 
-            for (k, v) in reqwest_response.headers_mut().drain() {
-                if let Some(key) = k {
-                    headers.insert(key, v);
-                }
-            }
+        Ok::<_, Error>(http::Response::from_parts(parts, body.to_vec()))
+    })
+    .await
+    .map_err(|_| Error::BadServerResponse("Server did not answer in time."))?
+}
 
-            let body = reqwest_response
-                .bytes()
-                .await
-                .unwrap()
-                .into_iter()
-                .collect();
-            Some(
-                <T::Response>::try_from(http_response.body(body).unwrap())
-                    .ok()
-                    .unwrap(),
-            )
+/// Returns the signing keys of a server that can verify signatures made at `ts`, e.g. the
+/// `origin_server_ts` of an event. Old keys are included if they expired after `ts`.
+///
+/// Keys are cached. If one of `key_ids` is not cached, the keys are fetched from the server itself
+/// and, if it doesn't have them anymore, from the trusted servers.
+pub async fn fetch_signing_keys(
+    globals: &Globals,
+    origin: &ServerName,
+    key_ids: &[String],
+    ts: u64,
+) -> Option<BTreeMap<String, String>> {
+    let has_all_keys = |keys: &BTreeMap<String, String>| {
+        !keys.is_empty() && key_ids.iter().all(|key_id| keys.contains_key(key_id))
+    };
+
+    let mut keys = cached_signing_keys(globals, origin, ts);
+    if has_all_keys(&keys) {
+        return Some(keys);
+    }
+
+    if let Some(server_keys) = request_server_keys(globals, origin).await {
+        save_signing_keys(globals, origin, &server_keys);
+        keys = cached_signing_keys(globals, origin, ts);
+    }
+
+    for notary in globals.trusted_servers() {
+        if has_all_keys(&keys) {
+            break;
         }
-        Err(e) => {
-            error!("{}", e);
-            None
+
+        if let Some(server_keys) =
+            request_keys_from_notary(globals, notary, origin, key_ids, ts).await
+        {
+            save_signing_keys(globals, origin, &server_keys);
+            keys = cached_signing_keys(globals, origin, ts);
         }
     }
+
+    Some(keys).filter(|keys| !keys.is_empty())
+}
+
+/// Returns the cached keys of the server that can verify signatures made at `ts`.
+fn cached_signing_keys(
+    globals: &Globals,
+    server: &ServerName,
+    ts: u64,
+) -> BTreeMap<String, String> {
+    globals
+        .verify_keys(server)
+        .unwrap_or_else(|e| {
+            warn!("Could not read signing keys of {}: {}", server, e);
+            BTreeMap::new()
+        })
+        .into_iter()
+        // Old keys are valid until they expired
+        .filter(|(_, verify_key)| verify_key.valid_until_ts > ts)
+        .map(|(key_id, verify_key)| (key_id, verify_key.key))
+        .collect()
+}
+
+fn save_signing_keys(
+    globals: &Globals,
+    server: &ServerName,
+    keys: &BTreeMap<String, ServerVerifyKey>,
+) {
+    if let Err(e) = globals.add_verify_keys(server, keys) {
+        warn!("Could not save signing keys of {}: {}", server, e);
+    }
+}
+
+/// Requests the keys of a server directly from that server.
+async fn request_server_keys(
+    globals: &Globals,
+    origin: &ServerName,
+) -> Option<BTreeMap<String, ServerVerifyKey>> {
+    let response = send_json_request(
+        globals,
+        origin,
+        http::Method::GET,
+        "/_matrix/key/v2/server",
+        None,
+    )
+    .await
+    .map_err(|e| warn!("Could not fetch signing keys of {}: {}", origin, e))
+    .ok()?;
+
+    parse_server_keys(origin, &response)
 }
 
-#[cfg_attr(feature = "conduit_bin",get("/.well-known/matrix/server"))]
-pub fn well_known_server() -> Json<String> {
-    rocket::response::content::Json(
-        json!({ "m.server": "matrixtesting.koesters.xyz:14004"}).to_string(),
+/// Requests the keys of a server from a notary server, which signs the keys too.
+async fn request_keys_from_notary(
+    globals: &Globals,
+    notary: &ServerName,
+    origin: &ServerName,
+    key_ids: &[String],
+    ts: u64,
+) -> Option<BTreeMap<String, ServerVerifyKey>> {
+    let now = utils::millis_since_unix_epoch();
+    let mut notary_keys = cached_signing_keys(globals, notary, now);
+    if notary_keys.is_empty() {
+        let keys = request_server_keys(globals, notary).await?;
+        save_signing_keys(globals, notary, &keys);
+        notary_keys = cached_signing_keys(globals, notary, now);
+    }
+
+    // The notary should return keys that are still valid at `ts`
+    let query = key_ids
+        .iter()
+        .map(|key_id| {
+            (
+                key_id.clone(),
+                json!({ "minimum_valid_until_ts": ts.saturating_add(1) }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    let response = send_json_request(
+        globals,
+        notary,
+        http::Method::POST,
+        "/_matrix/key/v2/query",
+        Some(&json!({ "server_keys": { origin.as_str(): query } })),
     )
+    .await
+    .map_err(|e| warn!("Could not query {} for keys of {}: {}", notary, origin, e))
+    .ok()?;
+
+    let mut notary_key_map = PublicKeyMap::new();
+    notary_key_map.insert(notary.to_string(), notary_keys.into_iter().collect());
+
+    let mut keys = BTreeMap::new();
+    for server_keys in response["server_keys"].as_array()? {
+        if ruma::signatures::verify_json(&notary_key_map, server_keys).is_err() {
+            warn!("Keys of {} from {} have no valid signature", origin, notary);
+            continue;
+        }
+
+        if let Some(server_keys) = parse_server_keys(origin, server_keys) {
+            keys.extend(server_keys);
+        }
+    }
+
+    Some(keys).filter(|keys| !keys.is_empty())
+}
+
+/// Reads the keys from a server keys response and checks that it is signed by the server itself.
+fn parse_server_keys(
+    origin: &ServerName,
+    response: &serde_json::Value,
+) -> Option<BTreeMap<String, ServerVerifyKey>> {
+    if response["server_name"] != origin.as_str() {
+        return None;
+    }
+
+    let valid_until_ts = response["valid_until_ts"].as_u64()?;
+
+    let mut keys = BTreeMap::new();
+    for (key_id, verify_key) in response["verify_keys"].as_object()? {
+        keys.insert(
+            key_id.clone(),
+            ServerVerifyKey {
+                key: verify_key["key"].as_str()?.to_owned(),
+                valid_until_ts,
+                expired_ts: None,
+            },
+        );
+    }
+
+    // The response has to be signed by the current keys
+    let mut public_key_map = PublicKeyMap::new();
+    public_key_map.insert(
+        origin.to_string(),
+        keys.iter()
+            .map(|(key_id, verify_key)| (key_id.clone(), verify_key.key.clone()))
+            .collect(),
+    );
+    if let Err(e) = ruma::signatures::verify_json(&public_key_map, response) {
+        warn!("Keys of {} have no valid signature: {}", origin, e);
+        return None;
+    }
+
+    for (key_id, old_verify_key) in response["old_verify_keys"]
+        .as_object()
+        .into_iter()
+        .flatten()
+    {
+        let expired_ts = old_verify_key["expired_ts"].as_u64()?;
+        keys.insert(
+            key_id.clone(),
+            ServerVerifyKey {
+                key: old_verify_key["key"].as_str()?.to_owned(),
+                valid_until_ts: expired_ts,
+                expired_ts: Some(expired_ts),
+            },
+        );
+    }
+
+    Some(keys)
+}
+
+#[cfg_attr(feature = "conduit_bin", get("/.well-known/matrix/server"))]
+pub fn well_known_server(db: State<'_, Database>) -> Option<Json<String>> {
+    db.globals
+        .well_known_server()
+        .map(|server| Json(json!({ "m.server": server }).to_string()))
 }
 
-#[cfg_attr(feature = "conduit_bin",get("/_matrix/federation/v1/version"))]
-pub fn get_server_version() -> MatrixResult<get_server_version::Response, Error> {
-    MatrixResult(Ok(get_server_version::Response {
+#[cfg_attr(feature = "conduit_bin", get("/_matrix/federation/v1/version"))]
+pub fn get_server_version() -> ConduitResult<get_server_version::Response> {
+    Ok(get_server_version::Response {
         server: Some(get_server_version::Server {
             name: Some("Conduit".to_owned()),
             version: Some(env!("CARGO_PKG_VERSION").to_owned()),
         }),
-    }))
+    }
+    .into())
 }
 
-#[cfg_attr(feature = "conduit_bin",get("/_matrix/key/v2/server"))]
-pub fn get_server_keys(db: State<'_, Database>) -> Json<String> {
+#[cfg_attr(feature = "conduit_bin", get("/_matrix/key/v2/server"))]
+pub fn get_server_keys(db: State<'_, Database>) -> Result<Json<String>> {
     let mut verify_keys = BTreeMap::new();
     verify_keys.insert(
         format!("ed25519:{}", db.globals.keypair().version()),
@@ -156,28 +624,1814 @@ pub fn get_server_keys(db: State<'_, Database>) -> Json<String> {
             key: base64::encode_config(db.globals.keypair().public_key(), base64::STANDARD_NO_PAD),
         },
     );
+
+    let old_verify_keys = db
+        .globals
+        .old_verify_keys()?
+        .into_iter()
+        .map(|(key_id, (key, expired_ts))| {
+            (
+                key_id,
+                get_server_keys::OldVerifyKey {
+                    expired_ts: SystemTime::UNIX_EPOCH + Duration::from_millis(expired_ts),
+                    key,
+                },
+            )
+        })
+        .collect();
+
     let mut response = serde_json::from_slice(
         http::Response::try_from(get_server_keys::Response {
             server_name: db.globals.server_name().to_owned(),
             verify_keys,
-            old_verify_keys: BTreeMap::new(),
+            old_verify_keys,
             signatures: BTreeMap::new(),
-            valid_until_ts: SystemTime::now() + Duration::from_secs(60 * 2),
+            valid_until_ts: SystemTime::now()
+                + Duration::from_secs(db.globals.key_validity_period()),
         })
-        .unwrap()
+        .expect("server keys response is valid")
         .body(),
     )
-    .unwrap();
+    .expect("server keys response body is valid json");
+
     ruma::signatures::sign_json(
-        db.globals.server_name(),
+        db.globals.server_name().as_str(),
         db.globals.keypair(),
         &mut response,
     )
-    .unwrap();
-    Json(response.to_string())
+    .expect("our server keys response is a json object");
+
+    Ok(Json(response.to_string()))
 }
 
-#[cfg_attr(feature = "conduit_bin",get("/_matrix/key/v2/server/<_key_id>"))]
-pub fn get_server_keys_deprecated(db: State<'_, Database>, _key_id: String) -> Json<String> {
+#[cfg_attr(feature = "conduit_bin", get("/_matrix/key/v2/server/<_key_id>"))]
+pub fn get_server_keys_deprecated(
+    db: State<'_, Database>,
+    _key_id: String,
+) -> Result<Json<String>> {
     get_server_keys(db)
 }
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/federation/v1/send/<_>", data = "<body>")
+)]
+pub async fn send_transaction_message_route(
+    db: State<'_, Database>,
+    body: Ruma<send_transaction_message::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    let transaction = body
+        .json_body
+        .as_ref()
+        .and_then(|json_body| serde_json::from_str::<serde_json::Value>(json_body.get()).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::BadJson,
+            "Transaction is not valid json.",
+        ))?;
+
+    Ok(Json(
+        handle_transaction(&db, origin, &transaction)
+            .await
+            .to_string(),
+    ))
+}
+
+/// Handles the pdus and edus of a transaction from `origin`. Returns the response body, which
+/// contains the errors of the pdus that were not accepted.
+async fn handle_transaction(
+    db: &Database,
+    origin: &ServerName,
+    transaction: &serde_json::Value,
+) -> serde_json::Value {
+    let mut pdu_results = serde_json::Map::new();
+    for pdu_json in transaction
+        .get("pdus")
+        .and_then(|pdus| pdus.as_array())
+        .into_iter()
+        .flatten()
+    {
+        match handle_incoming_pdu(db, origin, pdu_json.clone()).await {
+            Ok((event_id, Ok(()))) => {
+                pdu_results.insert(event_id.to_string(), json!({}));
+            }
+            Ok((event_id, Err(e))) => {
+                pdu_results.insert(event_id.to_string(), json!({ "error": e }));
+            }
+            Err(e) => warn!("Could not handle PDU from {}: {}", origin, e),
+        }
+    }
+
+    for edu in transaction
+        .get("edus")
+        .and_then(|edus| edus.as_array())
+        .into_iter()
+        .flatten()
+    {
+        if let Err(e) = handle_incoming_edu(db, origin, edu) {
+            warn!("Could not handle EDU from {}: {}", origin, e);
+        }
+    }
+
+    json!({ "pdus": pdu_results })
+}
+
+/// Checks the hashes and signatures of a pdu from another server and adds it to its room.
+///
+/// Returns the event id and whether the event was accepted.
+async fn handle_incoming_pdu(
+    db: &Database,
+    origin: &ServerName,
+    pdu_json: serde_json::Value,
+) -> Result<(EventId, std::result::Result<(), String>)> {
+    let room_id = pdu_json
+        .get("room_id")
+        .and_then(|room_id| room_id.as_str())
+        .and_then(|room_id| RoomId::try_from(room_id).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "PDU has no valid room id.",
+        ))?;
+
+    let room_version = db.rooms.room_version(&room_id)?;
+
+    let (event_id, verified) = verify_pdu(&db.globals, &room_version, pdu_json).await?;
+
+    let (pdu, pdu_json) = match verified {
+        Ok(verified) => verified,
+        Err(e) => return Ok((event_id, Err(e))),
+    };
+
+    fetch_state_before(db, origin, &room_version, &pdu).await?;
+
+    let result = db
+        .rooms
+        .append_incoming_pdu(&pdu, &pdu_json, &db.globals, &db.account_data)?
+        .map_err(|e| e.to_string());
+
+    Ok((event_id, result))
+}
+
+/// Makes sure we know the state after each prev event of the pdu, so we can check if the pdu is
+/// allowed by the state before it. Missing state is requested from `origin`, which sent the pdu.
+async fn fetch_state_before(
+    db: &Database,
+    origin: &ServerName,
+    room_version: &RoomVersionId,
+    pdu: &PduEvent,
+) -> Result<()> {
+    for prev_event in &pdu.prev_events {
+        if db.rooms.state_is_known(prev_event)? {
+            continue;
+        }
+
+        fetch_remote_state(db, Some(origin), room_version, &pdu.room_id, prev_event).await?;
+    }
+
+    Ok(())
+}
+
+/// Requests the state before an event from `origin` (or the other servers in the room if it's
+/// `None`) and saves the state after the event. The event itself is fetched too if we don't
+/// know it.
+async fn fetch_remote_state(
+    db: &Database,
+    origin: Option<&ServerName>,
+    room_version: &RoomVersionId,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<()> {
+    let request = |path: String| async move {
+        match origin {
+            Some(origin) => {
+                send_json_request(&db.globals, origin, http::Method::GET, &path, None).await
+            }
+            None => request_from_room_servers(db, room_id, http::Method::GET, &path, None).await,
+        }
+    };
+
+    // This is the state before the event
+    let response = request(format!(
+        "/_matrix/federation/v1/state/{}?event_id={}",
+        utils::percent_encode(room_id.as_str()),
+        utils::percent_encode(event_id.as_str())
+    ))
+    .await?;
+
+    let mut events = verify_pdus(&db.globals, room_version, &response["auth_chain"]).await;
+    let state = verify_pdus(&db.globals, room_version, &response["pdus"]).await;
+    let state_ids = state
+        .iter()
+        .map(|(pdu, _)| pdu.event_id.clone())
+        .collect::<HashSet<_>>();
+    events.extend(state);
+
+    if db.rooms.get_pdu_json(event_id)?.is_none() {
+        let event = request(format!(
+            "/_matrix/federation/v1/event/{}",
+            utils::percent_encode(event_id.as_str())
+        ))
+        .await?;
+
+        events.extend(
+            verify_pdus(&db.globals, room_version, &event["pdus"])
+                .await
+                .into_iter()
+                .filter(|(pdu, _)| &pdu.event_id == event_id),
+        );
+    }
+
+    let mut state_before = StateMap::new();
+    for event in db.rooms.add_outliers(room_version, room_id, events)? {
+        if let (true, Some(state_key)) = (state_ids.contains(&event.event_id), event.state_key) {
+            state_before.insert((event.kind, state_key), event.event_id);
+        }
+    }
+
+    let pdu = db
+        .rooms
+        .get_pdu(event_id)?
+        .ok_or(Error::BadServerResponse("Could not fetch the event."))?;
+
+    db.rooms.save_remote_state(&pdu, state_before, &db.globals)
+}
+
+/// Checks the hashes and signatures of a pdu from another server. Pdus with a content hash that
+/// doesn't match are redacted.
+///
+/// Returns the event id and either the pdu (with its event id added to the json) or why it is
+/// invalid.
+pub async fn verify_pdu(
+    globals: &Globals,
+    room_version: &RoomVersionId,
+    mut pdu_json: serde_json::Value,
+) -> Result<(
+    EventId,
+    std::result::Result<(PduEvent, serde_json::Value), String>,
+)> {
+    let sender = pdu_json
+        .get("sender")
+        .and_then(|sender| sender.as_str())
+        .and_then(|sender| UserId::try_from(sender).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "PDU has no valid sender.",
+        ))?;
+
+    let event_id = if pdu::uses_reference_hash_ids(room_version) {
+        pdu::reference_hash_event_id(room_version, &pdu_json)?
+    } else {
+        pdu_json
+            .get("event_id")
+            .and_then(|event_id| event_id.as_str())
+            .and_then(|event_id| EventId::try_from(event_id).ok())
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "PDU has no valid event id.",
+            ))?
+    };
+
+    let origin_server_ts = pdu_json
+        .get("origin_server_ts")
+        .and_then(|ts| ts.as_u64())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "PDU has no valid origin_server_ts.",
+        ))?;
+    let key_ids = pdu_json
+        .get("signatures")
+        .and_then(|signatures| signatures.get(sender.server_name().as_str()))
+        .and_then(|signatures| signatures.as_object())
+        .map_or_else(Vec::new, |signatures| signatures.keys().cloned().collect());
+
+    // Old keys can still verify events that were sent before the keys expired
+    let public_keys = fetch_signing_keys(globals, sender.server_name(), &key_ids, origin_server_ts)
+        .await
+        .ok_or(Error::BadServerResponse(
+            "Could not fetch the keys of the sender's server.",
+        ))?;
+    let mut public_key_map = PublicKeyMap::new();
+    public_key_map.insert(
+        sender.server_name().to_string(),
+        public_keys.into_iter().collect(),
+    );
+
+    // This checks both the signatures and the content hash
+    let verified = match ruma::signatures::verify_event(&public_key_map, &pdu_json) {
+        Ok(verified) => verified,
+        Err(e) => return Ok((event_id, Err(format!("Invalid signature: {}", e)))),
+    };
+
+    // We keep the event id in our copy of the event so we can find it again
+    pdu_json["event_id"] = json!(event_id);
+
+    let mut pdu = serde_json::from_value::<PduEvent>(pdu_json.clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "PDU is invalid."))?;
+
+    // Events with a content hash that doesn't match are redacted
+    if let ruma::signatures::Verified::Signatures = verified {
+        warn!("Content hash of {} does not match, redacting it", event_id);
+        pdu.redact()?;
+        pdu_json = serde_json::to_value(&pdu).expect("PduEvent can be serialized");
+    }
+
+    Ok((event_id, Ok((pdu, pdu_json))))
+}
+
+/// Verifies a list of pdus from another server and leaves out the invalid ones.
+async fn verify_pdus(
+    globals: &Globals,
+    room_version: &RoomVersionId,
+    pdus: &serde_json::Value,
+) -> Vec<(PduEvent, serde_json::Value)> {
+    let mut verified = Vec::new();
+
+    for pdu_json in pdus.as_array().into_iter().flatten() {
+        match verify_pdu(globals, room_version, pdu_json.clone()).await {
+            Ok((_, Ok(pdu))) => verified.push(pdu),
+            Ok((event_id, Err(e))) => warn!("Ignoring invalid event {}: {}", event_id, e),
+            Err(e) => warn!("Ignoring invalid event: {}", e),
+        }
+    }
+
+    verified
+}
+
+/// Applies an EDU from another server. Servers can only send EDUs for their own users.
+fn handle_incoming_edu(db: &Database, origin: &ServerName, edu: &serde_json::Value) -> Result<()> {
+    let content = edu.get("content").cloned().unwrap_or_default();
+    let invalid_edu = || Error::BadRequest(ErrorKind::BadJson, "EDU is invalid.");
+
+    match edu.get("edu_type").and_then(|edu_type| edu_type.as_str()) {
+        Some("m.typing") => {
+            let room_id = serde_json::from_value::<RoomId>(content["room_id"].clone())
+                .map_err(|_| invalid_edu())?;
+            let user_id = serde_json::from_value::<UserId>(content["user_id"].clone())
+                .map_err(|_| invalid_edu())?;
+
+            if user_id.server_name() != origin || !db.rooms.is_joined(&user_id, &room_id)? {
+                return Ok(());
+            }
+
+            if content["typing"].as_bool().unwrap_or(false) {
+                db.rooms.edus.roomactive_add(
+                    &user_id,
+                    &room_id,
+                    utils::millis_since_unix_epoch() + 30000,
+                    &db.globals,
+                )?;
+            } else {
+                db.rooms
+                    .edus
+                    .roomactive_remove(&user_id, &room_id, &db.globals)?;
+            }
+        }
+        Some("m.receipt") => {
+            for (room_id, receipts) in content.as_object().ok_or_else(invalid_edu)? {
+                let room_id = RoomId::try_from(&**room_id).map_err(|_| invalid_edu())?;
+
+                for (user_id, receipt) in receipts
+                    .get("m.read")
+                    .and_then(|read| read.as_object())
+                    .into_iter()
+                    .flatten()
+                {
+                    let user_id = UserId::try_from(&**user_id).map_err(|_| invalid_edu())?;
+
+                    if user_id.server_name() != origin || !db.rooms.is_joined(&user_id, &room_id)? {
+                        continue;
+                    }
+
+                    let event_ids =
+                        serde_json::from_value::<Vec<EventId>>(receipt["event_ids"].clone())
+                            .map_err(|_| invalid_edu())?;
+                    let ts = receipt["data"]["ts"]
+                        .as_u64()
+                        .map_or_else(SystemTime::now, |ts| {
+                            SystemTime::UNIX_EPOCH + Duration::from_millis(ts)
+                        });
+
+                    let mut user_receipts = BTreeMap::new();
+                    user_receipts.insert(user_id.clone(), receipt::Receipt { ts: Some(ts) });
+
+                    let mut receipt_content = BTreeMap::new();
+                    for event_id in event_ids {
+                        receipt_content.insert(
+                            event_id,
+                            receipt::Receipts {
+                                read: Some(user_receipts.clone()),
+                            },
+                        );
+                    }
+
+                    db.rooms.edus.roomlatest_update(
+                        &user_id,
+                        &room_id,
+                        AnyEvent::Ephemeral(AnyEphemeralRoomEvent::Receipt(
+                            receipt::ReceiptEvent {
+                                content: receipt::ReceiptEventContent(receipt_content),
+                                room_id: room_id.clone(),
+                            },
+                        )),
+                        &db.globals,
+                    )?;
+                }
+            }
+        }
+        Some("m.presence") => {
+            for update in content
+                .get("push")
+                .and_then(|push| push.as_array())
+                .into_iter()
+                .flatten()
+            {
+                let user_id = serde_json::from_value::<UserId>(update["user_id"].clone())
+                    .map_err(|_| invalid_edu())?;
+
+                if user_id.server_name() != origin {
+                    continue;
+                }
+
+                let presence = serde_json::from_value::<PresenceEventContent>(update.clone())
+                    .map_err(|_| invalid_edu())?;
+
+                for room_id in db.rooms.rooms_joined(&user_id) {
+                    db.rooms.edus.update_presence(
+                        &user_id,
+                        &room_id?,
+                        PresenceEvent {
+                            content: presence.clone(),
+                            sender: user_id.clone(),
+                        },
+                        &db.globals,
+                    )?;
+                }
+            }
+        }
+        Some("m.device_list_update") => {
+            let user_id = serde_json::from_value::<UserId>(content["user_id"].clone())
+                .map_err(|_| invalid_edu())?;
+
+            if user_id.server_name() != origin {
+                return Ok(());
+            }
+
+            let device_id = content["device_id"].as_str().map(|device_id| {
+                let device_id: Box<DeviceId> = device_id.to_owned().into();
+                device_id
+            });
+            let keys = serde_json::from_value::<Option<DeviceKeys>>(content["keys"].clone())
+                .ok()
+                .flatten();
+
+            match (device_id, keys) {
+                (Some(device_id), Some(keys)) if !content["deleted"].as_bool().unwrap_or(false) => {
+                    db.users.add_device_keys(
+                        &user_id,
+                        &device_id,
+                        &keys,
+                        &db.rooms,
+                        &db.globals,
+                    )?;
+                }
+                _ => db
+                    .users
+                    .mark_device_key_update(&user_id, &db.rooms, &db.globals)?,
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Asks the server of a room alias for the room id and the servers that are in the room.
+pub async fn query_remote_alias(
+    globals: &Globals,
+    room_alias: &RoomAliasId,
+) -> Result<(RoomId, Vec<Box<ServerName>>)> {
+    let response = send_json_request(
+        globals,
+        room_alias.server_name(),
+        http::Method::GET,
+        &format!(
+            "/_matrix/federation/v1/query/directory?room_alias={}",
+            utils::percent_encode(room_alias.as_str())
+        ),
+        None,
+    )
+    .await?;
+
+    let room_id = serde_json::from_value(response["room_id"].clone())
+        .map_err(|_| Error::BadServerResponse("Invalid room id in alias response."))?;
+    let servers = serde_json::from_value(response["servers"].clone()).unwrap_or_default();
+
+    Ok((room_id, servers))
+}
+
+/// Joins a room on another server using the first of `servers` that can help with the join.
+pub async fn join_remote_room(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+    servers: &[Box<ServerName>],
+) -> Result<()> {
+    let mut last_error = Error::BadRequest(
+        ErrorKind::NotFound,
+        "No server could help with joining the room.",
+    );
+
+    for server in servers {
+        if &**server == db.globals.server_name() {
+            continue;
+        }
+
+        match join_room_via(db, user_id, room_id, server).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!("Could not join {} via {}: {}", room_id, server, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Joins a room with make_join and send_join on a server that is in the room.
+async fn join_room_via(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+    server: &ServerName,
+) -> Result<()> {
+    let make_join = send_json_request(
+        &db.globals,
+        server,
+        http::Method::GET,
+        &format!(
+            "/_matrix/federation/v1/make_join/{}/{}?{}",
+            utils::percent_encode(room_id.as_str()),
+            utils::percent_encode(user_id.as_str()),
+            SUPPORTED_ROOM_VERSIONS
+                .iter()
+                .map(|version| format!("ver={}", version))
+                .collect::<Vec<_>>()
+                .join("&")
+        ),
+        None,
+    )
+    .await?;
+
+    // Servers that don't send a room version only support version 1
+    let room_version = make_join
+        .get("room_version")
+        .map_or(Ok(RoomVersionId::Version1), |room_version| {
+            serde_json::from_value(room_version.clone())
+        })
+        .map_err(|_| Error::BadServerResponse("Invalid room version in make_join response."))?;
+
+    if !SUPPORTED_ROOM_VERSIONS.contains(&room_version) {
+        return Err(Error::BadServerResponse(
+            "Room version of the room is not supported.",
+        ));
+    }
+
+    let mut join_json = make_join
+        .get("event")
+        .cloned()
+        .filter(|event| {
+            event["type"] == "m.room.member"
+                && event["room_id"] == room_id.as_str()
+                && event["sender"] == user_id.as_str()
+                && event["state_key"] == user_id.as_str()
+                && event["content"]["membership"] == "join"
+        })
+        .ok_or(Error::BadServerResponse(
+            "Invalid event template in make_join response.",
+        ))?;
+
+    // The resident server chose the prev and auth events, we fill in the rest
+    let object = join_json
+        .as_object_mut()
+        .expect("event template is an object");
+    for key in &["event_id", "hashes", "signatures", "unsigned"] {
+        object.remove(*key);
+    }
+    object.insert("origin".to_owned(), json!(db.globals.server_name()));
+    object.insert(
+        "origin_server_ts".to_owned(),
+        json!(utils::millis_since_unix_epoch()),
+    );
+    if let Some(displayname) = db.users.displayname(user_id)? {
+        join_json["content"]["displayname"] = json!(displayname);
+    }
+    if let Some(avatar_url) = db.users.avatar_url(user_id)? {
+        join_json["content"]["avatar_url"] = json!(avatar_url);
+    }
+
+    let uses_reference_hash_ids = pdu::uses_reference_hash_ids(&room_version);
+    if !uses_reference_hash_ids {
+        join_json["event_id"] = json!(format!(
+            "${}:{}",
+            utils::random_string(18),
+            db.globals.server_name()
+        ));
+    }
+
+    ruma::signatures::hash_and_sign_event(
+        db.globals.server_name().as_str(),
+        db.globals.keypair(),
+        &mut join_json,
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid event template in make_join response."))?;
+
+    let event_id = if uses_reference_hash_ids {
+        pdu::reference_hash_event_id(&room_version, &join_json)?
+    } else {
+        serde_json::from_value(join_json["event_id"].clone()).expect("we just set a valid event id")
+    };
+
+    let send_join = send_json_request(
+        &db.globals,
+        server,
+        http::Method::PUT,
+        &format!(
+            "/_matrix/federation/v1/send_join/{}/{}",
+            utils::percent_encode(room_id.as_str()),
+            utils::percent_encode(event_id.as_str())
+        ),
+        Some(&join_json),
+    )
+    .await?;
+
+    // The v1 response is [200, { ... }]
+    let send_join = send_join.get(1).cloned().unwrap_or(send_join);
+
+    // We keep the event id in our copy of the event so we can find it again
+    join_json["event_id"] = json!(event_id);
+    let join_pdu = serde_json::from_value::<PduEvent>(join_json.clone())
+        .map_err(|_| Error::BadServerResponse("Invalid event template in make_join response."))?;
+
+    let auth_chain = verify_pdus(&db.globals, &room_version, &send_join["auth_chain"]).await;
+    let state = verify_pdus(&db.globals, &room_version, &send_join["state"]).await;
+
+    db.rooms.join_remote_room(
+        &room_version,
+        &join_pdu,
+        &join_json,
+        auth_chain,
+        state,
+        &db.globals,
+    )
+}
+
+/// Sends an invite event to the server of the invited user, which has to sign it too. Returns
+/// the signatures of the event, including the new one.
+pub async fn send_invite(
+    db: &Database,
+    pdu: &PduEvent,
+    pdu_json: &serde_json::Value,
+) -> Result<serde_json::Value> {
+    let invited_user = pdu
+        .state_key
+        .as_deref()
+        .and_then(|state_key| UserId::try_from(state_key).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invite event has no valid state key.",
+        ))?;
+
+    let room_version = db.rooms.room_version(&pdu.room_id)?;
+
+    // Enough state for the invited user to see what room they are invited to
+    let invite_room_state = db
+        .rooms
+        .room_state_full(&pdu.room_id)?
+        .into_iter()
+        .filter(|((kind, state_key), _)| {
+            matches!(
+                kind,
+                EventType::RoomCreate
+                    | EventType::RoomJoinRules
+                    | EventType::RoomName
+                    | EventType::RoomCanonicalAlias
+                    | EventType::RoomAvatar
+                    | EventType::RoomEncryption
+            ) || (kind == &EventType::RoomMember && state_key == pdu.sender.as_str())
+        })
+        .map(|(_, pdu)| pdu.to_stripped_state_event())
+        .collect::<Vec<_>>();
+
+    let response = send_json_request(
+        &db.globals,
+        invited_user.server_name(),
+        http::Method::PUT,
+        &format!(
+            "/_matrix/federation/v2/invite/{}/{}",
+            utils::percent_encode(pdu.room_id.as_str()),
+            utils::percent_encode(pdu.event_id.as_str())
+        ),
+        Some(&json!({
+            "room_version": room_version,
+            "event": pdu::to_federation_json(&room_version, pdu_json.clone()),
+            "invite_room_state": invite_room_state,
+        })),
+    )
+    .await?;
+
+    let (event_id, verified) =
+        verify_pdu(&db.globals, &room_version, response["event"].clone()).await?;
+
+    let (_, signed_json) = verified.map_err(|e| {
+        warn!(
+            "Invalid invite event from {}: {}",
+            invited_user.server_name(),
+            e
+        );
+        Error::BadServerResponse("Server returned an invalid invite event.")
+    })?;
+
+    if event_id != pdu.event_id
+        || signed_json["signatures"]
+            .get(invited_user.server_name().as_str())
+            .is_none()
+    {
+        return Err(Error::BadServerResponse(
+            "Server returned an invalid invite event.",
+        ));
+    }
+
+    Ok(signed_json["signatures"].clone())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/query/directory", data = "<body>")
+)]
+pub fn get_room_information_route(
+    db: State<'_, Database>,
+    body: Ruma<get_room_information::Request>,
+) -> Result<Json<String>> {
+    let room_id = db
+        .rooms
+        .id_from_alias(&body.room_alias)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room with alias not found.",
+        ))?;
+
+    let servers = db.rooms.room_servers(&room_id)?;
+
+    Ok(Json(
+        json!({
+            "room_id": room_id,
+            "servers": servers,
+        })
+        .to_string(),
+    ))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/make_join/<_>/<_>", data = "<body>")
+)]
+pub fn make_join_route(
+    db: State<'_, Database>,
+    body: Ruma<create_join_event_template::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    if body.user_id.server_name() != &**origin {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Servers can only join their own users.",
+        ));
+    }
+
+    if !db
+        .rooms
+        .room_servers(&body.room_id)?
+        .contains(db.globals.server_name())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "This server is not in the room.",
+        ));
+    }
+
+    let room_version = db.rooms.room_version(&body.room_id)?;
+
+    // Servers that don't send versions only support version 1
+    if !body.ver.contains(&room_version)
+        && !(body.ver.is_empty() && room_version == RoomVersionId::Version1)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Room version is not supported by the joining server.",
+        ));
+    }
+
+    let (_, mut pdu_json) = db.rooms.build_pdu(
+        body.room_id.clone(),
+        body.user_id.clone(),
+        EventType::RoomMember,
+        json!({ "membership": "join" }),
+        None,
+        Some(body.user_id.to_string()),
+        None,
+        &db.globals,
+    )?;
+
+    // The joining server hashes and signs the event itself
+    let object = pdu_json.as_object_mut().expect("pdus are objects");
+    for key in &["event_id", "hashes", "signatures", "unsigned"] {
+        object.remove(*key);
+    }
+
+    Ok(Json(
+        json!({
+            "room_version": room_version,
+            "event": pdu_json,
+        })
+        .to_string(),
+    ))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/federation/v1/send_join/<_>/<_>", data = "<body>")
+)]
+pub async fn send_join_route(
+    db: State<'_, Database>,
+    body: Ruma<create_join_event::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    let pdu_json = body
+        .json_body
+        .as_ref()
+        .and_then(|json_body| serde_json::from_str::<serde_json::Value>(json_body.get()).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::BadJson,
+            "Event is not valid json.",
+        ))?;
+
+    let room_version = db.rooms.room_version(&body.room_id)?;
+
+    let (event_id, verified) = verify_pdu(&db.globals, &room_version, pdu_json).await?;
+    let (pdu, pdu_json) = verified.map_err(|e| {
+        warn!("Invalid join event {} from {}: {}", event_id, origin, e);
+        Error::BadRequest(ErrorKind::Forbidden, "Join event is invalid.")
+    })?;
+
+    if pdu.room_id != body.room_id
+        || pdu.kind != EventType::RoomMember
+        || pdu.state_key.as_deref() != Some(pdu.sender.as_str())
+        || pdu.sender.server_name() != &**origin
+        || pdu.content["membership"] != "join"
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event is not a join event of this server for this room.",
+        ));
+    }
+
+    fetch_state_before(&db, origin, &room_version, &pdu).await?;
+
+    db.rooms
+        .append_incoming_pdu(&pdu, &pdu_json, &db.globals, &db.account_data)?
+        .map_err(|e| {
+            warn!(
+                "Join event {} from {} is not allowed: {}",
+                event_id, origin, e
+            );
+            Error::BadRequest(ErrorKind::Forbidden, "Join event is not allowed.")
+        })?;
+
+    // Soft failed events are not added to the timeline, so the user didn't join
+    let pdu_id = db.rooms.get_pdu_id(&event_id)?.ok_or_else(|| {
+        warn!(
+            "Join event {} from {} is not allowed by the current state",
+            event_id, origin
+        );
+        Error::BadRequest(ErrorKind::Forbidden, "Join event is not allowed.")
+    })?;
+
+    // The other servers in the room need to know about the new member too
+    for server in db.rooms.room_servers(&pdu.room_id)? {
+        if &*server != db.globals.server_name() && server != *origin {
+            db.sending.send_pdu(&server, &pdu_id)?;
+        }
+    }
+
+    // The joining server needs the state before its join event
+    let state_ids = db
+        .rooms
+        .resolve_leaves(&pdu.room_id, &pdu.prev_events)?
+        .into_iter()
+        .map(|(_, event_id)| event_id)
+        .collect::<Vec<_>>();
+
+    let auth_chain_ids = db
+        .rooms
+        .get_auth_chain(state_ids.iter().chain(&pdu.auth_events).cloned().collect())?;
+
+    Ok(Json(
+        json!([
+            200,
+            {
+                "origin": db.globals.server_name(),
+                "auth_chain": federation_pdus(&db, &room_version, &auth_chain_ids)?,
+                "state": federation_pdus(&db, &room_version, &state_ids)?,
+            }
+        ])
+        .to_string(),
+    ))
+}
+
+/// Returns the json of the known events in the format other servers expect.
+fn federation_pdus<'a>(
+    db: &Database,
+    room_version: &RoomVersionId,
+    event_ids: impl IntoIterator<Item = &'a EventId>,
+) -> Result<Vec<serde_json::Value>> {
+    event_ids
+        .into_iter()
+        .filter_map(|event_id| db.rooms.get_pdu_json(event_id).transpose())
+        .map(|pdu_json| Ok(pdu::to_federation_json(room_version, pdu_json?)))
+        .collect()
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/federation/v2/invite/<_>/<_>", data = "<body>")
+)]
+pub async fn create_invite_route(
+    db: State<'_, Database>,
+    body: Ruma<create_invite::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    let request = body
+        .json_body
+        .as_ref()
+        .and_then(|json_body| serde_json::from_str::<serde_json::Value>(json_body.get()).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::BadJson,
+            "Invite is not valid json.",
+        ))?;
+
+    let room_version = serde_json::from_value::<RoomVersionId>(request["room_version"].clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid room version."))?;
+
+    if !SUPPORTED_ROOM_VERSIONS.contains(&room_version) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Room version is not supported by this server.",
+        ));
+    }
+
+    let (event_id, verified) =
+        verify_pdu(&db.globals, &room_version, request["event"].clone()).await?;
+    let (pdu, pdu_json) = verified.map_err(|e| {
+        warn!("Invalid invite event {} from {}: {}", event_id, origin, e);
+        Error::BadRequest(ErrorKind::Forbidden, "Invite event is invalid.")
+    })?;
+
+    let invited_user = pdu
+        .state_key
+        .as_deref()
+        .and_then(|state_key| UserId::try_from(state_key).ok())
+        .filter(|_| {
+            pdu.kind == EventType::RoomMember
+                && pdu.content["membership"] == "invite"
+                && pdu.sender.server_name() == &**origin
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event is not an invite event of this server.",
+        ))?;
+
+    if invited_user.server_name() != db.globals.server_name() || !db.users.exists(&invited_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Invited user does not exist.",
+        ));
+    }
+
+    // We sign the event too, so the other servers know that we got the invite
+    let mut signed_json = pdu::to_federation_json(&room_version, pdu_json);
+    ruma::signatures::hash_and_sign_event(
+        db.globals.server_name().as_str(),
+        db.globals.keypair(),
+        &mut signed_json,
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invite event is invalid."))?;
+
+    // If we are in the room, the invite will also arrive in a transaction
+    if !db
+        .rooms
+        .room_servers(&pdu.room_id)?
+        .contains(db.globals.server_name())
+    {
+        let mut invite_state = request["invite_room_state"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        invite_state.push(json!({
+            "type": pdu.kind,
+            "state_key": invited_user,
+            "sender": pdu.sender,
+            "content": pdu.content,
+        }));
+
+        db.rooms
+            .add_remote_invite(&pdu.room_id, &invited_user, &invite_state, &db.globals)?;
+    }
+
+    Ok(Json(json!({ "event": signed_json }).to_string()))
+}
+
+/// Sends a request to the other servers in the room until one of them answers.
+async fn request_from_room_servers(
+    db: &Database,
+    room_id: &RoomId,
+    method: http::Method,
+    path: &str,
+    body: Option<&serde_json::Value>,
+) -> Result<serde_json::Value> {
+    let mut last_error = Error::BadServerResponse("No other server is in the room.");
+
+    for server in db.rooms.room_servers(room_id)? {
+        if &*server == db.globals.server_name() {
+            continue;
+        }
+
+        match send_json_request(&db.globals, &server, method.clone(), path, body).await {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                warn!("Could not request {} from {}: {}", path, server, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Fetches up to `limit` older events of the room from the other servers in the room, starting at
+/// the events we are missing.
+pub async fn backfill(db: &Database, room_id: &RoomId, limit: usize) -> Result<()> {
+    let missing_events = db
+        .rooms
+        .missing_events(room_id)
+        .take(MAX_BACKFILL_EXTREMITIES)
+        .collect::<Result<Vec<_>>>()?;
+
+    // We already have the whole history
+    if missing_events.is_empty() {
+        return Ok(());
+    }
+
+    let room_version = db.rooms.room_version(room_id)?;
+
+    let response = request_from_room_servers(
+        db,
+        room_id,
+        http::Method::GET,
+        &format!(
+            "/_matrix/federation/v1/backfill/{}?{}&limit={}",
+            utils::percent_encode(room_id.as_str()),
+            missing_events
+                .iter()
+                .map(|event_id| format!("v={}", utils::percent_encode(event_id.as_str())))
+                .collect::<Vec<_>>()
+                .join("&"),
+            limit
+        ),
+        None,
+    )
+    .await?;
+
+    let pdus = verify_pdus(&db.globals, &room_version, &response["pdus"]).await;
+
+    let added = db
+        .rooms
+        .add_backfilled_pdus(&room_version, pdus, &db.globals)?;
+    save_backfilled_state(db, &room_version, added).await
+}
+
+/// Fetches an event we don't know and up to `limit` events before it from the other servers in
+/// the room.
+pub async fn fetch_event_with_history(
+    db: &Database,
+    room_id: &RoomId,
+    event_id: &EventId,
+    limit: usize,
+) -> Result<()> {
+    let room_version = db.rooms.room_version(room_id)?;
+
+    let event = request_from_room_servers(
+        db,
+        room_id,
+        http::Method::GET,
+        &format!(
+            "/_matrix/federation/v1/event/{}",
+            utils::percent_encode(event_id.as_str())
+        ),
+        None,
+    )
+    .await?;
+
+    let missing_events = request_from_room_servers(
+        db,
+        room_id,
+        http::Method::POST,
+        &format!(
+            "/_matrix/federation/v1/get_missing_events/{}",
+            utils::percent_encode(room_id.as_str())
+        ),
+        Some(&json!({
+            "earliest_events": db.rooms.get_pdu_leaves(room_id)?,
+            "latest_events": [event_id],
+            "limit": limit,
+            "min_depth": 0,
+        })),
+    )
+    .await
+    .unwrap_or_else(|e| {
+        warn!("Could not get events before {}: {}", event_id, e);
+        json!({})
+    });
+
+    let mut pdus = verify_pdus(&db.globals, &room_version, &event["pdus"]).await;
+    pdus.retain(|(pdu, _)| &pdu.event_id == event_id && &pdu.room_id == room_id);
+    pdus.extend(
+        verify_pdus(&db.globals, &room_version, &missing_events["events"])
+            .await
+            .into_iter()
+            .filter(|(pdu, _)| &pdu.room_id == room_id),
+    );
+
+    let added = db
+        .rooms
+        .add_backfilled_pdus(&room_version, pdus, &db.globals)?;
+    save_backfilled_state(db, &room_version, added).await
+}
+
+/// Saves the state after backfilled pdus. It's resolved from the state after the prev events if
+/// we know it, otherwise it's requested from the other servers in the room. If they can't tell
+/// us, the earliest state of the room we know is used.
+async fn save_backfilled_state(
+    db: &Database,
+    room_version: &RoomVersionId,
+    mut pdus: Vec<PduEvent>,
+) -> Result<()> {
+    // Oldest first, so the newer pdus can use the state of their prev events
+    pdus.sort_by_key(|pdu| pdu.depth);
+
+    for pdu in pdus {
+        if db.rooms.state_is_known(&pdu.event_id)?
+            || db.rooms.save_state_from_prev_events(&pdu, &db.globals)?
+        {
+            continue;
+        }
+
+        if let Err(e) =
+            fetch_remote_state(db, None, room_version, &pdu.room_id, &pdu.event_id).await
+        {
+            warn!("Could not fetch the state at {}: {}", pdu.event_id, e);
+            db.rooms.save_earliest_known_state(&pdu, &db.globals)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the ids of the state events before the event.
+///
+/// Fails if the state at the event or, for state events, at its prev events is unknown.
+fn state_ids_at(db: &Database, room_id: &RoomId, event_id: &EventId) -> Result<StateMap<EventId>> {
+    let pdu = match db.rooms.get_pdu(event_id)? {
+        Some(pdu) if &pdu.room_id == room_id => pdu,
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Event not found in this room.",
+            ))
+        }
+    };
+
+    // The state after state events contains the event itself
+    if pdu.state_key.is_some() {
+        return db.rooms.resolve_leaves(room_id, &pdu.prev_events);
+    }
+
+    db.rooms.state_at(event_id)?.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "The state at this event is unknown.",
+    ))
+}
+
+/// Makes sure the other server is allowed to see the room.
+fn check_server_in_room(db: &Database, origin: &ServerName, room_id: &RoomId) -> Result<()> {
+    if db.rooms.room_servers(room_id)?.contains(origin) {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server is not in the room.",
+        ))
+    }
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/backfill/<_>", data = "<body>")
+)]
+pub fn get_backfill_route(
+    db: State<'_, Database>,
+    body: Ruma<get_backfill::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    check_server_in_room(&db, origin, &body.room_id)?;
+
+    let limit = u64::from(body.limit).min(MAX_BACKFILL_LIMIT) as usize;
+    let room_version = db.rooms.room_version(&body.room_id)?;
+
+    // Walk back through the prev events, starting at the requested events
+    let mut todo = body.v.iter().cloned().collect::<VecDeque<_>>();
+    let mut seen = HashSet::new();
+    let mut pdus = Vec::new();
+    while let Some(event_id) = todo.pop_front() {
+        if pdus.len() >= limit {
+            break;
+        }
+
+        if !seen.insert(event_id.clone()) {
+            continue;
+        }
+
+        if let Some(pdu) = db.rooms.get_pdu(&event_id)? {
+            if pdu.room_id != body.room_id {
+                continue;
+            }
+
+            let pdu_json = db
+                .rooms
+                .get_pdu_json(&event_id)?
+                .ok_or_else(|| Error::bad_database("PDU disappeared."))?;
+            pdus.push(pdu::to_federation_json(&room_version, pdu_json));
+            todo.extend(pdu.prev_events);
+        }
+    }
+
+    Ok(Json(
+        json!({
+            "origin": db.globals.server_name(),
+            "origin_server_ts": utils::millis_since_unix_epoch(),
+            "pdus": pdus,
+        })
+        .to_string(),
+    ))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/event/<_>", data = "<body>")
+)]
+pub fn get_event_route(
+    db: State<'_, Database>,
+    body: Ruma<get_event::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    let pdu = db
+        .rooms
+        .get_pdu(&body.event_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+
+    check_server_in_room(&db, origin, &pdu.room_id)?;
+
+    let room_version = db.rooms.room_version(&pdu.room_id)?;
+
+    Ok(Json(
+        json!({
+            "origin": db.globals.server_name(),
+            "origin_server_ts": utils::millis_since_unix_epoch(),
+            "pdus": federation_pdus(&db, &room_version, &[body.event_id.clone()])?,
+        })
+        .to_string(),
+    ))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/state/<_>", data = "<body>")
+)]
+pub fn get_room_state_route(
+    db: State<'_, Database>,
+    body: Ruma<get_room_state::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    check_server_in_room(&db, origin, &body.room_id)?;
+
+    let room_version = db.rooms.room_version(&body.room_id)?;
+    let state_ids = state_ids_at(&db, &body.room_id, &body.event_id)?
+        .into_iter()
+        .map(|(_, event_id)| event_id)
+        .collect::<Vec<_>>();
+    let auth_chain_ids = db.rooms.get_auth_chain(state_ids.clone())?;
+
+    Ok(Json(
+        json!({
+            "pdus": federation_pdus(&db, &room_version, &state_ids)?,
+            "auth_chain": federation_pdus(&db, &room_version, &auth_chain_ids)?,
+        })
+        .to_string(),
+    ))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/state_ids/<_>", data = "<body>")
+)]
+pub fn get_room_state_ids_route(
+    db: State<'_, Database>,
+    body: Ruma<get_room_state_ids::Request>,
+) -> Result<Json<String>> {
+    let origin = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    check_server_in_room(&db, origin, &body.room_id)?;
+
+    let state_ids = state_ids_at(&db, &body.room_id, &body.event_id)?
+        .into_iter()
+        .map(|(_, event_id)| event_id)
+        .collect::<Vec<_>>();
+    let auth_chain_ids = db.rooms.get_auth_chain(state_ids.clone())?;
+
+    Ok(Json(
+        json!({
+            "pdu_ids": state_ids,
+            "auth_chain_ids": auth_chain_ids,
+        })
+        .to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use ruma::signatures::Ed25519KeyPair;
+    use std::sync::{atomic::Ordering, Arc, Mutex};
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    fn generate_keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::new(
+            &utils::generate_keypair(None).expect("generate_keypair always returns Some"),
+            "key1".to_owned(),
+        )
+        .expect("generated keypair is valid")
+    }
+
+    /// Starts a server that returns its keys: the current ed25519:key1 and ed25519:old, which
+    /// expired at `old_key_expired_ts`.
+    async fn key_server(old_key_expired_ts: u64) -> (Box<ServerName>, test_utils::StubRequests) {
+        // The server name contains the port, which is only known after the server started
+        let response = Arc::new(Mutex::new(String::new()));
+        let shared_response = response.clone();
+        let (address, requests) = test_utils::stub_https_server(move |_, _| {
+            (200, shared_response.lock().unwrap().clone())
+        })
+        .await;
+        let server_name = Box::<ServerName>::try_from(format!("127.0.0.1:{}", address.port()))
+            .expect("server name is valid");
+
+        let keypair = generate_keypair();
+        let mut keys = json!({
+            "server_name": server_name.as_str(),
+            "valid_until_ts": utils::millis_since_unix_epoch() + 24 * HOUR,
+            "verify_keys": {
+                "ed25519:key1": {
+                    "key": base64::encode_config(keypair.public_key(), base64::STANDARD_NO_PAD),
+                },
+            },
+            "old_verify_keys": {
+                "ed25519:old": { "key": "b2xkIGtleQ", "expired_ts": old_key_expired_ts },
+            },
+        });
+        ruma::signatures::sign_json(server_name.as_str(), &keypair, &mut keys)
+            .expect("keys are a json object");
+        *response.lock().unwrap() = keys.to_string();
+
+        (server_name, requests)
+    }
+
+    #[rocket::async_test]
+    async fn signing_keys_are_fetched_when_a_key_id_is_unknown() {
+        let db = Database::load_for_tests(&[]);
+        let now = utils::millis_since_unix_epoch();
+        let (origin, requests) = key_server(now - HOUR).await;
+
+        let mut cached = BTreeMap::new();
+        cached.insert(
+            "ed25519:cached".to_owned(),
+            ServerVerifyKey {
+                key: "Y2FjaGVkIGtleQ".to_owned(),
+                valid_until_ts: now + HOUR,
+                expired_ts: None,
+            },
+        );
+        db.globals.add_verify_keys(&origin, &cached).unwrap();
+
+        let keys = fetch_signing_keys(&db.globals, &origin, &["ed25519:cached".to_owned()], now)
+            .await
+            .expect("cached key is returned");
+        assert!(keys.contains_key("ed25519:cached"));
+        assert_eq!(requests.lock().unwrap().len(), 0);
+
+        let keys = fetch_signing_keys(&db.globals, &origin, &["ed25519:key1".to_owned()], now)
+            .await
+            .expect("server returns its keys");
+        assert!(keys.contains_key("ed25519:key1"));
+        assert!(keys.contains_key("ed25519:cached"));
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].0, "GET /_matrix/key/v2/server");
+        }
+
+        // The new key is cached now
+        fetch_signing_keys(&db.globals, &origin, &["ed25519:key1".to_owned()], now)
+            .await
+            .expect("key is cached");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn old_signing_keys_verify_signatures_made_before_they_expired() {
+        let db = Database::load_for_tests(&[]);
+        let now = utils::millis_since_unix_epoch();
+        let (origin, requests) = key_server(now - HOUR).await;
+        let old_key = ["ed25519:old".to_owned()];
+
+        let keys = fetch_signing_keys(&db.globals, &origin, &old_key, now - 2 * HOUR)
+            .await
+            .expect("server returns its keys");
+        assert!(keys.contains_key("ed25519:old"));
+        assert!(keys.contains_key("ed25519:key1"));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // The old key is kept in the cache
+        let keys = fetch_signing_keys(&db.globals, &origin, &old_key, now - 2 * HOUR)
+            .await
+            .expect("keys are cached");
+        assert!(keys.contains_key("ed25519:old"));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // But it can't verify signatures made after it expired
+        let keys = fetch_signing_keys(&db.globals, &origin, &old_key, now)
+            .await
+            .expect("current key is returned");
+        assert!(!keys.contains_key("ed25519:old"));
+        assert!(keys.contains_key("ed25519:key1"));
+    }
+
+    #[rocket::async_test]
+    async fn srv_lookups_prefer_the_lowest_priority_and_share_one_resolver() {
+        let (nameserver, queries) = test_utils::stub_dns_server(vec![
+            (10, 0, 1111, "backup.example.test."),
+            (5, 0, 2222, "primary.example.test."),
+        ])
+        .await;
+        let nameserver = nameserver.to_string();
+        let db = Database::load_for_tests(&[("dns_nameserver", &nameserver)]);
+
+        let (address, ttl) = query_srv_record(&db.globals, "example.test")
+            .await
+            .expect("stub returns SRV records");
+        assert_eq!(address, "primary.example.test:2222");
+        assert!(ttl <= Duration::from_secs(300));
+
+        let queries_after_first_lookup = queries.load(Ordering::SeqCst);
+        assert!(queries_after_first_lookup > 0);
+
+        // The second lookup is answered from the cache of the same resolver
+        let (address, _) = query_srv_record(&db.globals, "example.test")
+            .await
+            .expect("SRV records are cached");
+        assert_eq!(address, "primary.example.test:2222");
+        assert_eq!(queries.load(Ordering::SeqCst), queries_after_first_lookup);
+    }
+
+    #[rocket::async_test]
+    async fn requests_to_srv_targets_use_the_server_name_for_tls() {
+        let (address, requests, server_names) =
+            test_utils::stub_https_server_with_sni(|_, _| (200, "{}".to_owned())).await;
+        let (nameserver, _) =
+            test_utils::stub_dns_server(vec![(10, 0, address.port(), "localhost.")]).await;
+        let nameserver = nameserver.to_string();
+        let db = Database::load_for_tests(&[("dns_nameserver", &nameserver)]);
+        let server = Box::<ServerName>::try_from("example.test").expect("server name is valid");
+
+        let response = send_json_request(
+            &db.globals,
+            &server,
+            http::Method::GET,
+            "/_matrix/federation/v1/version",
+            None,
+        )
+        .await
+        .expect("SRV target answers");
+        assert_eq!(response, json!({}));
+
+        let destination = find_destination(&db.globals, &server)
+            .await
+            .expect("destination is cached");
+        assert_eq!(destination.address, format!("localhost:{}", address.port()));
+        assert_eq!(destination.host, "example.test");
+
+        // We connected to the SRV target, but asked for the certificate of the server name
+        assert_eq!(
+            requests.lock().unwrap()[0].0,
+            "GET /_matrix/federation/v1/version"
+        );
+        assert_eq!(
+            *server_names.lock().unwrap(),
+            vec![Some("example.test".to_owned())]
+        );
+    }
+
+    #[test]
+    fn changing_the_signing_key_version_retires_the_old_key() {
+        let path = std::env::temp_dir().join(format!("conduit-test-{}", utils::random_string(16)));
+        let path = path.to_str().expect("temporary directory is valid unicode");
+
+        let db = Database::load_for_tests(&[("database_path", path)]);
+        let key1 =
+            base64::encode_config(db.globals.keypair().public_key(), base64::STANDARD_NO_PAD);
+        assert!(db.globals.old_verify_keys().unwrap().is_empty());
+        drop(db);
+
+        let before_rotation = utils::millis_since_unix_epoch();
+        let rocket = test_utils::rocket(Database::load_for_tests(&[
+            ("database_path", path),
+            ("signing_key_version", "key2"),
+        ]));
+        let keys: serde_json::Value =
+            serde_json::from_str(&get_server_keys(State::from(&rocket).unwrap()).unwrap().0)
+                .unwrap();
+
+        assert!(keys["verify_keys"].get("ed25519:key1").is_none());
+        let key2 = keys["verify_keys"]["ed25519:key2"]["key"].clone();
+        assert!(key2.is_string());
+        assert_ne!(key2, key1.as_str());
+        assert_eq!(
+            keys["old_verify_keys"]["ed25519:key1"]["key"],
+            key1.as_str()
+        );
+        assert!(
+            keys["old_verify_keys"]["ed25519:key1"]["expired_ts"]
+                .as_u64()
+                .unwrap()
+                >= before_rotation
+        );
+        drop(rocket);
+
+        // Restarting with the same version keeps the key
+        let db =
+            Database::load_for_tests(&[("database_path", path), ("signing_key_version", "key2")]);
+        assert_eq!(
+            base64::encode_config(db.globals.keypair().public_key(), base64::STANDARD_NO_PAD),
+            key2.as_str().unwrap()
+        );
+        assert_eq!(db.globals.old_verify_keys().unwrap().len(), 1);
+    }
+
+    /// Starts a remote server with a public room that the local user bob joined.
+    async fn remote_room(db: &Database) -> (test_utils::RemoteServer, UserId, UserId) {
+        let remote = test_utils::RemoteServer::start().await;
+        remote.trust(db);
+        let alice = remote.create_room();
+        let bob = UserId::try_from("@bob:localhost").expect("user id is valid");
+        remote.join(db, &bob);
+
+        (remote, alice, bob)
+    }
+
+    /// Sends a transaction from the remote server and returns the results of the pdus.
+    async fn send_transaction(
+        db: &Database,
+        remote: &test_utils::RemoteServer,
+        pdus: Vec<serde_json::Value>,
+        edus: Vec<serde_json::Value>,
+    ) -> serde_json::Value {
+        let transaction = json!({
+            "origin": remote.server_name.as_str(),
+            "origin_server_ts": utils::millis_since_unix_epoch(),
+            "pdus": pdus,
+            "edus": edus,
+        });
+
+        handle_transaction(db, &remote.server_name, &transaction).await["pdus"].take()
+    }
+
+    fn message(remote: &test_utils::RemoteServer, sender: &UserId, body: &str) -> EventId {
+        let content = json!({ "msgtype": "m.text", "body": body });
+        remote.send(sender, "m.room.message", None, content)
+    }
+
+    #[rocket::async_test]
+    async fn transactions_report_which_pdus_were_not_accepted() {
+        let db = Database::load_for_tests(&[]);
+        let (remote, alice, _) = remote_room(&db).await;
+
+        let accepted = message(&remote, &alice, "hello");
+        // Mallory never joined the room
+        let rejected = message(&remote, &remote.user("mallory"), "spam");
+        let forged = message(&remote, &alice, "forged");
+        let mut forged_json = remote.federation_json(&forged);
+        forged_json["signatures"] = remote.federation_json(&accepted)["signatures"].clone();
+
+        let results = send_transaction(
+            &db,
+            &remote,
+            vec![
+                remote.federation_json(&accepted),
+                remote.federation_json(&rejected),
+                forged_json,
+            ],
+            Vec::new(),
+        )
+        .await;
+
+        assert_eq!(results[accepted.as_str()], json!({}));
+        assert!(db.rooms.get_pdu_id(&accepted).unwrap().is_some());
+        assert_eq!(
+            db.rooms.get_pdu_leaves(&remote.room_id).unwrap(),
+            vec![accepted.clone()]
+        );
+
+        assert!(results[rejected.as_str()]["error"].is_string());
+        assert!(db.rooms.rejection_reason(&rejected).unwrap().is_some());
+        assert!(db.rooms.get_pdu_id(&rejected).unwrap().is_none());
+
+        assert!(results[forged.as_str()]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid signature"));
+        assert!(db.rooms.get_pdu_json(&forged).unwrap().is_none());
+
+        // Sending the same events again gives the same results
+        let results_again = send_transaction(
+            &db,
+            &remote,
+            vec![
+                remote.federation_json(&accepted),
+                remote.federation_json(&rejected),
+            ],
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(results_again[accepted.as_str()], json!({}));
+        assert_eq!(results_again[rejected.as_str()], results[rejected.as_str()]);
+    }
+
+    #[rocket::async_test]
+    async fn events_only_forbidden_by_the_current_state_are_soft_failed() {
+        let db = Database::load_for_tests(&[]);
+        let (remote, alice, _) = remote_room(&db).await;
+
+        let carol = remote.user("carol");
+        let join = remote.send(
+            &carol,
+            "m.room.member",
+            Some(carol.as_str()),
+            json!({ "membership": "join" }),
+        );
+        let ban = remote.send(
+            &alice,
+            "m.room.member",
+            Some(carol.as_str()),
+            json!({ "membership": "ban" }),
+        );
+        let results = send_transaction(
+            &db,
+            &remote,
+            vec![remote.federation_json(&join), remote.federation_json(&ban)],
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(results[join.as_str()], json!({}));
+        assert_eq!(results[ban.as_str()], json!({}));
+        assert!(!db.rooms.is_joined(&carol, &remote.room_id).unwrap());
+
+        // Carol sends a message without knowing about the ban
+        remote.branch_from(&join);
+        let soft_failed = message(&remote, &carol, "I'm still here");
+        let results = send_transaction(
+            &db,
+            &remote,
+            vec![remote.federation_json(&soft_failed)],
+            Vec::new(),
+        )
+        .await;
+
+        // Soft failed events are not errors, the sender did nothing wrong
+        assert_eq!(results[soft_failed.as_str()], json!({}));
+        assert!(db.rooms.is_soft_failed(&soft_failed).unwrap());
+        assert!(db.rooms.rejection_reason(&soft_failed).unwrap().is_none());
+        assert!(db.rooms.get_pdu_id(&soft_failed).unwrap().is_none());
+        assert_eq!(db.rooms.get_pdu_leaves(&remote.room_id).unwrap(), vec![ban]);
+    }
+
+    #[rocket::async_test]
+    async fn servers_can_only_send_edus_for_their_own_users() {
+        let db = Database::load_for_tests(&[]);
+        let (remote, alice, bob) = remote_room(&db).await;
+        let hello = message(&remote, &alice, "hello");
+        send_transaction(
+            &db,
+            &remote,
+            vec![remote.federation_json(&hello)],
+            Vec::new(),
+        )
+        .await;
+
+        let typing = |user_id: &UserId| {
+            json!({
+                "edu_type": "m.typing",
+                "content": { "room_id": remote.room_id, "user_id": user_id, "typing": true },
+            })
+        };
+        let receipt = |user_id: &UserId| {
+            json!({
+                "edu_type": "m.receipt",
+                "content": {
+                    remote.room_id.as_str(): {
+                        "m.read": {
+                            user_id.as_str(): { "event_ids": [hello], "data": { "ts": 1 } },
+                        },
+                    },
+                },
+            })
+        };
+        send_transaction(
+            &db,
+            &remote,
+            Vec::new(),
+            vec![typing(&alice), typing(&bob), receipt(&alice), receipt(&bob)],
+        )
+        .await;
+
+        let typing_users = db
+            .rooms
+            .edus
+            .roomactives_all(&remote.room_id)
+            .unwrap()
+            .content
+            .user_ids;
+        assert_eq!(typing_users, vec![alice.clone()]);
+
+        let receipts = db
+            .rooms
+            .edus
+            .roomlatests_since(&remote.room_id, 0)
+            .unwrap()
+            .map(|receipt| receipt.unwrap().json().get().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts[0].contains(alice.as_str()));
+        assert!(!receipts[0].contains(bob.as_str()));
+    }
+}
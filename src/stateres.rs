@@ -1,59 +1,726 @@
-use std::collections::HashMap;
+use crate::{event_auth, Error, PduEvent, Result};
+use js_int::{Int, UInt};
+use ruma::{
+    events::{
+        room::{create::CreateEventContent, power_levels::PowerLevelsEventContent},
+        EventType,
+    },
+    EventId, Raw, RoomVersionId,
+};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
 
-fn stateres(state_a: HashMap<StateTuple, PduEvent>, state_b: HashMap<StateTuple, PduEvent>) {
-    let mut unconflicted = todo!("state at fork event");
+/// Maps (event type, state key) to a value, for example an event id or a pdu.
+pub type StateMap<T> = HashMap<(EventType, String), T>;
 
-    let mut conflicted: HashMap<StateTuple, PduEvent> = state_a
+/// Resolves multiple forks of room state into one state using the state resolution v2
+/// algorithm.
+///
+/// `fetch_event` is used to look up all events that are referenced by the state sets and their
+/// auth chains. Events that can't be found are ignored.
+pub fn resolve<F>(
+    room_version: &RoomVersionId,
+    state_sets: &[StateMap<EventId>],
+    fetch_event: F,
+) -> Result<StateMap<EventId>>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    if state_sets.len() <= 1 {
+        return Ok(state_sets.first().cloned().unwrap_or_default());
+    }
+
+    let (unconflicted, conflicted) = separate(state_sets);
+
+    if conflicted.is_empty() {
+        return Ok(unconflicted);
+    }
+
+    // Load all state events and their auth chains
+    let mut event_map = HashMap::new();
+    let mut auth_chains = Vec::new();
+    for state_set in state_sets {
+        auth_chains.push(load_auth_chain(
+            state_set.values(),
+            &fetch_event,
+            &mut event_map,
+        )?);
+    }
+
+    // The full conflicted set is the union of the conflicted events and the auth difference
+    let full_conflicted = conflicted
+        .values()
+        .flatten()
+        .cloned()
+        .chain(auth_chain_difference(&auth_chains))
+        .filter(|event_id| event_map.contains_key(event_id))
+        .collect::<HashSet<_>>();
+
+    // Control events (and the parts of their auth chains that are also conflicted) are resolved
+    // first, because they decide what other events are allowed
+    let mut control_events = HashSet::new();
+    for event_id in full_conflicted
         .iter()
-        .filter(|(key_a, value_a)| match state_b.remove(key_a) {
-            Some(value_b) if value_a == value_b => unconflicted.insert(key_a, value_a),
-            _ => false,
-        })
-        .collect();
+        .filter(|event_id| is_power_event(&event_map[*event_id]))
+    {
+        control_events.insert(event_id.clone());
+        control_events.extend(
+            auth_chain(event_id, &event_map)
+                .into_iter()
+                .filter(|auth_event_id| full_conflicted.contains(auth_event_id)),
+        );
+    }
+
+    let sorted_control_events = reverse_topological_power_sort(&control_events, &event_map);
+    let resolved = iterative_auth_check(
+        room_version,
+        &sorted_control_events,
+        unconflicted.clone(),
+        &event_map,
+    )?;
+
+    // All other events are ordered by their position relative to the resolved power levels
+    let other_events = full_conflicted
+        .into_iter()
+        .filter(|event_id| !control_events.contains(event_id))
+        .collect::<Vec<_>>();
+
+    let sorted_other_events = mainline_sort(
+        other_events,
+        resolved.get(&(EventType::RoomPowerLevels, "".to_owned())),
+        &event_map,
+    );
+    let mut resolved =
+        iterative_auth_check(room_version, &sorted_other_events, resolved, &event_map)?;
 
-    // We removed unconflicted from state_b, now we can easily insert all events that are only in fork b
-    conflicted.extend(state_b);
+    // The unconflicted state always wins
+    resolved.extend(unconflicted);
 
-    let partial_state = unconflicted.clone();
+    Ok(resolved)
+}
 
-    let full_conflicted = conflicted.clone(); // TODO: auth events
+/// Splits the state sets into the unconflicted state map and a map of all conflicting event ids.
+fn separate(state_sets: &[StateMap<EventId>]) -> (StateMap<EventId>, StateMap<HashSet<EventId>>) {
+    let mut unconflicted = StateMap::new();
+    let mut conflicted = StateMap::new();
 
-    let output_rev = Vec::new();
-    let event_map = HashMap::new();
-    let incoming_edges = HashMap::new();
+    let all_keys = state_sets
+        .iter()
+        .flat_map(|state_set| state_set.keys())
+        .collect::<HashSet<_>>();
 
-    for event in full_conflicted {
-        event_map.insert(event.event_id, event);
-        incoming_edges.insert(event.event_id, 0);
+    for key in all_keys {
+        let event_ids = state_sets
+            .iter()
+            .map(|state_set| state_set.get(key))
+            .collect::<Vec<_>>();
+
+        let first = event_ids[0];
+        if first.is_some() && event_ids.iter().all(|event_id| *event_id == first) {
+            unconflicted.insert(
+                key.clone(),
+                first.expect("we checked that it's some").clone(),
+            );
+        } else {
+            conflicted.insert(
+                key.clone(),
+                event_ids.into_iter().flatten().cloned().collect(),
+            );
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
+/// Fetches the given events and their full auth chains into `event_map` and returns the union of
+/// their auth chains.
+fn load_auth_chain<'a, F>(
+    event_ids: impl Iterator<Item = &'a EventId>,
+    fetch_event: &F,
+    event_map: &mut HashMap<EventId, PduEvent>,
+) -> Result<HashSet<EventId>>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    let mut chain = HashSet::new();
+    let mut todo = Vec::new();
+
+    for event_id in event_ids {
+        if let Some(pdu) = get_or_fetch(event_id, fetch_event, event_map)? {
+            todo.extend(pdu.auth_events.iter().cloned());
+        }
     }
 
-    for e in conflicted_control_events {
-        for a in e.auth_events {
-            incoming_edges[a.event_id] += 1;
+    while let Some(event_id) = todo.pop() {
+        if !chain.insert(event_id.clone()) {
+            continue;
+        }
+
+        if let Some(pdu) = get_or_fetch(&event_id, fetch_event, event_map)? {
+            todo.extend(
+                pdu.auth_events
+                    .iter()
+                    .filter(|auth_event_id| !chain.contains(*auth_event_id))
+                    .cloned(),
+            );
+        }
+    }
+
+    Ok(chain)
+}
+
+fn get_or_fetch<'a, F>(
+    event_id: &EventId,
+    fetch_event: &F,
+    event_map: &'a mut HashMap<EventId, PduEvent>,
+) -> Result<Option<&'a PduEvent>>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    if !event_map.contains_key(event_id) {
+        if let Some(pdu) = fetch_event(event_id)? {
+            event_map.insert(event_id.clone(), pdu);
         }
     }
 
-    while incoming_edges.len() > 0 {
-        let mut count_0 = incoming_edges
+    Ok(event_map.get(event_id))
+}
+
+/// Returns the auth chain of an event that was already loaded into `event_map`.
+fn auth_chain(event_id: &EventId, event_map: &HashMap<EventId, PduEvent>) -> HashSet<EventId> {
+    let mut chain = HashSet::new();
+    let mut todo = event_map
+        .get(event_id)
+        .map_or_else(Vec::new, |pdu| pdu.auth_events.clone());
+
+    while let Some(event_id) = todo.pop() {
+        if chain.insert(event_id.clone()) {
+            if let Some(pdu) = event_map.get(&event_id) {
+                todo.extend(pdu.auth_events.iter().cloned());
+            }
+        }
+    }
+
+    chain
+}
+
+/// Returns all events that are in some, but not all auth chains.
+fn auth_chain_difference(auth_chains: &[HashSet<EventId>]) -> HashSet<EventId> {
+    let union = auth_chains.iter().flatten().collect::<HashSet<_>>();
+
+    union
+        .into_iter()
+        .filter(|event_id| !auth_chains.iter().all(|chain| chain.contains(*event_id)))
+        .cloned()
+        .collect()
+}
+
+/// Power events are events that can remove permissions of other users.
+fn is_power_event(pdu: &PduEvent) -> bool {
+    match pdu.kind {
+        EventType::RoomPowerLevels | EventType::RoomJoinRules | EventType::RoomCreate => {
+            pdu.state_key.as_deref() == Some("")
+        }
+        EventType::RoomMember => {
+            matches!(
+                pdu.content.get("membership").and_then(|m| m.as_str()),
+                Some("leave") | Some("ban")
+            ) && pdu.state_key.as_ref() != Some(&pdu.sender.to_string())
+        }
+        _ => false,
+    }
+}
+
+/// Returns the power level of the sender of this event, according to its auth events.
+fn sender_power_level(pdu: &PduEvent, event_map: &HashMap<EventId, PduEvent>) -> Int {
+    let mut power_levels = None;
+    let mut create = None;
+
+    for auth_event in pdu
+        .auth_events
+        .iter()
+        .filter_map(|event_id| event_map.get(event_id))
+    {
+        match auth_event.kind {
+            EventType::RoomPowerLevels => power_levels = Some(auth_event),
+            EventType::RoomCreate => create = Some(auth_event),
+            _ => {}
+        }
+    }
+
+    if let Some(power_levels) = power_levels.and_then(|pdu| {
+        serde_json::from_value::<Raw<PowerLevelsEventContent>>(pdu.content.clone())
+            .expect("Raw::from_value always works")
+            .deserialize()
+            .ok()
+    }) {
+        power_levels
+            .users
+            .get(&pdu.sender)
+            .cloned()
+            .unwrap_or(power_levels.users_default)
+    } else if create
+        .and_then(|pdu| {
+            serde_json::from_value::<Raw<CreateEventContent>>(pdu.content.clone())
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .ok()
+        })
+        .filter(|create| create.creator == pdu.sender)
+        .is_some()
+    {
+        // Without a power levels event, the room creator has all the power
+        100.into()
+    } else {
+        0.into()
+    }
+}
+
+/// Sorts the events so that every event comes after its auth events. Ties are broken by the
+/// sender's power level (descending), the origin_server_ts and the event id.
+fn reverse_topological_power_sort(
+    events: &HashSet<EventId>,
+    event_map: &HashMap<EventId, PduEvent>,
+) -> Vec<EventId> {
+    let mut dependency_counts = HashMap::new();
+    let mut dependents = HashMap::<_, Vec<_>>::new();
+
+    for event_id in events {
+        let dependencies = event_map[event_id]
+            .auth_events
             .iter()
-            .filter(|(_, c)| c == 0)
+            .filter(|auth_event_id| events.contains(*auth_event_id))
             .collect::<Vec<_>>();
 
-        count_0.sort_by(|(x, _), (y, _)| {
-            x.power_level
-                .cmp(&a.power_level)
-                .then_with(|| x.origin_server.ts.cmp(&y.origin_server_ts))
-                .then_with(|| x.event_id.cmp(&y.event_id))
-        });
+        dependency_counts.insert(event_id.clone(), dependencies.len());
+
+        for dependency in dependencies {
+            dependents
+                .entry(dependency.clone())
+                .or_default()
+                .push(event_id.clone());
+        }
+    }
+
+    let sort_key = |event_id: &EventId| -> (Reverse<Int>, UInt, String) {
+        let pdu = &event_map[event_id];
+        (
+            Reverse(sender_power_level(pdu, event_map)),
+            pdu.origin_server_ts,
+            event_id.to_string(),
+        )
+    };
+
+    let mut ready = dependency_counts
+        .iter()
+        .filter(|(_, &count)| count == 0)
+        .map(|(event_id, _)| Reverse((sort_key(event_id), event_id.clone())))
+        .collect::<BinaryHeap<_>>();
+
+    let mut sorted = Vec::new();
+    while let Some(Reverse((_, event_id))) = ready.pop() {
+        for dependent in dependents.get(&event_id).into_iter().flatten() {
+            let count = dependency_counts
+                .get_mut(dependent)
+                .expect("all dependents are in the event set");
+            *count -= 1;
+            if *count == 0 {
+                ready.push(Reverse((sort_key(dependent), dependent.clone())));
+            }
+        }
+
+        sorted.push(event_id);
+    }
+
+    sorted
+}
+
+/// Sorts the events by their closest ancestor on the mainline of the given power levels event,
+/// then by origin_server_ts and then by event id.
+fn mainline_sort(
+    events: Vec<EventId>,
+    power_levels: Option<&EventId>,
+    event_map: &HashMap<EventId, PduEvent>,
+) -> Vec<EventId> {
+    // The mainline is the chain of power level events, starting at the resolved one
+    let mut mainline = Vec::new();
+    let mut current = power_levels.cloned();
+    while let Some(event_id) = current {
+        current = previous_power_levels(&event_id, event_map);
+        mainline.push(event_id);
+    }
 
-        for (id, count) in count_0 {
-            output_rev.push(event_map[id]);
+    // Position 0 is the oldest power levels event
+    let mainline_positions = mainline
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(position, event_id)| (event_id, position))
+        .collect::<HashMap<_, _>>();
 
-            for auth_event in event_map[id].auth_events {
-                incoming_edges[auth_event.event_id] -= 1;
+    let mainline_position = |event_id: &EventId| {
+        let mut current = Some(event_id.clone());
+        while let Some(event_id) = current {
+            if let Some(position) = mainline_positions.get(&event_id) {
+                return Some(*position);
             }
+            current = previous_power_levels(&event_id, event_map);
+        }
+        None
+    };
+
+    let mut events = events
+        .into_iter()
+        .map(|event_id| {
+            let pdu = &event_map[&event_id];
+            (
+                (
+                    mainline_position(&event_id),
+                    pdu.origin_server_ts,
+                    event_id.to_string(),
+                ),
+                event_id,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    events.sort_by(|(a, _), (b, _)| a.cmp(b));
 
-            incoming_edges.remove(id);
+    events.into_iter().map(|(_, event_id)| event_id).collect()
+}
+
+/// Returns the power levels event in the auth events of this event.
+fn previous_power_levels(
+    event_id: &EventId,
+    event_map: &HashMap<EventId, PduEvent>,
+) -> Option<EventId> {
+    event_map
+        .get(event_id)?
+        .auth_events
+        .iter()
+        .find_map(|auth_event_id| {
+            event_map
+                .get(auth_event_id)
+                .filter(|pdu| pdu.kind == EventType::RoomPowerLevels)
+                .map(|_| auth_event_id.clone())
+        })
+}
+
+/// Goes through the events in order and adds every event to the state that passes the auth
+/// checks against its auth events and the state resolved so far.
+fn iterative_auth_check(
+    room_version: &RoomVersionId,
+    events: &[EventId],
+    mut state: StateMap<EventId>,
+    event_map: &HashMap<EventId, PduEvent>,
+) -> Result<StateMap<EventId>> {
+    for event_id in events {
+        let pdu = &event_map[event_id];
+        let state_key = pdu
+            .state_key
+            .clone()
+            .ok_or_else(|| Error::bad_database("Room state contains event without state_key."))?;
+
+        let auth_types = event_auth::auth_types_for_event(
+            &pdu.kind,
+            &pdu.sender,
+            Some(&state_key),
+            &pdu.content,
+        );
+
+        let mut auth_state = StateMap::new();
+        for auth_event in pdu
+            .auth_events
+            .iter()
+            .filter_map(|auth_event_id| event_map.get(auth_event_id))
+        {
+            if let Some(auth_state_key) = &auth_event.state_key {
+                let key = (auth_event.kind.clone(), auth_state_key.clone());
+                if auth_types.contains(&key) {
+                    auth_state.insert(key, auth_event.clone());
+                }
+            }
+        }
+
+        // The state resolved so far overrides the auth events
+        for key in auth_types {
+            if let Some(auth_event) = state.get(&key).and_then(|id| event_map.get(id)) {
+                auth_state.insert(key, auth_event.clone());
+            }
+        }
+
+        if event_auth::auth_check(room_version, pdu, &auth_state).is_ok() {
+            state.insert((pdu.kind.clone(), state_key), event_id.clone());
         }
     }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{event_id, pdu};
+    use serde_json::json;
+
+    const ALICE: &str = "@alice:a.test";
+    const BOB: &str = "@bob:a.test";
+    const CHARLIE: &str = "@charlie:a.test";
+
+    fn event(
+        id: &str,
+        sender: &str,
+        kind: &str,
+        state_key: &str,
+        content: serde_json::Value,
+        auth_events: &[&str],
+        origin_server_ts: u32,
+    ) -> PduEvent {
+        PduEvent {
+            auth_events: auth_events.iter().map(|id| event_id(id)).collect(),
+            origin_server_ts: origin_server_ts.into(),
+            ..pdu(id, sender, kind, Some(state_key), content)
+        }
+    }
+
+    /// Alice created the room and made bob a moderator, bob and charlie joined.
+    fn room() -> Vec<PduEvent> {
+        vec![
+            event(
+                "$create:a.test",
+                ALICE,
+                "m.room.create",
+                "",
+                json!({ "creator": ALICE }),
+                &[],
+                1,
+            ),
+            event(
+                "$alice:a.test",
+                ALICE,
+                "m.room.member",
+                ALICE,
+                json!({ "membership": "join" }),
+                &["$create:a.test"],
+                2,
+            ),
+            event(
+                "$power_levels:a.test",
+                ALICE,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100, BOB: 50 }, "state_default": 50 }),
+                &["$create:a.test", "$alice:a.test"],
+                3,
+            ),
+            event(
+                "$join_rules:a.test",
+                ALICE,
+                "m.room.join_rules",
+                "",
+                json!({ "join_rule": "public" }),
+                &["$create:a.test", "$alice:a.test", "$power_levels:a.test"],
+                4,
+            ),
+            event(
+                "$bob:a.test",
+                BOB,
+                "m.room.member",
+                BOB,
+                json!({ "membership": "join" }),
+                &[
+                    "$create:a.test",
+                    "$power_levels:a.test",
+                    "$join_rules:a.test",
+                ],
+                5,
+            ),
+            event(
+                "$charlie:a.test",
+                CHARLIE,
+                "m.room.member",
+                CHARLIE,
+                json!({ "membership": "join" }),
+                &[
+                    "$create:a.test",
+                    "$power_levels:a.test",
+                    "$join_rules:a.test",
+                ],
+                6,
+            ),
+        ]
+    }
+
+    fn state_set(events: &[PduEvent]) -> StateMap<EventId> {
+        events
+            .iter()
+            .map(|pdu| {
+                (
+                    (pdu.kind.clone(), pdu.state_key.clone().unwrap()),
+                    pdu.event_id.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// Resolves the forks, which are the room state plus some events each.
+    fn resolve_forks(forks: &[Vec<PduEvent>]) -> StateMap<EventId> {
+        let room = room();
+        let events = room
+            .iter()
+            .chain(forks.iter().flatten())
+            .map(|pdu| (pdu.event_id.clone(), pdu.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let state_sets = forks
+            .iter()
+            .map(|fork| state_set(&room.iter().chain(fork).cloned().collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        resolve(&RoomVersionId::Version6, &state_sets, |event_id| {
+            Ok(events.get(event_id).cloned())
+        })
+        .expect("all events are known")
+    }
+
+    fn resolved_event(state: &StateMap<EventId>, kind: EventType, state_key: &str) -> String {
+        state[&(kind, state_key.to_owned())].to_string()
+    }
+
+    #[test]
+    fn unconflicted_state_is_kept() {
+        let state = state_set(&room());
+
+        let resolved = resolve(
+            &RoomVersionId::Version6,
+            &[state.clone(), state.clone()],
+            |_| panic!("events without conflicts don't have to be loaded"),
+        )
+        .expect("state has no conflicts");
+
+        assert_eq!(resolved, state);
+    }
+
+    #[test]
+    fn changes_of_the_demoted_user_are_rejected() {
+        // Alice demotes bob, while bob changes the topic in another fork
+        let resolved = resolve_forks(&[
+            vec![event(
+                "$demotion:a.test",
+                ALICE,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100 }, "state_default": 50 }),
+                &["$create:a.test", "$alice:a.test", "$power_levels:a.test"],
+                10,
+            )],
+            vec![event(
+                "$topic:a.test",
+                BOB,
+                "m.room.topic",
+                "",
+                json!({ "topic": "Bob's room" }),
+                &["$create:a.test", "$power_levels:a.test", "$bob:a.test"],
+                20,
+            )],
+        ]);
+
+        assert_eq!(
+            resolved_event(&resolved, EventType::RoomPowerLevels, ""),
+            "$demotion:a.test"
+        );
+        assert!(!resolved.contains_key(&(EventType::RoomTopic, "".to_owned())));
+    }
+
+    #[test]
+    fn ban_wins_against_the_banned_join() {
+        let join = event(
+            "$join:a.test",
+            "@dave:a.test",
+            "m.room.member",
+            "@dave:a.test",
+            json!({ "membership": "join" }),
+            &[
+                "$create:a.test",
+                "$power_levels:a.test",
+                "$join_rules:a.test",
+            ],
+            10,
+        );
+        let ban = event(
+            "$ban:a.test",
+            ALICE,
+            "m.room.member",
+            "@dave:a.test",
+            json!({ "membership": "ban" }),
+            &[
+                "$create:a.test",
+                "$alice:a.test",
+                "$power_levels:a.test",
+                "$join:a.test",
+            ],
+            11,
+        );
+
+        let resolved = resolve_forks(&[vec![ban.clone()], vec![join.clone()]]);
+        assert_eq!(
+            resolved_event(&resolved, EventType::RoomMember, "@dave:a.test"),
+            "$ban:a.test"
+        );
+
+        // The order of the forks doesn't matter
+        let resolved = resolve_forks(&[vec![join], vec![ban]]);
+        assert_eq!(
+            resolved_event(&resolved, EventType::RoomMember, "@dave:a.test"),
+            "$ban:a.test"
+        );
+    }
+
+    #[test]
+    fn events_are_ordered_by_their_power_levels_before_their_timestamp() {
+        let promotion = event(
+            "$promotion:a.test",
+            ALICE,
+            "m.room.power_levels",
+            "",
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 50 }, "state_default": 50 }),
+            &["$create:a.test", "$alice:a.test", "$power_levels:a.test"],
+            10,
+        );
+
+        // The second topic is based on newer power levels, but was sent earlier
+        let resolved = resolve_forks(&[
+            vec![
+                promotion.clone(),
+                event(
+                    "$topic_1:a.test",
+                    ALICE,
+                    "m.room.topic",
+                    "",
+                    json!({ "topic": "First" }),
+                    &["$create:a.test", "$alice:a.test", "$power_levels:a.test"],
+                    30,
+                ),
+            ],
+            vec![
+                promotion,
+                event(
+                    "$topic_2:a.test",
+                    ALICE,
+                    "m.room.topic",
+                    "",
+                    json!({ "topic": "Second" }),
+                    &["$create:a.test", "$alice:a.test", "$promotion:a.test"],
+                    20,
+                ),
+            ],
+        ]);
+
+        assert_eq!(
+            resolved_event(&resolved, EventType::RoomTopic, ""),
+            "$topic_2:a.test"
+        );
+    }
 }
@@ -2,7 +2,7 @@ use argon2::{Config, Variant};
 use rand::prelude::*;
 use std::{
     convert::TryInto,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 pub fn millis_since_unix_epoch() -> u64 {
@@ -49,6 +49,34 @@ pub fn random_string(length: usize) -> String {
         .collect()
 }
 
+/// Returns how long to wait before retrying something that failed `failures` times in a row. The
+/// wait starts at `initial` and doubles after every failure, up to `max`.
+pub fn backoff_duration(failures: u32, initial: Duration, max: Duration) -> Duration {
+    initial
+        .checked_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+        .map_or(max, |duration| duration.min(max))
+}
+
+/// Splits text into the lowercase words that are saved in the search index.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        // Very long words are most likely not something anyone searches for
+        .filter(|word| !word.is_empty() && word.len() <= 50)
+        .map(str::to_lowercase)
+}
+
+/// Percent-encodes a string so it can be used as one segment of a url path or query.
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
 /// Calculate a new hash for the given password
 pub fn calculate_hash(password: &str) -> Result<String, argon2::Error> {
     let hashing_config = Config {